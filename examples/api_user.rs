@@ -72,24 +72,18 @@ fn add(client: &mut Client, args: Vec<String>) {
             return
         }
     };
-    match client.call(URI::new("ca.test.add"), Some(vec![Value::Integer(a), Value::Integer(b)]), None).unwrap().await() {
+    match client.call(URI::new("ca.test.add"), Some(vec![Value::Integer(a), Value::Integer(b)]), None).unwrap().wait() {
         Ok((args, _)) => {
             println!("Result: {}", args.get_int(0).unwrap().unwrap());
         } Err(e) => {
-            match e.take() {
-                Some(e) => {
-                    println!("Error: {:?}", e);
-                } None => {
-                    println!("Aborted");
-                }
-            }
+            println!("Error: {:?}", e);
         }
     }
 }
 
 fn echo(client: &mut Client, args: Vec<String>) {
     let args = args.into_iter().map(|arg| {Value::String(arg)}).collect();
-    let result = client.call(URI::new("ca.test.echo"), Some(args), None).unwrap().await();
+    let result = client.call(URI::new("ca.test.echo"), Some(args), None).unwrap().wait();
     println!("Result: {:?}", result);
 }
 
@@ -119,7 +113,7 @@ fn event_loop(mut client: Client) {
             Command::Invalid(bad_command) => print!("Invalid command: {}", bad_command)
         }
     }
-    client.shutdown().unwrap().await().unwrap();
+    client.shutdown().unwrap().wait().unwrap();
 
 }
 
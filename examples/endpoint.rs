@@ -38,19 +38,19 @@ fn main() {
 
     info!("Connected");
     info!("Registering Addition Procedure");
-    client.register(URI::new("ca.test.add"), Box::new(addition_callback)).unwrap().await().unwrap();
+    client.register(URI::new("ca.test.add"), Box::new(addition_callback)).unwrap().wait().unwrap();
 
     info!("Registering Multiplication Procedure");
-    let mult_reg = client.register(URI::new("ca.test.mult"), Box::new(multiplication_callback)).unwrap().await().unwrap();
+    let mult_reg = client.register(URI::new("ca.test.mult"), Box::new(multiplication_callback)).unwrap().wait().unwrap();
 
     info!("Unregistering Multiplication Procedure");
-    client.unregister(mult_reg).unwrap().await().unwrap();
+    client.unregister(mult_reg).unwrap().wait().unwrap();
 
     info!("Registering Echo Procedure");
-    client.register(URI::new("ca.test.echo"), Box::new(echo_callback)).unwrap().await().unwrap();
+    client.register(URI::new("ca.test.echo"), Box::new(echo_callback)).unwrap().wait().unwrap();
 
     println!("Press enter to quit");
     let mut input = String::new();
     io::stdin().read_line(&mut input).unwrap();
-    client.shutdown().unwrap().await().unwrap();
+    client.shutdown().unwrap().wait().unwrap();
 }
@@ -0,0 +1,47 @@
+//! Runs a load test against a router and prints a report.
+//!
+//! cargo run --example loadtest -- ws://127.0.0.1:8090/ws realm1 --sessions 4 --rate 1000 --duration 10 --payload 256
+extern crate wamp;
+extern crate env_logger;
+
+use std::env;
+use std::process;
+use std::time::Duration;
+use wamp::loadtest::LoadTest;
+
+fn usage() -> ! {
+    println!("Usage: loadtest <url> <realm> [--topic <uri>] [--sessions <n>] [--rate <per second>] [--duration <seconds>] [--payload <bytes>]");
+    process::exit(2);
+}
+
+fn main() {
+    env_logger::init().unwrap();
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() < 2 {
+        usage();
+    }
+    let mut test = LoadTest::new(&args[0], &args[1]);
+    let mut options = args[2..].iter();
+    while let Some(option) = options.next() {
+        let value = match options.next() {
+            Some(value) => value,
+            None => usage()
+        };
+        let number = || value.parse::<u64>().unwrap_or_else(|_| usage());
+        test = match option.as_str() {
+            "--topic" => test.with_topic(value),
+            "--sessions" => test.with_sessions(number() as usize),
+            "--rate" => test.with_rate(number() as u32),
+            "--duration" => test.with_duration(Duration::from_secs(number())),
+            "--payload" => test.with_payload_size(number() as usize),
+            _ => usage()
+        };
+    }
+    match test.run() {
+        Ok(report) => println!("{}", report),
+        Err(e) => {
+            println!("Load test failed: {}", e);
+            process::exit(1);
+        }
+    }
+}
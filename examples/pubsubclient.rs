@@ -79,7 +79,7 @@ fn subscribe(client: &mut Client, subscriptions: &mut Arc<Mutex<Vec<Subscription
     let subscriptions = subscriptions.clone();
     client.subscribe_with_pattern(URI::new(&topic), Box::new(move |args, kwargs|{
         println!("Recieved message on topic {} with args {:?} and kwargs {:?}", topic, args, kwargs);
-    }), policy).unwrap().and_then(move |subscription|{
+    }), policy).unwrap().into_future().and_then(move |subscription|{
         println!("Subscribed to topic {}", subscription.topic.uri);
         subscriptions.lock().unwrap().push(subscription);
         Ok(())
@@ -102,7 +102,7 @@ fn unsubscribe(client: &mut Client, subscriptions: &mut Arc<Mutex<Vec<Subscripti
             }
             let subscription = subscriptions.remove(i);
             let topic = subscription.topic.uri.clone();
-            client.unsubscribe(subscription).unwrap().and_then(move |()| {
+            client.unsubscribe(subscription).unwrap().into_future().and_then(move |()| {
                 println!("Successfully unsubscribed from {}", topic);
                 Ok(())
             }).await().unwrap();
@@ -132,7 +132,7 @@ fn publish(client: &mut Client, args: Vec<String>) {
             Err(_) => Value::String(arg.clone())
         }
     }).collect();
-    client.publish_and_acknowledge(URI::new(&topic_arr[0]), Some(args), None).unwrap().await().unwrap();
+    client.publish_and_acknowledge(URI::new(&topic_arr[0]), Some(args), None).unwrap().wait().unwrap();
 }
 
 fn help() {
@@ -169,7 +169,7 @@ fn event_loop(mut client: Client) {
             Command::Invalid(bad_command) => print!("Invalid command: {}", bad_command)
         }
     }
-    client.shutdown().unwrap().await().unwrap();
+    client.shutdown().unwrap().wait().unwrap();
 
 }
 
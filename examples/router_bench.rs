@@ -0,0 +1,115 @@
+//! Measures how the router's throughput scales with worker threads.
+//!
+//! For each worker count, starts a router with a number of realms and runs a load test in every
+//! realm at once, then prints what the load tests published and delivered together.  A worker
+//! count of 0 handles every message on the listener's event loop, which is the single threaded
+//! baseline.  Without workers, a router that falls behind can stop delivering altogether, since
+//! its event loop then waits for room in its own queue of writes.
+//!
+//! cargo run --release --example router_bench -- --workers 0,2,4 --realms 4 --sessions 4 --rate 2000 --duration 5
+extern crate wamp;
+extern crate env_logger;
+
+use std::env;
+use std::process;
+use std::thread;
+use std::time::Duration;
+use wamp::router::Router;
+use wamp::loadtest::{LoadTest, LoadReport};
+
+fn usage() -> ! {
+    println!("Usage: router_bench [--workers <n,n,...>] [--realms <n>] [--sessions <n>] [--rate <per second per realm>] [--duration <seconds>] [--payload <bytes>]");
+    process::exit(2);
+}
+
+struct Settings {
+    workers: Vec<usize>,
+    realms: usize,
+    sessions: usize,
+    rate: u32,
+    duration: u64,
+    payload: usize
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+/// Runs one load test per realm against a router with `workers` workers
+fn run(settings: &Settings, workers: usize, port: u16) -> Vec<LoadReport> {
+    let mut router = Router::new();
+    router.set_workers(workers);
+    for realm in 0..settings.realms {
+        router.add_realm(&format!("bench{}", realm));
+    }
+    router.listen(&format!("127.0.0.1:{}", port));
+    thread::sleep(Duration::from_millis(200));
+    let tests: Vec<_> = (0..settings.realms).map(|realm| {
+        let test = LoadTest::new(&format!("ws://127.0.0.1:{}", port), &format!("bench{}", realm))
+            .with_sessions(settings.sessions)
+            .with_rate(settings.rate)
+            .with_duration(Duration::from_secs(settings.duration))
+            .with_payload_size(settings.payload);
+        thread::spawn(move || test.run())
+    }).collect();
+    tests.into_iter().map(|test| {
+        match test.join().unwrap() {
+            Ok(report) => report,
+            Err(e) => {
+                println!("Load test failed: {}", e);
+                process::exit(1);
+            }
+        }
+    }).collect()
+}
+
+fn main() {
+    env_logger::init().unwrap();
+    let mut settings = Settings {
+        workers: vec![0, 2, 4],
+        realms: 4,
+        sessions: 4,
+        rate: 2000,
+        duration: 5,
+        payload: 0
+    };
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut options = args.iter();
+    while let Some(option) = options.next() {
+        let value = match options.next() {
+            Some(value) => value,
+            None => usage()
+        };
+        let number = |value: &str| value.parse::<u64>().unwrap_or_else(|_| usage());
+        match option.as_str() {
+            "--workers" => settings.workers = value.split(',').map(|workers| number(workers) as usize).collect(),
+            "--realms" => settings.realms = number(value) as usize,
+            "--sessions" => settings.sessions = number(value) as usize,
+            "--rate" => settings.rate = number(value) as u32,
+            "--duration" => settings.duration = number(value),
+            "--payload" => settings.payload = number(value) as usize,
+            _ => usage()
+        }
+    }
+
+    println!("{} realms, {} sessions each, {} publications a second in each realm", settings.realms, settings.sessions, settings.rate);
+    println!("{:>8} {:>12} {:>12} {:>14} {:>10} {:>10}", "workers", "published", "delivered", "deliveries/s", "p50", "p99");
+    let mut baseline = None;
+    for (index, &workers) in settings.workers.iter().enumerate() {
+        let reports = run(&settings, workers, 8190 + index as u16);
+        let published: u64 = reports.iter().map(|report| report.published).sum();
+        let delivered: u64 = reports.iter().map(|report| report.delivered).sum();
+        let elapsed = reports.iter().map(|report| seconds(report.elapsed)).fold(0.0, f64::max);
+        let rate = delivered as f64 / elapsed;
+        let p50 = reports.iter().map(|report| report.latency_p50).max().unwrap();
+        let p99 = reports.iter().map(|report| report.latency_p99).max().unwrap();
+        let speedup = match baseline {
+            Some(baseline) => format!("{:.2}x", rate / baseline),
+            None => {
+                baseline = Some(rate);
+                "baseline".to_string()
+            }
+        };
+        println!("{:>8} {:>12} {:>12} {:>14.0} {:>10.1?} {:>10.1?}  {}", workers, published, delivered, rate, p50, p99, speedup);
+    }
+}
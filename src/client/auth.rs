@@ -0,0 +1,136 @@
+//! Contains the `Authenticator` trait, which lets a client authenticate with any scheme the
+//! router supports, along with the ticket and WAMP-CRA authenticators built on it.
+use messages::Dict;
+use cra::{WAMPCRA_AUTH, sign_challenge};
+use eventual::{Async, AsyncError, Future};
+use std::collections::HashMap;
+
+pub const TICKET_AUTH: &'static str = "ticket";
+
+/// Fetches a ticket for ticket based authentication, such as an OAuth access token or a JWT
+pub type TicketProvider = Box<Fn() -> Future<String, String> + Send>;
+
+/// The answer to an authentication challenge
+#[derive(PartialEq, Debug)]
+pub struct AuthenticateMessage {
+    pub signature: String,
+    pub extra: Dict
+}
+
+impl AuthenticateMessage {
+    pub fn new(signature: String) -> AuthenticateMessage {
+        AuthenticateMessage {
+            signature: signature,
+            extra: HashMap::new()
+        }
+    }
+
+    pub fn with_extra(mut self, extra: Dict) -> AuthenticateMessage {
+        self.extra = extra;
+        self
+    }
+}
+
+/// An authentication scheme.  It tells the router which methods the client supports when the
+/// client says hello, and answers the router's challenges, both while joining the realm and any
+/// time the router challenges the client again later in the session.
+pub trait Authenticator: Send {
+    /// The authentication methods to offer the router, most preferred first
+    fn authmethods(&self) -> Vec<String>;
+
+    /// Extra details for the router, sent as the hello message's `authextra`.  Nothing is sent
+    /// when they are empty.
+    fn hello_details(&self) -> Dict {
+        HashMap::new()
+    }
+
+    /// Answers a challenge for `authmethod`, given the challenge's extra details, or returns a
+    /// reason the challenge can't be answered
+    fn on_challenge(&mut self, authmethod: &str, extra: &Dict) -> Result<AuthenticateMessage, String>;
+}
+
+fn check_authmethod(expected: &str, authmethod: &str) -> Result<(), String> {
+    if authmethod == expected {
+        Ok(())
+    } else {
+        Err(format!("only the {} authentication method is supported, not {}", expected, authmethod))
+    }
+}
+
+/// Ticket authentication, asking a provider for a new ticket whenever the router challenges
+/// the client.
+///
+/// The connection waits for the ticket before processing any more messages, so the provider
+/// should fail its future rather than leave it pending if it can't get a ticket.
+pub struct TicketAuthenticator {
+    provider: TicketProvider
+}
+
+impl TicketAuthenticator {
+    pub fn new(provider: TicketProvider) -> TicketAuthenticator {
+        TicketAuthenticator {
+            provider: provider
+        }
+    }
+}
+
+impl Authenticator for TicketAuthenticator {
+    fn authmethods(&self) -> Vec<String> {
+        vec![TICKET_AUTH.to_string()]
+    }
+
+    fn on_challenge(&mut self, authmethod: &str, _extra: &Dict) -> Result<AuthenticateMessage, String> {
+        try!(check_authmethod(TICKET_AUTH, authmethod));
+        match (self.provider)().await() {
+            Ok(ticket) => Ok(AuthenticateMessage::new(ticket)),
+            Err(AsyncError::Failed(e)) => Err(format!("could not get a ticket: {}", e)),
+            Err(AsyncError::Aborted) => Err("the ticket provider was dropped before it returned a ticket".to_string())
+        }
+    }
+}
+
+/// WAMP-CRA authentication, signing the router's challenges with a secret.  Salted challenges
+/// are supported, in which case the router only needs to know the key derived from the secret.
+pub struct WampCraAuthenticator {
+    secret: String
+}
+
+impl WampCraAuthenticator {
+    pub fn new(secret: &str) -> WampCraAuthenticator {
+        WampCraAuthenticator {
+            secret: secret.to_string()
+        }
+    }
+}
+
+impl Authenticator for WampCraAuthenticator {
+    fn authmethods(&self) -> Vec<String> {
+        vec![WAMPCRA_AUTH.to_string()]
+    }
+
+    fn on_challenge(&mut self, authmethod: &str, extra: &Dict) -> Result<AuthenticateMessage, String> {
+        try!(check_authmethod(WAMPCRA_AUTH, authmethod));
+        sign_challenge(&self.secret, extra).map(AuthenticateMessage::new)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Authenticator, AuthenticateMessage, TicketAuthenticator, WampCraAuthenticator};
+    use eventual::Future;
+    use std::collections::HashMap;
+
+    #[test]
+    fn builtin_authenticators() {
+        let mut ticket = TicketAuthenticator::new(Box::new(|| Future::of("secret".to_string())));
+        assert_eq!(ticket.authmethods(), vec!["ticket".to_string()]);
+        assert!(ticket.hello_details().is_empty());
+        assert_eq!(ticket.on_challenge("ticket", &HashMap::new()), Ok(AuthenticateMessage::new("secret".to_string())));
+        assert!(ticket.on_challenge("wampcra", &HashMap::new()).is_err());
+
+        let mut cra = WampCraAuthenticator::new("secret");
+        assert_eq!(cra.authmethods(), vec!["wampcra".to_string()]);
+        assert!(cra.on_challenge("ticket", &HashMap::new()).is_err());
+        assert!(cra.on_challenge("wampcra", &HashMap::new()).is_err());
+    }
+}
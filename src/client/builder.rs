@@ -0,0 +1,291 @@
+//! Contains `ConnectionBuilder`, which configures a `Connection` in one expression, and the
+//! `ReconnectPolicy` struct, which retries connections that fail.
+//!
+//! Each builder method matches one of `Connection`'s setters, so options added to `Connection`
+//! only need a new method here rather than a new constructor.
+//!
+//! A reconnect policy only applies while `Connection::connect` is joining the realm.  Failures
+//! to reach the router, including timeouts, are retried after a delay that doubles each time;
+//! the router refusing the client, for instance because authentication failed, isn't.
+//!
+//! Applications that need other rules give the connection a `ReconnectDecider`, which decides
+//! after every failed attempt instead of the policy.  It can retry, wait longer, move on to
+//! another URL, or stop, for instance to leave resuming to the user.
+use super::{Connection, Serializer, PingPolicy, TlsPolicy, ConnectionConfig, ThreadHints, Authenticator, RealmPolicy, Transport, HttpProxy, is_transport_error};
+use codec::Codec;
+use std::cmp;
+use std::sync::Arc;
+use std::time::Duration;
+use ::{WampResult, Error};
+
+/// How often a failed connection is retried.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReconnectPolicy {
+    max_attempts: usize,
+    initial_delay: Duration,
+    max_delay: Duration
+}
+
+impl ReconnectPolicy {
+    /// Doesn't retry.  Once retries are allowed, the first waits half a second, and no retry
+    /// waits more than 30 seconds.
+    pub fn new() -> ReconnectPolicy {
+        ReconnectPolicy {
+            max_attempts: 0,
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30)
+        }
+    }
+
+    /// Sets how many times a failed connection is retried
+    pub fn with_max_attempts(mut self, attempts: usize) -> ReconnectPolicy {
+        self.max_attempts = attempts;
+        self
+    }
+
+    /// Sets how long to wait before the first retry
+    pub fn with_initial_delay(mut self, delay: Duration) -> ReconnectPolicy {
+        self.initial_delay = delay;
+        self
+    }
+
+    /// Sets the longest wait between two retries
+    pub fn with_max_delay(mut self, delay: Duration) -> ReconnectPolicy {
+        self.max_delay = delay;
+        self
+    }
+
+    pub fn max_attempts(&self) -> usize {
+        self.max_attempts
+    }
+
+    /// What the policy does after `attempts` failed attempts, the last of which failed with
+    /// `error`
+    pub fn decide(&self, attempts: usize, error: &Error) -> ReconnectDecision {
+        if attempts > self.max_attempts || !is_transport_error(error) {
+            ReconnectDecision::Stop
+        } else {
+            ReconnectDecision::Retry
+        }
+    }
+
+    /// How long to wait before the given retry, counting from 0
+    pub fn delay(&self, attempt: usize) -> Duration {
+        let factor = 1u32.checked_shl(cmp::min(attempt, 31) as u32).unwrap_or(u32::max_value());
+        cmp::min(self.initial_delay.checked_mul(factor).unwrap_or(self.max_delay), self.max_delay)
+    }
+}
+
+/// What to do after a connection attempt fails.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReconnectDecision {
+    /// Retries after the reconnect policy's delay
+    Retry,
+    /// Retries after the given delay
+    RetryAfter(Duration),
+    /// Retries at another URL, after the reconnect policy's delay.  Later attempts use that URL
+    /// too.
+    RetryAt(String),
+    /// Gives up, and returns the last error
+    Stop
+}
+
+/// Decides whether to try connecting again.  It is given how many attempts have failed so far,
+/// the last attempt's error, and how long it has been since the first attempt started.
+pub type ReconnectDecider = Box<FnMut(usize, &Error, Duration) -> ReconnectDecision + Send>;
+
+/// Builds a `Connection`.  Made with `Connection::builder`.
+pub struct ConnectionBuilder {
+    connection: Connection
+}
+
+impl ConnectionBuilder {
+    pub fn new(url: &str, realm: &str) -> ConnectionBuilder {
+        ConnectionBuilder {
+            connection: Connection::new(url, realm)
+        }
+    }
+
+    /// Sets the serializers offered to the router, most preferred first
+    pub fn with_serializers(mut self, serializers: Vec<Serializer>) -> ConnectionBuilder {
+        self.connection.set_serializers(serializers);
+        self
+    }
+
+    /// Offers the router a custom codec, which is preferred over the serializers
+    pub fn with_codec(mut self, codec: Arc<Codec>) -> ConnectionBuilder {
+        self.connection.add_codec(codec);
+        self
+    }
+
+    /// Adds a header to the websocket handshake request, for example for a proxy in front of the
+    /// router
+    pub fn with_header(mut self, name: &str, value: &str) -> ConnectionBuilder {
+        self.connection.add_header(name, value);
+        self
+    }
+
+    /// Sends a cookie with the websocket handshake request
+    pub fn with_cookie(mut self, name: &str, value: &str) -> ConnectionBuilder {
+        self.connection.add_cookie(name, value);
+        self
+    }
+
+    /// Sets the agent string sent in the hello message
+    pub fn with_agent(mut self, agent: &str) -> ConnectionBuilder {
+        self.connection.set_agent(agent);
+        self
+    }
+
+    /// Authenticates as `authid` with `authenticator`
+    pub fn with_authentication(mut self, authid: &str, authenticator: Box<Authenticator>) -> ConnectionBuilder {
+        self.connection.set_authentication(authid, authenticator);
+        self
+    }
+
+    /// Authenticates as `authid` using ticket authentication, always sending `ticket`
+    pub fn with_ticket(mut self, authid: &str, ticket: &str) -> ConnectionBuilder {
+        self.connection.set_ticket(authid, ticket);
+        self
+    }
+
+    /// Authenticates as `authid` using WAMP-CRA with `secret`
+    pub fn with_wampcra_secret(mut self, authid: &str, secret: &str) -> ConnectionBuilder {
+        self.connection.set_wampcra_secret(authid, secret);
+        self
+    }
+
+    /// Sets how long the whole connection may take
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> ConnectionBuilder {
+        self.connection.set_connect_timeout(timeout);
+        self
+    }
+
+    /// Sets how long each step of connecting may take
+    pub fn with_connection_config(mut self, config: ConnectionConfig) -> ConnectionBuilder {
+        self.connection.set_connection_config(config);
+        self
+    }
+
+    /// Sets how often a failed connection is retried
+    pub fn with_reconnect_policy(mut self, policy: ReconnectPolicy) -> ConnectionBuilder {
+        self.connection.set_reconnect_policy(policy);
+        self
+    }
+
+    /// Decides with `decider` whether failed connections are retried, instead of the reconnect
+    /// policy
+    pub fn with_reconnect_decider(mut self, decider: ReconnectDecider) -> ConnectionBuilder {
+        self.connection.set_reconnect_decider(decider);
+        self
+    }
+
+    pub fn with_ping_policy(mut self, policy: PingPolicy) -> ConnectionBuilder {
+        self.connection.set_ping_policy(policy);
+        self
+    }
+
+    pub fn with_tls_policy(mut self, policy: TlsPolicy) -> ConnectionBuilder {
+        self.connection.set_tls_policy(policy);
+        self
+    }
+
+    pub fn with_thread_hints(mut self, hints: ThreadHints) -> ConnectionBuilder {
+        self.connection.set_thread_hints(hints);
+        self
+    }
+
+    /// Falls back to the router's long-poll endpoint at `url` when the websocket can't be set up
+    pub fn with_long_poll_fallback(mut self, url: &str) -> ConnectionBuilder {
+        self.connection.set_long_poll_fallback(url);
+        self
+    }
+
+    /// Reaches the router with `transport` instead of the one the URL implies
+    pub fn with_transport(mut self, transport: Arc<Transport>) -> ConnectionBuilder {
+        self.connection.set_transport(transport);
+        self
+    }
+
+    /// Tunnels the connection through the HTTP proxy `proxy`
+    pub fn with_proxy(mut self, proxy: HttpProxy) -> ConnectionBuilder {
+        self.connection.set_proxy(proxy);
+        self
+    }
+
+    /// Normalizes the realm with `policy`.  If it still isn't valid, connecting fails.
+    pub fn with_realm_policy(mut self, policy: RealmPolicy) -> ConnectionBuilder {
+        self.connection.set_realm_policy(policy).ok();
+        self
+    }
+
+    pub fn build(self) -> Connection {
+        self.connection
+    }
+
+    /// Builds the connection, failing if the realm isn't valid
+    pub fn try_build(self) -> WampResult<Connection> {
+        try!(self.connection.check_realm());
+        Ok(self.connection)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ReconnectPolicy, ReconnectDecision};
+    use client::{Connection, Serializer, RealmPolicy};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[test]
+    fn reconnect_delays_double_up_to_the_limit() {
+        let policy = ReconnectPolicy::new().with_max_attempts(10).with_initial_delay(Duration::from_millis(100)).with_max_delay(Duration::from_secs(1));
+        let delays: Vec<Duration> = (0..6).map(|attempt| policy.delay(attempt)).collect();
+        assert_eq!(delays, vec![Duration::from_millis(100), Duration::from_millis(200), Duration::from_millis(400), Duration::from_millis(800), Duration::from_secs(1), Duration::from_secs(1)]);
+        assert_eq!(policy.delay(200), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn building_connections() {
+        let connection = Connection::builder("ws://127.0.0.1:8090/ws", "realm1")
+            .with_serializers(vec![Serializer::Json])
+            .with_header("X-Route", "edge")
+            .with_cookie("cbtid", "abc")
+            .with_agent("sensor-gateway/1.0")
+            .with_ticket("joe", "secret")
+            .with_connect_timeout(Duration::from_secs(2))
+            .with_reconnect_policy(ReconnectPolicy::new().with_max_attempts(3))
+            .build();
+        assert_eq!(connection.url, "ws://127.0.0.1:8090/ws");
+        assert_eq!(connection.serializers, vec![Serializer::Json]);
+        assert_eq!(connection.headers, vec![("X-Route".to_string(), "edge".to_string())]);
+        assert_eq!(connection.cookies, vec![("cbtid".to_string(), "abc".to_string())]);
+        assert_eq!(connection.agent, Some("sensor-gateway/1.0".to_string()));
+        assert_eq!(connection.authentication.as_ref().unwrap().0, "joe");
+        assert_eq!(connection.connection_config.timeout(), Duration::from_secs(2));
+        assert_eq!(connection.reconnect_policy.max_attempts(), 3);
+
+        // Realms are checked as they are given, unless they are normalized first
+        assert!(Connection::builder("ws://127.0.0.1:8090/ws", "Realm1 ").try_build().is_err());
+        let connection = Connection::builder("ws://127.0.0.1:8090/ws", "Realm1 ").with_realm_policy(RealmPolicy::new().with_trim(true).with_lowercase(true)).try_build().unwrap();
+        assert_eq!(connection.realm.uri, "realm1");
+    }
+
+    #[test]
+    fn reconnect_deciders_replace_the_policy() {
+        let attempts = Arc::new(Mutex::new(Vec::new()));
+        let seen = attempts.clone();
+        let connection = Connection::builder("ws://127.0.0.1:1/ws", "realm1")
+            .with_reconnect_decider(Box::new(move |attempt, _, _| {
+                seen.lock().unwrap().push(attempt);
+                match attempt {
+                    1 => ReconnectDecision::RetryAt("ws://127.0.0.1:2/ws".to_string()),
+                    2 => ReconnectDecision::RetryAfter(Duration::from_millis(0)),
+                    _ => ReconnectDecision::Stop
+                }
+            }))
+            .build();
+        assert!(connection.connect().is_err());
+        assert_eq!(*attempts.lock().unwrap(), vec![1, 2, 3]);
+    }
+}
@@ -0,0 +1,99 @@
+//! Contains the `SubscriptionCache` struct, which records the subscriptions and registrations
+//! a client wants so that they can be saved to disk and re-established after a restart.
+use messages::{URI, SubscribeOptions, RegisterOptions};
+use serde_json;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use ::{WampResult, Error, ErrorKind};
+
+/// A serializable record of a client's desired subscriptions and registrations.
+///
+/// Callbacks cannot be written to disk, so each entry names the handler that should be bound
+/// to it instead.  When the cache is loaded again, `Client::rehydrate()` looks up each handler
+/// by that name.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct SubscriptionCache {
+    #[serde(default)]
+    pub subscriptions: Vec<CachedSubscription>,
+    #[serde(default)]
+    pub registrations: Vec<CachedRegistration>
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct CachedSubscription {
+    pub topic: URI,
+    pub options: SubscribeOptions,
+    pub handler: String
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct CachedRegistration {
+    pub procedure: URI,
+    pub options: RegisterOptions,
+    pub handler: String
+}
+
+impl SubscriptionCache {
+    #[inline]
+    pub fn new() -> SubscriptionCache {
+        SubscriptionCache {
+            subscriptions: Vec::new(),
+            registrations: Vec::new()
+        }
+    }
+
+    pub fn add_subscription(&mut self, topic: URI, options: SubscribeOptions, handler: &str) {
+        self.subscriptions.push(CachedSubscription {
+            topic: topic,
+            options: options,
+            handler: handler.to_string()
+        });
+    }
+
+    pub fn add_registration(&mut self, procedure: URI, options: RegisterOptions, handler: &str) {
+        self.registrations.push(CachedRegistration {
+            procedure: procedure,
+            options: options,
+            handler: handler.to_string()
+        });
+    }
+
+    /// Reads a cache that was previously written with `save()`
+    pub fn load<P: AsRef<Path>>(path: P) -> WampResult<SubscriptionCache> {
+        let mut contents = String::new();
+        let mut file = try!(File::open(path).map_err(|e| Error::new(ErrorKind::IOError(e))));
+        try!(file.read_to_string(&mut contents).map_err(|e| Error::new(ErrorKind::IOError(e))));
+        serde_json::from_str(&contents).map_err(|e| Error::new(ErrorKind::JSONError(e)))
+    }
+
+    /// Writes the cache to the given path as JSON, replacing any existing file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> WampResult<()> {
+        let contents = try!(serde_json::to_string_pretty(self).map_err(|e| Error::new(ErrorKind::JSONError(e))));
+        let mut file = try!(File::create(path).map_err(|e| Error::new(ErrorKind::IOError(e))));
+        file.write_all(contents.as_bytes()).map_err(|e| Error::new(ErrorKind::IOError(e)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SubscriptionCache;
+    use messages::{URI, SubscribeOptions, RegisterOptions, MatchingPolicy};
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn save_and_load() {
+        let mut cache = SubscriptionCache::new();
+        let mut options = SubscribeOptions::new();
+        options.pattern_match = MatchingPolicy::Prefix;
+        cache.add_subscription(URI::new("ca.test.events"), options, "on_event");
+        cache.add_registration(URI::new("ca.test.add"), RegisterOptions::new(), "add");
+
+        let path = env::temp_dir().join("wamp_subscription_cache_test.json");
+        cache.save(&path).unwrap();
+        let loaded = SubscriptionCache::load(&path).unwrap();
+        fs::remove_file(&path).ok();
+        assert_eq!(loaded, cache);
+    }
+}
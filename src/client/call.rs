@@ -0,0 +1,116 @@
+//! Contains the `CallHandle` struct, which waits for the result of a call and can cancel it, and
+//! the `ProgressiveCall` struct, which sends the rest of a progressive call.
+use super::{ConnectionInfo, Pending};
+use eventual::Future;
+use messages::{Message, CancelMode, CancelOptions, CallOptions, Dict, List, URI};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use ::{WampResult, Error, ErrorKind, CallError, ID};
+
+/// A call the router hasn't answered yet.
+///
+/// Waiting works like `Pending`.  Cancelling asks the router to stop the call; once the router
+/// gives up on it, the call fails with `Reason::Cancelled`.  With `CancelMode::Kill`, the router
+/// waits for the callee to stop first, so the call may still succeed if the callee finishes
+/// anyway.
+pub struct CallHandle {
+    pending: Pending<(List, Dict)>,
+    request_id: ID,
+    connection_info: Arc<Mutex<ConnectionInfo>>
+}
+
+impl CallHandle {
+    pub(crate) fn new(pending: Pending<(List, Dict)>, request_id: ID, connection_info: Arc<Mutex<ConnectionInfo>>) -> CallHandle {
+        CallHandle {
+            pending: pending,
+            request_id: request_id,
+            connection_info: connection_info
+        }
+    }
+
+    pub fn request_id(&self) -> ID {
+        self.request_id
+    }
+
+    /// Asks the router to cancel the call.  Fails if the call has already been answered.
+    pub fn cancel(&self, mode: CancelMode) -> WampResult<()> {
+        let mut info = self.connection_info.lock().unwrap();
+        if !info.call_requests.contains_key(&self.request_id) {
+            return Err(Error::new(ErrorKind::InvalidState("The call has already been answered")));
+        }
+        debug!("Cancelling call {} ({:?})", self.request_id, mode);
+        info.queue_message(Message::Cancel(self.request_id, CancelOptions::new(mode)))
+    }
+
+    /// Blocks until the router answers
+    pub fn wait(self) -> Result<(List, Dict), CallError> {
+        self.pending.wait()
+    }
+
+    /// Blocks until the router answers, or fails with `Reason::Timeout` after `timeout`.  The
+    /// call isn't cancelled.
+    pub fn wait_timeout(self, timeout: Duration) -> Result<(List, Dict), CallError> {
+        self.pending.wait_timeout(timeout)
+    }
+
+    pub fn into_future(self) -> Future<(List, Dict), CallError> {
+        self.pending.into_future()
+    }
+}
+
+impl From<CallHandle> for Pending<(List, Dict)> {
+    fn from(handle: CallHandle) -> Pending<(List, Dict)> {
+        handle.pending
+    }
+}
+
+/// A progressive call whose caller is still sending chunks.  Made with
+/// `Client::call_progressive()`, which sends the first chunk.
+pub struct ProgressiveCall {
+    handle: CallHandle,
+    procedure: URI,
+    options: CallOptions
+}
+
+impl ProgressiveCall {
+    pub(crate) fn new(handle: CallHandle, procedure: URI, options: CallOptions) -> ProgressiveCall {
+        ProgressiveCall {
+            handle: handle,
+            procedure: procedure,
+            options: options
+        }
+    }
+
+    pub fn request_id(&self) -> ID {
+        self.handle.request_id
+    }
+
+    /// Sends another chunk, which more chunks follow.  Fails if the call has already been
+    /// answered, which callees may do before the last chunk, for instance to refuse the call.
+    pub fn send(&mut self, args: Option<List>, kwargs: Option<Dict>) -> WampResult<()> {
+        let options = self.options.clone().with_progress(true);
+        self.send_chunk(options, args, kwargs)
+    }
+
+    /// Sends the last chunk, and returns the handle that waits for the result
+    pub fn finish(mut self, args: Option<List>, kwargs: Option<Dict>) -> WampResult<CallHandle> {
+        let mut options = self.options.clone();
+        options.progress = None;
+        try!(self.send_chunk(options, args, kwargs));
+        Ok(self.handle)
+    }
+
+    /// Asks the router to cancel the call, like `CallHandle::cancel()`
+    pub fn cancel(&self, mode: CancelMode) -> WampResult<()> {
+        self.handle.cancel(mode)
+    }
+
+    fn send_chunk(&mut self, options: CallOptions, args: Option<List>, kwargs: Option<Dict>) -> WampResult<()> {
+        let mut info = self.handle.connection_info.lock().unwrap();
+        if !info.call_requests.contains_key(&self.handle.request_id) {
+            return Err(Error::new(ErrorKind::InvalidState("The call has already been answered")));
+        }
+        let (args, kwargs) = info.compress_payload(&self.procedure, args, kwargs);
+        info.queue_message(Message::Call(self.handle.request_id, options, self.procedure.clone(), args, kwargs))
+    }
+}
@@ -0,0 +1,80 @@
+//! Contains the `CancellationToken` struct, which tells long running work that the session it
+//! was started for has gone away.
+use std::sync::{Arc, Mutex, Condvar};
+use std::time::{Duration, Instant};
+
+/// Fires when a session starts shutting down or ends, however it ends.
+///
+/// Each session has its own token.  Handlers registered with `Client::subscribe_cancellable()`
+/// or `Client::register_cancellable()` are given the token of the session they were called in,
+/// and `Client::cancellation_token()` returns the current session's token for handing to other
+/// threads.  Once a token has fired it stays fired, even if the client rejoins.
+#[derive(Clone)]
+pub struct CancellationToken {
+    state: Arc<(Mutex<bool>, Condvar)>
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken {
+            state: Arc::new((Mutex::new(false), Condvar::new()))
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.state.0.lock().unwrap()
+    }
+
+    /// Fires the token, waking every thread waiting on it
+    pub fn cancel(&self) {
+        let (ref cancelled, ref condvar) = *self.state;
+        *cancelled.lock().unwrap() = true;
+        condvar.notify_all();
+    }
+
+    /// Blocks until the token fires
+    pub fn wait(&self) {
+        let (ref cancelled, ref condvar) = *self.state;
+        let mut cancelled = cancelled.lock().unwrap();
+        while !*cancelled {
+            cancelled = condvar.wait(cancelled).unwrap();
+        }
+    }
+
+    /// Blocks until the token fires or `timeout` passes, and returns whether it fired.  Handy
+    /// for sleeping between units of work.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let (ref cancelled, ref condvar) = *self.state;
+        let mut cancelled = cancelled.lock().unwrap();
+        while !*cancelled {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            cancelled = condvar.wait_timeout(cancelled, deadline - now).unwrap().0;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::CancellationToken;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn tokens_fire_once_for_every_clone() {
+        let token = CancellationToken::new();
+        assert!(!token.is_cancelled());
+        assert!(!token.wait_timeout(Duration::from_millis(10)));
+
+        let waiter = token.clone();
+        let thread = thread::spawn(move || waiter.wait_timeout(Duration::from_secs(10)));
+        token.cancel();
+        assert!(thread.join().unwrap());
+        assert!(token.is_cancelled());
+        token.wait();
+    }
+}
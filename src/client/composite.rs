@@ -0,0 +1,138 @@
+//! Contains the `CompositeClient` struct, which presents sessions to several routers as a
+//! single client.
+use super::{Client, Subscription, Registration, Pending, CallHandle};
+use messages::{URI, Dict, List, MatchingPolicy};
+use ::{WampResult, CallResult, ID};
+
+/// A client that holds sessions to several routers and forwards each operation to one of them
+/// based on the URI involved.
+///
+/// Routes are registered as URI prefixes, and the longest matching prefix wins.  Prefixes are
+/// matched on whole URI components, so the prefix `com.example` matches `com.example.topic` but
+/// not `com.examples.topic`.  Any URI that doesn't match a route goes to the default client,
+/// which is always the first client added.
+///
+/// This is mostly useful for applications that straddle two WAMP deployments during a migration.
+pub struct CompositeClient {
+    clients: Vec<Client>,
+    routes: Vec<(String, usize)>
+}
+
+fn select_route(routes: &[(String, usize)], uri: &str) -> usize {
+    let mut best: Option<(usize, usize)> = None;
+    for &(ref prefix, index) in routes {
+        let matches = uri == prefix || (uri.starts_with(prefix.as_str()) && uri[prefix.len()..].starts_with('.'));
+        if matches {
+            match best {
+                Some((length, _)) if length >= prefix.len() => {},
+                _ => best = Some((prefix.len(), index))
+            }
+        }
+    }
+    best.map(|(_, index)| index).unwrap_or(0)
+}
+
+impl CompositeClient {
+    /// Constructs a composite client that forwards everything to `default_client` until other
+    /// routes are added.
+    pub fn new(default_client: Client) -> CompositeClient {
+        CompositeClient {
+            clients: vec![default_client],
+            routes: Vec::new()
+        }
+    }
+
+    /// Adds a session to the composite client, returning the index to use when adding routes to it.
+    pub fn add_client(&mut self, client: Client) -> usize {
+        self.clients.push(client);
+        self.clients.len() - 1
+    }
+
+    /// Forwards all operations on URIs starting with `prefix` to the client with the given index.
+    ///
+    /// # Panics
+    /// Panics if `client_index` doesn't refer to a client that has been added.
+    pub fn add_route(&mut self, prefix: &str, client_index: usize) {
+        assert!(client_index < self.clients.len(), "No client with index {}", client_index);
+        self.routes.retain(|&(ref existing, _)| existing != prefix);
+        self.routes.push((prefix.to_string(), client_index));
+    }
+
+    /// Gets the client that operations on the given URI are forwarded to
+    pub fn client_for(&mut self, uri: &URI) -> &mut Client {
+        let index = select_route(&self.routes, &uri.uri);
+        &mut self.clients[index]
+    }
+
+    #[cfg(feature = "subscriber")]
+    pub fn subscribe(&mut self, topic: URI, callback: Box<FnMut(List, Dict)>) -> WampResult<Pending<Subscription>> {
+        self.client_for(&topic).subscribe(topic, callback)
+    }
+
+    #[cfg(feature = "subscriber")]
+    pub fn subscribe_with_pattern(&mut self, topic_pattern: URI, callback: Box<FnMut(List, Dict)>, policy: MatchingPolicy) -> WampResult<Pending<Subscription>> {
+        self.client_for(&topic_pattern).subscribe_with_pattern(topic_pattern, callback, policy)
+    }
+
+    /// Unsubscribes using the client that the subscription's topic is currently routed to, so
+    /// routes shouldn't be changed while subscriptions that depend on them are active.
+    #[cfg(feature = "subscriber")]
+    pub fn unsubscribe(&mut self, subscription: Subscription) -> WampResult<Pending<()>> {
+        let topic = subscription.topic.clone();
+        self.client_for(&topic).unsubscribe(subscription)
+    }
+
+    #[cfg(feature = "callee")]
+    pub fn register(&mut self, procedure: URI, callback: Box<FnMut(List, Dict) -> CallResult<(Option<List>, Option<Dict>)>>) -> WampResult<Pending<Registration>> {
+        self.client_for(&procedure).register(procedure, callback)
+    }
+
+    /// Unregisters using the client that the procedure is currently routed to.
+    #[cfg(feature = "callee")]
+    pub fn unregister(&mut self, registration: Registration) -> WampResult<Pending<()>> {
+        let procedure = registration.procedure.clone();
+        self.client_for(&procedure).unregister(registration)
+    }
+
+    #[cfg(feature = "publisher")]
+    pub fn publish(&mut self, topic: URI, args: Option<List>, kwargs: Option<Dict>) -> WampResult<()> {
+        self.client_for(&topic).publish(topic, args, kwargs)
+    }
+
+    #[cfg(feature = "publisher")]
+    pub fn publish_and_acknowledge(&mut self, topic: URI, args: Option<List>, kwargs: Option<Dict>) -> WampResult<Pending<ID>> {
+        self.client_for(&topic).publish_and_acknowledge(topic, args, kwargs)
+    }
+
+    #[cfg(feature = "caller")]
+    pub fn call(&mut self, procedure: URI, args: Option<List>, kwargs: Option<Dict>) -> WampResult<CallHandle> {
+        self.client_for(&procedure).call(procedure, args, kwargs)
+    }
+
+    /// Shuts down every session held by the composite client.
+    pub fn shutdown(&mut self) -> WampResult<Vec<Pending<()>>> {
+        let mut futures = Vec::new();
+        for client in self.clients.iter_mut() {
+            futures.push(try!(client.shutdown()));
+        }
+        Ok(futures)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::select_route;
+
+    #[test]
+    fn routing_by_prefix() {
+        let routes = vec![
+            ("com.legacy".to_string(), 1),
+            ("com.legacy.billing".to_string(), 2)
+        ];
+        assert_eq!(select_route(&routes, "com.legacy.inventory.update"), 1);
+        assert_eq!(select_route(&routes, "com.legacy.billing.charge"), 2);
+        assert_eq!(select_route(&routes, "com.legacy"), 1);
+        assert_eq!(select_route(&routes, "com.legacyish.topic"), 0);
+        assert_eq!(select_route(&routes, "org.other"), 0);
+    }
+}
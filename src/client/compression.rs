@@ -0,0 +1,242 @@
+//! Contains the `PayloadCompression` struct, which transparently compresses large application
+//! payloads sent by the client and decompresses those it receives.
+//!
+//! A compressed payload replaces the arguments and keyword arguments of a message with a single
+//! keyword argument named `_compressed`, containing the name of the encoding and the compressed
+//! data in base64:
+//!
+//! ```text
+//! {"_compressed": {"encoding": "gzip", "data": "H4sIAAAAAAAA..."}}
+//! ```
+//!
+//! The original arguments are serialized as JSON before being compressed.  Peers that don't know
+//! about this convention just see an ordinary keyword argument, so it should only be enabled for
+//! topics and procedures where every peer uses it.
+use messages::{URI, Dict, List, Value};
+use serde_json;
+use std::collections::HashMap;
+use utils::{base64_encode, base64_decode};
+use super::ConnectionInfo;
+
+static COMPRESSED_PAYLOAD_KEY: &'static str = "_compressed";
+
+/// An algorithm that can be used to compress payloads.
+pub trait PayloadCompressor: Send {
+    /// The name of the encoding, which is sent along with the compressed data
+    fn encoding(&self) -> &str;
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, String>;
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, String>;
+}
+
+/// Configures when and how payloads are compressed.
+pub struct PayloadCompression {
+    compressor: Box<PayloadCompressor>,
+    threshold: usize,
+    filter: Option<Box<Fn(&URI, &Option<List>, &Option<Dict>) -> bool + Send>>
+}
+
+impl PayloadCompression {
+    /// Compresses payloads with the given compressor once they are at least 1KB
+    pub fn new(compressor: Box<PayloadCompressor>) -> PayloadCompression {
+        PayloadCompression {
+            compressor: compressor,
+            threshold: 1024,
+            filter: None
+        }
+    }
+
+    /// Only payloads whose serialized size is at least `threshold` bytes are compressed
+    pub fn with_threshold(mut self, threshold: usize) -> PayloadCompression {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Only payloads for which `filter` returns true are compressed.  This can be used to limit
+    /// compression to particular topics and procedures, or to payloads with a particular content type.
+    pub fn with_filter(mut self, filter: Box<Fn(&URI, &Option<List>, &Option<Dict>) -> bool + Send>) -> PayloadCompression {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Replaces the given payload with its compressed form, if it should be compressed.
+    pub fn compress(&self, uri: &URI, args: Option<List>, kwargs: Option<Dict>) -> (Option<List>, Option<Dict>) {
+        if args.is_none() && kwargs.is_none() {
+            return (args, kwargs);
+        }
+        if let Some(ref filter) = self.filter {
+            if !filter(uri, &args, &kwargs) {
+                return (args, kwargs);
+            }
+        }
+        let data = match serde_json::to_vec(&(&args, &kwargs)) {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("Could not serialize payload for compression: {}", e);
+                return (args, kwargs);
+            }
+        };
+        if data.len() < self.threshold {
+            return (args, kwargs);
+        }
+        match self.compressor.compress(&data) {
+            Ok(compressed) => {
+                debug!("Compressed payload for {} from {} to {} bytes", uri.uri, data.len(), compressed.len());
+                let mut envelope = HashMap::new();
+                envelope.insert("encoding".to_string(), Value::String(self.compressor.encoding().to_string()));
+                envelope.insert("data".to_string(), Value::String(base64_encode(&compressed)));
+                let mut kwargs = HashMap::new();
+                kwargs.insert(COMPRESSED_PAYLOAD_KEY.to_string(), Value::Dict(envelope));
+                (None, Some(kwargs))
+            },
+            Err(e) => {
+                warn!("Could not compress payload for {}: {}", uri.uri, e);
+                (args, kwargs)
+            }
+        }
+    }
+
+    /// Restores a payload that was compressed by a peer.  Payloads that aren't compressed, or that
+    /// use a different encoding, are returned unchanged.
+    pub fn decompress(&self, args: Option<List>, kwargs: Option<Dict>) -> (Option<List>, Option<Dict>) {
+        let data = match (&args, &kwargs) {
+            (&None, &Some(ref kwargs)) if kwargs.len() == 1 => {
+                match kwargs.get(COMPRESSED_PAYLOAD_KEY) {
+                    Some(&Value::Dict(ref envelope)) => {
+                        match (envelope.get("encoding"), envelope.get("data")) {
+                            (Some(&Value::String(ref encoding)), Some(&Value::String(ref data))) => {
+                                if encoding != self.compressor.encoding() {
+                                    warn!("Recieved payload compressed with unsupported encoding {}", encoding);
+                                    None
+                                } else {
+                                    base64_decode(data)
+                                }
+                            },
+                            _ => None
+                        }
+                    },
+                    _ => None
+                }
+            },
+            _ => None
+        };
+        let data = match data {
+            Some(data) => data,
+            None => return (args, kwargs)
+        };
+        let decompressed = match self.compressor.decompress(&data) {
+            Ok(decompressed) => decompressed,
+            Err(e) => {
+                warn!("Could not decompress payload: {}", e);
+                return (args, kwargs);
+            }
+        };
+        match serde_json::from_slice(&decompressed) {
+            Ok(payload) => payload,
+            Err(e) => {
+                warn!("Could not parse decompressed payload: {}", e);
+                (args, kwargs)
+            }
+        }
+    }
+}
+
+impl ConnectionInfo {
+    pub fn compress_payload(&self, uri: &URI, args: Option<List>, kwargs: Option<Dict>) -> (Option<List>, Option<Dict>) {
+        match self.compression {
+            Some(ref compression) => compression.compress(uri, args, kwargs),
+            None => (args, kwargs)
+        }
+    }
+
+    pub fn decompress_payload(&self, args: Option<List>, kwargs: Option<Dict>) -> (Option<List>, Option<Dict>) {
+        match self.compression {
+            Some(ref compression) => compression.decompress(args, kwargs),
+            None => (args, kwargs)
+        }
+    }
+}
+
+/// Compresses payloads with gzip.  Requires the `gzip` feature.
+#[cfg(feature = "gzip")]
+pub struct GzipCompressor;
+
+#[cfg(feature = "gzip")]
+impl PayloadCompressor for GzipCompressor {
+    fn encoding(&self) -> &str {
+        "gzip"
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, String> {
+        use flate2::Compression;
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::Default);
+        try!(encoder.write_all(data).map_err(|e| e.to_string()));
+        encoder.finish().map_err(|e| e.to_string())
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, String> {
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        let mut decoder = try!(GzDecoder::new(data).map_err(|e| e.to_string()));
+        let mut result = Vec::new();
+        try!(decoder.read_to_end(&mut result).map_err(|e| e.to_string()));
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PayloadCompression, PayloadCompressor};
+    use messages::{URI, Value};
+    use utils::{base64_encode, base64_decode};
+    use std::collections::HashMap;
+
+    /// A "compressor" that reverses its input, which is enough to check the envelope handling
+    struct ReverseCompressor;
+
+    impl PayloadCompressor for ReverseCompressor {
+        fn encoding(&self) -> &str {
+            "reverse"
+        }
+
+        fn compress(&self, data: &[u8]) -> Result<Vec<u8>, String> {
+            Ok(data.iter().rev().cloned().collect())
+        }
+
+        fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, String> {
+            Ok(data.iter().rev().cloned().collect())
+        }
+    }
+
+    #[test]
+    fn base64_round_trip() {
+        for data in [&b""[..], &b"a"[..], &b"ab"[..], &b"abc"[..], &b"\x00\xff\x10binary"[..]].iter() {
+            assert_eq!(base64_decode(&base64_encode(data)).unwrap(), data.to_vec());
+        }
+        assert_eq!(base64_encode(b"wamp"), "d2FtcA==");
+        assert_eq!(base64_decode("not base64!"), None);
+    }
+
+    #[test]
+    fn compression_round_trip() {
+        let compression = PayloadCompression::new(Box::new(ReverseCompressor)).with_threshold(10);
+        let mut kwargs = HashMap::new();
+        kwargs.insert("blob".to_string(), Value::String("a fairly long string value".to_string()));
+        let args = Some(vec![Value::Integer(5)]);
+
+        let (compressed_args, compressed_kwargs) = compression.compress(&URI::new("ca.test.blob"), args.clone(), Some(kwargs.clone()));
+        assert_eq!(compressed_args, None);
+        assert!(compressed_kwargs.as_ref().unwrap().contains_key("_compressed"));
+        assert_eq!(compression.decompress(compressed_args, compressed_kwargs), (args, Some(kwargs)));
+    }
+
+    #[test]
+    fn small_payloads_are_not_compressed() {
+        let compression = PayloadCompression::new(Box::new(ReverseCompressor));
+        let args = Some(vec![Value::Integer(5)]);
+        assert_eq!(compression.compress(&URI::new("ca.test.small"), args.clone(), None), (args, None));
+    }
+}
@@ -0,0 +1,461 @@
+//! Contains the `ClientConfig` struct, which describes how a client connects and behaves, so
+//! that the settings can live in an application's own configuration files.
+//!
+//! Settings that need code, such as authenticators other than a fixed ticket, codecs, payload
+//! compression and hooks, are still set on the `Connection` or `Client` directly.
+use super::{Client, Connection, HttpProxy, RealmPolicy, ConnectionConfig, PingPolicy, TlsPolicy, RateLimit, Overflow, OptionDefaults, PublishDefaults, CallDefaults};
+use messages::validation::ValidationMode;
+use serde_json;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use ::{WampResult, Error, ErrorKind};
+
+/// The serializers the client can offer the router.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub enum Serializer {
+    #[serde(rename="json")]
+    Json,
+    #[serde(rename="msgpack")]
+    MsgPack,
+    #[serde(rename="cbor")]
+    Cbor
+}
+
+/// Everything about a client that can be configured without code, usually read from a JSON
+/// file.  Durations are in milliseconds:
+///
+/// ```text
+/// {
+///     "url": "ws://127.0.0.1:8090/ws",
+///     "realm": "realm1",
+///     "trim_realm": true,
+///     "lowercase_realm": true,
+///     "connect_timeout": 5000,
+///     "handshake_timeout": 2000,
+///     "welcome_timeout": 2000,
+///     "serializers": ["json"],
+///     "headers": {"Authorization": "Bearer abc", "X-Trace-Id": "gateway-1"},
+///     "cookies": {"cbtid": "S8Cd0iy1"},
+///     "authentication": {"authid": "joe", "ticket": "secret"},
+///     "ping": {"interval": 10000, "pong_timeout": 3000},
+///     "tls": {"ca_file": "/etc/wamp/ca.pem"},
+///     "long_poll_url": "http://127.0.0.1:8090/lp",
+///     "proxy": {"host": "proxy.example.com", "port": 3128, "username": "joe", "password": "secret"},
+///     "outbound_ttl": 2000,
+///     "write_coalescing": 5,
+///     "validation_mode": "strict",
+///     "response_cache": {"default_ttl": 1000},
+///     "rate_limits": [{"prefix": "ca.test.sensors", "per_second": 10.0, "burst": 5, "overflow": "coalesce"}],
+///     "conflated_topics": ["ca.test.sensors"],
+///     "activity_history": {"capacity": 100, "max_payload_len": 256},
+///     "option_defaults": [
+///         {"publish": {"exclude_me": false}},
+///         {"authrole": "backend", "publish": {"disclose_me": true}, "call": {"disclose_me": true, "timeout": 2000}}
+///     ]
+/// }
+/// ```
+///
+/// Every entry of `option_defaults` whose realm and authrole match the client's applies, with
+/// later entries overriding earlier ones.  An entry without a realm or an authrole matches any.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct ClientConfig {
+    pub url: String,
+    pub realm: String,
+    /// Removes whitespace around the realm before it is checked
+    #[serde(default)]
+    pub trim_realm: bool,
+    /// Lowercases the realm before it is checked
+    #[serde(default)]
+    pub lowercase_realm: bool,
+    /// How long to wait for the router to welcome the client
+    #[serde(default="default_connect_timeout")]
+    pub connect_timeout: u64,
+    /// How long the websocket handshake may take, including making the TCP connection
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub handshake_timeout: Option<u64>,
+    /// How long to wait for the router's welcome once the websocket is open
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub welcome_timeout: Option<u64>,
+    /// The serializers to offer the router, most preferred first
+    #[serde(default="default_serializers")]
+    pub serializers: Vec<Serializer>,
+    /// Headers to add to the websocket handshake request
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Cookies to send with the websocket handshake request
+    #[serde(default)]
+    pub cookies: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub authentication: Option<TicketAuthentication>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub ping: Option<PingConfig>,
+    /// How TLS is set up when the URL is `wss://`
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub tls: Option<TlsConfig>,
+    /// The router's long-poll endpoint, to fall back to when the websocket can't be set up
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub long_poll_url: Option<String>,
+    /// An HTTP proxy to tunnel the websocket through
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub proxy: Option<ProxyConfig>,
+    /// How long an outgoing message may wait to be written before it expires
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub outbound_ttl: Option<u64>,
+    /// The longest time outgoing messages may be held back to be written together
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub write_coalescing: Option<u64>,
+    #[serde(default="default_validation_mode")]
+    pub validation_mode: ValidationMode,
+    /// Caches call results when present
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub response_cache: Option<ResponseCacheConfig>,
+    #[serde(default)]
+    pub rate_limits: Vec<RateLimitConfig>,
+    /// The topic prefixes that publications are conflated under
+    #[serde(default)]
+    pub conflated_topics: Vec<String>,
+    /// Remembers recent events and invocations when present
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub activity_history: Option<ActivityHistoryConfig>,
+    #[serde(default)]
+    pub option_defaults: Vec<OptionDefaultsConfig>
+}
+
+/// Ticket authentication with a ticket that doesn't change.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct TicketAuthentication {
+    pub authid: String,
+    pub ticket: String
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct PingConfig {
+    #[serde(default="default_auto_pong")]
+    pub auto_pong: bool,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub interval: Option<u64>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub pong_timeout: Option<u64>
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct TlsConfig {
+    /// A PEM file of root certificates to use instead of the system's
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub ca_file: Option<PathBuf>,
+    #[serde(default)]
+    pub accept_invalid_certs: bool,
+    /// A PEM file with the client's certificate, for mutual TLS
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub client_certificate: Option<PathBuf>,
+    /// A PEM file with the private key for `client_certificate`
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub client_key: Option<PathBuf>
+}
+
+/// An HTTP proxy, with credentials for basic authentication if it needs them
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct ProxyConfig {
+    pub host: String,
+    pub port: u16,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub password: Option<String>
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct ResponseCacheConfig {
+    /// How long results are cached when the callee doesn't say
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub default_ttl: Option<u64>
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct RateLimitConfig {
+    pub prefix: String,
+    pub per_second: f64,
+    pub burst: u32,
+    #[serde(default="default_overflow")]
+    pub overflow: Overflow
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct ActivityHistoryConfig {
+    pub capacity: usize,
+    pub max_payload_len: usize
+}
+
+/// Default publish and call options, for clients in `realm` that the router gives `authrole`.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct OptionDefaultsConfig {
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub realm: Option<String>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub authrole: Option<String>,
+    #[serde(default)]
+    pub publish: PublishDefaults,
+    #[serde(default)]
+    pub call: CallDefaults
+}
+
+fn default_connect_timeout() -> u64 {
+    5000
+}
+
+fn default_serializers() -> Vec<Serializer> {
+    vec![Serializer::MsgPack, Serializer::Cbor, Serializer::Json]
+}
+
+fn default_validation_mode() -> ValidationMode {
+    ValidationMode::Lenient
+}
+
+fn default_auto_pong() -> bool {
+    true
+}
+
+fn default_overflow() -> Overflow {
+    Overflow::Reject
+}
+
+impl ClientConfig {
+    /// The default settings for connecting to `realm` at `url`
+    pub fn new(url: &str, realm: &str) -> ClientConfig {
+        ClientConfig {
+            url: url.to_string(),
+            realm: realm.to_string(),
+            trim_realm: false,
+            lowercase_realm: false,
+            connect_timeout: default_connect_timeout(),
+            handshake_timeout: None,
+            welcome_timeout: None,
+            serializers: default_serializers(),
+            headers: BTreeMap::new(),
+            cookies: BTreeMap::new(),
+            authentication: None,
+            ping: None,
+            tls: None,
+            long_poll_url: None,
+            proxy: None,
+            outbound_ttl: None,
+            write_coalescing: None,
+            validation_mode: default_validation_mode(),
+            response_cache: None,
+            rate_limits: Vec::new(),
+            conflated_topics: Vec::new(),
+            activity_history: None,
+            option_defaults: Vec::new()
+        }
+    }
+
+    /// The option defaults for a client in this realm that the router gave `authrole`
+    pub fn option_defaults_for(&self, authrole: Option<&str>) -> OptionDefaults {
+        let mut defaults = OptionDefaults::new();
+        let own_realm = self.realm_policy().apply(&self.realm).unwrap_or_else(|_| self.realm.clone());
+        for entry in &self.option_defaults {
+            let realm_matches = entry.realm.as_ref().map_or(true, |realm| *realm == own_realm);
+            let authrole_matches = entry.authrole.as_ref().map_or(true, |role| Some(role.as_str()) == authrole);
+            if realm_matches && authrole_matches {
+                defaults.merge(&OptionDefaults {
+                    publish: entry.publish.clone(),
+                    call: entry.call.clone()
+                });
+            }
+        }
+        defaults
+    }
+
+    /// Reads a configuration file in the format above
+    pub fn load<P: AsRef<Path>>(path: P) -> WampResult<ClientConfig> {
+        let mut contents = String::new();
+        let mut file = try!(File::open(path).map_err(|e| Error::new(ErrorKind::IOError(e))));
+        try!(file.read_to_string(&mut contents).map_err(|e| Error::new(ErrorKind::IOError(e))));
+        serde_json::from_str(&contents).map_err(|e| Error::new(ErrorKind::JSONError(e)))
+    }
+
+    /// The policy the realm is normalized and checked with
+    pub fn realm_policy(&self) -> RealmPolicy {
+        RealmPolicy::new().with_trim(self.trim_realm).with_lowercase(self.lowercase_realm)
+    }
+
+    /// Connects with these settings, and applies the ones that belong to the client.  An invalid
+    /// realm fails before connecting.
+    pub fn connect(&self) -> WampResult<Client> {
+        let mut client = try!(Connection::from_config(self).connect());
+        client.apply_config(self);
+        Ok(client)
+    }
+}
+
+impl PingConfig {
+    pub fn to_policy(&self) -> PingPolicy {
+        let mut policy = PingPolicy::new().with_auto_pong(self.auto_pong);
+        if let Some(interval) = self.interval {
+            policy = policy.with_interval(Duration::from_millis(interval));
+        }
+        if let Some(timeout) = self.pong_timeout {
+            policy = policy.with_pong_timeout(Duration::from_millis(timeout));
+        }
+        policy
+    }
+}
+
+impl TlsConfig {
+    /// The policy for these settings.  A client certificate is only presented if its key is
+    /// given too.
+    pub fn to_policy(&self) -> TlsPolicy {
+        let mut policy = TlsPolicy::new().with_accept_invalid_certs(self.accept_invalid_certs);
+        if let Some(ref ca_file) = self.ca_file {
+            policy = policy.with_ca_file(ca_file);
+        }
+        if let (&Some(ref certificate), &Some(ref key)) = (&self.client_certificate, &self.client_key) {
+            policy = policy.with_client_certificate(certificate, key);
+        }
+        policy
+    }
+}
+
+impl ProxyConfig {
+    /// The proxy for these settings.  Credentials are only sent if there is a username; a
+    /// missing password is taken to be empty.
+    pub fn to_proxy(&self) -> HttpProxy {
+        let proxy = HttpProxy::new(&self.host, self.port);
+        match self.username {
+            Some(ref username) => proxy.with_basic_auth(username, self.password.as_ref().map_or("", |password| &password[..])),
+            None => proxy
+        }
+    }
+}
+
+impl Connection {
+    /// Makes a connection with the settings in `config` that apply before the client joins the
+    /// realm.  The rest are applied by `Client::apply_config`.  If the realm isn't valid,
+    /// `connect()` fails.
+    pub fn from_config(config: &ClientConfig) -> Connection {
+        let mut connection = Connection::new(&config.url, &config.realm);
+        connection.set_realm_policy(config.realm_policy()).ok();
+        let mut connection_config = ConnectionConfig::new().with_timeout(Duration::from_millis(config.connect_timeout));
+        if let Some(timeout) = config.handshake_timeout {
+            connection_config = connection_config.with_handshake_timeout(Duration::from_millis(timeout));
+        }
+        if let Some(timeout) = config.welcome_timeout {
+            connection_config = connection_config.with_welcome_timeout(Duration::from_millis(timeout));
+        }
+        connection.set_connection_config(connection_config);
+        connection.set_serializers(config.serializers.clone());
+        for (name, value) in &config.headers {
+            connection.add_header(name, value);
+        }
+        for (name, value) in &config.cookies {
+            connection.add_cookie(name, value);
+        }
+        if let Some(ref authentication) = config.authentication {
+            connection.set_ticket(&authentication.authid, &authentication.ticket);
+        }
+        if let Some(ref ping) = config.ping {
+            connection.set_ping_policy(ping.to_policy());
+        }
+        if let Some(ref tls) = config.tls {
+            connection.set_tls_policy(tls.to_policy());
+        }
+        if let Some(ref url) = config.long_poll_url {
+            connection.set_long_poll_fallback(url);
+        }
+        if let Some(ref proxy) = config.proxy {
+            connection.set_proxy(proxy.to_proxy());
+        }
+        connection
+    }
+}
+
+impl Client {
+    /// Applies the settings in `config` that belong to a connected client: outbound expiry,
+    /// write coalescing, validation, the response cache, rate limits, conflation, activity
+    /// history and option defaults.  Rate limits and conflated topics are added to any the
+    /// client already has.
+    pub fn apply_config(&mut self, config: &ClientConfig) {
+        self.set_outbound_ttl(config.outbound_ttl.map(Duration::from_millis));
+        self.set_write_coalescing(config.write_coalescing.map(Duration::from_millis));
+        self.set_validation_mode(config.validation_mode);
+        match config.response_cache {
+            Some(ref cache) => self.enable_response_cache(cache.default_ttl.map(Duration::from_millis)),
+            None => self.disable_response_cache()
+        }
+        for limit in &config.rate_limits {
+            self.set_rate_limit(&limit.prefix, Some(RateLimit::new(limit.per_second, limit.burst).with_overflow(limit.overflow)));
+        }
+        for prefix in &config.conflated_topics {
+            self.set_conflation(prefix, true);
+        }
+        match config.activity_history {
+            Some(ref history) => self.enable_activity_history(history.capacity, history.max_payload_len),
+            None => self.disable_activity_history()
+        }
+        let defaults = config.option_defaults_for(self.authrole().as_ref().map(|role| role.as_str()));
+        self.set_option_defaults(defaults);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ClientConfig, Serializer};
+    use client::{Overflow, PingPolicy, TlsPolicy, HttpProxy};
+    use messages::validation::ValidationMode;
+    use serde_json;
+    use std::time::Duration;
+
+    #[test]
+    fn parse_config() {
+        let config: ClientConfig = serde_json::from_str(r#"{
+            "url": "ws://127.0.0.1:8090/ws",
+            "realm": " Realm1\n",
+            "trim_realm": true,
+            "lowercase_realm": true,
+            "welcome_timeout": 2000,
+            "serializers": ["json"],
+            "headers": {"Authorization": "Bearer abc"},
+            "cookies": {"cbtid": "S8Cd0iy1"},
+            "authentication": {"authid": "joe", "ticket": "secret"},
+            "ping": {"interval": 10000, "pong_timeout": 3000},
+            "tls": {"ca_file": "/etc/wamp/ca.pem", "client_certificate": "client.pem", "client_key": "client.key"},
+            "long_poll_url": "http://127.0.0.1:8090/lp",
+            "proxy": {"host": "proxy.example.com", "port": 3128, "username": "joe"},
+            "validation_mode": "strict",
+            "rate_limits": [{"prefix": "ca.test.sensors", "per_second": 10.0, "burst": 5, "overflow": "coalesce"}, {"prefix": "ca.test", "per_second": 100.0, "burst": 10}],
+            "option_defaults": [
+                {"publish": {"exclude_me": false}},
+                {"authrole": "backend", "publish": {"disclose_me": true}, "call": {"timeout": 2000}},
+                {"realm": "realm2", "call": {"timeout": 100}}
+            ]
+        }"#).unwrap();
+        assert_eq!(config.realm_policy().apply(&config.realm).unwrap(), "realm1");
+        assert_eq!(config.connect_timeout, 5000);
+        assert_eq!((config.handshake_timeout, config.welcome_timeout), (None, Some(2000)));
+        assert_eq!(config.serializers, vec![Serializer::Json]);
+        assert_eq!(config.headers["Authorization"], "Bearer abc");
+        assert_eq!(config.cookies["cbtid"], "S8Cd0iy1");
+        assert_eq!(config.authentication.as_ref().unwrap().authid, "joe");
+        assert_eq!(config.ping.as_ref().unwrap().to_policy(), PingPolicy::new().with_interval(Duration::from_secs(10)).with_pong_timeout(Duration::from_secs(3)));
+        assert_eq!(config.tls.as_ref().unwrap().to_policy(), TlsPolicy::new().with_ca_file("/etc/wamp/ca.pem").with_client_certificate("client.pem", "client.key"));
+        assert_eq!(config.long_poll_url, Some("http://127.0.0.1:8090/lp".to_string()));
+        assert_eq!(config.proxy.as_ref().unwrap().to_proxy(), HttpProxy::new("proxy.example.com", 3128).with_basic_auth("joe", ""));
+        assert_eq!(config.validation_mode, ValidationMode::Strict);
+        assert_eq!(config.rate_limits[0].overflow, Overflow::Coalesce);
+        assert_eq!(config.rate_limits[1].overflow, Overflow::Reject);
+        assert!(config.response_cache.is_none());
+        assert_eq!(config.option_defaults_for(None).publish.exclude_me, Some(false));
+        assert_eq!(config.option_defaults_for(None).call.timeout, None);
+        let backend = config.option_defaults_for(Some("backend"));
+        assert_eq!((backend.publish.exclude_me, backend.publish.disclose_me, backend.call.timeout), (Some(false), Some(true), Some(2000)));
+        assert_eq!(config.option_defaults_for(Some("frontend")).publish.disclose_me, None);
+
+        let defaults: ClientConfig = serde_json::from_str(r#"{"url": "ws://127.0.0.1:8090/ws", "realm": "realm1"}"#).unwrap();
+        assert_eq!(defaults, ClientConfig::new("ws://127.0.0.1:8090/ws", "realm1"));
+        let round_trip: ClientConfig = serde_json::from_str(&serde_json::to_string(&config).unwrap()).unwrap();
+        assert_eq!(round_trip, config);
+    }
+}
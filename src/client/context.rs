@@ -0,0 +1,68 @@
+//! Contains the `EventContext` and `InvocationContext` structs, which tell subscription and
+//! registration callbacks about the event or invocation they are handling.
+use messages::{URI, EventDetails, InvocationDetails};
+use ::ID;
+
+/// What a subscriber is told about an event besides its arguments.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EventContext {
+    pub subscription_id: ID,
+    pub publication_id: ID,
+    /// The topic the event was published to, if the router named it, as it does for events
+    /// matched by a pattern
+    pub topic: Option<URI>,
+    /// When the router received the publication, in milliseconds since the Unix epoch, if the
+    /// router stamps events
+    pub timestamp: Option<u64>,
+    /// The publisher's session ID, if the publisher asked the router to disclose it
+    pub publisher: Option<ID>,
+    pub publisher_authid: Option<String>,
+    pub publisher_authrole: Option<String>
+}
+
+impl EventContext {
+    pub fn new(subscription_id: ID, publication_id: ID, details: EventDetails) -> EventContext {
+        EventContext {
+            subscription_id: subscription_id,
+            publication_id: publication_id,
+            topic: details.topic,
+            timestamp: details.timestamp,
+            publisher: details.publisher,
+            publisher_authid: details.publisher_authid,
+            publisher_authrole: details.publisher_authrole
+        }
+    }
+}
+
+/// What a callee is told about an invocation besides its arguments.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvocationContext {
+    pub registration_id: ID,
+    pub request_id: ID,
+    /// The procedure that was called.  For procedures registered with a pattern, this is the
+    /// URI the caller used, which the router names in the invocation.
+    pub procedure: URI,
+    /// The caller's session ID, if the caller asked the router to disclose it or the procedure
+    /// was registered with `RegisterOptions::with_disclose_caller()`
+    pub caller: Option<ID>,
+    pub caller_authid: Option<String>,
+    pub caller_authrole: Option<String>,
+    /// Set on the chunks of a progressive call that more chunks follow
+    pub progress: bool
+}
+
+impl InvocationContext {
+    /// `registered` is the procedure or pattern the callee registered, for invocations that
+    /// don't name the called procedure
+    pub fn new(registration_id: ID, request_id: ID, registered: &URI, details: InvocationDetails) -> InvocationContext {
+        InvocationContext {
+            registration_id: registration_id,
+            request_id: request_id,
+            procedure: details.procedure.unwrap_or_else(|| registered.clone()),
+            caller: details.caller,
+            caller_authid: details.caller_authid,
+            caller_authrole: details.caller_authrole,
+            progress: details.progress.unwrap_or(false)
+        }
+    }
+}
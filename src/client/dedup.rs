@@ -0,0 +1,222 @@
+//! Contains the `InvocationDedup` struct, which keeps a callee from running a call twice when
+//! the caller retries it, for instance after a timeout on a shared registration.
+//!
+//! WAMP doesn't tell the callee the caller's own request ID, so callers mark the calls that may
+//! be retried with a keyword argument named `_idempotency_key`, holding a string or an integer
+//! that stays the same across retries.  Invocations are keyed by the procedure, the key and the
+//! caller's session, so that two callers can't answer each other's calls by picking the same
+//! key.  Calls without the key always run, and so do calls whose caller the router didn't
+//! disclose, which procedures registered with `RegisterOptions::with_disclose_caller()` avoid.
+//!
+//! A retry that arrives while its call is still running, which only happens for deferred
+//! handlers, is answered when the call is.  Only successful results are kept, so a retry of a
+//! call that failed runs again.  Results are kept for the TTL the cache was made with, and the
+//! oldest are forgotten first once it holds `capacity` results.
+use super::{Client, ConnectionInfo, MessageSender};
+use messages::{Dict, List, URI, Value, InvocationDetails};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use ::{WampResult, CallResult, ID};
+
+pub static IDEMPOTENCY_KEY: &'static str = "_idempotency_key";
+
+type DedupKey = (String, ID, String);
+
+enum Entry {
+    // The call is still running, and these retries of it are waiting for its answer
+    Running(Vec<ID>),
+    Done(Instant, (Option<List>, Option<Dict>))
+}
+
+/// What to do with an invocation
+#[derive(Debug, PartialEq)]
+pub enum Dedup {
+    Run,
+    /// Answer with the result of an earlier invocation of the same call
+    Answer((Option<List>, Option<Dict>)),
+    /// The same call is still running, and its answer will answer this invocation too
+    Wait
+}
+
+pub struct InvocationDedup {
+    ttl: Duration,
+    capacity: usize,
+    entries: HashMap<DedupKey, Entry>,
+    // The keys of finished calls, oldest first
+    finished: VecDeque<DedupKey>,
+    // The running calls that retries may be waiting for, by invocation request ID
+    running: HashMap<ID, DedupKey>
+}
+
+fn idempotency_key(kwargs: &Dict) -> Option<String> {
+    match kwargs.get(IDEMPOTENCY_KEY) {
+        Some(&Value::String(ref key)) => Some(key.clone()),
+        Some(&Value::Integer(key)) => Some(key.to_string()),
+        Some(&Value::UnsignedInteger(key)) => Some(key.to_string()),
+        _ => None
+    }
+}
+
+impl InvocationDedup {
+    pub fn new(ttl: Duration, capacity: usize) -> InvocationDedup {
+        InvocationDedup {
+            ttl: ttl,
+            capacity: capacity,
+            entries: HashMap::new(),
+            finished: VecDeque::new(),
+            running: HashMap::new()
+        }
+    }
+
+    /// Decides what to do with the invocation `request_id` of `procedure`
+    pub fn begin(&mut self, request_id: ID, procedure: &URI, details: &InvocationDetails, kwargs: &Dict) -> Dedup {
+        let key = match (idempotency_key(kwargs), details.caller) {
+            (Some(key), Some(caller)) => (procedure.uri.to_string(), caller, key),
+            (Some(_), None) => {
+                debug!("Not deduplicating a call to {}, whose caller wasn't disclosed", procedure.uri);
+                return Dedup::Run;
+            },
+            (None, _) => return Dedup::Run
+        };
+        self.expire();
+        match self.entries.get_mut(&key) {
+            Some(&mut Entry::Done(_, ref result)) => return Dedup::Answer(result.clone()),
+            Some(&mut Entry::Running(ref mut retries)) => {
+                retries.push(request_id);
+                return Dedup::Wait;
+            },
+            None => {}
+        }
+        self.running.insert(request_id, key.clone());
+        self.entries.insert(key, Entry::Running(Vec::new()));
+        Dedup::Run
+    }
+
+    /// Records the answer to the invocation `request_id`, and returns the retries waiting for it
+    pub fn finish(&mut self, request_id: ID, result: &CallResult<(Option<List>, Option<Dict>)>) -> Vec<ID> {
+        let key = match self.running.remove(&request_id) {
+            Some(key) => key,
+            None => return Vec::new()
+        };
+        let retries = match self.entries.remove(&key) {
+            Some(Entry::Running(retries)) => retries,
+            _ => Vec::new()
+        };
+        if let Ok(ref result) = *result {
+            self.entries.insert(key.clone(), Entry::Done(Instant::now(), result.clone()));
+            self.finished.push_back(key);
+            while self.finished.len() > self.capacity {
+                if let Some(oldest) = self.finished.pop_front() {
+                    self.entries.remove(&oldest);
+                }
+            }
+        }
+        retries
+    }
+
+    /// Forgets the calls that are still running, whose answers will never be recorded, such as
+    /// when the session they were running in ends
+    pub fn forget_running(&mut self) {
+        for (_, key) in self.running.drain() {
+            self.entries.remove(&key);
+        }
+    }
+
+    fn expire(&mut self) {
+        let now = Instant::now();
+        while let Some(oldest) = self.finished.front().cloned() {
+            let expired = match self.entries.get(&oldest) {
+                Some(&Entry::Done(finished, _)) if now.duration_since(finished) < self.ttl => return,
+                Some(&Entry::Done(..)) => true,
+                _ => false
+            };
+            self.finished.pop_front();
+            if expired {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+}
+
+impl ConnectionInfo {
+    /// Answers the invocation `request_id` of `procedure`, along with any retries waiting for it
+    pub fn answer_invocation(&mut self, request_id: ID, procedure: &URI, result: CallResult<(Option<List>, Option<Dict>)>) -> WampResult<()> {
+        self.answer_retries(request_id, procedure, &result);
+        let message = self.invocation_reply(request_id, procedure, result);
+        self.send_message(message)
+    }
+
+    /// Ends the invocation `request_id` of `procedure` with `result`, answering only the retries
+    /// waiting for it.  For invocations whose caller has stopped waiting.
+    pub fn answer_retries(&mut self, request_id: ID, procedure: &URI, result: &CallResult<(Option<List>, Option<Dict>)>) {
+        let retries = match self.invocation_dedup {
+            Some(ref mut dedup) => dedup.finish(request_id, result),
+            None => Vec::new()
+        };
+        for retry in retries {
+            debug!("Answering retried invocation {} with the answer to {}", retry, request_id);
+            let message = self.invocation_reply(retry, procedure, result.clone());
+            self.send_message(message).ok();
+        }
+    }
+}
+
+impl Client {
+    /// Keeps the results of calls marked with an `_idempotency_key` keyword argument for `ttl`,
+    /// and answers retries of them with the kept result instead of running the handler again.
+    /// At most `capacity` results are kept.  Only calls whose caller the router discloses are
+    /// deduplicated.
+    #[cfg(feature = "callee")]
+    pub fn enable_invocation_dedup(&mut self, ttl: Duration, capacity: usize) {
+        self.connection_info.lock().unwrap().invocation_dedup = Some(InvocationDedup::new(ttl, capacity));
+    }
+
+    #[cfg(feature = "callee")]
+    pub fn disable_invocation_dedup(&mut self) {
+        self.connection_info.lock().unwrap().invocation_dedup = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{InvocationDedup, Dedup, IDEMPOTENCY_KEY};
+    use messages::{URI, Value, InvocationDetails, CallError, Reason};
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    #[test]
+    fn retries_are_answered_once() {
+        let mut dedup = InvocationDedup::new(Duration::from_secs(60), 1);
+        let procedure = URI::new("ca.test.charge");
+        let mut details = InvocationDetails::new();
+        details.caller = Some(3);
+        let mut kwargs = HashMap::new();
+        assert_eq!(dedup.begin(1, &procedure, &details, &kwargs), Dedup::Run);
+        assert_eq!(dedup.begin(2, &procedure, &details, &kwargs), Dedup::Run);
+
+        kwargs.insert(IDEMPOTENCY_KEY.to_string(), Value::String("a".to_string()));
+        assert_eq!(dedup.begin(3, &procedure, &details, &kwargs), Dedup::Run);
+        assert_eq!(dedup.begin(4, &procedure, &details, &kwargs), Dedup::Wait);
+        let result = (Some(vec![Value::Integer(1)]), None);
+        assert_eq!(dedup.finish(3, &Ok(result.clone())), vec![4]);
+        assert_eq!(dedup.begin(5, &procedure, &details, &kwargs), Dedup::Answer(result));
+
+        // Another caller's key is its own
+        let mut other = InvocationDetails::new();
+        other.caller = Some(7);
+        assert_eq!(dedup.begin(6, &procedure, &other, &kwargs), Dedup::Run);
+        assert_eq!(dedup.finish(6, &Err(CallError::new(Reason::InvalidArgument, None, None))), Vec::<u64>::new());
+        assert_eq!(dedup.begin(7, &procedure, &other, &kwargs), Dedup::Run);
+
+        // Only `capacity` results are kept
+        dedup.finish(7, &Ok((None, None)));
+        assert_eq!(dedup.begin(8, &procedure, &details, &kwargs), Dedup::Run);
+
+        // Callers the router didn't disclose can't be told apart, so their calls always run
+        let anonymous = InvocationDetails::new();
+        assert_eq!(dedup.begin(9, &procedure, &anonymous, &kwargs), Dedup::Run);
+        assert_eq!(dedup.begin(10, &procedure, &anonymous, &kwargs), Dedup::Run);
+        assert_eq!(dedup.finish(9, &Ok((None, None))), Vec::<u64>::new());
+        assert_eq!(dedup.begin(11, &procedure, &anonymous, &kwargs), Dedup::Run);
+    }
+}
@@ -0,0 +1,96 @@
+//! Contains the `OptionDefaults` struct, which fills in the options that calls and publications
+//! leave unset, so that policies such as disclosing the caller don't have to be repeated at every
+//! call site.
+//!
+//! Defaults only fill options that are `None`, so options set on a call or publication always
+//! win.  `ClientConfig` can choose defaults by realm and by the role the router gives the client.
+use messages::{PublishOptions, CallOptions};
+
+/// What publications are sent with unless they say otherwise.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+pub struct PublishDefaults {
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub disclose_me: Option<bool>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub exclude_me: Option<bool>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub retain: Option<bool>
+}
+
+/// What calls are sent with unless they say otherwise.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+pub struct CallDefaults {
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub disclose_me: Option<bool>,
+    /// In milliseconds
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub timeout: Option<u64>
+}
+
+/// The default options of a client's publications and calls.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+pub struct OptionDefaults {
+    #[serde(default)]
+    pub publish: PublishDefaults,
+    #[serde(default)]
+    pub call: CallDefaults
+}
+
+impl OptionDefaults {
+    /// Leaves every option alone
+    pub fn new() -> OptionDefaults {
+        OptionDefaults::default()
+    }
+
+    /// Takes every default that `other` sets, keeping the rest
+    pub fn merge(&mut self, other: &OptionDefaults) {
+        fn take<T: Clone>(default: &mut Option<T>, other: &Option<T>) {
+            if other.is_some() {
+                *default = other.clone();
+            }
+        }
+        take(&mut self.publish.disclose_me, &other.publish.disclose_me);
+        take(&mut self.publish.exclude_me, &other.publish.exclude_me);
+        take(&mut self.publish.retain, &other.publish.retain);
+        take(&mut self.call.disclose_me, &other.call.disclose_me);
+        take(&mut self.call.timeout, &other.call.timeout);
+    }
+
+    /// Fills in the options the publication leaves unset
+    pub fn apply_to_publish(&self, options: &mut PublishOptions) {
+        options.disclose_me = options.disclose_me.or(self.publish.disclose_me);
+        options.exclude_me = options.exclude_me.or(self.publish.exclude_me);
+        options.retain = options.retain.or(self.publish.retain);
+    }
+
+    /// Fills in the options the call leaves unset
+    pub fn apply_to_call(&self, options: &mut CallOptions) {
+        options.disclose_me = options.disclose_me.or(self.call.disclose_me);
+        options.timeout = options.timeout.or(self.call.timeout);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::OptionDefaults;
+    use messages::{PublishOptions, CallOptions};
+
+    #[test]
+    fn defaults_fill_unset_options() {
+        let mut defaults = OptionDefaults::new();
+        defaults.publish.disclose_me = Some(true);
+        defaults.publish.exclude_me = Some(false);
+        defaults.call.timeout = Some(2000);
+        let mut overrides = OptionDefaults::new();
+        overrides.call.timeout = Some(500);
+        defaults.merge(&overrides);
+
+        let mut options = PublishOptions::new(false).with_exclude_me(true);
+        defaults.apply_to_publish(&mut options);
+        assert_eq!(options, PublishOptions::new(false).with_exclude_me(true).with_disclose_me(true));
+
+        let mut options = CallOptions::new().with_disclose_me(false);
+        defaults.apply_to_call(&mut options);
+        assert_eq!(options, CallOptions::new().with_disclose_me(false).with_timeout(500));
+    }
+}
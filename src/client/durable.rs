@@ -0,0 +1,144 @@
+//! Contains the `DurableQueue` struct, which keeps durable publications in a `Store` until the
+//! router has answered them.
+//!
+//! A durable publication is written to the store before it is sent, and is only removed once
+//! the router has acknowledged or refused it.  A publication that was dropped on the client's
+//! side, because the connection was lost or it was coalesced with a later one, stays in the
+//! store, and is published again the next time the store is given to a client.
+use super::{Client, ConnectionInfo, Pending, RequestKind};
+use messages::{URI, Dict, List, Message, PublishOptions};
+use store::Store;
+use serde_json;
+use std::collections::HashMap;
+use eventual::Future;
+use ::{WampResult, Error, ErrorKind, ID, CallError};
+
+/// How many publications are acknowledged between compactions of the store
+const COMPACT_INTERVAL: usize = 128;
+
+/// What is written to the store for each durable publication
+#[derive(Serialize, Deserialize)]
+struct DurablePublication {
+    topic: URI,
+    args: Option<List>,
+    kwargs: Option<Dict>
+}
+
+pub struct DurableQueue {
+    store: Box<Store>,
+    // The sequence number in the store of each durable publication sent, keyed by request ID
+    in_flight: HashMap<ID, u64>,
+    acked_since_compact: usize
+}
+
+impl DurableQueue {
+    fn new(store: Box<Store>) -> DurableQueue {
+        DurableQueue {
+            store: store,
+            in_flight: HashMap::new(),
+            acked_since_compact: 0
+        }
+    }
+
+    fn ack(&mut self, sequence: u64) {
+        if let Err(e) = self.store.ack(sequence) {
+            warn!("Could not remove durable publication {} from its store: {}", sequence, e);
+            return;
+        }
+        self.acked_since_compact += 1;
+        if self.acked_since_compact >= COMPACT_INTERVAL {
+            self.acked_since_compact = 0;
+            if let Err(e) = self.store.compact() {
+                warn!("Could not compact the durable publication store: {}", e);
+            }
+        }
+    }
+}
+
+impl ConnectionInfo {
+    /// Removes a durable publication from the store once the router has answered it.  Does
+    /// nothing for other publications.
+    pub fn settle_durable_publication(&mut self, request_id: ID) {
+        if let Some(ref mut queue) = self.durable {
+            if let Some(sequence) = queue.in_flight.remove(&request_id) {
+                queue.ack(sequence);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "publisher")]
+impl Client {
+    /// Keeps durable publications in `store` from now on, and publishes every publication left
+    /// in it, for instance by an earlier run of the application.  Returns how many publications
+    /// were sent again.
+    pub fn set_durable_store(&mut self, store: Box<Store>) -> WampResult<usize> {
+        let records: Vec<_> = store.iter().collect();
+        self.connection_info.lock().unwrap().durable = Some(DurableQueue::new(store));
+        let mut replayed = 0;
+        for (sequence, record) in records {
+            match serde_json::from_slice::<DurablePublication>(&record) {
+                Ok(publication) => {
+                    try!(self.send_durable_publication(sequence, publication).map(|_| ()));
+                    replayed += 1;
+                },
+                Err(e) => {
+                    warn!("Discarding durable publication {}, which can't be read: {}", sequence, e);
+                    if let Some(ref mut queue) = self.connection_info.lock().unwrap().durable {
+                        queue.ack(sequence);
+                    }
+                }
+            }
+        }
+        if replayed > 0 {
+            info!("Published {} durable publications again", replayed);
+        }
+        Ok(replayed)
+    }
+
+    /// Publishes to a topic with acknowledgement, keeping the publication in the durable store
+    /// until the router has answered it.  A durable store must have been set first.
+    pub fn publish_durable(&mut self, topic: URI, args: Option<List>, kwargs: Option<Dict>) -> WampResult<Pending<ID>> {
+        info!("Publishing durably to {:?} with {:?} | {:?}", topic, args, kwargs);
+        try!(self.check_publish(&topic));
+        let publication = DurablePublication {
+            topic: topic,
+            args: args,
+            kwargs: kwargs
+        };
+        let record = try!(serde_json::to_vec(&publication).map_err(|e| Error::new(ErrorKind::JSONError(e))));
+        let sequence = match self.connection_info.lock().unwrap().durable {
+            Some(ref mut queue) => try!(queue.store.append(&record)),
+            None => return Err(Error::new(ErrorKind::InvalidState("Tried to publish durably without a durable store")))
+        };
+        self.send_durable_publication(sequence, publication).map(Pending::new).map_err(|e| {
+            // The application is told the publication failed, so it isn't sent again later
+            if let Some(ref mut queue) = self.connection_info.lock().unwrap().durable {
+                queue.ack(sequence);
+            }
+            e
+        })
+    }
+
+    fn send_durable_publication(&mut self, sequence: u64, publication: DurablePublication) -> WampResult<Future<ID, CallError>> {
+        let request_id = self.next_publication_id(&publication.topic);
+        let (complete, future) = Future::<ID, CallError>::pair();
+        let connection_info = self.connection_info.clone();
+        let mut info = connection_info.lock().unwrap();
+        info.publish_requests.insert(request_id, complete);
+        if let Some(ref mut queue) = info.durable {
+            queue.in_flight.insert(request_id, sequence);
+        }
+        info.note_request(request_id, RequestKind::Publish, publication.topic.clone());
+        self.track_request(&info, request_id);
+        let (args, kwargs) = info.compress_payload(&publication.topic, publication.args, publication.kwargs);
+        let sent = info.submit_publication(Message::Publish(request_id, PublishOptions::new(true), publication.topic, args, kwargs), None);
+        if sent.is_err() {
+            if let Some(ref mut queue) = info.durable {
+                queue.in_flight.remove(&request_id);
+            }
+        }
+        try!(sent);
+        Ok(future)
+    }
+}
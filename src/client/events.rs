@@ -0,0 +1,84 @@
+//! Contains the `ClientEvent` notifications, which let supervisory code watch what happens to a
+//! client's session from one channel, instead of setting a hook or callback for each.
+//!
+//! `Client::events()` can be called any number of times, and every receiver gets every event
+//! from then on.  Receivers that have been dropped are forgotten the next time an event is sent.
+//! The channels aren't bounded, so a receiver that is never read keeps every event.  Events
+//! keep coming through `leave_and_rejoin`, which moves the receivers to the new session.
+use super::Client;
+use std::sync::mpsc::{channel, Sender, Receiver};
+use ::{URI, ID};
+
+/// Something that happened to one of the client's subscriptions or registrations, or to its
+/// session.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientEvent {
+    /// The router confirmed a subscription, including when it was made again in a new session.
+    /// Subscriptions that share a router subscription are each confirmed.
+    Subscribed { topic: URI, subscription_id: ID },
+    /// A subscription stopped receiving events without being unsubscribed, because the
+    /// connection closed without the client saying goodbye, or the router didn't confirm it
+    /// again in a new session
+    SubscriptionLost { topic: URI },
+    /// The client left the session with this ID, and is connecting again
+    Reconnecting { session_id: ID },
+    /// The client joined a new session, with this ID, and has made its subscriptions and
+    /// registrations again
+    Rejoined { session_id: ID },
+    /// The router confirmed a registration again in a new session
+    RegistrationRestored { procedure: URI, registration_id: ID },
+    /// The router closed the connection because the client didn't read its events fast enough
+    SlowConsumer
+}
+
+/// The receivers of a client's events.
+pub struct EventBus {
+    senders: Vec<Sender<ClientEvent>>
+}
+
+impl EventBus {
+    pub fn new() -> EventBus {
+        EventBus {
+            senders: Vec::new()
+        }
+    }
+
+    pub fn subscribe(&mut self) -> Receiver<ClientEvent> {
+        let (sender, receiver) = channel();
+        self.senders.push(sender);
+        receiver
+    }
+
+    pub fn emit(&mut self, event: ClientEvent) {
+        if self.senders.is_empty() {
+            return;
+        }
+        trace!("Sending client event {:?}", event);
+        self.senders.retain(|sender| sender.send(event.clone()).is_ok());
+    }
+}
+
+impl Client {
+    /// Returns a channel that receives every `ClientEvent` from now on
+    pub fn events(&self) -> Receiver<ClientEvent> {
+        self.connection_info.lock().unwrap().event_bus.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{EventBus, ClientEvent};
+
+    #[test]
+    fn events_reach_every_live_receiver() {
+        let mut bus = EventBus::new();
+        bus.emit(ClientEvent::SlowConsumer);
+        let first = bus.subscribe();
+        let second = bus.subscribe();
+        bus.emit(ClientEvent::Reconnecting { session_id: 1 });
+        drop(second);
+        bus.emit(ClientEvent::Rejoined { session_id: 2 });
+        assert_eq!(bus.senders.len(), 1);
+        assert_eq!(first.try_iter().collect::<Vec<_>>(), vec![ClientEvent::Reconnecting { session_id: 1 }, ClientEvent::Rejoined { session_id: 2 }]);
+    }
+}
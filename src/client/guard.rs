@@ -0,0 +1,69 @@
+//! Contains the `AllowList` struct, which limits the URIs a client may publish to and call.
+//!
+//! This lets an application hand a restricted session handle to code it doesn't fully trust,
+//! such as a third party plugin, without needing the router to enforce anything.
+use std::collections::HashSet;
+
+/// A set of URI prefixes that publications and calls are restricted to.
+///
+/// A prefix matches the URI itself and any URI below it, so `com.myapp.plugin` allows
+/// `com.myapp.plugin` and `com.myapp.plugin.status`, but not `com.myapp.plugins`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AllowList {
+    publish: HashSet<String>,
+    call: HashSet<String>
+}
+
+/// Checks whether `uri` or any of its ancestors is in the set.  This takes one lookup per
+/// component of the URI, no matter how many prefixes are allowed.
+fn allowed(prefixes: &HashSet<String>, uri: &str) -> bool {
+    if prefixes.contains(uri) {
+        return true;
+    }
+    uri.match_indices('.').any(|(index, _)| prefixes.contains(&uri[..index]))
+}
+
+impl AllowList {
+    /// Creates an allow list that doesn't allow anything
+    pub fn new() -> AllowList {
+        AllowList {
+            publish: HashSet::new(),
+            call: HashSet::new()
+        }
+    }
+
+    pub fn allow_publish(mut self, prefix: &str) -> AllowList {
+        self.publish.insert(prefix.to_string());
+        self
+    }
+
+    pub fn allow_call(mut self, prefix: &str) -> AllowList {
+        self.call.insert(prefix.to_string());
+        self
+    }
+
+    pub fn may_publish(&self, topic: &str) -> bool {
+        allowed(&self.publish, topic)
+    }
+
+    pub fn may_call(&self, procedure: &str) -> bool {
+        allowed(&self.call, procedure)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AllowList;
+
+    #[test]
+    fn prefix_matching() {
+        let allow_list = AllowList::new().allow_publish("ca.test.plugin").allow_call("ca.test.api.read");
+        assert!(allow_list.may_publish("ca.test.plugin"));
+        assert!(allow_list.may_publish("ca.test.plugin.status"));
+        assert!(!allow_list.may_publish("ca.test.plugins"));
+        assert!(!allow_list.may_publish("ca.test"));
+        assert!(!allow_list.may_call("ca.test.plugin.status"));
+        assert!(allow_list.may_call("ca.test.api.read.users"));
+        assert!(!allow_list.may_call("ca.test.api.write"));
+    }
+}
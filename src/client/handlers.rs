@@ -0,0 +1,111 @@
+//! Contains the `HandlerRegistry` struct, which allows subscription and registration callbacks
+//! to be looked up by name and replaced while the client is running.
+use messages::{Dict, List, Reason, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use ::{CallResult, CallError};
+
+pub type EventHandler = Box<FnMut(List, Dict) + Send>;
+pub type ProcedureHandler = Box<FnMut(List, Dict) -> CallResult<(Option<List>, Option<Dict>)> + Send>;
+
+/// A shared table of named event and procedure handlers.
+///
+/// Rather than passing a handler directly to `Client::subscribe()` or `Client::register()`,
+/// pass the callback returned by `event_callback()` or `procedure_callback()`.  That callback
+/// looks up the handler with the given name each time it is invoked, so the handler can be
+/// swapped out with `set_event_handler()` or `set_procedure_handler()` without having to
+/// unsubscribe and resubscribe.
+///
+/// Handlers are called while the registry is locked, so they must not modify the registry
+/// themselves.
+#[derive(Clone)]
+pub struct HandlerRegistry {
+    event_handlers: Arc<Mutex<HashMap<String, EventHandler>>>,
+    procedure_handlers: Arc<Mutex<HashMap<String, ProcedureHandler>>>
+}
+
+impl HandlerRegistry {
+    #[inline]
+    pub fn new() -> HandlerRegistry {
+        HandlerRegistry {
+            event_handlers: Arc::new(Mutex::new(HashMap::new())),
+            procedure_handlers: Arc::new(Mutex::new(HashMap::new()))
+        }
+    }
+
+    /// Binds an event handler to the given name, returning the handler it replaced (if any)
+    pub fn set_event_handler(&self, name: &str, handler: EventHandler) -> Option<EventHandler> {
+        self.event_handlers.lock().unwrap().insert(name.to_string(), handler)
+    }
+
+    pub fn remove_event_handler(&self, name: &str) -> Option<EventHandler> {
+        self.event_handlers.lock().unwrap().remove(name)
+    }
+
+    /// Binds a procedure handler to the given name, returning the handler it replaced (if any)
+    pub fn set_procedure_handler(&self, name: &str, handler: ProcedureHandler) -> Option<ProcedureHandler> {
+        self.procedure_handlers.lock().unwrap().insert(name.to_string(), handler)
+    }
+
+    pub fn remove_procedure_handler(&self, name: &str) -> Option<ProcedureHandler> {
+        self.procedure_handlers.lock().unwrap().remove(name)
+    }
+
+    pub fn has_event_handler(&self, name: &str) -> bool {
+        self.event_handlers.lock().unwrap().contains_key(name)
+    }
+
+    pub fn has_procedure_handler(&self, name: &str) -> bool {
+        self.procedure_handlers.lock().unwrap().contains_key(name)
+    }
+
+    /// Constructs a subscription callback that dispatches to whichever handler is currently bound
+    /// to `name`.  Events that arrive while no handler is bound are dropped.
+    pub fn event_callback(&self, name: &str) -> Box<FnMut(List, Dict)> {
+        let handlers = self.event_handlers.clone();
+        let name = name.to_string();
+        Box::new(move |args, kwargs| {
+            match handlers.lock().unwrap().get_mut(&name) {
+                Some(handler) => handler(args, kwargs),
+                None => {
+                    warn!("Recieved an event for handler {}, but no handler is bound to that name", name);
+                }
+            }
+        })
+    }
+
+    /// Constructs a registration callback that dispatches to whichever handler is currently bound
+    /// to `name`.  Invocations that arrive while no handler is bound fail with `NoSuchProcedure`.
+    pub fn procedure_callback(&self, name: &str) -> Box<FnMut(List, Dict) -> CallResult<(Option<List>, Option<Dict>)>> {
+        let handlers = self.procedure_handlers.clone();
+        let name = name.to_string();
+        Box::new(move |args, kwargs| {
+            match handlers.lock().unwrap().get_mut(&name) {
+                Some(handler) => handler(args, kwargs),
+                None => {
+                    Err(CallError::new(Reason::NoSuchProcedure, Some(vec![Value::String(format!("No handler bound to {}", name))]), None))
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::HandlerRegistry;
+    use messages::{Value, Reason};
+    use std::collections::HashMap;
+
+    #[test]
+    fn rebinding_handlers() {
+        let registry = HandlerRegistry::new();
+        let mut callback = registry.procedure_callback("answer");
+        assert_eq!(callback(Vec::new(), HashMap::new()).unwrap_err().get_reason(), &Reason::NoSuchProcedure);
+
+        registry.set_procedure_handler("answer", Box::new(|_, _| Ok((Some(vec![Value::Integer(1)]), None))));
+        assert_eq!(callback(Vec::new(), HashMap::new()).unwrap().0, Some(vec![Value::Integer(1)]));
+
+        registry.set_procedure_handler("answer", Box::new(|_, _| Ok((Some(vec![Value::Integer(2)]), None))));
+        assert_eq!(callback(Vec::new(), HashMap::new()).unwrap().0, Some(vec![Value::Integer(2)]));
+    }
+}
@@ -0,0 +1,66 @@
+//! Contains the functions that add a connection's own headers and cookies to the websocket
+//! handshake request.
+//!
+//! A header replaces any header of the same name that the request would have had, such as
+//! `Host` or one added before it, so adding a header again changes its value.  The headers that
+//! set up the websocket itself, and the subprotocols, which come from the serializers and
+//! codecs, can't be changed.  Cookies are sent together in one `Cookie` header, after any
+//! `Cookie` header added directly.
+//!
+//! Raw socket connections have no handshake request, so they send neither.
+
+// The headers ws needs to set up the websocket, compared in lowercase
+const RESERVED: [&'static str; 5] = ["connection", "upgrade", "sec-websocket-key", "sec-websocket-version", "sec-websocket-protocol"];
+
+/// The headers to add to the handshake request, with the cookies joined into a `Cookie` header
+pub fn handshake_headers(headers: &[(String, String)], cookies: &[(String, String)]) -> Vec<(String, String)> {
+    let mut headers = headers.to_vec();
+    if !cookies.is_empty() {
+        let mut cookie = cookies.iter().map(|&(ref name, ref value)| format!("{}={}", name, value)).collect::<Vec<_>>().join("; ");
+        if let Some(index) = headers.iter().position(|&(ref name, _)| name.eq_ignore_ascii_case("cookie")) {
+            cookie = format!("{}; {}", headers.remove(index).1, cookie);
+        }
+        headers.push(("Cookie".to_string(), cookie));
+    }
+    headers
+}
+
+/// Adds `headers` to the headers of a handshake request
+pub fn apply_headers(request: &mut Vec<(String, Vec<u8>)>, headers: &[(String, String)]) {
+    for &(ref name, ref value) in headers {
+        if RESERVED.contains(&&name.to_lowercase()[..]) {
+            warn!("Not sending the {} header given for the handshake, which would break the websocket", name);
+            continue;
+        }
+        if name.contains(|c| c == '\r' || c == '\n' || c == ':') || value.contains(|c| c == '\r' || c == '\n') {
+            warn!("Not sending the {:?} header given for the handshake, which isn't a valid header", name);
+            continue;
+        }
+        request.retain(|&(ref existing, _)| !existing.eq_ignore_ascii_case(name));
+        request.push((name.clone(), value.clone().into_bytes()));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{handshake_headers, apply_headers};
+
+    fn pairs(headers: &[(&str, &str)]) -> Vec<(String, String)> {
+        headers.iter().map(|&(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn headers_replace_and_cookies_combine() {
+        let headers = handshake_headers(&pairs(&[("cookie", "a=1"), ("X-Trace", "1")]), &pairs(&[("cbtid", "abc"), ("b", "2")]));
+        assert_eq!(headers, pairs(&[("X-Trace", "1"), ("Cookie", "a=1; cbtid=abc; b=2")]));
+        assert_eq!(handshake_headers(&pairs(&[("X-Trace", "1")]), &[]), pairs(&[("X-Trace", "1")]));
+
+        let mut request = vec![("Host".to_string(), b"127.0.0.1:8090".to_vec()), ("Upgrade".to_string(), b"websocket".to_vec())];
+        apply_headers(&mut request, &pairs(&[("host", "router.example.com"), ("Authorization", "Bearer a"), ("authorization", "Bearer b"), ("upgrade", "h2c"), ("X-Bad", "1\r\nX-Injected: 1")]));
+        assert_eq!(request, vec![
+            ("Upgrade".to_string(), b"websocket".to_vec()),
+            ("host".to_string(), b"router.example.com".to_vec()),
+            ("authorization".to_string(), b"Bearer b".to_vec())
+        ]);
+    }
+}
@@ -0,0 +1,117 @@
+//! Contains the `ActivityHistory` struct, which remembers the last few events and invocations
+//! the client received.
+//!
+//! This answers "what did we actually receive?" while debugging, without logging every frame.
+//! Payloads are kept as JSON, cut short after a configurable number of bytes, so that a history
+//! of large payloads doesn't hold on to much memory.
+use messages::{URI, Dict, List};
+use serde_json;
+use std::collections::VecDeque;
+use std::time::SystemTime;
+use ::ID;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ActivityKind {
+    Event,
+    Invocation
+}
+
+/// An event or invocation the client received.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Activity {
+    pub kind: ActivityKind,
+    /// The topic or procedure, if it is known.  It is always known unless the subscription or
+    /// registration was already removed.
+    pub uri: Option<URI>,
+    /// The subscription or registration the message was for
+    pub target_id: ID,
+    /// The publication ID of an event, or the request ID of an invocation
+    pub message_id: ID,
+    pub received_at: SystemTime,
+    /// The arguments and keyword arguments as a JSON array, possibly truncated
+    pub payload: String,
+    /// The length of the whole payload, before it was truncated
+    pub payload_len: usize
+}
+
+pub struct ActivityHistory {
+    capacity: usize,
+    max_payload_len: usize,
+    entries: VecDeque<Activity>
+}
+
+impl Activity {
+    pub fn is_truncated(&self) -> bool {
+        self.payload.len() < self.payload_len
+    }
+}
+
+impl ActivityHistory {
+    pub fn new(capacity: usize, max_payload_len: usize) -> ActivityHistory {
+        ActivityHistory {
+            capacity: capacity,
+            max_payload_len: max_payload_len,
+            entries: VecDeque::with_capacity(capacity)
+        }
+    }
+
+    pub fn record(&mut self, kind: ActivityKind, uri: Option<URI>, target_id: ID, message_id: ID, args: &List, kwargs: &Dict) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut payload = serde_json::to_string(&(args, kwargs)).unwrap_or_default();
+        let payload_len = payload.len();
+        if payload_len > self.max_payload_len {
+            let mut end = self.max_payload_len;
+            while !payload.is_char_boundary(end) {
+                end -= 1;
+            }
+            payload.truncate(end);
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(Activity {
+            kind: kind,
+            uri: uri,
+            target_id: target_id,
+            message_id: message_id,
+            received_at: SystemTime::now(),
+            payload: payload,
+            payload_len: payload_len
+        });
+    }
+
+    /// The recorded activity, oldest first
+    pub fn entries(&self) -> Vec<Activity> {
+        self.entries.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ActivityHistory, ActivityKind};
+    use messages::{URI, Value};
+    use std::collections::HashMap;
+
+    #[test]
+    fn bounded_history() {
+        let mut history = ActivityHistory::new(2, 12);
+        let kwargs = HashMap::new();
+        for id in 1..4 {
+            history.record(ActivityKind::Event, Some(URI::new("ca.test.topic")), 7, id, &vec![Value::Integer(id as i64)], &kwargs);
+        }
+        let entries = history.entries();
+        assert_eq!(entries.iter().map(|entry| entry.message_id).collect::<Vec<_>>(), vec![2, 3]);
+        assert_eq!(entries[1].payload, "[[3],{}]");
+        assert!(!entries[1].is_truncated());
+
+        // Payloads are only cut between characters
+        let mut history = ActivityHistory::new(1, 4);
+        history.record(ActivityKind::Invocation, None, 8, 4, &vec![Value::String("é a long string".to_string())], &kwargs);
+        let entry = history.entries().pop().unwrap();
+        assert_eq!(entry.payload, "[[\"");
+        assert_eq!(entry.payload_len, 25);
+        assert!(entry.is_truncated());
+    }
+}
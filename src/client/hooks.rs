@@ -0,0 +1,126 @@
+//! Contains the hooks that tell an application about changes to the connection as they happen:
+//! the transport closing, the router saying goodbye, and the client joining a new session.
+//!
+//! The hooks run on the connection's event loop, but without the connection locked, so they can
+//! publish and call through a `Publisher`.
+use super::{Client, DisconnectCause};
+use messages::Reason;
+use std::sync::{Arc, Mutex};
+use ::ID;
+
+/// Runs when the connection closes, however it closes.  See `Client::on_disconnect`.
+pub type DisconnectHook = Box<FnMut(&DisconnectCause)>;
+
+/// Runs when the router says goodbye.  See `Client::on_goodbye`.
+pub type GoodbyeHook = Box<FnMut(&Reason)>;
+
+/// Runs when the client has joined a new session.  See `Client::on_reconnect`.
+pub type ReconnectHook = Box<FnMut(ID)>;
+
+/// The hooks of a client.  They are shared, rather than moved, between sessions, so that a hook
+/// that is running when the client rejoins isn't lost.
+#[derive(Clone)]
+pub struct ConnectionHooks {
+    disconnect: Option<Arc<Mutex<DisconnectHook>>>,
+    goodbye: Option<Arc<Mutex<GoodbyeHook>>>,
+    reconnect: Option<Arc<Mutex<ReconnectHook>>>
+}
+
+impl ConnectionHooks {
+    pub fn new() -> ConnectionHooks {
+        ConnectionHooks {
+            disconnect: None,
+            goodbye: None,
+            reconnect: None
+        }
+    }
+
+    pub fn disconnected(&self, cause: &DisconnectCause) {
+        if let Some(ref hook) = self.disconnect {
+            (hook.lock().unwrap())(cause);
+        }
+    }
+
+    pub fn said_goodbye(&self, reason: &Reason) {
+        if let Some(ref hook) = self.goodbye {
+            (hook.lock().unwrap())(reason);
+        }
+    }
+
+    pub fn reconnected(&self, session_id: ID) {
+        if let Some(ref hook) = self.reconnect {
+            (hook.lock().unwrap())(session_id);
+        }
+    }
+}
+
+impl Client {
+    /// Sets a hook that runs as soon as the connection closes, with the reason it closed,
+    /// replacing any hook set before.  Unlike shutdown hooks, it also runs when the client leaves
+    /// a session to rejoin.
+    pub fn on_disconnect(&mut self, hook: DisconnectHook) {
+        self.connection_info.lock().unwrap().hooks.disconnect = Some(Arc::new(Mutex::new(hook)));
+    }
+
+    /// Sets a hook that runs when the router says goodbye, with the router's reason, replacing
+    /// any hook set before.  It runs before the connection closes.
+    pub fn on_goodbye(&mut self, hook: GoodbyeHook) {
+        self.connection_info.lock().unwrap().hooks.goodbye = Some(Arc::new(Mutex::new(hook)));
+    }
+
+    /// Sets a hook that runs when `leave_and_rejoin` has joined a new session, with the new
+    /// session's ID, replacing any hook set before.  It runs once the subscriptions and
+    /// registrations have been made again.
+    pub fn on_reconnect(&mut self, hook: ReconnectHook) {
+        self.connection_info.lock().unwrap().hooks.reconnect = Some(Arc::new(Mutex::new(hook)));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[cfg(feature = "router")]
+    use client::Connection;
+    use client::DisconnectCause;
+    use messages::{Message, ErrorDetails, Reason};
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+    use testing::{ScriptedRouter, join};
+    #[cfg(feature = "router")]
+    use testing::{start_router, REALM};
+
+    #[test]
+    fn router_goodbyes_run_the_goodbye_and_disconnect_hooks() {
+        let router = ScriptedRouter::start();
+        let mut client = join(&router.url);
+        let (goodbyes, goodbye) = channel();
+        let (disconnects, disconnect) = channel();
+        client.on_goodbye(Box::new(move |reason| goodbyes.send(reason.clone()).unwrap()));
+        client.on_disconnect(Box::new(move |cause| disconnects.send(cause.clone()).unwrap()));
+        router.send(&Message::Goodbye(ErrorDetails::new(), Reason::SystemShutdown));
+        assert_eq!(goodbye.recv_timeout(Duration::from_secs(5)).unwrap(), Reason::SystemShutdown);
+        assert_eq!(router.next(), "[6,{},\"wamp.error.goodbye_and_out\"]");
+        assert!(disconnect.try_recv().is_err());
+        router.close();
+        assert_eq!(disconnect.recv_timeout(Duration::from_secs(5)).unwrap(), DisconnectCause::RouterGoodbye(Reason::SystemShutdown));
+        assert!(disconnect.try_recv().is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "router")]
+    fn rejoining_runs_the_disconnect_and_reconnect_hooks() {
+        let (_router, url) = start_router();
+        let mut client = join(&url);
+        let (disconnects, disconnect) = channel();
+        let (reconnects, reconnect) = channel();
+        client.on_disconnect(Box::new(move |cause| disconnects.send(cause.clone()).unwrap()));
+        client.on_reconnect(Box::new(move |session_id| reconnects.send(session_id).unwrap()));
+        let summary = client.leave_and_rejoin(&Connection::new(&url, REALM), Duration::from_secs(5)).unwrap();
+        assert_eq!(disconnect.recv_timeout(Duration::from_secs(5)).unwrap(), DisconnectCause::Shutdown);
+        assert_eq!(reconnect.recv_timeout(Duration::from_secs(5)).unwrap(), summary.session_id);
+
+        // The hooks carry over to the new session
+        client.shutdown().unwrap();
+        assert_eq!(disconnect.recv_timeout(Duration::from_secs(5)).unwrap(), DisconnectCause::Shutdown);
+        assert!(reconnect.try_recv().is_err());
+    }
+}
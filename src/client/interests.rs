@@ -0,0 +1,167 @@
+//! Contains `Interests`, the client's record of the subscriptions or registrations it wants,
+//! kept apart from the IDs the router gave them in the current session.
+//!
+//! Each subscription or registration the client makes is added under a key that never changes,
+//! and stays until the client removes it or the router refuses it.  The router's IDs are only
+//! bound to keys as the router confirms them, and are all forgotten when the client moves to a
+//! new session, which then makes every interest again, in the order they were first made.
+//! Several interests can share one ID, such as two subscriptions to the same topic with the same
+//! options, which the router only needs to hear about once.
+use std::collections::{BTreeMap, HashMap};
+use ::ID;
+
+/// Identifies one interest for as long as the client has it
+pub type InterestKey = u64;
+
+pub struct Interests<W> {
+    next_key: InterestKey,
+    // Keyed in the order the interests were added
+    wanted: BTreeMap<InterestKey, W>,
+    // The interests the current session has made, by the router's ID for them
+    live: HashMap<ID, Vec<InterestKey>>
+}
+
+impl<W> Interests<W> {
+    pub fn new() -> Interests<W> {
+        Interests {
+            next_key: 1,
+            wanted: BTreeMap::new(),
+            live: HashMap::new()
+        }
+    }
+
+    pub fn add(&mut self, interest: W) -> InterestKey {
+        let key = self.next_key;
+        self.next_key += 1;
+        self.wanted.insert(key, interest);
+        key
+    }
+
+    /// Records that the router knows the interest by `id`.  Does nothing for interests that have
+    /// been removed.
+    pub fn bind(&mut self, key: InterestKey, id: ID) {
+        if !self.wanted.contains_key(&key) {
+            return;
+        }
+        self.unbind_key(key);
+        self.live.entry(id).or_insert_with(Vec::new).push(key);
+    }
+
+    /// Forgets an interest.  Returns it, along with its ID if no other interest shares the ID,
+    /// in which case the router should be told.
+    pub fn remove(&mut self, key: InterestKey) -> Option<(W, Option<ID>)> {
+        let interest = match self.wanted.remove(&key) {
+            Some(interest) => interest,
+            None => return None
+        };
+        Some((interest, self.unbind_key(key)))
+    }
+
+    /// Forgets every interest the router knows by `id`, and returns them
+    pub fn remove_id(&mut self, id: ID) -> Vec<W> {
+        let keys = self.live.remove(&id).unwrap_or_default();
+        keys.into_iter().filter_map(|key| self.wanted.remove(&key)).collect()
+    }
+
+    // Returns the key's ID if the key was the last one bound to it
+    fn unbind_key(&mut self, key: InterestKey) -> Option<ID> {
+        let id = match self.live.iter().find(|&(_, keys)| keys.contains(&key)) {
+            Some((id, _)) => *id,
+            None => return None
+        };
+        let emptied = {
+            let keys = self.live.get_mut(&id).unwrap();
+            keys.retain(|bound| *bound != key);
+            keys.is_empty()
+        };
+        if emptied {
+            self.live.remove(&id);
+            Some(id)
+        } else {
+            None
+        }
+    }
+
+    /// Forgets every ID, for a new session.  The interests themselves are kept.
+    pub fn unbind_all(&mut self) {
+        self.live.clear();
+    }
+
+    pub fn is_live(&self, id: ID) -> bool {
+        self.live.contains_key(&id)
+    }
+
+    pub fn ids(&self) -> Vec<ID> {
+        self.live.keys().cloned().collect()
+    }
+
+    /// The ID the router knows the interest by, if the current session has made it
+    pub fn id_of(&self, key: InterestKey) -> Option<ID> {
+        self.live.iter().find(|&(_, keys)| keys.contains(&key)).map(|(id, _)| *id)
+    }
+
+    /// An ID the current session has for an interest that `matches`
+    pub fn find_live<F>(&self, matches: F) -> Option<ID> where F: Fn(&W) -> bool {
+        self.live.iter().find(|&(_, keys)| keys.iter().any(|key| self.wanted.get(key).map_or(false, &matches))).map(|(id, _)| *id)
+    }
+
+    /// One of the interests the router knows by `id`
+    pub fn first(&self, id: ID) -> Option<&W> {
+        self.live.get(&id).and_then(|keys| keys.first()).and_then(|key| self.wanted.get(key))
+    }
+
+    /// Every interest the router knows by `id`, in the order they were added
+    pub fn by_id_mut(&mut self, id: ID) -> Vec<&mut W> {
+        let keys = match self.live.get(&id) {
+            Some(keys) => keys,
+            None => return Vec::new()
+        };
+        self.wanted.iter_mut().filter(|&(key, _)| keys.contains(key)).map(|(_, interest)| interest).collect()
+    }
+
+    /// Every interest, in the order they were added
+    pub fn iter(&self) -> ::std::collections::btree_map::Iter<'_, InterestKey, W> {
+        self.wanted.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> ::std::collections::btree_map::IterMut<'_, InterestKey, W> {
+        self.wanted.iter_mut()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Interests;
+
+    #[test]
+    fn ids_are_bound_separately_from_interests() {
+        let mut interests = Interests::new();
+        let first = interests.add("ca.test.a");
+        let second = interests.add("ca.test.a");
+        let third = interests.add("ca.test.b");
+        interests.bind(first, 10);
+        interests.bind(second, 10);
+        interests.bind(third, 20);
+        assert_eq!(interests.ids().len(), 2);
+        assert_eq!(interests.find_live(|topic| *topic == "ca.test.a"), Some(10));
+        assert_eq!(interests.by_id_mut(10).len(), 2);
+
+        // Only the last interest sharing an ID needs the router to forget it
+        assert_eq!(interests.remove(first), Some(("ca.test.a", None)));
+        assert_eq!(interests.remove(second), Some(("ca.test.a", Some(10))));
+        assert_eq!(interests.remove(second), None);
+        assert!(!interests.is_live(10));
+
+        // A new session makes what is left again, in order
+        let fourth = interests.add("ca.test.c");
+        interests.unbind_all();
+        assert!(interests.ids().is_empty());
+        assert_eq!(interests.iter().map(|(key, _)| *key).collect::<Vec<_>>(), vec![third, fourth]);
+        interests.bind(first, 30);
+        assert_eq!(interests.id_of(first), None);
+        assert_eq!(interests.remove_id(20), Vec::<&str>::new());
+        interests.bind(third, 40);
+        assert_eq!(interests.remove_id(40), vec!["ca.test.b"]);
+        assert_eq!(interests.iter().count(), 1);
+    }
+}
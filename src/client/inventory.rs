@@ -0,0 +1,162 @@
+//! Contains `Client::pending_requests`, which lists the requests still waiting for the router's
+//! answer, for diagnosing a client that seems to be stuck.
+use super::{Client, ConnectionInfo, RequestKind};
+use messages::{Message, URI, Reason, CancelMode, CancelOptions};
+use std::collections::HashMap;
+use std::mem;
+use std::time::{Duration, Instant};
+use ::{CallError, ID};
+
+/// A request that the router hasn't answered yet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingRequest {
+    pub request_id: ID,
+    pub kind: RequestKind,
+    /// The topic or procedure the request is about
+    pub uri: URI,
+    /// How long ago the request was made
+    pub age: Duration,
+    /// How long the request could wait to be written before expiring, if the client had an
+    /// outbound TTL when it was made
+    pub timeout: Option<Duration>
+}
+
+pub struct RequestRecord {
+    kind: RequestKind,
+    uri: URI,
+    made: Instant,
+    timeout: Option<Duration>
+}
+
+// Records of answered requests are only dropped once there are this many records
+const RECORD_SLACK: usize = 64;
+
+impl ConnectionInfo {
+    /// Remembers when a request was made, for `Client::pending_requests`.  Requests are removed
+    /// from their maps in many places once they are answered, so the records of answered
+    /// requests are dropped here from time to time instead.
+    pub fn note_request(&mut self, request_id: ID, kind: RequestKind, uri: URI) {
+        if self.request_records.len() >= RECORD_SLACK && self.request_records.len() >= 2 * self.pending_count() {
+            self.prune_request_records();
+        }
+        let timeout = self.outbound.ttl;
+        self.request_records.insert(request_id, RequestRecord {
+            kind: kind,
+            uri: uri,
+            made: Instant::now(),
+            timeout: timeout
+        });
+    }
+
+    fn pending_count(&self) -> usize {
+        self.subscription_requests.len() + self.unsubscription_requests.len() + self.registration_requests.len() + self.publish_requests.len() + self.call_requests.len()
+    }
+
+    fn is_pending(&self, request_id: &ID, kind: RequestKind) -> bool {
+        match kind {
+            RequestKind::Subscribe => self.subscription_requests.contains_key(request_id),
+            RequestKind::Unsubscribe => self.unsubscription_requests.contains_key(request_id),
+            RequestKind::Register | RequestKind::Unregister => self.registration_requests.contains_key(request_id),
+            RequestKind::Publish => self.publish_requests.contains_key(request_id),
+            RequestKind::Call => self.call_requests.contains_key(request_id)
+        }
+    }
+
+    fn prune_request_records(&mut self) {
+        let records = mem::replace(&mut self.request_records, HashMap::new());
+        self.request_records = records.into_iter().filter(|&(ref request_id, ref record)| self.is_pending(request_id, record.kind)).collect();
+    }
+}
+
+impl Client {
+    /// Lists the requests the router hasn't answered yet, oldest first
+    pub fn pending_requests(&self) -> Vec<PendingRequest> {
+        let mut info = self.connection_info.lock().unwrap();
+        info.prune_request_records();
+        let now = Instant::now();
+        let mut requests: Vec<PendingRequest> = info.request_records.iter().map(|(request_id, record)| {
+            PendingRequest {
+                request_id: *request_id,
+                kind: record.kind,
+                uri: record.uri.clone(),
+                age: now.duration_since(record.made),
+                timeout: record.timeout
+            }
+        }).collect();
+        requests.sort_by(|a, b| b.age.cmp(&a.age).then(a.request_id.cmp(&b.request_id)));
+        requests
+    }
+
+    /// Fails every request the router hasn't answered yet with `Reason::Cancelled`, and returns
+    /// how many there were.  Answers that arrive for them later are ignored.  Calls are
+    /// cancelled with the router too, in the `killnowait` mode, and durable publications that
+    /// are cancelled aren't sent again.
+    pub fn cancel_all_pending(&mut self) -> usize {
+        let mut info = self.connection_info.lock().unwrap();
+        let cancelled = info.pending_count();
+        info.fail_interest_requests(Reason::Cancelled);
+        let publications: Vec<_> = info.publish_requests.drain().collect();
+        for (request_id, promise) in publications {
+            info.settle_durable_publication(request_id);
+            promise.fail(CallError::new(Reason::Cancelled, None, None));
+        }
+        let calls: Vec<_> = info.call_requests.drain().collect();
+        for (request_id, promise) in calls {
+            info.progress_handlers.remove(&request_id);
+            if let Some(ref mut cache) = info.response_cache {
+                cache.forget(request_id);
+            }
+            if let Err(e) = info.queue_message(Message::Cancel(request_id, CancelOptions::new(CancelMode::KillNoWait))) {
+                warn!("Could not cancel call {} with the router: {}", request_id, e);
+            }
+            promise.fail(CallError::new(Reason::Cancelled, None, None));
+        }
+        info.request_records.clear();
+        if cancelled > 0 {
+            warn!("Cancelled {} pending requests", cancelled);
+        }
+        cancelled
+    }
+}
+
+#[cfg(all(test, feature = "router", feature = "caller", feature = "callee"))]
+mod test {
+    use client::{Reply, RequestKind};
+    use messages::{URI, CallOptions, Reason};
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::Duration;
+    use testing::{start_router, join};
+
+    #[test]
+    fn cancelled_requests_fail_and_are_forgotten() {
+        let (_router, url) = start_router();
+        let mut callee = join(&url);
+        let mut caller = join(&url);
+        let (interrupted, interrupts) = channel();
+        callee.register_deferred(URI::new("ca.test.stuck"), Box::new(move |_, _, responder| {
+            let interrupted = interrupted.clone();
+            thread::spawn(move || {
+                interrupted.send(responder.cancellation_token().wait_timeout(Duration::from_secs(5))).unwrap();
+            });
+            Reply::Deferred
+        })).unwrap().wait().unwrap();
+
+        let call = caller.call(URI::new("ca.test.stuck"), None, None).unwrap();
+        let progressive = caller.call_with_progress(URI::new("ca.test.stuck"), None, None, CallOptions::new(), Box::new(|_, _| {})).unwrap();
+        let pending = caller.pending_requests();
+        assert_eq!(pending.iter().map(|request| request.request_id).collect::<Vec<_>>(), vec![call.request_id(), progressive.request_id()]);
+        assert!(pending.iter().all(|request| request.kind == RequestKind::Call && request.uri == URI::new("ca.test.stuck")));
+
+        assert_eq!(caller.cancel_all_pending(), 2);
+        assert!(caller.pending_requests().is_empty());
+        assert!(caller.connection_info.lock().unwrap().progress_handlers.is_empty());
+        for call in vec![call, progressive] {
+            assert_eq!(*call.wait_timeout(Duration::from_secs(5)).unwrap_err().get_reason(), Reason::Cancelled);
+        }
+        // The router was asked to cancel the calls, so it interrupts the callee
+        assert!(interrupts.recv_timeout(Duration::from_secs(5)).unwrap());
+        assert!(interrupts.recv_timeout(Duration::from_secs(5)).unwrap());
+        assert_eq!(caller.cancel_all_pending(), 0);
+    }
+}
@@ -0,0 +1,67 @@
+//! Decides what a session does with messages that arrive out of order around joining a realm.
+//!
+//! Until the router welcomes the client, only WELCOME, CHALLENGE and ABORT make sense.  Anything
+//! else means the router and the client disagree about the state of the session, so the client
+//! aborts with `wamp.error.protocol_violation` instead of waiting for a welcome that may never
+//! come.  Once the client has joined, a repeated WELCOME for the same session changes nothing and
+//! is ignored, while a WELCOME for another session is a protocol violation.  A CHALLENGE after
+//! joining is the router asking for fresh credentials, and is answered as usual.
+use messages::Message;
+use ::ID;
+
+/// What to do with a message from the router.
+#[derive(Debug, Clone, PartialEq)]
+pub enum JoinStep {
+    /// Handle the message as usual
+    Handle,
+    /// Drop the message
+    Ignore,
+    /// Abort the session, explaining why
+    Violation(String)
+}
+
+/// Checks a message against the state of the join.  `session_id` is the ID the router welcomed
+/// the client with, or `None` if it hasn't yet.
+pub fn join_step(session_id: Option<ID>, message: &Message) -> JoinStep {
+    match (session_id, message) {
+        (None, &Message::Welcome(..)) |
+        (None, &Message::Challenge(..)) |
+        (None, &Message::Abort(..)) => JoinStep::Handle,
+        (None, message) => JoinStep::Violation(format!("{} received before WELCOME", message.name())),
+        (Some(joined), &Message::Welcome(session_id, _)) => {
+            if session_id == joined {
+                JoinStep::Ignore
+            } else {
+                JoinStep::Violation(format!("WELCOME for session {} received in session {}", session_id, joined))
+            }
+        },
+        (Some(_), _) => JoinStep::Handle
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{join_step, JoinStep};
+    use messages::{Message, WelcomeDetails, RouterRoles, ErrorDetails, Reason, ResultDetails};
+    use std::collections::HashMap;
+
+    fn welcome(session_id: u64) -> Message {
+        Message::Welcome(session_id, WelcomeDetails::new(RouterRoles::new()))
+    }
+
+    #[test]
+    fn only_joining_messages_before_welcome() {
+        assert_eq!(join_step(None, &welcome(7)), JoinStep::Handle);
+        assert_eq!(join_step(None, &Message::Challenge("ticket".to_string(), HashMap::new())), JoinStep::Handle);
+        assert_eq!(join_step(None, &Message::Abort(ErrorDetails::new(), Reason::NoSuchRealm)), JoinStep::Handle);
+        assert_eq!(join_step(None, &Message::Result(1, ResultDetails::new(), None, None)), JoinStep::Violation("RESULT received before WELCOME".to_string()));
+        assert_eq!(join_step(None, &Message::Goodbye(ErrorDetails::new(), Reason::CloseRealm)), JoinStep::Violation("GOODBYE received before WELCOME".to_string()));
+    }
+
+    #[test]
+    fn repeated_welcomes() {
+        assert_eq!(join_step(Some(7), &welcome(7)), JoinStep::Ignore);
+        assert_eq!(join_step(Some(7), &welcome(8)), JoinStep::Violation("WELCOME for session 8 received in session 7".to_string()));
+        assert_eq!(join_step(Some(7), &Message::Challenge("ticket".to_string(), HashMap::new())), JoinStep::Handle);
+    }
+}
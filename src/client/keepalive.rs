@@ -0,0 +1,239 @@
+//! Contains the `PingPolicy` struct, which configures how the client answers and sends websocket
+//! pings.
+//!
+//! By default the client answers every ping from the router and never sends pings of its own.
+//! When an interval is set, the client pings the router that often, and measures the round trip
+//! time from the matching pong.  Only one ping is outstanding at a time: if the router hasn't
+//! answered the last ping when the next one is due, that ping is skipped.  When a pong timeout is
+//! also set, a ping that goes unanswered for that long closes the connection.
+use super::{ConnectionInfo, DisconnectCause, PING};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use utils::as_millis;
+use ws::CloseCode;
+use ::{WampResult, Error, ErrorKind};
+
+/// How many round trip times are kept in `PingStats::rtt_samples`
+const RTT_SAMPLES: usize = 16;
+
+/// How the client answers and sends websocket pings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PingPolicy {
+    auto_pong: bool,
+    interval: Option<Duration>,
+    pong_timeout: Option<Duration>
+}
+
+/// Statistics about the websocket pings the client has sent and received.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PingStats {
+    pub pings_received: u64,
+    pub pings_sent: u64,
+    pub pongs_received: u64,
+    /// The number of pings that weren't answered within the pong timeout
+    pub pongs_missed: u64,
+    /// The round trip times of the most recent pings, oldest first
+    pub rtt_samples: VecDeque<Duration>
+}
+
+pub struct Keepalive {
+    pub policy: PingPolicy,
+    pub stats: PingStats,
+    // The payload of the ping waiting for a pong, and when it was sent
+    outstanding: Option<(Vec<u8>, Instant)>,
+    next_ping: u64,
+    // When the next ping is due, if the policy sends pings
+    ping_at: Option<Instant>
+}
+
+impl PingPolicy {
+    /// Answers pings, and sends none
+    pub fn new() -> PingPolicy {
+        PingPolicy {
+            auto_pong: true,
+            interval: None,
+            pong_timeout: None
+        }
+    }
+
+    /// Whether pings from the router are answered.  Turning this off is only useful for testing
+    /// how a router treats an unresponsive client.
+    pub fn with_auto_pong(mut self, auto_pong: bool) -> PingPolicy {
+        self.auto_pong = auto_pong;
+        self
+    }
+
+    /// Pings the router every `interval`
+    pub fn with_interval(mut self, interval: Duration) -> PingPolicy {
+        self.interval = Some(interval);
+        self
+    }
+
+    /// Closes the connection when a ping goes unanswered for `timeout`.  Has no effect unless an
+    /// interval is set.
+    pub fn with_pong_timeout(mut self, timeout: Duration) -> PingPolicy {
+        self.pong_timeout = Some(timeout);
+        self
+    }
+}
+
+impl PingStats {
+    /// The round trip time of the most recent ping, if one has been answered
+    pub fn last_rtt(&self) -> Option<Duration> {
+        self.rtt_samples.back().cloned()
+    }
+}
+
+impl Keepalive {
+    pub fn new(policy: PingPolicy) -> Keepalive {
+        Keepalive {
+            policy: policy,
+            stats: PingStats {
+                pings_received: 0,
+                pings_sent: 0,
+                pongs_received: 0,
+                pongs_missed: 0,
+                rtt_samples: VecDeque::with_capacity(RTT_SAMPLES)
+            },
+            outstanding: None,
+            next_ping: 0,
+            ping_at: None
+        }
+    }
+
+    /// Starts counting down to the first ping
+    pub fn start(&mut self, now: Instant) {
+        self.ping_at = self.policy.interval.map(|interval| now + interval);
+    }
+
+    /// Records a ping from the router, and returns whether it should be answered
+    pub fn ping_received(&mut self) -> bool {
+        self.stats.pings_received += 1;
+        self.policy.auto_pong
+    }
+
+    /// Returns the payload of the ping to send, if one is due and no ping is still waiting for a
+    /// pong
+    pub fn ping_due(&mut self, now: Instant) -> Option<Vec<u8>> {
+        match (self.ping_at, self.policy.interval) {
+            (Some(ping_at), Some(interval)) if ping_at <= now => self.ping_at = Some(now + interval),
+            _ => return None
+        }
+        if self.outstanding.is_some() {
+            return None;
+        }
+        self.next_ping += 1;
+        let payload = self.next_ping.to_string().into_bytes();
+        self.outstanding = Some((payload.clone(), now));
+        self.stats.pings_sent += 1;
+        Some(payload)
+    }
+
+    /// Records a pong, taking a round trip time sample if it answers the outstanding ping
+    pub fn pong_received(&mut self, payload: &[u8], now: Instant) {
+        self.stats.pongs_received += 1;
+        let answered = match self.outstanding {
+            Some((ref expected, _)) => &expected[..] == payload,
+            None => false
+        };
+        if answered {
+            let (_, sent_at) = self.outstanding.take().unwrap();
+            if self.stats.rtt_samples.len() == RTT_SAMPLES {
+                self.stats.rtt_samples.pop_front();
+            }
+            self.stats.rtt_samples.push_back(now.duration_since(sent_at));
+        }
+    }
+
+    /// Whether the outstanding ping has gone unanswered for longer than the pong timeout
+    pub fn pong_overdue(&self, now: Instant) -> bool {
+        match (&self.outstanding, self.policy.pong_timeout) {
+            (&Some((_, sent_at)), Some(timeout)) => now.duration_since(sent_at) >= timeout,
+            _ => false
+        }
+    }
+
+    /// How long until the connection's ping timer should next fire
+    fn next_tick(&self, now: Instant) -> Option<Duration> {
+        let ping_at = match self.ping_at {
+            Some(ping_at) => ping_at,
+            None => return None
+        };
+        let tick_at = match (&self.outstanding, self.policy.pong_timeout) {
+            (&Some((_, sent_at)), Some(timeout)) => ::std::cmp::min(ping_at, sent_at + timeout),
+            _ => ping_at
+        };
+        Some(tick_at.checked_duration_since(now).unwrap_or(Duration::from_millis(0)))
+    }
+}
+
+impl ConnectionInfo {
+    /// Starts the ping timer, if the policy sends pings
+    pub fn schedule_ping(&mut self) -> WampResult<()> {
+        if let Some(delay) = self.keepalive.next_tick(Instant::now()) {
+            // ws timeouts can't be shorter than a millisecond
+            let delay = ::std::cmp::max(as_millis(delay), 1);
+            try!(self.sender.timeout(delay, PING).map_err(|e| Error::new(ErrorKind::WSError(e))));
+        }
+        Ok(())
+    }
+
+    /// Runs when the ping timer fires.  Closes the connection if the router hasn't answered in
+    /// time, and otherwise sends a ping if one is due.
+    pub fn ping_tick(&mut self) -> WampResult<()> {
+        let now = Instant::now();
+        if self.keepalive.pong_overdue(now) {
+            self.keepalive.stats.pongs_missed += 1;
+            warn!("The router didn't answer a ping within {:?}.  Closing the connection", self.keepalive.policy.pong_timeout.unwrap());
+            self.disconnect_cause = Some(DisconnectCause::ConnectionLost("The router stopped answering pings".to_string()));
+            return self.sender.close(CloseCode::Away).map_err(|e| Error::new(ErrorKind::WSError(e)));
+        }
+        if let Some(payload) = self.keepalive.ping_due(now) {
+            try!(self.sender.ping(payload).map_err(|e| Error::new(ErrorKind::WSError(e))));
+        }
+        self.schedule_ping()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Keepalive, PingPolicy};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn ping_round_trips() {
+        let mut keepalive = Keepalive::new(PingPolicy::new().with_interval(Duration::from_secs(10)).with_pong_timeout(Duration::from_secs(3)));
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        keepalive.start(start);
+        assert_eq!(keepalive.next_tick(start), Some(Duration::from_secs(10)));
+        assert_eq!(keepalive.ping_due(at(9)), None);
+        let payload = keepalive.ping_due(at(10)).unwrap();
+        assert_eq!(keepalive.next_tick(at(11)), Some(Duration::from_secs(2)));
+
+        // A pong for some other ping doesn't count
+        keepalive.pong_received(b"other", at(10) + Duration::from_millis(10));
+        assert_eq!(keepalive.stats.last_rtt(), None);
+        keepalive.pong_received(&payload, at(10) + Duration::from_millis(40));
+        assert_eq!(keepalive.stats.last_rtt(), Some(Duration::from_millis(40)));
+        assert_eq!(keepalive.stats.pongs_received, 2);
+        assert_eq!(keepalive.next_tick(at(11)), Some(Duration::from_secs(9)));
+
+        keepalive.ping_due(at(20)).unwrap();
+        assert!(!keepalive.pong_overdue(at(22)));
+        // Only one ping is outstanding at a time
+        assert_eq!(keepalive.ping_due(at(30)), None);
+        assert!(keepalive.pong_overdue(at(23)));
+        assert_eq!(keepalive.stats.pings_sent, 2);
+    }
+
+    #[test]
+    fn auto_pong_can_be_disabled() {
+        let mut keepalive = Keepalive::new(PingPolicy::new());
+        assert!(keepalive.ping_received());
+        assert_eq!(keepalive.next_tick(Instant::now()), None);
+        let mut keepalive = Keepalive::new(PingPolicy::new().with_auto_pong(false));
+        assert!(!keepalive.ping_received());
+        assert_eq!(keepalive.stats.pings_received, 1);
+    }
+}
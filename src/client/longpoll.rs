@@ -0,0 +1,280 @@
+//! Contains the client side of the WAMP long-poll transport, which `Connection::connect` falls
+//! back to when a router can't be reached over a websocket, for instance because a proxy on the
+//! way blocks websockets.
+//!
+//! The rest of the client only speaks websockets, so the long-poll transport is bridged: a
+//! websocket server is started on a loopback port for the one connection, and relays every
+//! message the client sends to the router's `send` endpoint, while a thread relays whatever the
+//! router's `receive` endpoint returns back to the client.  Closing the connection closes the
+//! long-poll transport and stops the bridge.
+//!
+//! Only `http://` long-poll URLs and the JSON serializer are supported.
+use super::transport;
+use messages::Value;
+use serde_json;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::str;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use url::Url;
+use ws::{CloseCode, Factory, Handler, Handshake, Message as WSMessage, Request, Response, Result as WSResult, Sender};
+
+const WAMP_JSON: &'static str = "wamp.2.json";
+
+/// How long a receive request may wait for messages before the router is considered gone.
+/// Routers answer receive requests that have nothing to return well before this.
+const RECEIVE_TIMEOUT: u64 = 60;
+
+// Messages in a receive response may be batched, separated by this character
+const BATCH_SEPARATOR: char = '\u{1e}';
+
+/// A transport opened on a router's long-poll endpoint
+pub struct LongPollTransport {
+    base: Url,
+    transport: String
+}
+
+impl LongPollTransport {
+    /// Opens a transport on the long-poll endpoint at `url`
+    pub fn open(url: &str) -> Result<LongPollTransport, String> {
+        let base = try!(Url::parse(url).map_err(|e| format!("invalid long-poll URL: {}", e)));
+        if base.scheme() != "http" {
+            return Err("only http:// long-poll URLs are supported".to_string());
+        }
+        let mut request = HashMap::new();
+        request.insert("protocols".to_string(), Value::List(vec![Value::String(WAMP_JSON.to_string())]));
+        let body = try!(serde_json::to_vec(&request).map_err(|e| e.to_string()));
+        let response = try!(post(&endpoint(&base, "open"), &body, None));
+        let response: HashMap<String, Value> = try!(serde_json::from_slice(&response).map_err(|e| format!("invalid open response: {}", e)));
+        match (response.get("protocol"), response.get("transport")) {
+            (Some(&Value::String(ref protocol)), Some(&Value::String(ref transport))) if protocol == WAMP_JSON => {
+                info!("Opened long-poll transport {} at {}", transport, url);
+                Ok(LongPollTransport {
+                    base: base.clone(),
+                    transport: transport.clone()
+                })
+            },
+            _ => Err("the router didn't open a wamp.2.json transport".to_string())
+        }
+    }
+
+    fn url(&self, action: &str) -> Url {
+        endpoint(&self.base, &format!("{}/{}", self.transport, action))
+    }
+
+    pub fn send(&self, message: &str) -> Result<(), String> {
+        post(&self.url("send"), message.as_bytes(), None).map(|_| ())
+    }
+
+    /// Waits for the router to send messages, and returns them
+    pub fn receive(&self) -> Result<Vec<String>, String> {
+        let body = try!(post(&self.url("receive"), &[], Some(Duration::from_secs(RECEIVE_TIMEOUT))));
+        let body = try!(String::from_utf8(body).map_err(|_| "the router sent a message that isn't UTF-8".to_string()));
+        Ok(body.split(BATCH_SEPARATOR).filter(|message| !message.trim().is_empty()).map(|message| message.to_string()).collect())
+    }
+
+    pub fn close(&self) {
+        if let Err(e) = post(&self.url("close"), &[], None) {
+            debug!("Could not close long-poll transport {}: {}", self.transport, e);
+        }
+    }
+}
+
+/// Opens a long-poll transport on `url`, and starts a bridge to it.  Returns the URL of the
+/// bridge's websocket, which accepts a single connection, and only for the random path in the
+/// URL.
+pub fn start_bridge(url: &str) -> Result<String, String> {
+    let transport = Arc::new(try!(LongPollTransport::open(url)));
+    let path = try!(transport::bridge_path());
+    let bridge = BridgeFactory {
+        transport: transport,
+        path: path.clone(),
+        claimed: Arc::new(AtomicBool::new(false))
+    };
+    transport::start_bridge("Long-poll", &path, bridge)
+}
+
+struct BridgeFactory {
+    transport: Arc<LongPollTransport>,
+    path: String,
+    // Set once a connection has been bridged.  Only the first websocket is bridged, since the
+    // transport can't be shared.
+    claimed: Arc<AtomicBool>
+}
+
+impl Factory for BridgeFactory {
+    type Handler = BridgeHandler;
+
+    fn connection_made(&mut self, out: Sender) -> BridgeHandler {
+        BridgeHandler {
+            out: out,
+            transport: self.transport.clone(),
+            path: self.path.clone(),
+            claimed: self.claimed.clone(),
+            bridged: false,
+            closed: Arc::new(AtomicBool::new(false))
+        }
+    }
+
+    fn connection_lost(&mut self, handler: BridgeHandler) {
+        // Stopping from on_close would drop the reply to the client's close frame, so the bridge
+        // only stops once the bridged connection is gone
+        if handler.bridged {
+            handler.out.shutdown().ok();
+        }
+    }
+}
+
+struct BridgeHandler {
+    out: Sender,
+    transport: Arc<LongPollTransport>,
+    path: String,
+    claimed: Arc<AtomicBool>,
+    bridged: bool,
+    closed: Arc<AtomicBool>
+}
+
+impl Handler for BridgeHandler {
+    fn on_request(&mut self, request: &Request) -> WSResult<Response> {
+        transport::accept_bridge_request(request, &self.path, WAMP_JSON)
+    }
+
+    fn on_open(&mut self, _: Handshake) -> WSResult<()> {
+        if self.claimed.swap(true, Ordering::SeqCst) {
+            return self.out.close(CloseCode::Normal);
+        }
+        self.bridged = true;
+        let out = self.out.clone();
+        let transport = self.transport.clone();
+        let closed = self.closed.clone();
+        thread::spawn(move || {
+            while !closed.load(Ordering::SeqCst) {
+                match transport.receive() {
+                    Ok(messages) => {
+                        for message in messages {
+                            if out.send(WSMessage::Text(message)).is_err() {
+                                return;
+                            }
+                        }
+                    },
+                    Err(e) => {
+                        // Routers stop answering receive requests once they have closed the
+                        // transport, which is also how a goodbye ends the session
+                        if !closed.load(Ordering::SeqCst) {
+                            info!("The long-poll transport ended: {}", e);
+                            out.close(CloseCode::Away).ok();
+                        }
+                        return;
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn on_message(&mut self, message: WSMessage) -> WSResult<()> {
+        let message = match message {
+            WSMessage::Text(message) => message,
+            WSMessage::Binary(_) => {
+                warn!("Dropping a binary message, which the long-poll bridge can't send");
+                return Ok(());
+            }
+        };
+        if let Err(e) = self.transport.send(&message) {
+            warn!("Could not send a message over the long-poll transport: {}", e);
+            try!(self.out.close(CloseCode::Away));
+        }
+        Ok(())
+    }
+
+    fn on_close(&mut self, _: CloseCode, _: &str) {
+        if !self.bridged {
+            return;
+        }
+        self.closed.store(true, Ordering::SeqCst);
+        self.transport.close();
+    }
+}
+
+fn endpoint(base: &Url, path: &str) -> Url {
+    let mut url = base.clone();
+    url.set_path(&format!("{}/{}", base.path().trim_end_matches('/'), path));
+    url
+}
+
+/// Makes a POST request and returns the response's body
+fn post(url: &Url, body: &[u8], timeout: Option<Duration>) -> Result<Vec<u8>, String> {
+    let host = try!(url.host_str().ok_or("the long-poll URL has no host".to_string()));
+    let port = url.port_or_known_default().unwrap_or(80);
+    let mut stream = try!(TcpStream::connect((host, port)).map_err(|e| e.to_string()));
+    try!(stream.set_read_timeout(timeout).map_err(|e| e.to_string()));
+    let head = format!("POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", url.path(), host, port, body.len());
+    try!(stream.write_all(head.as_bytes()).and_then(|_| stream.write_all(body)).map_err(|e| e.to_string()));
+    let mut response = Vec::new();
+    try!(stream.read_to_end(&mut response).map_err(|e| e.to_string()));
+    parse_response(&response)
+}
+
+fn parse_response(response: &[u8]) -> Result<Vec<u8>, String> {
+    let end = try!(response.windows(4).position(|window| window == b"\r\n\r\n").ok_or("the router sent an incomplete response".to_string()));
+    let head = try!(str::from_utf8(&response[..end]).map_err(|_| "the router sent invalid headers".to_string()));
+    let body = &response[end + 4..];
+    let mut lines = head.split("\r\n");
+    let status = lines.next().and_then(|line| line.split(' ').nth(1)).and_then(|status| status.parse::<u16>().ok());
+    match status {
+        Some(status) if status >= 200 && status < 300 => (),
+        Some(status) => return Err(format!("the router answered with status {}", status)),
+        None => return Err("the router sent an invalid status line".to_string())
+    }
+    let chunked = lines.any(|line| {
+        let line = line.to_lowercase();
+        line.starts_with("transfer-encoding:") && line.contains("chunked")
+    });
+    if chunked {
+        decode_chunked(body)
+    } else {
+        Ok(body.to_vec())
+    }
+}
+
+fn decode_chunked(mut body: &[u8]) -> Result<Vec<u8>, String> {
+    let mut decoded = Vec::new();
+    loop {
+        let line_end = try!(body.windows(2).position(|window| window == b"\r\n").ok_or("the router sent a truncated chunk".to_string()));
+        let size = try!(str::from_utf8(&body[..line_end]).ok().and_then(|size| usize::from_str_radix(size.split(';').next().unwrap_or("").trim(), 16).ok()).ok_or("the router sent an invalid chunk size".to_string()));
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Ok(decoded);
+        }
+        if body.len() < size {
+            return Err("the router sent a truncated chunk".to_string());
+        }
+        decoded.extend_from_slice(&body[..size]);
+        body = &body[size..];
+        if body.starts_with(b"\r\n") {
+            body = &body[2..];
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{endpoint, parse_response};
+    use url::Url;
+
+    #[test]
+    fn parsing_responses() {
+        assert_eq!(parse_response(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n[]").unwrap(), b"[]".to_vec());
+        assert_eq!(parse_response(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\n[1,\r\n2\r\n2]\r\n0\r\n\r\n").unwrap(), b"[1,2]".to_vec());
+        assert!(parse_response(b"HTTP/1.1 404 Not Found\r\n\r\n").is_err());
+        assert!(parse_response(b"HTTP/1.1 200 OK\r\n").is_err());
+
+        let base = Url::parse("http://127.0.0.1:8080/lp/").unwrap();
+        assert_eq!(endpoint(&base, "open").as_str(), "http://127.0.0.1:8080/lp/open");
+        assert_eq!(endpoint(&base, "kjmd3sBLOUnb3Fyr/receive").as_str(), "http://127.0.0.1:8080/lp/kjmd3sBLOUnb3Fyr/receive");
+    }
+}
@@ -0,0 +1,661 @@
+//! Contains the `SessionMachine` struct, which runs a client session without threads or sockets.
+//!
+//! The machine only turns bytes into events and commands into bytes, so it can be driven by any
+//! I/O stack.  The embedder opens a websocket offering `wamp.2.json`, `wamp.2.msgpack` or
+//! `wamp.2.cbor`, creates a machine for whichever the router chose, then repeatedly:
+//!
+//! * writes every frame from `poll_output` to the socket, as a text frame for JSON and a binary
+//!   frame otherwise,
+//! * passes every frame read from the socket to `feed_bytes`,
+//! * handles every event from `poll_event`, and
+//! * calls `handle_timeout` once the instant from `next_timeout` has passed.
+//!
+//! Requests are identified by the request ID each command returns, and their outcome arrives
+//! later as an event carrying the same ID.
+//!
+//! The session lifecycle itself (joining, leaving and what each router message means for it)
+//! lives in `SessionCore`, which the threaded `Client` uses as well, so both follow the same
+//! rules.  Request tracking stays separate because the `Client` resolves futures and runs
+//! callbacks where the machine emits events.
+use super::DisconnectCause;
+use super::join::{join_step, JoinStep};
+use codec::Frame;
+use messages::{URI, Dict, List, Message, HelloDetails, WelcomeDetails, ClientRoles, SubscribeOptions, PublishOptions, RegisterOptions, CallOptions, YieldOptions, ErrorDetails, ErrorType, Reason};
+use serializer::{self, Serializer, Serialization};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use ::{WampResult, Error, ErrorKind, ID, CallError};
+
+/// The kind of request an event answers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RequestKind {
+    Subscribe,
+    Unsubscribe,
+    Publish,
+    Register,
+    Unregister,
+    Call
+}
+
+/// Something that happened in a session, returned by `SessionMachine::poll_event`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SessionEvent {
+    /// The router welcomed the client, with this session ID
+    Joined(ID),
+    /// The router challenged the client, with the authentication method and extra details.
+    /// Answer it with `SessionMachine::authenticate`.
+    Challenge(String, Dict),
+    Subscribed { request_id: ID, subscription_id: ID },
+    Unsubscribed { request_id: ID },
+    Published { request_id: ID, publication_id: ID },
+    Registered { request_id: ID, registration_id: ID },
+    Unregistered { request_id: ID },
+    Event { subscription_id: ID, publication_id: ID, topic: Option<URI>, args: List, kwargs: Dict },
+    /// One of the client's procedures was called.  Answer it with `SessionMachine::yield_result`
+    /// or `SessionMachine::yield_error`, using the request ID.
+    Invocation { request_id: ID, registration_id: ID, procedure: Option<URI>, args: List, kwargs: Dict },
+    Result { request_id: ID, args: List, kwargs: Dict },
+    /// A request failed, because the router refused it, it timed out or the session ended
+    Error { request_id: ID, kind: RequestKind, error: CallError },
+    /// The session is over.  Nothing more is sent or received.
+    Closed(DisconnectCause)
+}
+
+/// Where a session is in its life.  Shared by `SessionMachine` and `Client`, which both run
+/// their sessions through a `SessionCore`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SessionState {
+    Joining,
+    Joined,
+    /// The client or the router has said goodbye, and the session ends once the other answers or
+    /// the connection closes
+    Leaving,
+    Closed
+}
+
+/// What a message from the router means for the session, decided by `SessionCore::receive`.
+#[derive(Debug, PartialEq)]
+pub enum SessionStep {
+    /// The router welcomed the client, which has joined
+    Welcomed(ID, WelcomeDetails),
+    Challenged(String, Dict),
+    /// A message about the session's requests, subscriptions or registrations
+    Session(Message),
+    /// The router said goodbye, which is answered with `wamp.close.goodbye_and_out`
+    RouterGoodbye(Reason),
+    /// The router answered the client's goodbye
+    Left,
+    /// The router aborted the session, which is now closed
+    Aborted(Reason),
+    /// The router broke the protocol, so the client aborts the session, which is now closed
+    Violation(String),
+    /// Nothing to do, because the message changes nothing
+    Ignored(Message)
+}
+
+/// The life of a session, without its requests: joining, leaving and the messages that make
+/// no sense in the session's state.  Which messages are sent in answer is up to the caller.
+pub struct SessionCore {
+    state: SessionState,
+    session_id: ID
+}
+
+impl SessionCore {
+    pub fn new() -> SessionCore {
+        SessionCore {
+            state: SessionState::Joining,
+            session_id: 0
+        }
+    }
+
+    pub fn state(&self) -> SessionState {
+        self.state
+    }
+
+    /// The session ID, or 0 before the router has welcomed the client
+    pub fn session_id(&self) -> ID {
+        self.session_id
+    }
+
+    /// Starts leaving a session the client has joined, and returns whether it had
+    pub fn leave(&mut self) -> bool {
+        if self.state == SessionState::Joined {
+            self.state = SessionState::Leaving;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn close(&mut self) {
+        self.state = SessionState::Closed;
+    }
+
+    pub fn receive(&mut self, message: Message) -> SessionStep {
+        let session_id = match self.state {
+            SessionState::Joining => Some(None),
+            SessionState::Joined => Some(Some(self.session_id)),
+            _ => None
+        };
+        if let Some(session_id) = session_id {
+            match join_step(session_id, &message) {
+                JoinStep::Handle => {},
+                JoinStep::Ignore => return SessionStep::Ignored(message),
+                JoinStep::Violation(violation) => {
+                    self.state = SessionState::Closed;
+                    return SessionStep::Violation(violation);
+                }
+            }
+        }
+        match (self.state, message) {
+            (SessionState::Closed, message) => SessionStep::Ignored(message),
+            (_, Message::Challenge(authmethod, extra)) => SessionStep::Challenged(authmethod, extra),
+            (SessionState::Joining, Message::Welcome(session_id, details)) => {
+                self.session_id = session_id;
+                self.state = SessionState::Joined;
+                SessionStep::Welcomed(session_id, details)
+            },
+            (SessionState::Leaving, Message::Goodbye(..)) |
+            (SessionState::Leaving, Message::Abort(..)) => SessionStep::Left,
+            (_, Message::Abort(_, reason)) => {
+                self.state = SessionState::Closed;
+                SessionStep::Aborted(reason)
+            },
+            (SessionState::Joined, Message::Goodbye(_, reason)) => {
+                self.state = SessionState::Leaving;
+                SessionStep::RouterGoodbye(reason)
+            },
+            // join_step lets nothing else through while joining, and requests made before
+            // leaving may still be answered
+            (_, message) => SessionStep::Session(message)
+        }
+    }
+}
+
+struct PendingRequest {
+    kind: RequestKind,
+    // The subscription or registration being removed, or the topic or procedure being added
+    target_id: ID,
+    uri: Option<URI>,
+    deadline: Option<Instant>
+}
+
+pub struct SessionMachine {
+    core: SessionCore,
+    serializer: Arc<Serializer>,
+    max_request_id: ID,
+    request_timeout: Option<Duration>,
+    pending: HashMap<ID, PendingRequest>,
+    subscriptions: HashMap<ID, URI>,
+    registrations: HashMap<ID, URI>,
+    output: VecDeque<Vec<u8>>,
+    events: VecDeque<SessionEvent>
+}
+
+impl SessionMachine {
+    /// Starts joining `realm` over the protocol the router chose, which must be `wamp.2.json`,
+    /// `wamp.2.msgpack` or `wamp.2.cbor`.  The hello message is ready in `poll_output` straight away.
+    pub fn new(realm: &str, protocol: &str) -> WampResult<SessionMachine> {
+        SessionMachine::new_with_details(realm, protocol, HelloDetails::new(ClientRoles::new()))
+    }
+
+    /// Starts joining `realm` as `authid`, offering the router `authmethods`.  Challenges arrive
+    /// as `SessionEvent::Challenge`.
+    pub fn new_with_authentication(realm: &str, protocol: &str, authid: &str, authmethods: Vec<String>) -> WampResult<SessionMachine> {
+        SessionMachine::new_with_details(realm, protocol, HelloDetails::new_with_authentication(ClientRoles::new(), authid, authmethods))
+    }
+
+    fn new_with_details(realm: &str, protocol: &str, details: HelloDetails) -> WampResult<SessionMachine> {
+        let serializer = match serializer::for_protocol(protocol, &[]) {
+            Some(serializer) => serializer,
+            None => return Err(Error::new(ErrorKind::InvalidState("Session machines only support JSON, MsgPack and CBOR")))
+        };
+        let mut machine = SessionMachine {
+            core: SessionCore::new(),
+            serializer: serializer,
+            max_request_id: 0,
+            request_timeout: None,
+            pending: HashMap::new(),
+            subscriptions: HashMap::new(),
+            registrations: HashMap::new(),
+            output: VecDeque::new(),
+            events: VecDeque::new()
+        };
+        try!(machine.send(Message::Hello(URI::new(realm), details)));
+        Ok(machine)
+    }
+
+    /// Fails requests the router hasn't answered within `timeout`, with `Reason::Timeout`.
+    /// Only requests made afterwards are affected.
+    pub fn with_request_timeout(mut self, timeout: Duration) -> SessionMachine {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    /// The session ID, or 0 before the router has welcomed the client
+    pub fn session_id(&self) -> ID {
+        self.core.session_id()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.core.state() == SessionState::Closed
+    }
+
+    /// Processes one websocket frame from the router.  A frame that can't be parsed is an
+    /// error, and leaves the machine as it was.
+    pub fn feed_bytes(&mut self, frame: &[u8]) -> WampResult<()> {
+        let frame = match self.serializer.serialization() {
+            Some(Serialization::Json) => Frame::Text(try!(String::from_utf8(frame.to_vec()).map_err(|_| Error::new(ErrorKind::MalformedData)))),
+            _ => Frame::Binary(frame.to_vec())
+        };
+        let message = try!(self.serializer.decode(frame));
+        self.handle_message(message);
+        Ok(())
+    }
+
+    /// The next frame to write to the socket, if there is one
+    pub fn poll_output(&mut self) -> Option<Vec<u8>> {
+        self.output.pop_front()
+    }
+
+    /// The next event, if there is one
+    pub fn poll_event(&mut self) -> Option<SessionEvent> {
+        self.events.pop_front()
+    }
+
+    /// When `handle_timeout` next needs to be called, if a request can time out
+    pub fn next_timeout(&self) -> Option<Instant> {
+        self.pending.values().filter_map(|request| request.deadline).min()
+    }
+
+    /// Fails every request whose deadline is at or before `now`
+    pub fn handle_timeout(&mut self, now: Instant) {
+        let expired: Vec<ID> = self.pending.iter()
+            .filter(|&(_, request)| request.deadline.map_or(false, |deadline| deadline <= now))
+            .map(|(request_id, _)| *request_id)
+            .collect();
+        for request_id in expired {
+            self.fail_request(request_id, CallError::new(Reason::Timeout, None, None));
+        }
+    }
+
+    /// Ends the session because the connection was lost, failing every request still waiting for
+    /// an answer
+    pub fn connection_lost(&mut self, reason: &str) {
+        if self.core.state() != SessionState::Closed {
+            self.close(DisconnectCause::ConnectionLost(reason.to_string()));
+        }
+    }
+
+    /// Answers an authentication challenge
+    pub fn authenticate(&mut self, signature: &str, extra: Dict) -> WampResult<()> {
+        if self.core.state() == SessionState::Closed {
+            return Err(Error::new(ErrorKind::InvalidState("Tried to authenticate after the session ended")));
+        }
+        try!(self.send(Message::Authenticate(signature.to_string(), extra)));
+        Ok(())
+    }
+
+    #[cfg(feature = "subscriber")]
+    pub fn subscribe(&mut self, topic: URI) -> WampResult<ID> {
+        let request_id = try!(self.start_request(RequestKind::Subscribe, 0, Some(topic.clone())));
+        try!(self.send(Message::Subscribe(request_id, SubscribeOptions::new(), topic)));
+        Ok(request_id)
+    }
+
+    #[cfg(feature = "subscriber")]
+    pub fn unsubscribe(&mut self, subscription_id: ID) -> WampResult<ID> {
+        let request_id = try!(self.start_request(RequestKind::Unsubscribe, subscription_id, None));
+        try!(self.send(Message::Unsubscribe(request_id, subscription_id)));
+        Ok(request_id)
+    }
+
+    /// Publishes to a topic.  An acknowledged publication is answered with
+    /// `SessionEvent::Published` or `SessionEvent::Error`, and others aren't answered at all.
+    #[cfg(feature = "publisher")]
+    pub fn publish(&mut self, topic: URI, args: Option<List>, kwargs: Option<Dict>, acknowledge: bool) -> WampResult<ID> {
+        let request_id = if acknowledge {
+            try!(self.start_request(RequestKind::Publish, 0, None))
+        } else {
+            try!(self.check_joined());
+            self.next_request_id()
+        };
+        try!(self.send(Message::Publish(request_id, PublishOptions::new(acknowledge), topic, args, kwargs)));
+        Ok(request_id)
+    }
+
+    #[cfg(feature = "callee")]
+    pub fn register(&mut self, procedure: URI) -> WampResult<ID> {
+        let request_id = try!(self.start_request(RequestKind::Register, 0, Some(procedure.clone())));
+        try!(self.send(Message::Register(request_id, RegisterOptions::new(), procedure)));
+        Ok(request_id)
+    }
+
+    #[cfg(feature = "callee")]
+    pub fn unregister(&mut self, registration_id: ID) -> WampResult<ID> {
+        let request_id = try!(self.start_request(RequestKind::Unregister, registration_id, None));
+        try!(self.send(Message::Unregister(request_id, registration_id)));
+        Ok(request_id)
+    }
+
+    #[cfg(feature = "caller")]
+    pub fn call(&mut self, procedure: URI, args: Option<List>, kwargs: Option<Dict>) -> WampResult<ID> {
+        let request_id = try!(self.start_request(RequestKind::Call, 0, None));
+        try!(self.send(Message::Call(request_id, CallOptions::new(), procedure, args, kwargs)));
+        Ok(request_id)
+    }
+
+    /// Returns the result of an invocation
+    pub fn yield_result(&mut self, request_id: ID, args: Option<List>, kwargs: Option<Dict>) -> WampResult<()> {
+        try!(self.check_joined());
+        try!(self.send(Message::Yield(request_id, YieldOptions::new(), args, kwargs)));
+        Ok(())
+    }
+
+    /// Fails an invocation
+    pub fn yield_error(&mut self, request_id: ID, error: CallError) -> WampResult<()> {
+        try!(self.check_joined());
+        let details = error.get_details().clone();
+        let (reason, args, kwargs) = error.to_tuple();
+        try!(self.send(Message::Error(ErrorType::Invocation, request_id, details, reason, args, kwargs)));
+        Ok(())
+    }
+
+    /// Says goodbye to the router.  The session is closed once the router says goodbye back.
+    pub fn leave(&mut self) -> WampResult<()> {
+        try!(self.check_joined());
+        try!(self.send(Message::Goodbye(ErrorDetails::new(), Reason::SystemShutdown)));
+        self.core.leave();
+        Ok(())
+    }
+
+    fn handle_message(&mut self, message: Message) {
+        match self.core.receive(message) {
+            SessionStep::Welcomed(session_id, _) => {
+                self.events.push_back(SessionEvent::Joined(session_id));
+            },
+            SessionStep::Challenged(authmethod, extra) => {
+                self.events.push_back(SessionEvent::Challenge(authmethod, extra));
+            },
+            SessionStep::Session(message) => self.handle_session_message(message),
+            SessionStep::RouterGoodbye(reason) => {
+                self.send_or_log(Message::Goodbye(ErrorDetails::new(), Reason::GoodbyeAndOut));
+                self.close(DisconnectCause::RouterGoodbye(reason));
+            },
+            SessionStep::Left => self.close(DisconnectCause::Shutdown),
+            SessionStep::Aborted(reason) => self.close(DisconnectCause::Aborted(reason)),
+            SessionStep::Violation(violation) => {
+                warn!("Protocol violation: {}", violation);
+                self.send_or_log(Message::Abort(ErrorDetails::new_with_message(&violation), Reason::ProtocolViolation));
+                self.close(DisconnectCause::ProtocolViolation(violation));
+            },
+            SessionStep::Ignored(message) => {
+                debug!("Ignoring a {} from the router in a {:?} session", message.name(), self.core.state());
+            }
+        }
+    }
+
+    fn handle_session_message(&mut self, message: Message) {
+        match message {
+            Message::Subscribed(request_id, subscription_id) => {
+                if let Some(request) = self.take_request(request_id, RequestKind::Subscribe) {
+                    if let Some(topic) = request.uri {
+                        self.subscriptions.insert(subscription_id, topic);
+                    }
+                    self.events.push_back(SessionEvent::Subscribed { request_id: request_id, subscription_id: subscription_id });
+                }
+            },
+            Message::Unsubscribed(request_id) => {
+                if let Some(request) = self.take_request(request_id, RequestKind::Unsubscribe) {
+                    self.subscriptions.remove(&request.target_id);
+                    self.events.push_back(SessionEvent::Unsubscribed { request_id: request_id });
+                }
+            },
+            Message::Published(request_id, publication_id) => {
+                if self.take_request(request_id, RequestKind::Publish).is_some() {
+                    self.events.push_back(SessionEvent::Published { request_id: request_id, publication_id: publication_id });
+                }
+            },
+            Message::Registered(request_id, registration_id) => {
+                if let Some(request) = self.take_request(request_id, RequestKind::Register) {
+                    if let Some(procedure) = request.uri {
+                        self.registrations.insert(registration_id, procedure);
+                    }
+                    self.events.push_back(SessionEvent::Registered { request_id: request_id, registration_id: registration_id });
+                }
+            },
+            Message::Unregistered(request_id) => {
+                if let Some(request) = self.take_request(request_id, RequestKind::Unregister) {
+                    self.registrations.remove(&request.target_id);
+                    self.events.push_back(SessionEvent::Unregistered { request_id: request_id });
+                }
+            },
+            Message::Event(subscription_id, publication_id, details, args, kwargs) => {
+                let topic = details.topic.or_else(|| self.subscriptions.get(&subscription_id).cloned());
+                self.events.push_back(SessionEvent::Event {
+                    subscription_id: subscription_id,
+                    publication_id: publication_id,
+                    topic: topic,
+                    args: args.unwrap_or_default(),
+                    kwargs: kwargs.unwrap_or_default()
+                });
+            },
+            Message::Invocation(request_id, registration_id, details, args, kwargs) => {
+                let procedure = details.procedure.or_else(|| self.registrations.get(&registration_id).cloned());
+                self.events.push_back(SessionEvent::Invocation {
+                    request_id: request_id,
+                    registration_id: registration_id,
+                    procedure: procedure,
+                    args: args.unwrap_or_default(),
+                    kwargs: kwargs.unwrap_or_default()
+                });
+            },
+            Message::Result(request_id, _, args, kwargs) => {
+                if self.take_request(request_id, RequestKind::Call).is_some() {
+                    self.events.push_back(SessionEvent::Result { request_id: request_id, args: args.unwrap_or_default(), kwargs: kwargs.unwrap_or_default() });
+                }
+            },
+            Message::Error(_, request_id, details, reason, args, kwargs) => {
+                if self.pending.contains_key(&request_id) {
+                    self.fail_request(request_id, CallError::new(reason, args, kwargs).with_details(details));
+                } else {
+                    warn!("Received an error for a request that wasn't made.  ID: {}", request_id);
+                }
+            },
+            message => {
+                warn!("Received unknown message.  Ignoring. {:?}", message);
+            }
+        }
+    }
+
+    fn check_joined(&self) -> WampResult<()> {
+        if self.core.state() == SessionState::Joined {
+            Ok(())
+        } else {
+            Err(Error::new(ErrorKind::InvalidState("Tried to make a request while not in a session")))
+        }
+    }
+
+    fn next_request_id(&mut self) -> ID {
+        self.max_request_id += 1;
+        self.max_request_id
+    }
+
+    fn start_request(&mut self, kind: RequestKind, target_id: ID, uri: Option<URI>) -> WampResult<ID> {
+        try!(self.check_joined());
+        let request_id = self.next_request_id();
+        self.pending.insert(request_id, PendingRequest {
+            kind: kind,
+            target_id: target_id,
+            uri: uri,
+            deadline: self.request_timeout.map(|timeout| Instant::now() + timeout)
+        });
+        Ok(request_id)
+    }
+
+    /// Removes a pending request, if it is of the kind the router's answer was for
+    fn take_request(&mut self, request_id: ID, kind: RequestKind) -> Option<PendingRequest> {
+        if self.pending.get(&request_id).map(|request| request.kind) != Some(kind) {
+            warn!("Received an answer for a {:?} request that wasn't made.  ID: {}", kind, request_id);
+            return None;
+        }
+        self.pending.remove(&request_id)
+    }
+
+    fn fail_request(&mut self, request_id: ID, error: CallError) {
+        if let Some(request) = self.pending.remove(&request_id) {
+            self.events.push_back(SessionEvent::Error { request_id: request_id, kind: request.kind, error: error });
+        }
+    }
+
+    fn close(&mut self, cause: DisconnectCause) {
+        let mut pending: Vec<ID> = self.pending.keys().cloned().collect();
+        pending.sort();
+        for request_id in pending {
+            self.fail_request(request_id, CallError::new(Reason::NetworkFailure, None, None));
+        }
+        self.subscriptions.clear();
+        self.registrations.clear();
+        self.core.close();
+        self.events.push_back(SessionEvent::Closed(cause));
+    }
+
+    fn send(&mut self, message: Message) -> WampResult<()> {
+        let frame = match try!(self.serializer.encode(&message)) {
+            Frame::Text(text) => text.into_bytes(),
+            Frame::Binary(data) => data
+        };
+        self.output.push_back(frame);
+        Ok(())
+    }
+
+    // For replies to the router, which have no caller to fail
+    fn send_or_log(&mut self, message: Message) {
+        let name = message.name();
+        if let Err(e) = self.send(message) {
+            warn!("Could not send {}: {}", name, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SessionMachine, SessionEvent, RequestKind};
+    use client::DisconnectCause;
+    use messages::{to_msgpack, URI, Value, Reason, Message, WelcomeDetails, RouterRoles};
+    use serde_json;
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    fn output(machine: &mut SessionMachine) -> Vec<String> {
+        let mut frames = Vec::new();
+        while let Some(frame) = machine.poll_output() {
+            frames.push(String::from_utf8(frame).unwrap());
+        }
+        frames
+    }
+
+    fn events(machine: &mut SessionMachine) -> Vec<SessionEvent> {
+        let mut events = Vec::new();
+        while let Some(event) = machine.poll_event() {
+            events.push(event);
+        }
+        events
+    }
+
+    #[test]
+    #[cfg(all(feature = "subscriber", feature = "caller"))]
+    fn session_without_io() {
+        let mut machine = SessionMachine::new("ca.test", "wamp.2.json").unwrap();
+        let hello = output(&mut machine);
+        assert_eq!(hello.len(), 1);
+        assert!(hello[0].starts_with("[1,\"ca.test\","));
+        assert!(machine.subscribe(URI::new("ca.test.topic")).is_err());
+
+        let welcome = serde_json::to_string(&Message::Welcome(77, WelcomeDetails::new(RouterRoles::new()))).unwrap();
+        machine.feed_bytes(welcome.as_bytes()).unwrap();
+        assert_eq!(events(&mut machine), vec![SessionEvent::Joined(77)]);
+
+        let request_id = machine.subscribe(URI::new("ca.test.topic")).unwrap();
+        assert_eq!(output(&mut machine), vec![format!("[32,{},{{}},\"ca.test.topic\"]", request_id)]);
+        machine.feed_bytes(format!("[33,{},5]", request_id).as_bytes()).unwrap();
+        machine.feed_bytes(b"[36,5,9,{},[1]]").unwrap();
+        assert_eq!(events(&mut machine), vec![
+            SessionEvent::Subscribed { request_id: request_id, subscription_id: 5 },
+            SessionEvent::Event { subscription_id: 5, publication_id: 9, topic: Some(URI::new("ca.test.topic")), args: vec![Value::Integer(1)], kwargs: HashMap::new() }
+        ]);
+        assert!(machine.feed_bytes(b"[36,5").is_err());
+
+        let call_id = machine.call(URI::new("ca.test.add"), None, None).unwrap();
+        output(&mut machine);
+        machine.feed_bytes(b"[6,{},\"wamp.error.system_shutdown\"]").unwrap();
+        assert_eq!(output(&mut machine), vec!["[6,{},\"wamp.error.goodbye_and_out\"]".to_string()]);
+        let closed = events(&mut machine);
+        assert_eq!(closed.len(), 2);
+        match closed[0] {
+            SessionEvent::Error { request_id, kind: RequestKind::Call, ref error } => {
+                assert_eq!(request_id, call_id);
+                assert_eq!(*error.get_reason(), Reason::NetworkFailure);
+            },
+            ref event => panic!("Unexpected event {:?}", event)
+        }
+        assert_eq!(closed[1], SessionEvent::Closed(DisconnectCause::RouterGoodbye(Reason::SystemShutdown)));
+        assert!(machine.is_closed());
+    }
+
+    #[test]
+    fn aborts_on_messages_before_welcome() {
+        let mut machine = SessionMachine::new("ca.test", "wamp.2.json").unwrap();
+        output(&mut machine);
+        machine.feed_bytes(b"[4,\"ticket\",{}]").unwrap();
+        assert_eq!(events(&mut machine), vec![SessionEvent::Challenge("ticket".to_string(), HashMap::new())]);
+        machine.feed_bytes(b"[36,5,9,{},[1]]").unwrap();
+        assert_eq!(output(&mut machine), vec!["[3,{\"message\":\"EVENT received before WELCOME\"},\"wamp.error.protocol_violation\"]".to_string()]);
+        assert_eq!(events(&mut machine), vec![SessionEvent::Closed(DisconnectCause::ProtocolViolation("EVENT received before WELCOME".to_string()))]);
+        assert!(machine.is_closed());
+
+        // A welcome that arrives after the abort doesn't revive the session
+        machine.feed_bytes(serde_json::to_string(&Message::Welcome(77, WelcomeDetails::new(RouterRoles::new()))).unwrap().as_bytes()).unwrap();
+        assert_eq!(events(&mut machine), vec![]);
+    }
+
+    #[test]
+    fn repeated_welcomes() {
+        let mut machine = SessionMachine::new("ca.test", "wamp.2.json").unwrap();
+        output(&mut machine);
+        let welcome = serde_json::to_string(&Message::Welcome(77, WelcomeDetails::new(RouterRoles::new()))).unwrap();
+        machine.feed_bytes(welcome.as_bytes()).unwrap();
+        machine.feed_bytes(welcome.as_bytes()).unwrap();
+        assert_eq!(events(&mut machine), vec![SessionEvent::Joined(77)]);
+        assert_eq!(output(&mut machine), Vec::<String>::new());
+
+        let other = serde_json::to_string(&Message::Welcome(78, WelcomeDetails::new(RouterRoles::new()))).unwrap();
+        machine.feed_bytes(other.as_bytes()).unwrap();
+        assert_eq!(output(&mut machine).len(), 1);
+        assert_eq!(events(&mut machine), vec![SessionEvent::Closed(DisconnectCause::ProtocolViolation("WELCOME for session 78 received in session 77".to_string()))]);
+    }
+
+    #[test]
+    #[cfg(feature = "callee")]
+    fn requests_time_out() {
+        let mut machine = SessionMachine::new("ca.test", "wamp.2.msgpack").unwrap().with_request_timeout(Duration::from_secs(5));
+        assert_eq!(machine.next_timeout(), None);
+        machine.feed_bytes(&to_msgpack(&Message::Welcome(7, WelcomeDetails::new(RouterRoles::new()))).unwrap()).unwrap();
+        assert_eq!(events(&mut machine), vec![SessionEvent::Joined(7)]);
+        let request_id = machine.register(URI::new("ca.test.add")).unwrap();
+        let deadline = machine.next_timeout().unwrap();
+
+        machine.handle_timeout(deadline - Duration::from_secs(1));
+        assert_eq!(events(&mut machine), vec![]);
+        machine.handle_timeout(Instant::now() + Duration::from_secs(6));
+        match events(&mut machine).pop() {
+            Some(SessionEvent::Error { request_id: failed, kind: RequestKind::Register, ref error }) => {
+                assert_eq!(failed, request_id);
+                assert_eq!(*error.get_reason(), Reason::Timeout);
+            },
+            event => panic!("Unexpected event {:?}", event)
+        }
+        assert_eq!(machine.next_timeout(), None);
+    }
+}
@@ -0,0 +1,138 @@
+//! Contains the `Mirror` struct, which bridges topics and procedures from one router or realm to
+//! another.
+use super::{Client, Subscription, Registration, Pending};
+use messages::{URI, Reason};
+use std::time::Duration;
+use ::{WampResult, CallError};
+
+/// Mirrors topics and procedures between two sessions, typically on different routers or
+/// realms, for instance while services move from one deployment to another, or to gather the
+/// events of several edge routers on a central one.
+///
+/// Events published to a mirrored topic in the source session are published again in the
+/// destination session, and calls to a mirrored procedure in the destination session are
+/// forwarded to the source session.  URIs are rewritten on the way by prefix rules, which are
+/// matched on whole URI components like `CompositeClient`'s routes; the longest matching prefix
+/// wins, and URIs that match no rule are left alone.
+///
+/// Callbacks don't see which topic or procedure a pattern matched, so only exact URIs can be
+/// mirrored.  Forwarded calls block the destination session's thread until the source session
+/// answers or the call timeout passes.
+pub struct Mirror {
+    source: Client,
+    destination: Client,
+    rules: Vec<(String, String)>,
+    call_timeout: Duration
+}
+
+fn rewrite(rules: &[(String, String)], uri: &str) -> String {
+    let mut best: Option<&(String, String)> = None;
+    for rule in rules {
+        let prefix = &rule.0;
+        let matches = uri == prefix || (uri.starts_with(prefix.as_str()) && uri[prefix.len()..].starts_with('.'));
+        if matches && best.map_or(true, |best| best.0.len() < prefix.len()) {
+            best = Some(rule);
+        }
+    }
+    match best {
+        Some(&(ref from, ref to)) => format!("{}{}", to, &uri[from.len()..]),
+        None => uri.to_string()
+    }
+}
+
+impl Mirror {
+    /// Constructs a mirror from `source` to `destination`, without any rewrite rules.  Forwarded
+    /// calls may take up to 30 seconds.
+    pub fn new(source: Client, destination: Client) -> Mirror {
+        Mirror {
+            source: source,
+            destination: destination,
+            rules: Vec::new(),
+            call_timeout: Duration::from_secs(30)
+        }
+    }
+
+    /// Rewrites URIs starting with `from` to start with `to` instead in the destination session
+    pub fn add_rewrite(&mut self, from: &str, to: &str) {
+        self.rules.retain(|&(ref existing, _)| existing != from);
+        self.rules.push((from.to_string(), to.to_string()));
+    }
+
+    /// Sets how long a forwarded call may take before it fails
+    pub fn set_call_timeout(&mut self, timeout: Duration) {
+        self.call_timeout = timeout;
+    }
+
+    /// The URI that `uri` in the source session is mirrored as
+    pub fn rewrite(&self, uri: &URI) -> URI {
+        URI::new(&rewrite(&self.rules, &uri.uri))
+    }
+
+    /// Publishes the events of `topic` in the source session to the rewritten topic in the
+    /// destination session
+    #[cfg(all(feature = "subscriber", feature = "publisher"))]
+    pub fn mirror_topic(&mut self, topic: URI) -> WampResult<Pending<Subscription>> {
+        let mirrored = self.rewrite(&topic);
+        let mut publisher = self.destination.publisher();
+        debug!("Mirroring topic {} as {}", topic.uri, mirrored.uri);
+        self.source.subscribe(topic, Box::new(move |args, kwargs| {
+            let args = if args.is_empty() { None } else { Some(args) };
+            let kwargs = if kwargs.is_empty() { None } else { Some(kwargs) };
+            if let Err(e) = publisher.publish(mirrored.clone(), args, kwargs) {
+                warn!("Could not mirror an event to {}: {}", mirrored.uri, e);
+            }
+        }))
+    }
+
+    /// Registers the rewritten procedure in the destination session, forwarding its calls to
+    /// `procedure` in the source session
+    #[cfg(all(feature = "callee", feature = "caller"))]
+    pub fn mirror_procedure(&mut self, procedure: URI) -> WampResult<Pending<Registration>> {
+        let mirrored = self.rewrite(&procedure);
+        let mut caller = self.source.publisher();
+        let timeout = self.call_timeout;
+        debug!("Mirroring procedure {} as {}", procedure.uri, mirrored.uri);
+        self.destination.register(mirrored, Box::new(move |args, kwargs| {
+            let args = if args.is_empty() { None } else { Some(args) };
+            let kwargs = if kwargs.is_empty() { None } else { Some(kwargs) };
+            match caller.call(procedure.clone(), args, kwargs) {
+                Ok(pending) => pending.wait_timeout(timeout).map(|(args, kwargs)| (Some(args), Some(kwargs))),
+                Err(e) => {
+                    warn!("Could not forward a call to {}: {}", procedure.uri, e);
+                    Err(CallError::new(Reason::NetworkFailure, None, None))
+                }
+            }
+        }))
+    }
+
+    pub fn source(&mut self) -> &mut Client {
+        &mut self.source
+    }
+
+    pub fn destination(&mut self) -> &mut Client {
+        &mut self.destination
+    }
+
+    /// Shuts down both sessions.
+    pub fn shutdown(&mut self) -> WampResult<Vec<Pending<()>>> {
+        Ok(vec![try!(self.source.shutdown()), try!(self.destination.shutdown())])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::rewrite;
+
+    #[test]
+    fn rewriting_by_prefix() {
+        let rules = vec![
+            ("com.legacy".to_string(), "com.edge1".to_string()),
+            ("com.legacy.billing".to_string(), "com.billing".to_string())
+        ];
+        assert_eq!(rewrite(&rules, "com.legacy.inventory.update"), "com.edge1.inventory.update");
+        assert_eq!(rewrite(&rules, "com.legacy.billing.charge"), "com.billing.charge");
+        assert_eq!(rewrite(&rules, "com.legacy"), "com.edge1");
+        assert_eq!(rewrite(&rules, "com.legacyish.topic"), "com.legacyish.topic");
+        assert_eq!(rewrite(&rules, "org.other"), "org.other");
+    }
+}
@@ -0,0 +1,2170 @@
+use ws::{
+    connect,
+    Sender,
+    CloseCode,
+    Handler,
+    Message as WSMessage,
+    Handshake,
+    Result as WSResult,
+    Error as WSError,
+    ErrorKind as WSErrorKind,
+    Request,
+};
+
+use ws::util::Token;
+use ws::{Frame as WSFrame, OpCode};
+
+mod auth;
+mod builder;
+mod cache;
+mod call;
+mod cancel;
+mod composite;
+mod config;
+mod context;
+mod dedup;
+mod defaults;
+mod compression;
+mod durable;
+mod events;
+mod guard;
+mod handlers;
+mod headers;
+mod history;
+mod hooks;
+mod interests;
+mod inventory;
+mod join;
+mod keepalive;
+mod longpoll;
+mod machine;
+mod mirror;
+mod oneshot;
+mod orphans;
+mod pending;
+mod pinning;
+mod proxy;
+mod publisher;
+mod queue;
+mod rate_limit;
+mod rawsocket;
+mod realm;
+mod rejoin;
+mod responder;
+mod response_cache;
+mod session;
+mod shutdown;
+mod timeouts;
+mod tls;
+mod transform;
+mod transport;
+mod typed;
+pub use client::auth::{Authenticator, AuthenticateMessage, TicketAuthenticator, WampCraAuthenticator, TicketProvider};
+pub use client::call::{CallHandle, ProgressiveCall};
+pub use client::cancel::CancellationToken;
+pub use client::responder::{Responder, Reply};
+use client::responder::DispatchState;
+pub use client::composite::CompositeClient;
+pub use client::mirror::Mirror;
+pub use client::context::{EventContext, InvocationContext};
+pub use client::dedup::IDEMPOTENCY_KEY;
+pub use client::defaults::{OptionDefaults, PublishDefaults, CallDefaults};
+pub use client::config::{ClientConfig, Serializer, TicketAuthentication, PingConfig, TlsConfig, ProxyConfig, ResponseCacheConfig, RateLimitConfig, ActivityHistoryConfig, OptionDefaultsConfig};
+pub use client::queue::{ExpiredMessage, WriterStats, FlushSummary, PublishOrdering};
+pub use client::compression::{PayloadCompression, PayloadCompressor};
+#[cfg(feature = "gzip")]
+pub use client::compression::GzipCompressor;
+use client::queue::OutboundQueue;
+use client::response_cache::ResponseCache;
+use client::dedup::{InvocationDedup, Dedup};
+pub use client::cache::{SubscriptionCache, CachedSubscription, CachedRegistration};
+pub use client::handlers::{HandlerRegistry, EventHandler, ProcedureHandler};
+pub use client::session::SessionHandle;
+pub use client::history::{Activity, ActivityKind};
+pub use client::events::ClientEvent;
+pub use client::typed::ConversionError;
+#[cfg(feature = "caller")]
+pub use client::typed::TypedCall;
+use client::events::EventBus;
+pub use client::hooks::{DisconnectHook, GoodbyeHook, ReconnectHook};
+use client::hooks::ConnectionHooks;
+pub use client::inventory::PendingRequest;
+use client::inventory::RequestRecord;
+use client::interests::{Interests, InterestKey};
+pub use client::keepalive::{PingPolicy, PingStats};
+use client::keepalive::Keepalive;
+pub use client::machine::{SessionMachine, SessionEvent, RequestKind};
+use client::machine::{SessionCore, SessionState, SessionStep};
+pub use client::orphans::{OrphanEvent, OrphanEventHook};
+#[cfg(feature = "publisher")]
+pub use client::oneshot::publish_once;
+#[cfg(feature = "caller")]
+pub use client::oneshot::call_once;
+pub use client::pending::Pending;
+pub use client::publisher::Publisher;
+use client::history::ActivityHistory;
+use client::durable::DurableQueue;
+pub use client::shutdown::{ShutdownPlan, ShutdownSummary, ShutdownHook, SessionEnd, DisconnectCause};
+use client::shutdown::run_shutdown_hooks;
+pub use client::guard::AllowList;
+pub use client::rate_limit::{RateLimit, Overflow};
+pub use client::realm::RealmPolicy;
+pub use client::rejoin::RejoinSummary;
+pub use client::tls::TlsPolicy;
+pub use client::transport::{Transport, WebSocketTransport};
+pub use client::rawsocket::RawSocketTransport;
+pub use client::proxy::HttpProxy;
+pub use client::transform::{EventChain, ProcedureChain, ArgumentTransformer, ResultTransformer, rename_kwargs, require_kwargs};
+pub use client::timeouts::ConnectionConfig;
+pub use client::pinning::ThreadHints;
+pub use client::builder::{ConnectionBuilder, ReconnectPolicy, ReconnectDecision, ReconnectDecider};
+use client::rate_limit::RateLimiter;
+
+use messages::{DEFAULT_ERROR_URI, GET_EVENTS_PROCEDURE, URI, Dict, List, Value, WelcomeDetails, EventDetails, SubscribeOptions, PublishOptions, CallOptions, InvocationDetails, ResultDetails, RegisterOptions, Message,  HelloDetails, Reason, ErrorDetails, ClientRoles, MatchingPolicy, InvocationPolicy, ErrorType};
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+use std::time::{Duration, Instant};
+use ::{WampResult, Error, ErrorKind, ID, CallResult, CallError};
+use std::thread;
+use std::sync::{Mutex, Arc, MutexGuard};
+use utils::{canonical_key, as_millis};
+use codec::{Codec, Frame};
+use logging::{TRANSPORT_TARGET, PROTOCOL_TARGET};
+use messages::validation::ValidationMode;
+use serializer::{self, JsonSerializer, WAMP_JSON, WAMP_MSGPACK, WAMP_CBOR};
+use eventual::{Complete, Future};
+use url::Url;
+#[cfg(feature = "ssl")]
+use openssl::ssl::Ssl;
+use std::sync::mpsc::{channel, Sender as CHSender};
+
+macro_rules! try_websocket {
+    ($e: expr) => (
+        match $e {
+            Ok(result) => result,
+            Err(e) => return Err(Error::new(ErrorKind::WSError(e)))
+        }
+    );
+}
+
+/// Decides whether an invocation of one of the client's procedures may go ahead
+pub type InvocationAuthorizer = Box<FnMut(&URI, &InvocationDetails, &List, &Dict) -> bool>;
+
+const CONNECTION_TIMEOUT:Token = Token(124);
+const WRITE_QUEUE:Token = Token(125);
+const RATE_LIMIT:Token = Token(126);
+const PING:Token = Token(127);
+const HANDSHAKE_TIMEOUT:Token = Token(128);
+const WELCOME_TIMEOUT:Token = Token(129);
+
+/// How many events are held for a subscription ID the client hasn't been told about yet
+const EARLY_EVENT_LIMIT: usize = 256;
+
+pub struct Connection {
+    // sender: Sender,
+    // receiver: client::Receiver<stream::WebSocketStream>,
+    realm: URI,
+    // What is wrong with the realm, if it isn't valid
+    realm_error: Option<String>,
+    url: String,
+    codecs: Vec<Arc<Codec>>,
+    authentication: Option<(String, Arc<Mutex<Box<Authenticator>>>)>,
+    ping_policy: PingPolicy,
+    serializers: Vec<Serializer>,
+    connection_config: ConnectionConfig,
+    tls_policy: TlsPolicy,
+    thread_hints: ThreadHints,
+    long_poll_url: Option<String>,
+    transport: Option<Arc<Transport>>,
+    headers: Vec<(String, String)>,
+    cookies: Vec<(String, String)>,
+    agent: Option<String>,
+    reconnect_policy: ReconnectPolicy,
+    reconnect_decider: Option<Arc<Mutex<ReconnectDecider>>>
+}
+
+pub struct Subscription {
+    pub topic: URI,
+    subscription_id: ID,
+    // The client's key for the subscription, which outlives its ID
+    key: InterestKey
+}
+
+pub struct Registration {
+    pub procedure: URI,
+    registration_id: ID,
+    key: InterestKey
+}
+
+struct SubscriptionCallbackWrapper {
+    callback: Box<FnMut(List, Dict, &EventContext, &CancellationToken)>,
+    topic: URI,
+    // Kept so that the subscription can be made again in a new session
+    options: SubscribeOptions,
+    // The session handle that made the subscription, or 0 for the client itself
+    owner: ID
+}
+
+struct RegistrationCallbackWrapper {
+    callback: Box<FnMut(List, Dict, &InvocationContext, &CancellationToken, Responder) -> Reply>,
+    procedure: URI,
+    options: RegisterOptions,
+    owner: ID,
+    // Whether the callback takes progressive calls a chunk at a time
+    progressive: bool
+}
+
+/// A subscribe request waiting for the router's answer.  Subscriptions with the same topic and
+/// options that are made while it waits share its answer, rather than being sent again.
+struct SubscriptionRequest {
+    topic: URI,
+    options: SubscribeOptions,
+    // Subscriptions made again in a new session have nobody waiting for them
+    waiting: Vec<(InterestKey, Option<Complete<Subscription, CallError>>)>
+}
+
+/// A register or unregister request waiting for the router's answer.  Both kinds share one map,
+/// keyed by request ID, so that an answer can only ever settle one of them.
+enum RegistrationRequest {
+    Register(Option<Complete<Registration, CallError>>, InterestKey, URI),
+    // Holds the ID of the registration being removed
+    Unregister(Complete<(), CallError>, ID)
+}
+
+
+type ConnectionResult = Result<Arc<Mutex<ConnectionInfo>>, Error>;
+
+unsafe impl <'a> Send for ConnectionInfo {}
+
+unsafe impl<'a> Sync for ConnectionInfo {}
+
+unsafe impl <'a> Send for SubscriptionCallbackWrapper {}
+
+unsafe impl<'a> Sync for SubscriptionCallbackWrapper {}
+
+unsafe impl <'a> Send for RegistrationCallbackWrapper {}
+
+unsafe impl<'a> Sync for RegistrationCallbackWrapper {}
+
+pub struct Client {
+    connection_info: Arc<Mutex<ConnectionInfo>>,
+    // Identifies the session handle this client belongs to, or 0 if it is the original client
+    owner: ID,
+    // The calls and acknowledged publications made through a session handle
+    pending_requests: Vec<ID>,
+    allow_list: Option<AllowList>,
+    // The thread running the connection's event loop, if this client started it
+    receive_thread: Option<thread::JoinHandle<()>>
+}
+
+pub struct ConnectionHandler {
+    connection_info: Arc<Mutex<ConnectionInfo>>,
+    realm: URI,
+    state_transmission: CHSender<ConnectionResult>,
+    codecs: Vec<Arc<Codec>>,
+    serializers: Vec<Serializer>,
+    // Only used with the ssl feature
+    #[cfg_attr(not(feature = "ssl"), allow(dead_code))]
+    tls_policy: TlsPolicy,
+    // The host name to send when setting up TLS
+    #[cfg_attr(not(feature = "ssl"), allow(dead_code))]
+    host: Option<String>,
+    // The router's websocket URL, which the handshake names even when the transport connects
+    // the client somewhere else, such as a proxy's tunnel
+    router_url: Option<Url>,
+    // The authentication ID, methods and extra details to announce in the hello message
+    authentication: Option<(String, Vec<String>, Dict)>,
+    // Extra headers for the handshake request
+    headers: Vec<(String, String)>,
+    agent: Option<String>,
+    welcome_timeout: Option<Duration>,
+    // Whether the websocket handshake has finished
+    opened: bool
+}
+
+pub(crate) struct ConnectionInfo {
+    // Joining, leaving and the session ID, as a `SessionMachine` runs them
+    session: SessionCore,
+    sender: Sender,
+    subscription_requests: HashMap<ID, SubscriptionRequest>,
+    unsubscription_requests: HashMap<ID, (Complete<(), CallError>, ID)>,
+    // Every subscription the client wants, whichever session it is in
+    subscriptions: Interests<SubscriptionCallbackWrapper>,
+    // Events that arrived for an unknown subscription ID while a subscription was pending, in
+    // case they were for that subscription
+    early_events: HashMap<ID, Vec<OrphanEvent>>,
+    orphan_events: u64,
+    orphan_event_hook: Option<OrphanEventHook>,
+    registrations: Interests<RegistrationCallbackWrapper>,
+    call_requests: HashMap<ID, Complete<(List, Dict), CallError>>,
+    // The callbacks given the progressive results of calls made with `Client::call_with_progress()`
+    progress_handlers: HashMap<ID, Box<FnMut(List, Dict)>>,
+    registration_requests: HashMap<ID, RegistrationRequest>,
+    protocol: String,
+    serializer: Arc<serializer::Serializer>,
+    publish_requests: HashMap<ID, Complete<ID, CallError>>,
+    shutdown_complete: Option<Complete<(), CallError>>,
+    max_request_id: ID,
+    max_owner_id: ID,
+    outbound: OutboundQueue,
+    rate_limiter: RateLimiter,
+    compression: Option<PayloadCompression>,
+    response_cache: Option<ResponseCache>,
+    invocation_dedup: Option<InvocationDedup>,
+    // The invocations whose responders haven't answered yet, so interrupts can stop them
+    deferred_invocations: HashMap<ID, (URI, Arc<DispatchState>)>,
+    validation_mode: ValidationMode,
+    protocol_violations: u64,
+    invocation_authorizer: Option<InvocationAuthorizer>,
+    authenticator: Option<Arc<Mutex<Box<Authenticator>>>>,
+    credentials_refreshed: Option<Box<FnMut(&str)>>,
+    // Who the router authenticated the client as, from its welcome
+    authid: Option<String>,
+    authrole: Option<String>,
+    authmethod: Option<String>,
+    // Given to application errors from registered procedures
+    default_error_reason: Reason,
+    option_defaults: OptionDefaults,
+    activity_history: Option<ActivityHistory>,
+    durable: Option<DurableQueue>,
+    keepalive: Keepalive,
+    shutdown_hooks: Vec<ShutdownHook>,
+    // Set when either side says goodbye, or the router aborts
+    disconnect_cause: Option<DisconnectCause>,
+    session_end: Option<SessionEnd>,
+    // While a graceful shutdown is under way, it runs the shutdown hooks itself
+    graceful_shutdown: bool,
+    // Fired when the session starts shutting down or ends
+    cancellation: CancellationToken,
+    // When each request was made, for listing the ones still pending
+    request_records: HashMap<ID, RequestRecord>,
+    hooks: ConnectionHooks,
+    event_bus: EventBus
+}
+
+trait MessageSender {
+    fn send_message(&self, message: Message) -> WampResult<()>;
+
+    /// Sends a frame that `message` was already encoded into
+    fn send_encoded(&self, message: &Message, frame: Frame) -> WampResult<()>;
+}
+
+impl MessageSender for ConnectionInfo{
+    fn send_message(&self, message: Message) -> WampResult<()> {
+
+        debug!(target: TRANSPORT_TARGET, "Sending message {:?} via {}", message, self.protocol);
+        let frame = try!(self.serializer.encode(&message));
+        send_frame(&self.sender, frame)
+    }
+
+    fn send_encoded(&self, message: &Message, frame: Frame) -> WampResult<()> {
+        debug!(target: TRANSPORT_TARGET, "Sending encoded message {:?} via {}", message, self.protocol);
+        send_frame(&self.sender, frame)
+    }
+}
+
+
+
+impl SubscriptionRequest {
+    fn fail(self, error: CallError) {
+        for (_, promise) in self.waiting {
+            if let Some(promise) = promise {
+                promise.fail(error.clone());
+            }
+        }
+    }
+}
+
+impl RegistrationRequest {
+    fn fail(self, error: CallError) {
+        match self {
+            RegistrationRequest::Register(promise, _, _) => {
+                if let Some(promise) = promise {
+                    promise.fail(error);
+                }
+            },
+            RegistrationRequest::Unregister(promise, _) => promise.fail(error)
+        }
+    }
+}
+
+impl ConnectionInfo {
+    /// Forgets the subscriptions a failed subscribe request was making
+    fn forget_subscription_request(&mut self, request: &SubscriptionRequest) {
+        for &(key, _) in &request.waiting {
+            self.subscriptions.remove(key);
+        }
+    }
+
+    /// Forgets the registration a failed register request was making
+    fn forget_registration_request(&mut self, request: &RegistrationRequest) {
+        if let RegistrationRequest::Register(_, key, _) = *request {
+            self.registrations.remove(key);
+        }
+    }
+
+    /// Fails every subscribe, unsubscribe, register and unregister request the router hasn't
+    /// answered.  The subscriptions and registrations they were making are forgotten.
+    fn fail_interest_requests(&mut self, reason: Reason) {
+        let subscription_requests: Vec<_> = self.subscription_requests.drain().map(|(_, request)| request).collect();
+        for request in subscription_requests {
+            self.forget_subscription_request(&request);
+            request.fail(CallError::new(reason.clone(), None, None));
+        }
+        for (_, (promise, _)) in self.unsubscription_requests.drain() {
+            promise.fail(CallError::new(reason.clone(), None, None));
+        }
+        let registration_requests: Vec<_> = self.registration_requests.drain().map(|(_, request)| request).collect();
+        for request in registration_requests {
+            self.forget_registration_request(&request);
+            request.fail(CallError::new(reason.clone(), None, None));
+        }
+    }
+}
+
+fn send_frame(sender: &Sender, frame: Frame) -> WampResult<()> {
+    let frame = match frame {
+        Frame::Text(text) => WSMessage::Text(text),
+        Frame::Binary(data) => WSMessage::Binary(data)
+    };
+    sender.send(frame).map_err(|e| Error::new(ErrorKind::WSError(e)))
+}
+
+impl Connection {
+    /// Makes a connection to `url`, joining `realm`.  The realm is used as given; if it isn't
+    /// valid, `connect()` fails without connecting.  Prefer `Connection::try_new`, which
+    /// normalizes the realm and fails here instead.
+    pub fn new(url: &str, realm: &str) -> Connection {
+        let mut connection = Connection {
+            realm: URI::new(realm),
+            realm_error: None,
+            url: url.to_string(),
+            codecs: Vec::new(),
+            authentication: None,
+            ping_policy: PingPolicy::new(),
+            serializers: vec![Serializer::MsgPack, Serializer::Cbor, Serializer::Json],
+            connection_config: ConnectionConfig::new(),
+            tls_policy: TlsPolicy::new(),
+            thread_hints: ThreadHints::new(),
+            long_poll_url: None,
+            transport: None,
+            headers: Vec::new(),
+            cookies: Vec::new(),
+            agent: None,
+            reconnect_policy: ReconnectPolicy::new(),
+            reconnect_decider: None
+        };
+        connection.set_realm_policy(RealmPolicy::new()).ok();
+        connection
+    }
+
+    /// Starts building a connection to `url`, joining `realm`.  See `ConnectionBuilder`.
+    pub fn builder(url: &str, realm: &str) -> ConnectionBuilder {
+        ConnectionBuilder::new(url, realm)
+    }
+
+    /// Asks the router to authenticate the client as `authid`, using one of the authenticator's
+    /// methods.  The authenticator answers the router's challenges, both while joining the realm
+    /// and any time the router challenges the client again later in the session, for example
+    /// because its ticket is about to expire.
+    pub fn set_authentication(&mut self, authid: &str, authenticator: Box<Authenticator>) {
+        self.authentication = Some((authid.to_string(), Arc::new(Mutex::new(authenticator))));
+    }
+
+    /// Authenticates the client as `authid` using ticket authentication, asking `provider` for
+    /// a new ticket whenever the router challenges the client.  Every connection made with this
+    /// `Connection`, and every challenge the router sends later in a session, gets a fresh
+    /// ticket, so an expired ticket is never sent again.
+    ///
+    /// The connection waits for the ticket before processing any more messages, so the provider
+    /// should fail its future rather than leave it pending if it can't get a ticket.
+    pub fn set_ticket_provider(&mut self, authid: &str, provider: TicketProvider) {
+        self.set_authentication(authid, Box::new(TicketAuthenticator::new(provider)));
+    }
+
+    /// Authenticates the client as `authid` using ticket authentication, always sending `ticket`
+    pub fn set_ticket(&mut self, authid: &str, ticket: &str) {
+        let ticket = ticket.to_string();
+        self.set_ticket_provider(authid, Box::new(move || Future::of(ticket.clone())));
+    }
+
+    /// Authenticates the client as `authid` using WAMP-CRA, signing the router's challenges with
+    /// `secret`.  Salted challenges are supported, in which case the router only needs to know
+    /// the key derived from the secret.
+    pub fn set_wampcra_secret(&mut self, authid: &str, secret: &str) {
+        self.set_authentication(authid, Box::new(WampCraAuthenticator::new(secret)));
+    }
+
+    /// Offers the router a custom codec when connecting.  Codecs are preferred over JSON and
+    /// MsgPack, in the order they were added, but the router falls back to those if it doesn't
+    /// support any of them.
+    pub fn add_codec(&mut self, codec: Arc<Codec>) {
+        self.codecs.push(codec);
+    }
+
+    /// Sets how the client answers and sends websocket pings.  See `PingPolicy`.
+    pub fn set_ping_policy(&mut self, policy: PingPolicy) {
+        self.ping_policy = policy;
+    }
+
+    /// Sets the serializers offered to the router, most preferred first.  The default is MsgPack,
+    /// then CBOR, then JSON.  Custom codecs are still offered before any of them.
+    pub fn set_serializers(&mut self, serializers: Vec<Serializer>) {
+        self.serializers = serializers;
+    }
+
+    /// Sets how long to wait for the router to welcome the client.  The default is 5 seconds.
+    pub fn set_connect_timeout(&mut self, timeout: Duration) {
+        self.connection_config = self.connection_config.with_timeout(timeout);
+    }
+
+    /// Sets how long each step of connecting may take.  See `ConnectionConfig`.
+    pub fn set_connection_config(&mut self, config: ConnectionConfig) {
+        self.connection_config = config;
+    }
+
+    /// Sets how TLS is set up when the URL is `wss://`.  See `TlsPolicy`.
+    pub fn set_tls_policy(&mut self, policy: TlsPolicy) {
+        self.tls_policy = policy;
+    }
+
+    /// Sets the scheduling hints for the connection's thread.  See `ThreadHints`.
+    pub fn set_thread_hints(&mut self, hints: ThreadHints) {
+        self.thread_hints = hints;
+    }
+
+    /// Falls back to the router's long-poll endpoint at `url`, an `http://` URL, when the
+    /// websocket can't be set up.  Long-poll connections always use JSON.
+    pub fn set_long_poll_fallback(&mut self, url: &str) {
+        self.long_poll_url = Some(url.to_string());
+    }
+
+    /// Reaches the router with `transport`.  By default connections use the raw socket
+    /// transport for `tcp://` URLs and websockets otherwise.
+    pub fn set_transport(&mut self, transport: Arc<Transport>) {
+        self.transport = Some(transport);
+    }
+
+    /// Tunnels the connection through the HTTP proxy `proxy`.  This replaces any transport set
+    /// with `set_transport`.
+    pub fn set_proxy(&mut self, proxy: HttpProxy) {
+        self.set_transport(Arc::new(proxy));
+    }
+
+    /// Adds a header to the websocket handshake request, such as `Authorization`.  It replaces
+    /// any header of the same name, except for the ones that set up the websocket.  See the
+    /// `headers` module.
+    pub fn add_header(&mut self, name: &str, value: &str) {
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    /// Sends a cookie with the websocket handshake request, for example the one a router that
+    /// uses cookie authentication set in an earlier session
+    pub fn add_cookie(&mut self, name: &str, value: &str) {
+        self.cookies.push((name.to_string(), value.to_string()));
+    }
+
+    /// Sets the agent string sent to the router in the hello message
+    pub fn set_agent(&mut self, agent: &str) {
+        self.agent = Some(agent.to_string());
+    }
+
+    /// Sets how often a connection that fails is retried.  See `ReconnectPolicy`.
+    pub fn set_reconnect_policy(&mut self, policy: ReconnectPolicy) {
+        self.reconnect_policy = policy;
+    }
+
+    /// Decides with `decider` whether failed connections are retried, instead of the reconnect
+    /// policy.  See `ReconnectDecider`.
+    pub fn set_reconnect_decider(&mut self, decider: ReconnectDecider) {
+        self.reconnect_decider = Some(Arc::new(Mutex::new(decider)));
+    }
+
+    pub fn connect<'a>(&self) -> WampResult<Client> {
+        try!(self.check_realm());
+        let started = Instant::now();
+        let mut url = self.url.clone();
+        let mut attempts = 0;
+        loop {
+            if cfg!(not(feature = "ssl")) && url.starts_with("wss:") {
+                return Err(Error::new(ErrorKind::InvalidState("wss:// URLs need the ssl feature")));
+            }
+            let error = match self.connect_once(&url) {
+                Ok(client) => return Ok(client),
+                Err(e) => e
+            };
+            attempts += 1;
+            let decision = match self.reconnect_decider {
+                Some(ref decider) => (&mut *decider.lock().unwrap())(attempts, &error, started.elapsed()),
+                None => self.reconnect_policy.decide(attempts, &error)
+            };
+            let delay = match decision {
+                ReconnectDecision::Retry => self.reconnect_policy.delay(attempts - 1),
+                ReconnectDecision::RetryAfter(delay) => delay,
+                ReconnectDecision::RetryAt(new_url) => {
+                    url = new_url;
+                    self.reconnect_policy.delay(attempts - 1)
+                },
+                ReconnectDecision::Stop => return Err(error)
+            };
+            warn!(target: TRANSPORT_TARGET, "Could not connect ({}), retrying {} in {:?}", error, url, delay);
+            thread::sleep(delay);
+        }
+    }
+
+    fn connect_once(&self, url: &str) -> WampResult<Client> {
+        let transport = self.transport.clone().unwrap_or_else(|| transport::for_url(url));
+        let protocols = offered_protocols(&self.codecs, &self.serializers);
+        let error = match transport.open(url, &protocols).and_then(|transport_url| self.connect_to(transport_url, url)) {
+            Ok(client) => return Ok(client),
+            Err(e) => e
+        };
+        let long_poll_url = match self.long_poll_url {
+            Some(ref url) if is_transport_error(&error) => url,
+            _ => return Err(error)
+        };
+        warn!(target: TRANSPORT_TARGET, "Could not connect over a websocket ({}), falling back to long-poll at {}", error, long_poll_url);
+        match longpoll::start_bridge(long_poll_url) {
+            Ok(bridge_url) => self.connect_to(bridge_url.clone(), &bridge_url),
+            Err(e) => {
+                warn!(target: TRANSPORT_TARGET, "Could not connect over long-poll either: {}", e);
+                Err(error)
+            }
+        }
+    }
+
+    /// Connects to the websocket at `url`, which the transport opened for the router at
+    /// `router_url`
+    fn connect_to(&self, url: String, router_url: &str) -> WampResult<Client> {
+        let (tx, rx) = channel();
+        let realm = self.realm.clone();
+        let codecs = self.codecs.clone();
+        let authentication = self.authentication.clone();
+        let ping_policy = self.ping_policy;
+        let serializers = self.serializers.clone();
+        let connection_config = self.connection_config;
+        let tls_policy = self.tls_policy.clone();
+        let thread_hints = self.thread_hints;
+        let headers = headers::handshake_headers(&self.headers, &self.cookies);
+        let agent = self.agent.clone();
+        let router_url = Url::parse(router_url).ok().and_then(|router_url| match router_url.scheme() {
+            "ws" | "wss" => Some(router_url),
+            _ => None
+        });
+        let host = router_url.clone().or_else(|| Url::parse(&url).ok()).and_then(|url| url.host_str().map(|host| host.to_string()));
+        let receive_thread = thread::spawn(move || {
+            thread_hints.apply();
+            trace!(target: TRANSPORT_TARGET, "Beginning Connection");
+            let connect_result = connect(url, |out| {
+                trace!(target: TRANSPORT_TARGET, "Got sender");
+                // Set up timeouts
+                out.timeout(as_millis(connection_config.timeout()), CONNECTION_TIMEOUT).unwrap();
+                if let Some(timeout) = connection_config.handshake_timeout() {
+                    out.timeout(as_millis(timeout), HANDSHAKE_TIMEOUT).unwrap();
+                }
+                let info = Arc::new(Mutex::new(ConnectionInfo {
+                    protocol: String::new(),
+                    serializer: Arc::new(JsonSerializer),
+                    subscription_requests: HashMap::new(),
+                    unsubscription_requests: HashMap::new(),
+                    subscriptions: Interests::new(),
+                    early_events: HashMap::new(),
+                    orphan_events: 0,
+                    orphan_event_hook: None,
+                    registrations: Interests::new(),
+                    call_requests: HashMap::new(),
+                    progress_handlers: HashMap::new(),
+                    registration_requests: HashMap::new(),
+                    sender: out,
+                    session: SessionCore::new(),
+                    publish_requests: HashMap::new(),
+                    shutdown_complete: None,
+                    max_request_id: 0,
+                    max_owner_id: 0,
+                    outbound: OutboundQueue::new(),
+                    rate_limiter: RateLimiter::new(),
+                    compression: None,
+                    response_cache: None,
+                    invocation_dedup: None,
+                    deferred_invocations: HashMap::new(),
+                    validation_mode: ValidationMode::Lenient,
+                    protocol_violations: 0,
+                    invocation_authorizer: None,
+                    authenticator: authentication.as_ref().map(|&(_, ref authenticator)| authenticator.clone()),
+                    credentials_refreshed: None,
+                    authid: None,
+                    authrole: None,
+                    authmethod: None,
+                    default_error_reason: Reason::CustomReason(URI::new(DEFAULT_ERROR_URI)),
+                    option_defaults: OptionDefaults::new(),
+                    activity_history: None,
+                    durable: None,
+                    keepalive: Keepalive::new(ping_policy),
+                    shutdown_hooks: Vec::new(),
+                    disconnect_cause: None,
+                    session_end: None,
+                    graceful_shutdown: false,
+                    cancellation: CancellationToken::new(),
+                    request_records: HashMap::new(),
+                    hooks: ConnectionHooks::new(),
+                    event_bus: EventBus::new()
+                }));
+                let handler = ConnectionHandler {
+                    state_transmission: tx.clone(),
+                    connection_info: info,
+                    realm: realm.clone(),
+                    codecs: codecs.clone(),
+                    serializers: serializers.clone(),
+                    tls_policy: tls_policy.clone(),
+                    host: host.clone(),
+                    router_url: router_url.clone(),
+                    authentication: authentication.as_ref().map(|&(ref authid, ref authenticator)| {
+                        let authenticator = authenticator.lock().unwrap();
+                        (authid.clone(), authenticator.authmethods(), authenticator.hello_details())
+                    }),
+                    headers: headers.clone(),
+                    agent: agent.clone(),
+                    welcome_timeout: connection_config.welcome_timeout(),
+                    opened: false
+                };
+                handler
+            }).map_err(|e| {
+                Error::new(ErrorKind::WSError(e))
+            });
+            debug!(target: TRANSPORT_TARGET, "Result of connection: {:?}", connect_result);
+            match connect_result {
+                Ok(_) => (),
+                Err(e) => {tx.send(Err(e)).unwrap();}
+            }
+        });
+        // The connection thread drops its sender without a result if the connection is lost
+        // before the handshake finishes
+        let info = try!(rx.recv().unwrap_or(Err(Error::new(ErrorKind::ConnectionLost))));
+        Ok(Client{
+            connection_info: info,
+            owner: 0,
+            pending_requests: Vec::new(),
+            allow_list: None,
+            receive_thread: Some(receive_thread)
+        })
+    }
+
+}
+
+/// Whether connecting failed because the router couldn't be reached, rather than because it
+/// refused the client
+fn is_transport_error(error: &Error) -> bool {
+    match error.kind {
+        ErrorKind::WSError(_) | ErrorKind::ConnectionLost | ErrorKind::Timeout | ErrorKind::TransportError(_) => true,
+        _ => false
+    }
+}
+
+/// The subprotocols a client offers the router, most preferred first
+fn offered_protocols(codecs: &[Arc<Codec>], serializers: &[Serializer]) -> Vec<String> {
+    let codecs = codecs.iter().map(|codec| codec.protocol().to_string());
+    codecs.chain(serializers.iter().map(|serializer| match *serializer {
+        Serializer::Json => WAMP_JSON,
+        Serializer::MsgPack => WAMP_MSGPACK,
+        Serializer::Cbor => WAMP_CBOR
+    }.to_string())).collect()
+}
+
+macro_rules! cancel_future {
+    ($dict: expr) => ({
+        for (_, future) in $dict.drain() {
+            future.fail(CallError::new(Reason::NetworkFailure, None, None));
+        }
+    });
+}
+
+impl Handler for ConnectionHandler {
+    fn on_open(&mut self, handshake: Handshake) -> WSResult<()> {
+        debug!(target: TRANSPORT_TARGET, "Connection Opened");
+        self.opened = true;
+        let mut info = self.connection_info.lock().unwrap();
+        if let Some(timeout) = self.welcome_timeout {
+            try!(info.sender.timeout(as_millis(timeout), WELCOME_TIMEOUT));
+        }
+        info.protocol = match try!(handshake.response.protocol()) {
+            Some(protocol) => {
+                protocol.to_string()
+            } None => {
+                warn!(target: TRANSPORT_TARGET, "Router did not specify protocol. Defaulting to wamp.2.json");
+                WAMP_JSON.to_string()
+            }
+        };
+        info.serializer = match serializer::for_protocol(&info.protocol, &self.codecs) {
+            Some(serializer) => serializer,
+            None => {
+                warn!(target: TRANSPORT_TARGET, "Router chose {}, which the client didn't offer. Using wamp.2.json", info.protocol);
+                Arc::new(JsonSerializer)
+            }
+        };
+        info.keepalive.start(Instant::now());
+        if let Err(e) = info.schedule_ping() {
+            error!(target: TRANSPORT_TARGET, "Could not start sending pings: {}", e);
+        }
+
+        let details = match self.authentication {
+            Some((ref authid, ref authmethods, ref authextra)) => {
+                let details = HelloDetails::new_with_authentication(ClientRoles::new(), authid, authmethods.clone());
+                if authextra.is_empty() {
+                    details
+                } else {
+                    details.with_authextra(authextra.clone())
+                }
+            },
+            None => HelloDetails::new(ClientRoles::new())
+        };
+        let details = match self.agent {
+            Some(ref agent) => details.with_agent(agent),
+            None => details
+        };
+        let hello_message = Message::Hello(self.realm.clone(), details);
+        debug!("Sending Hello message");
+        thread::sleep(Duration::from_millis(200));
+        match info.send_message(hello_message) {
+            Ok(_)  => Ok(()),
+            Err(e) => {
+                if let ErrorKind::WSError(e) = e.kind {
+                    Err(e)
+                } else {
+                    Err(WSError::new(WSErrorKind::Internal, "Unknown error"))
+                }
+            }
+        }
+    }
+
+    fn on_message(&mut self, message: WSMessage) -> WSResult<()> {
+        debug!(target: TRANSPORT_TARGET, "Server sent a message: {:?}", message);
+        if let Some(message) = self.parse_message(message) {
+            self.handle_message(message);
+            self.connection_info.lock().unwrap().fail_abandoned_invocations();
+        }
+        Ok(())
+    }
+
+
+
+    fn on_close(&mut self, code: CloseCode, reason: &str) {
+        debug!(target: TRANSPORT_TARGET, "Closing connection");
+        let mut info = self.connection_info.lock().unwrap();
+        info.sender.close(CloseCode::Normal).ok();
+        info.session.close();
+        let cause = info.disconnect_cause.take().unwrap_or_else(|| DisconnectCause::ConnectionLost(format!("{:?} {}", code, reason)));
+        info.record_session_end(cause.clone());
+        if cause != DisconnectCause::Shutdown {
+            // Routers close the connection with the policy code when a subscriber falls too far
+            // behind
+            if code == CloseCode::Policy {
+                info.event_bus.emit(ClientEvent::SlowConsumer);
+            }
+            let lost: Vec<URI> = info.subscriptions.iter().filter(|&(key, _)| info.subscriptions.id_of(*key).is_some()).map(|(_, subscription)| subscription.topic.clone()).collect();
+            for topic in lost {
+                info.event_bus.emit(ClientEvent::SubscriptionLost { topic: topic });
+            }
+        }
+        info.fail_interest_requests(Reason::NetworkFailure);
+        cancel_future!(info.publish_requests);
+        cancel_future!(info.call_requests);
+        info.progress_handlers.clear();
+        info.deferred_invocations.clear();
+        if let Some(ref mut dedup) = info.invocation_dedup {
+            dedup.forget_running();
+        }
+        info.sender.shutdown().ok();
+
+        match info.shutdown_complete.take() {
+            Some(promise) => {
+                promise.complete(());
+            },
+            None => {}
+        }
+        let connection_hooks = info.hooks.clone();
+        let shutdown = if info.graceful_shutdown {
+            None
+        } else {
+            Some((mem::replace(&mut info.shutdown_hooks, Vec::new()), info.session_end.clone()))
+        };
+        drop(info);
+        connection_hooks.disconnected(&cause);
+        if let Some((hooks, Some(end))) = shutdown {
+            run_shutdown_hooks(hooks, &end);
+        }
+    }
+
+    fn on_timeout(&mut self, token: Token) -> WSResult<()> {
+        if token == CONNECTION_TIMEOUT {
+            self.connection_timed_out("router to welcome the client");
+        } else if token == HANDSHAKE_TIMEOUT {
+            if !self.opened {
+                self.connection_timed_out("websocket handshake");
+            }
+        } else if token == WELCOME_TIMEOUT {
+            self.connection_timed_out("router's welcome");
+        } else if token == WRITE_QUEUE {
+            let mut info = self.connection_info.lock().unwrap();
+            if let Err(e) = info.write_queue() {
+                error!("Could not write queued messages: {}", e);
+            }
+        } else if token == RATE_LIMIT {
+            let mut info = self.connection_info.lock().unwrap();
+            if let Err(e) = info.release_publications() {
+                error!("Could not release rate limited publications: {}", e);
+            }
+        } else if token == PING {
+            let mut info = self.connection_info.lock().unwrap();
+            if let Err(e) = info.ping_tick() {
+                error!(target: TRANSPORT_TARGET, "Could not send a ping: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    fn on_frame(&mut self, frame: WSFrame) -> WSResult<Option<WSFrame>> {
+        if frame.has_rsv1() || frame.has_rsv2() || frame.has_rsv3() {
+            return Err(WSError::new(WSErrorKind::Protocol, "Encountered frame with reserved bits set."));
+        }
+        match frame.opcode() {
+            OpCode::Ping => {
+                // ws answers the pings it is passed
+                if self.connection_info.lock().unwrap().keepalive.ping_received() {
+                    Ok(Some(frame))
+                } else {
+                    trace!(target: TRANSPORT_TARGET, "Not answering a ping");
+                    Ok(None)
+                }
+            },
+            OpCode::Pong => {
+                self.connection_info.lock().unwrap().keepalive.pong_received(frame.payload(), Instant::now());
+                Ok(None)
+            },
+            _ => Ok(Some(frame))
+        }
+    }
+
+    #[cfg(feature = "ssl")]
+    fn build_ssl(&mut self) -> WSResult<Ssl> {
+        trace!(target: TRANSPORT_TARGET, "Setting up TLS");
+        self.tls_policy.build_ssl(self.host.as_ref().map(|host| &host[..]))
+    }
+
+    fn build_request(&mut self, url: &Url) -> WSResult<Request> {
+        trace!(target: TRANSPORT_TARGET, "Building request");
+        let mut request = try!(Request::from_url(self.router_url.as_ref().unwrap_or(url)));
+        for protocol in offered_protocols(&self.codecs, &self.serializers) {
+            request.add_protocol(&protocol);
+        }
+        headers::apply_headers(request.headers_mut(), &self.headers);
+        Ok(request)
+    }
+
+}
+
+
+impl ConnectionHandler {
+
+    /// Decodes a message from the router with the connection's serializer, checking it first in
+    /// strict mode.  Malformed messages are logged, counted and dropped.
+    fn parse_message(&self, message: WSMessage) -> Option<Message> {
+        let frame = match message {
+            WSMessage::Text(payload) => Frame::Text(payload),
+            WSMessage::Binary(payload) => Frame::Binary(payload)
+        };
+        let (mode, serializer) = {
+            let info = self.connection_info.lock().unwrap();
+            (info.validation_mode, info.serializer.clone())
+        };
+        if mode == ValidationMode::Strict {
+            if let Err(violation) = serializer::validate(&*serializer, &frame) {
+                error!(target: PROTOCOL_TARGET, "Protocol violation: {}", violation);
+                self.connection_info.lock().unwrap().protocol_violations += 1;
+                return None;
+            }
+        }
+        match serializer.decode(frame) {
+            Ok(message) => Some(message),
+            Err(e) => {
+                error!(target: PROTOCOL_TARGET, "Could not understand {} message: {}", serializer.protocol(), e);
+                self.connection_info.lock().unwrap().protocol_violations += 1;
+                None
+            }
+        }
+    }
+
+    fn handle_message(&mut self, message: Message) {
+        let mut info = self.connection_info.lock().unwrap();
+        debug!("Processing message from server (state: {:?})", info.session.state());
+        let joining = info.session.state() == SessionState::Joining;
+        match info.session.receive(message) {
+            SessionStep::Welcomed(session_id, details) => self.handle_welcome(info, session_id, details),
+            SessionStep::Challenged(authmethod, extra) => self.handle_challenge(info, authmethod, extra),
+            SessionStep::Session(message) => self.handle_session_message(info, message),
+            SessionStep::RouterGoodbye(reason) => self.handle_goodbye(info, reason),
+            SessionStep::Left => {
+                // The router has seen our goodbye message and has responded in kind
+                info!("Router acknolwedged disconnect");
+                match info.shutdown_complete.take() {
+                    Some(promise) => promise.complete(()),
+                    None          => {}
+                }
+            },
+            SessionStep::Aborted(reason) => self.handle_abort(info, reason, joining),
+            SessionStep::Violation(violation) => self.handle_protocol_violation(info, violation, joining),
+            SessionStep::Ignored(message) => {
+                debug!(target: PROTOCOL_TARGET, "Ignoring a {} from the router (state: {:?})", message.name(), info.session.state());
+            }
+        }
+    }
+
+    fn handle_session_message(&self, mut info: MutexGuard<ConnectionInfo>, message: Message) {
+        debug!("Recieved a message from the server: {:?}", message);
+        match message {
+            Message::Subscribed(request_id, subscription_id) => {
+                self.handle_subscribed(info, request_id, subscription_id)
+            },
+            Message::Unsubscribed(request_id) => {
+                self.handle_unsubscribed(info, request_id)
+            },
+            Message::Event(subscription_id, publication_id, details, args, kwargs) => {
+                self.handle_event(info, subscription_id, publication_id, details, args, kwargs)
+            },
+            Message::Published(request_id, publication_id) => {
+                self.handle_published(info, request_id, publication_id)
+            },
+            Message::Registered(request_id, registration_id) => {
+                self.handle_registered(info, request_id, registration_id)
+            },
+            Message::Unregistered(request_id) => {
+                self.handle_unregistered(info, request_id)
+            },
+            Message::Invocation(request_id, registration_id, details, args, kwargs) => {
+                self.handle_invocation(info, request_id, registration_id, details, args, kwargs)
+            },
+            Message::Interrupt(request_id, options) => {
+                info.interrupt_invocation(request_id, options.mode)
+            },
+            Message::Result(call_id, details, args, kwargs) => {
+                self.handle_result(info, call_id, details, args, kwargs)
+            },
+            Message::Error(e_type, request_id, details, reason, args, kwargs) => {
+                self.handle_error(info, e_type, request_id, details, reason, args, kwargs)
+            }
+            _ => {
+                warn!("Recieved unknown message.  Ignoring. {:?}", message)
+            }
+        }
+    }
+
+    fn handle_subscribed(&self, mut info: MutexGuard<ConnectionInfo>, request_id: ID, subscription_id: ID) {
+        // TODO handle errors here
+        info!("Recieved a subscribed notification");
+        match info.subscription_requests.remove(&request_id) {
+            Some(request) => {
+                debug!("Completing promise");
+                for &(key, _) in &request.waiting {
+                    info.subscriptions.bind(key, subscription_id);
+                    info.event_bus.emit(ClientEvent::Subscribed { topic: request.topic.clone(), subscription_id: subscription_id });
+                }
+                if let Some(events) = info.early_events.remove(&subscription_id) {
+                    debug!("Delivering {} events that arrived before the subscription to {} was confirmed", events.len(), request.topic.uri);
+                    let info = &mut *info;
+                    for event in events {
+                        let context = EventContext {
+                            subscription_id: subscription_id,
+                            publication_id: event.publication_id,
+                            topic: event.topic,
+                            timestamp: event.timestamp,
+                            publisher: event.publisher,
+                            publisher_authid: event.publisher_authid,
+                            publisher_authrole: event.publisher_authrole
+                        };
+                        for callback in info.subscriptions.by_id_mut(subscription_id) {
+                            (callback.callback)(event.args.clone(), event.kwargs.clone(), &context, &info.cancellation);
+                        }
+                    }
+                }
+                info.discard_early_events();
+                drop(info);
+                for (key, promise) in request.waiting {
+                    if let Some(promise) = promise {
+                        promise.complete(Subscription{topic: request.topic.clone(), subscription_id: subscription_id, key: key});
+                    }
+                }
+            },
+            None => {
+                warn!("Recieved a subscribed notification for a subscription we don't have.  ID: {}", request_id);
+            }
+        }
+    }
+
+    fn handle_subscribe_error(&self, mut info: MutexGuard<ConnectionInfo>, request_id: ID, reason: Reason, args: Option<List>, kwargs: Option<Dict>) {
+        warn!("Recieved an error for a subscription");
+        match info.subscription_requests.remove(&request_id) {
+            Some(request) => {
+                info.forget_subscription_request(&request);
+                info.discard_early_events();
+                drop(info);
+                request.fail(CallError::new(reason, args, kwargs));
+            },
+            None => {
+                warn!("Recieved a an error notification for a request we didn't make.  ID: {}", request_id);
+            }
+        }
+    }
+
+    fn handle_unsubscribed(&self, mut info: MutexGuard<ConnectionInfo>, request_id: ID) {
+        match info.unsubscription_requests.remove(&request_id) {
+            Some((promise, subscription_id)) => {
+                // Graceful shutdown leaves the subscriptions bound until the router confirms
+                info.subscriptions.remove_id(subscription_id);
+                drop(info);
+                promise.complete(())
+            },
+            None => {
+                warn!("Recieved a unsubscribed notification for a subscription we don't have.  ID: {}", request_id);
+            }
+        }
+    }
+
+    fn handle_unsubscribe_error(&self, mut info: MutexGuard<ConnectionInfo>, request_id: ID, reason: Reason, args: Option<List>, kwargs: Option<Dict>) {
+        match info.unsubscription_requests.remove(&request_id) {
+            Some((promise, _)) => {
+                drop(info);
+                promise.fail(CallError::new(reason, args, kwargs))
+            },
+            None => {
+                warn!("Recieved a unsubscribed error for a subscription we don't have.  ID: {}", request_id);
+            }
+        }
+    }
+
+    fn handle_registered(&self, mut info: MutexGuard<ConnectionInfo>, request_id: ID, registration_id: ID) {
+        // TODO handle errors here
+        info!("Recieved a registered notification");
+        match info.registration_requests.remove(&request_id) {
+            Some(RegistrationRequest::Register(promise, key, procedure)) => {
+                info.registrations.bind(key, registration_id);
+                drop(info);
+                if let Some(promise) = promise {
+                    promise.complete(Registration{procedure: procedure, registration_id: registration_id, key: key});
+                }
+            },
+            Some(request) => {
+                warn!("Recieved a registered notification for an unregister request.  ID: {}", request_id);
+                info.registration_requests.insert(request_id, request);
+            },
+            None => {
+                warn!("Recieved a registered notification for a registration we don't have.  ID: {}", request_id);
+            }
+        }
+    }
+
+    fn handle_unregistered(&self, mut info: MutexGuard<ConnectionInfo>, request_id: ID) {
+        match info.registration_requests.remove(&request_id) {
+            Some(RegistrationRequest::Unregister(promise, registration_id)) => {
+                info.registrations.remove_id(registration_id);
+                drop(info);
+                promise.complete(())
+            },
+            Some(request) => {
+                warn!("Recieved a unregistered notification for a register request.  ID: {}", request_id);
+                info.registration_requests.insert(request_id, request);
+            },
+            None => {
+                warn!("Recieved a unregistered notification for a registration we don't have.  ID: {}", request_id);
+            }
+        }
+    }
+
+    /// Fails a register or unregister request
+    fn handle_registration_error(&self, mut info: MutexGuard<ConnectionInfo>, request_id: ID, reason: Reason, args: Option<List>, kwargs: Option<Dict>) {
+        info!("Recieved a registration error");
+        match info.registration_requests.remove(&request_id) {
+            Some(request) => {
+                info.forget_registration_request(&request);
+                drop(info);
+                request.fail(CallError::new(reason, args, kwargs))
+            },
+            None => {
+                warn!("Recieved a registration error for a request we didn't make.  ID: {}", request_id);
+            }
+        }
+    }
+
+    fn handle_published(&self, mut info: MutexGuard<ConnectionInfo>, request_id: ID, publication_id: ID) {
+        info.settle_durable_publication(request_id);
+        match info.publish_requests.remove(&request_id) {
+            Some(promise) => {
+                promise.complete(publication_id);
+            },
+            None => {
+                warn!("Recieved published notification for a request we weren't tracking: {}", request_id)
+            }
+        }
+    }
+    fn handle_publish_error(&self, mut info: MutexGuard<ConnectionInfo>, request_id: ID, reason: Reason, args: Option<List>, kwargs: Option<Dict>) {
+        // Sending a publication the router refused again wouldn't help
+        info.settle_durable_publication(request_id);
+        match info.publish_requests.remove(&request_id) {
+            Some(promise) => {
+                promise.fail(CallError::new(reason, args, kwargs))
+            },
+            None => {
+                warn!("Recieved published error for a publication: {}", request_id)
+            }
+        }
+    }
+
+    fn handle_welcome(&self, mut info: MutexGuard<ConnectionInfo>, session_id: ID, details: WelcomeDetails) {
+        info!("Joined the realm as session {}", session_id);
+        info.authid = details.authid().map(|authid| authid.to_string());
+        info.authrole = details.authrole().map(|authrole| authrole.to_string());
+        info.authmethod = details.authmethod().map(|authmethod| authmethod.to_string());
+        drop(info);
+        self.state_transmission.send(Ok(self.connection_info.clone())).unwrap();
+    }
+
+    /// Ends a session the router aborted, failing `connect` if the client hadn't joined yet
+    fn handle_abort(&self, mut info: MutexGuard<ConnectionInfo>, reason: Reason, joining: bool) {
+        if joining {
+            info!("Router refused to let the client join: {}", reason);
+        } else {
+            warn!("Router aborted the session: {}", reason);
+        }
+        info.disconnect_cause = Some(DisconnectCause::Aborted(reason.clone()));
+        info.session.close();
+        info.sender.close(CloseCode::Normal).ok();
+        drop(info);
+        if joining {
+            self.state_transmission.send(Err(Error::new(ErrorKind::Closing(reason.to_string())))).ok();
+        }
+    }
+
+    /// Aborts the session because the router sent a message that makes no sense in its state,
+    /// failing `connect` if the client hadn't joined yet
+    fn handle_protocol_violation(&self, mut info: MutexGuard<ConnectionInfo>, violation: String, joining: bool) {
+        error!(target: PROTOCOL_TARGET, "Protocol violation: {}", violation);
+        info.protocol_violations += 1;
+        info.send_message(Message::Abort(ErrorDetails::new_with_message(&violation), Reason::ProtocolViolation)).ok();
+        info.disconnect_cause = Some(DisconnectCause::ProtocolViolation(violation));
+        info.sender.close(CloseCode::Protocol).ok();
+        drop(info);
+        if joining {
+            self.state_transmission.send(Err(Error::new(ErrorKind::Closing(Reason::ProtocolViolation.to_string())))).ok();
+        }
+    }
+
+    /// Answers an authentication challenge.  A challenge that arrives after the client has
+    /// joined the realm means the router wants fresh credentials, so the application is told
+    /// once they have been sent.
+    fn handle_challenge(&self, mut info: MutexGuard<ConnectionInfo>, authmethod: String, extra: Dict) {
+        let response = match info.authenticator {
+            Some(ref authenticator) => {
+                authenticator.lock().unwrap().on_challenge(&authmethod, &extra)
+            },
+            None => Err("no authenticator has been set".to_string())
+        };
+        match response {
+            Ok(answer) => {
+                if let Err(e) = info.send_message(Message::Authenticate(answer.signature, answer.extra)) {
+                    error!("Could not answer {} challenge: {}", authmethod, e);
+                } else if info.session.state() == SessionState::Joined {
+                    debug!("Answered {} challenge from the router", authmethod);
+                    if let Some(ref mut handler) = info.credentials_refreshed {
+                        handler(&authmethod);
+                    }
+                }
+            },
+            Err(e) => {
+                error!("Could not answer {} challenge: {}", authmethod, e);
+                if info.session.state() == SessionState::Joining {
+                    info.send_message(Message::Abort(ErrorDetails::new_with_message(&e), Reason::AuthorizationFailed)).ok();
+                    self.handle_abort(info, Reason::AuthorizationFailed, true);
+                }
+            }
+        }
+    }
+
+    fn handle_event(&self, mut info: MutexGuard<ConnectionInfo>, subscription_id: ID, publication_id: ID, details: EventDetails, args: Option<List>, kwargs: Option<Dict>) {
+        let (args, kwargs) = info.decompress_payload(args, kwargs);
+        let args = args.unwrap_or(Vec::new());
+        let kwargs = kwargs.unwrap_or(HashMap::new());
+        let context = EventContext::new(subscription_id, publication_id, details);
+        let info = &mut *info;
+        if let Some(ref mut history) = info.activity_history {
+            let subscriptions = &info.subscriptions;
+            let topic = context.topic.clone().or_else(|| subscriptions.first(subscription_id).map(|subscription| subscription.topic.clone()));
+            history.record(ActivityKind::Event, topic, subscription_id, publication_id, &args, &kwargs);
+        }
+        if info.subscriptions.is_live(subscription_id) {
+            // Subscriptions that share an ID each get the event, with the last given its arguments
+            let mut subscriptions = info.subscriptions.by_id_mut(subscription_id);
+            if let Some((last, others)) = subscriptions.split_last_mut() {
+                for subscription in others {
+                    (subscription.callback)(args.clone(), kwargs.clone(), &context, &info.cancellation);
+                }
+                (last.callback)(args, kwargs, &context, &info.cancellation);
+            }
+        } else {
+            let event = OrphanEvent {
+                subscription_id: subscription_id,
+                publication_id: publication_id,
+                topic: context.topic,
+                timestamp: context.timestamp,
+                publisher: context.publisher,
+                publisher_authid: context.publisher_authid,
+                publisher_authrole: context.publisher_authrole,
+                args: args,
+                kwargs: kwargs
+            };
+            // The router may send events for a new subscription before it confirms it
+            if !info.subscription_requests.is_empty() {
+                let events = info.early_events.entry(subscription_id).or_insert_with(Vec::new);
+                if events.len() < EARLY_EVENT_LIMIT {
+                    events.push(event);
+                    return;
+                }
+            }
+            info.orphan_event(event);
+        }
+    }
+
+    fn handle_invocation(&self, mut info: MutexGuard<ConnectionInfo>, request_id: ID, registration_id: ID, details: InvocationDetails, args: Option<List>, kwargs: Option<Dict>) {
+        let (args, kwargs) = info.decompress_payload(args, kwargs);
+        let args = args.unwrap_or(Vec::new());
+        let kwargs = kwargs.unwrap_or(HashMap::new());
+        let info = &mut *info;
+        if let Some(ref mut history) = info.activity_history {
+            let registrations = &info.registrations;
+            let procedure = details.procedure.clone().or_else(|| registrations.first(registration_id).map(|registration| registration.procedure.clone()));
+            history.record(ActivityKind::Invocation, procedure, registration_id, request_id, &args, &kwargs);
+        }
+        let (procedure, dispatch, reply, more_chunks) = match info.registrations.by_id_mut(registration_id).into_iter().next() {
+            Some(registration) => {
+                if let Some(ref mut authorizer) = info.invocation_authorizer {
+                    let procedure = details.procedure.as_ref().unwrap_or(&registration.procedure);
+                    if !authorizer(procedure, &details, &args, &kwargs) {
+                        info!("Refusing invocation of {} (caller: {:?})", procedure.uri, details.caller_authid);
+                        info.send_message(Message::Error(ErrorType::Invocation, request_id, HashMap::new(), Reason::NotAuthorized, None, None)).ok();
+                        return;
+                    }
+                }
+                let more_chunks = details.progress.unwrap_or(false);
+                if more_chunks && !registration.progressive {
+                    info!("Refusing a progressive call of {}, which takes whole calls", registration.procedure.uri);
+                    let args = vec![Value::String("The procedure doesn't take progressive calls".to_string())];
+                    info.send_message(Message::Error(ErrorType::Invocation, request_id, HashMap::new(), Reason::OptionNotAllowed, Some(args), None)).ok();
+                    return;
+                }
+                // The last chunk of a progressive call looks like a whole call, so procedures that
+                // take progressive calls are never deduplicated.  Pattern registrations are
+                // keyed by the procedure that was called.
+                let called = details.procedure.clone().unwrap_or_else(|| registration.procedure.clone());
+                let dedup = match info.invocation_dedup.as_mut() {
+                    Some(dedup) if !registration.progressive => dedup.begin(request_id, &called, &details, &kwargs),
+                    _ => Dedup::Run
+                };
+                match dedup {
+                    Dedup::Run => {},
+                    Dedup::Answer(result) => {
+                        let procedure = registration.procedure.clone();
+                        debug!("Answering a retry of a call to {} with its earlier result", procedure.uri);
+                        let message = info.invocation_reply(request_id, &procedure, Ok(result));
+                        info.send_message(message).ok();
+                        return;
+                    },
+                    Dedup::Wait => return
+                }
+                let responder = Responder::new(request_id, registration.procedure.clone(), details.receive_progress.unwrap_or(false), self.connection_info.clone());
+                let dispatch = responder.dispatch();
+                let context = InvocationContext::new(registration_id, request_id, &registration.procedure, details);
+                let ref mut callback = registration.callback;
+                let reply = callback(args, kwargs, &context, &info.cancellation, responder);
+                (registration.procedure.clone(), dispatch, reply, more_chunks)
+            },
+            None => {
+                warn!("Recieved an invocation for a procedure we don't have.  ID: {}", registration_id);
+                return;
+            }
+        };
+        match dispatch.finish(reply, more_chunks) {
+            Some(result) => {
+                info.answer_invocation(request_id, &procedure, result).ok();
+            },
+            None => {
+                if !dispatch.is_answered() {
+                    info.deferred_invocations.insert(request_id, (procedure, dispatch));
+                }
+            }
+        }
+    }
+
+    fn handle_result(&self, mut info: MutexGuard<ConnectionInfo>, call_id: ID, details: ResultDetails, args: Option<List>, kwargs: Option<Dict>) {
+        let (args, kwargs) = info.decompress_payload(args, kwargs);
+        let args = args.unwrap_or(Vec::new());
+        let kwargs = kwargs.unwrap_or(HashMap::new());
+        if details.is_progressive() {
+            match info.progress_handlers.get_mut(&call_id) {
+                Some(handler) => handler(args, kwargs),
+                None => warn!("Recieved a progressive result for a call that didn't ask for one.  ID: {}", call_id)
+            }
+            return;
+        }
+        info.progress_handlers.remove(&call_id);
+        if let Some(ref mut cache) = info.response_cache {
+            cache.insert_result(call_id, &args, &kwargs);
+        }
+        match info.call_requests.remove(&call_id) {
+            Some(promise) => {
+                promise.complete((args, kwargs));
+            },
+            None => {
+                warn!("Recieved a result for a call we didn't make.  ID: {}", call_id);
+            }
+        }
+    }
+
+    fn handle_call_error(&self, mut info: MutexGuard<ConnectionInfo>, request_id: ID, details: Dict, reason: Reason, args: Option<List>, kwargs: Option<Dict>) {
+        info.progress_handlers.remove(&request_id);
+        if let Some(ref mut cache) = info.response_cache {
+            cache.forget(request_id);
+        }
+        match info.call_requests.remove(&request_id) {
+            Some(promise) => {
+                promise.fail(CallError::new(reason, args, kwargs).with_details(details))
+            },
+            None => {
+                warn!("Recieved an error for a call we didn't make.  ID: {}", request_id);
+            }
+        }
+    }
+
+    fn handle_goodbye(&self, mut info: MutexGuard<ConnectionInfo>, reason: Reason) {
+        info!("Router said goodbye.  Reason: {:?}", reason);
+
+        info.send_message(Message::Goodbye(ErrorDetails::new(), Reason::GoodbyeAndOut)).unwrap();
+        info.disconnect_cause = Some(DisconnectCause::RouterGoodbye(reason.clone()));
+        let hooks = info.hooks.clone();
+        drop(info);
+        hooks.said_goodbye(&reason);
+    }
+
+    fn handle_error(&self, info: MutexGuard<ConnectionInfo>, e_type: ErrorType, request_id: ID, details: Dict, reason: Reason, args: Option<List>, kwargs: Option<Dict>) {
+        match e_type {
+            ErrorType::Subscribe => {
+                self.handle_subscribe_error(info, request_id, reason, args, kwargs)
+            },
+            ErrorType::Unsubscribe => {
+                self.handle_unsubscribe_error(info, request_id, reason, args, kwargs)
+            },
+            ErrorType::Publish => {
+                self.handle_publish_error(info, request_id, reason, args, kwargs)
+            },
+            ErrorType::Register | ErrorType::Unregister => {
+                self.handle_registration_error(info, request_id, reason, args, kwargs)
+            },
+            ErrorType::Invocation => {
+                warn!("Recieved an error for an invocation message, which we did not (and could not) send")
+            },
+            ErrorType::Call => {
+                self.handle_call_error(info, request_id, details, reason, args, kwargs)
+            }
+        }
+    }
+
+}
+
+impl Client {
+
+    fn get_next_session_id(&mut self) -> ID {
+        let mut info = self.connection_info.lock().unwrap();
+        info.max_request_id += 1;
+        info.max_request_id
+    }
+
+    /// The request ID for a publication to `topic`, which must then be submitted or abandoned
+    fn next_publication_id(&mut self, topic: &URI) -> ID {
+        self.connection_info.lock().unwrap().reserve_publication(topic)
+    }
+
+    /// Remembers a request made through a session handle, so that it can be cancelled when the
+    /// handle shuts down.
+    fn track_request(&mut self, info: &ConnectionInfo, request_id: ID) {
+        if self.owner != 0 {
+            self.pending_requests.retain(|id| info.call_requests.contains_key(id) || info.publish_requests.contains_key(id));
+            self.pending_requests.push(request_id);
+        }
+    }
+
+    /// Sets how long publications and calls may wait in the outbound queue before they are
+    /// dropped instead of being sent.  By default messages never expire.
+    ///
+    /// Calls and acknowledged publications that expire fail with `Reason::Timeout`.
+    pub fn set_outbound_ttl(&mut self, ttl: Option<Duration>) {
+        self.connection_info.lock().unwrap().outbound.ttl = ttl;
+    }
+
+    /// Enables (or with `None`, disables) transparent compression of large payloads.
+    ///
+    /// Compression applies to publications, calls and the results of registered procedures.
+    /// Compressed payloads that are recieved are decompressed before being passed on, as long as
+    /// they use the same encoding.
+    pub fn set_payload_compression(&mut self, compression: Option<PayloadCompression>) {
+        self.connection_info.lock().unwrap().compression = compression;
+    }
+
+    /// Sets (or with `None`, removes) a function that is consulted before any registered
+    /// procedure runs.  It recieves the procedure being called, the invocation details (which
+    /// include the caller's identity if the router disclosed it) and the arguments.  When it
+    /// returns false the procedure isn't run, and the caller gets `Reason::NotAuthorized`.
+    pub fn set_invocation_authorizer(&mut self, authorizer: Option<InvocationAuthorizer>) {
+        self.connection_info.lock().unwrap().invocation_authorizer = authorizer;
+    }
+
+    /// Restricts (or with `None`, stops restricting) the topics this client may publish to and
+    /// the procedures it may call.  Anything else fails with `ErrorKind::NotAllowed` before
+    /// reaching the router.
+    pub fn set_allow_list(&mut self, allow_list: Option<AllowList>) {
+        self.allow_list = allow_list;
+    }
+
+    /// Enables caching of call results.  Results are cached for as long as the callee asks for
+    /// with a `_cache_ttl` keyword argument, or for `default_ttl` if it doesn't give one.  Without
+    /// a default TTL, only results carrying the hint are cached.
+    ///
+    /// Cached results are only reused for calls to the same procedure with the same arguments.
+    pub fn enable_response_cache(&mut self, default_ttl: Option<Duration>) {
+        self.connection_info.lock().unwrap().response_cache = Some(ResponseCache::new(default_ttl));
+    }
+
+    pub fn disable_response_cache(&mut self) {
+        self.connection_info.lock().unwrap().response_cache = None;
+    }
+
+    /// Throws away every cached call result
+    pub fn clear_response_cache(&mut self) {
+        if let Some(ref mut cache) = self.connection_info.lock().unwrap().response_cache {
+            cache.clear();
+        }
+    }
+
+    /// Sets how strictly messages from the router are checked.  The default is
+    /// `ValidationMode::Lenient`.  Either way, malformed messages are logged and ignored.
+    pub fn set_validation_mode(&mut self, mode: ValidationMode) {
+        self.connection_info.lock().unwrap().validation_mode = mode;
+    }
+
+    /// Sets the options that publications and calls are sent with when they don't set them
+    /// themselves.  This applies to every client and publisher sharing the connection.
+    pub fn set_option_defaults(&mut self, defaults: OptionDefaults) {
+        self.connection_info.lock().unwrap().option_defaults = defaults;
+    }
+
+    pub fn option_defaults(&self) -> OptionDefaults {
+        self.connection_info.lock().unwrap().option_defaults.clone()
+    }
+
+    /// The ID the router gave the session, which other sessions can use to exclude it from, or
+    /// target it with, their publications
+    pub fn session_id(&self) -> ID {
+        self.connection_info.lock().unwrap().session.session_id()
+    }
+
+    /// The authentication ID the router gave the session, if it authenticated the client
+    pub fn authid(&self) -> Option<String> {
+        self.connection_info.lock().unwrap().authid.clone()
+    }
+
+    /// The role the router gave the session, if it authenticated the client
+    pub fn authrole(&self) -> Option<String> {
+        self.connection_info.lock().unwrap().authrole.clone()
+    }
+
+    /// The method the router authenticated the client with, such as `ticket`
+    pub fn authmethod(&self) -> Option<String> {
+        self.connection_info.lock().unwrap().authmethod.clone()
+    }
+
+    /// The number of malformed messages recieved from the router
+    pub fn protocol_violations(&self) -> u64 {
+        self.connection_info.lock().unwrap().protocol_violations
+    }
+
+    /// Sets how publications to the same topic are ordered.  By default they are written in the
+    /// order they reach the outbound queue, which for publications made at the same time from
+    /// different threads needn't be the order they were made in.  `PublishOrdering::PerTopic`
+    /// writes each topic's publications in the order they were made, holding back any that
+    /// overtake an earlier one.
+    pub fn set_publish_ordering(&mut self, ordering: PublishOrdering) {
+        self.connection_info.lock().unwrap().outbound.ordering = ordering;
+    }
+
+    /// Lets the client hold outgoing messages back for up to `max_window`, so that messages sent
+    /// in quick succession are written together.  The window actually used is tuned
+    /// automatically, and is reported by `writer_stats()`.  `None` (the default) writes every
+    /// message as soon as possible.
+    pub fn set_write_coalescing(&mut self, max_window: Option<Duration>) {
+        let mut info = self.connection_info.lock().unwrap();
+        info.outbound.stats.max_coalescing_window = max_window;
+        if max_window.is_none() {
+            info.outbound.stats.coalescing_window = Duration::from_millis(0);
+        }
+    }
+
+    /// Turns conflation on or off for the topics under `prefix`.  While a publication to a
+    /// conflated topic is waiting to be written, a newer publication to the same topic replaces
+    /// it, so a client that publishes faster than it can write only sends the latest value.
+    ///
+    /// This is most useful along with `set_write_coalescing`, which gives publications time to
+    /// be replaced.  Acknowledged publications that are replaced fail with `Reason::Cancelled`.
+    pub fn set_conflation(&mut self, prefix: &str, enabled: bool) {
+        let conflated_topics = &mut self.connection_info.lock().unwrap().outbound.conflated_topics;
+        conflated_topics.retain(|existing| existing != prefix);
+        if enabled {
+            conflated_topics.push(prefix.to_string());
+        }
+    }
+
+    /// Gets statistics about the messages this client's connection has written
+    pub fn writer_stats(&self) -> WriterStats {
+        self.connection_info.lock().unwrap().outbound.stats.clone()
+    }
+
+    /// Statistics about websocket pings, including round trip times if the client sends pings
+    pub fn ping_stats(&self) -> PingStats {
+        self.connection_info.lock().unwrap().keepalive.stats.clone()
+    }
+
+    /// Limits how quickly the client publishes to each topic under `prefix`, or removes the
+    /// limit if `limit` is `None`.  When several prefixes match a topic, the longest one applies.
+    ///
+    /// Acknowledged publications that are coalesced away fail with `Reason::Cancelled`.
+    pub fn set_rate_limit(&mut self, prefix: &str, limit: Option<RateLimit>) {
+        self.connection_info.lock().unwrap().rate_limiter.set_limit(prefix, limit);
+    }
+
+    /// The current session's cancellation token, which fires when the session starts shutting
+    /// down or ends.  See `CancellationToken`.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.connection_info.lock().unwrap().cancellation.clone()
+    }
+
+    /// Sets a callback that is notified whenever the client answers a challenge from the router
+    /// after joining the realm.  It is given the authentication method that was used.
+    pub fn on_credentials_refreshed(&mut self, handler: Box<FnMut(&str)>) {
+        self.connection_info.lock().unwrap().credentials_refreshed = Some(handler);
+    }
+
+    /// Starts remembering the last `capacity` events and invocations the client receives, with
+    /// their payloads cut short after `max_payload_len` bytes of JSON.  Any activity that was
+    /// already remembered is forgotten.
+    pub fn enable_activity_history(&mut self, capacity: usize, max_payload_len: usize) {
+        self.connection_info.lock().unwrap().activity_history = Some(ActivityHistory::new(capacity, max_payload_len));
+    }
+
+    pub fn disable_activity_history(&mut self) {
+        self.connection_info.lock().unwrap().activity_history = None;
+    }
+
+    /// The events and invocations the client received most recently, oldest first.  This is
+    /// empty unless `enable_activity_history` has been called.
+    pub fn recent_activity(&self) -> Vec<Activity> {
+        match self.connection_info.lock().unwrap().activity_history {
+            Some(ref history) => history.entries(),
+            None => Vec::new()
+        }
+    }
+
+    /// Sets a callback that is notified whenever a message expires in the outbound queue.
+    pub fn on_message_expired(&mut self, handler: Box<FnMut(ExpiredMessage)>) {
+        self.connection_info.lock().unwrap().outbound.expiry_handler = Some(handler);
+    }
+
+    #[cfg(feature = "subscriber")]
+    pub fn subscribe_with_options(&mut self, topic_pattern: URI, mut callback: Box<FnMut(List, Dict)>, options: SubscribeOptions) -> WampResult<Pending<Subscription>> {
+        self.subscribe_cancellable_with_options(topic_pattern, Box::new(move |args, kwargs, _| callback(args, kwargs)), options)
+    }
+
+    /// Subscribes with a callback that is also given the session's cancellation token, so that
+    /// long running work can stop once the session goes away
+    #[cfg(feature = "subscriber")]
+    pub fn subscribe_cancellable(&mut self, topic: URI, callback: Box<FnMut(List, Dict, &CancellationToken)>) -> WampResult<Pending<Subscription>> {
+        self.subscribe_cancellable_with_options(topic, callback, SubscribeOptions::new())
+    }
+
+    #[cfg(feature = "subscriber")]
+    pub fn subscribe_cancellable_with_options(&mut self, topic_pattern: URI, mut callback: Box<FnMut(List, Dict, &CancellationToken)>, options: SubscribeOptions) -> WampResult<Pending<Subscription>> {
+        let callback = SubscriptionCallbackWrapper {
+            callback: Box::new(move |args, kwargs, _: &EventContext, token: &CancellationToken| callback(args, kwargs, token)),
+            topic: topic_pattern,
+            options: options,
+            owner: self.owner
+        };
+        self.subscribe_wrapper(callback).map(Pending::new)
+    }
+
+    /// Subscribes with a callback that is also given each event's context, such as the topic a
+    /// pattern matched, when the router received the publication and who published it
+    #[cfg(feature = "subscriber")]
+    pub fn subscribe_with_details(&mut self, topic: URI, callback: Box<FnMut(List, Dict, &EventContext)>) -> WampResult<Pending<Subscription>> {
+        self.subscribe_with_details_and_options(topic, callback, SubscribeOptions::new())
+    }
+
+    #[cfg(feature = "subscriber")]
+    pub fn subscribe_with_details_and_options(&mut self, topic_pattern: URI, mut callback: Box<FnMut(List, Dict, &EventContext)>, options: SubscribeOptions) -> WampResult<Pending<Subscription>> {
+        let callback = SubscriptionCallbackWrapper {
+            callback: Box::new(move |args, kwargs, context: &EventContext, _: &CancellationToken| callback(args, kwargs, context)),
+            topic: topic_pattern,
+            options: options,
+            owner: self.owner
+        };
+        self.subscribe_wrapper(callback).map(Pending::new)
+    }
+
+    /// Adds a subscription.  A subscription with the same topic and options as one the router
+    /// already has, or is being asked for, shares its ID instead of being sent again.
+    fn subscribe_wrapper(&mut self, callback: SubscriptionCallbackWrapper) -> WampResult<Future<Subscription, CallError>> {
+        let request_id = self.get_next_session_id();
+        let (complete, future) = Future::<Subscription, CallError>::pair();
+        let topic = callback.topic.clone();
+        let options = callback.options.clone();
+        let mut info = self.connection_info.lock().unwrap();
+        let key = info.subscriptions.add(callback);
+        let live = info.subscriptions.find_live(|wanted| wanted.topic == topic && wanted.options == options);
+        if let Some(subscription_id) = live {
+            debug!("Sharing subscription {} to {}", subscription_id, topic.uri);
+            info.subscriptions.bind(key, subscription_id);
+            drop(info);
+            complete.complete(Subscription{topic: topic, subscription_id: subscription_id, key: key});
+            return Ok(future);
+        }
+        if let Some(request) = info.subscription_requests.values_mut().find(|request| request.topic == topic && request.options == options) {
+            request.waiting.push((key, Some(complete)));
+            return Ok(future);
+        }
+        // Send a subscribe messages
+        let message = Message::Subscribe(request_id, options.clone(), topic.clone());
+        if let Err(e) = info.queue_message(message) {
+            info.subscriptions.remove(key);
+            return Err(e);
+        }
+        info.note_request(request_id, RequestKind::Subscribe, topic.clone());
+        info.subscription_requests.insert(request_id, SubscriptionRequest {
+            topic: topic,
+            options: options,
+            waiting: vec![(key, Some(complete))]
+        });
+        Ok(future)
+    }
+
+    #[cfg(feature = "subscriber")]
+    pub fn subscribe_with_pattern(&mut self, topic_pattern: URI, callback: Box<FnMut(List, Dict)>, policy: MatchingPolicy) -> WampResult<Pending<Subscription>> {
+        let mut options = SubscribeOptions::new();
+        if policy != MatchingPolicy::Strict {
+            options.pattern_match = policy
+        }
+        self.subscribe_with_options(topic_pattern, callback, options)
+    }
+
+    #[cfg(feature = "subscriber")]
+    pub fn subscribe(&mut self, topic: URI, callback: Box<FnMut(List, Dict)>) -> WampResult<Pending<Subscription>> {
+        self.subscribe_with_pattern(topic, callback, MatchingPolicy::Strict)
+    }
+
+    /// Subscribes to a topic as a member of a named consumer group.
+    ///
+    /// Each event published to the topic is delivered to exactly one member of the group, which
+    /// allows the members to share the work of processing the events.  Publishers can pass a
+    /// shard key in their publish options to make sure related events go to the same member.
+    #[cfg(feature = "subscriber")]
+    pub fn subscribe_group(&mut self, topic: URI, group_name: &str, callback: Box<FnMut(List, Dict)>) -> WampResult<Pending<Subscription>> {
+        let mut options = SubscribeOptions::new();
+        options.shard_group = Some(group_name.to_string());
+        self.subscribe_with_options(topic, callback, options)
+    }
+
+    #[cfg(feature = "callee")]
+    pub fn register_with_pattern(&mut self, procedure_pattern: URI, callback: Box<FnMut(List, Dict) -> CallResult<(Option<List>, Option<Dict>)> >, policy: MatchingPolicy) -> WampResult<Pending<Registration>> {
+        let mut options = RegisterOptions::new();
+        if policy != MatchingPolicy::Strict {
+            options.pattern_match = policy
+        }
+        self.register_with_options(procedure_pattern, callback, options)
+    }
+
+    /// Registers a procedure that other sessions may register too, with the router sharing
+    /// calls between them according to `policy`
+    #[cfg(feature = "callee")]
+    pub fn register_shared(&mut self, procedure: URI, policy: InvocationPolicy, callback: Box<FnMut(List, Dict) -> CallResult<(Option<List>, Option<Dict>)> >) -> WampResult<Pending<Registration>> {
+        self.register_with_options(procedure, callback, RegisterOptions::new().with_invocation_policy(policy))
+    }
+
+    #[cfg(feature = "callee")]
+    pub fn register(&mut self, procedure: URI, callback: Box<FnMut(List, Dict) -> CallResult<(Option<List>, Option<Dict>)> >) -> WampResult<Pending<Registration>> {
+        self.register_with_pattern(procedure, callback, MatchingPolicy::Strict)
+    }
+
+    #[cfg(feature = "callee")]
+    pub fn register_with_options(&mut self, procedure_pattern: URI, mut callback: Box<FnMut(List, Dict) -> CallResult<(Option<List>, Option<Dict>)> >, options: RegisterOptions) -> WampResult<Pending<Registration>> {
+        self.register_cancellable_with_options(procedure_pattern, Box::new(move |args, kwargs, _| callback(args, kwargs)), options)
+    }
+
+    /// Registers a procedure whose callback is also given the session's cancellation token, so
+    /// that long running work can stop once the session goes away
+    #[cfg(feature = "callee")]
+    pub fn register_cancellable(&mut self, procedure: URI, callback: Box<FnMut(List, Dict, &CancellationToken) -> CallResult<(Option<List>, Option<Dict>)> >) -> WampResult<Pending<Registration>> {
+        self.register_cancellable_with_options(procedure, callback, RegisterOptions::new())
+    }
+
+    #[cfg(feature = "callee")]
+    pub fn register_cancellable_with_options(&mut self, procedure_pattern: URI, mut callback: Box<FnMut(List, Dict, &CancellationToken) -> CallResult<(Option<List>, Option<Dict>)> >, options: RegisterOptions) -> WampResult<Pending<Registration>> {
+        let callback = RegistrationCallbackWrapper {
+            callback: Box::new(move |args, kwargs, _: &InvocationContext, token: &CancellationToken, _| Reply::Done(callback(args, kwargs, token))),
+            procedure: procedure_pattern,
+            options: options,
+            owner: self.owner,
+            progressive: false
+        };
+        self.register_wrapper(callback).map(Pending::new)
+    }
+
+    /// Registers a procedure whose callback is also told about each invocation, including the
+    /// procedure that was called.  Registered with a prefix or wildcard pattern, one callback
+    /// can serve a whole tree of procedures and tell them apart.
+    #[cfg(feature = "callee")]
+    pub fn register_with_details(&mut self, procedure: URI, callback: Box<FnMut(List, Dict, &InvocationContext) -> CallResult<(Option<List>, Option<Dict>)> >) -> WampResult<Pending<Registration>> {
+        self.register_with_details_and_options(procedure, callback, RegisterOptions::new())
+    }
+
+    #[cfg(feature = "callee")]
+    pub fn register_with_details_and_options(&mut self, procedure_pattern: URI, mut callback: Box<FnMut(List, Dict, &InvocationContext) -> CallResult<(Option<List>, Option<Dict>)> >, options: RegisterOptions) -> WampResult<Pending<Registration>> {
+        let callback = RegistrationCallbackWrapper {
+            callback: Box::new(move |args, kwargs, context: &InvocationContext, _: &CancellationToken, _| Reply::Done(callback(args, kwargs, context))),
+            procedure: procedure_pattern,
+            options: options,
+            owner: self.owner,
+            progressive: false
+        };
+        self.register_wrapper(callback).map(Pending::new)
+    }
+
+    /// Registers a procedure whose callback may answer later.  The callback either returns
+    /// `Reply::Done` with its result, or returns `Reply::Deferred` and hands its `Responder` to
+    /// another thread, which answers once the work is done, optionally sending progressive
+    /// results first.
+    #[cfg(feature = "callee")]
+    pub fn register_deferred(&mut self, procedure: URI, callback: Box<FnMut(List, Dict, Responder) -> Reply>) -> WampResult<Pending<Registration>> {
+        self.register_deferred_with_options(procedure, callback, RegisterOptions::new())
+    }
+
+    #[cfg(feature = "callee")]
+    pub fn register_deferred_with_options(&mut self, procedure_pattern: URI, mut callback: Box<FnMut(List, Dict, Responder) -> Reply>, options: RegisterOptions) -> WampResult<Pending<Registration>> {
+        let callback = RegistrationCallbackWrapper {
+            callback: Box::new(move |args, kwargs, _: &InvocationContext, _: &CancellationToken, responder| callback(args, kwargs, responder)),
+            procedure: procedure_pattern,
+            options: options,
+            owner: self.owner,
+            progressive: false
+        };
+        self.register_wrapper(callback).map(Pending::new)
+    }
+
+    /// Registers a procedure that takes progressive calls, whose arguments arrive in chunks.  The
+    /// callback is called with each chunk as it arrives, and `InvocationContext::progress` says
+    /// whether more follow; the request ID tells the calls apart.  It returns `Reply::Deferred`
+    /// to wait for the next chunk, and answers with `Reply::Done`, usually on the last chunk.
+    /// Calls that aren't progressive arrive as a single, last chunk.
+    #[cfg(feature = "callee")]
+    pub fn register_progressive(&mut self, procedure: URI, callback: Box<FnMut(List, Dict, &InvocationContext) -> Reply>) -> WampResult<Pending<Registration>> {
+        self.register_progressive_with_options(procedure, callback, RegisterOptions::new())
+    }
+
+    #[cfg(feature = "callee")]
+    pub fn register_progressive_with_options(&mut self, procedure_pattern: URI, mut callback: Box<FnMut(List, Dict, &InvocationContext) -> Reply>, options: RegisterOptions) -> WampResult<Pending<Registration>> {
+        let callback = RegistrationCallbackWrapper {
+            callback: Box::new(move |args, kwargs, context: &InvocationContext, _: &CancellationToken, _| {
+                match callback(args, kwargs, context) {
+                    Reply::Deferred if !context.progress => Reply::Done(Err(CallError::application("The procedure didn't answer after the last chunk"))),
+                    reply => reply
+                }
+            }),
+            procedure: procedure_pattern,
+            options: options,
+            owner: self.owner,
+            progressive: true
+        };
+        self.register_wrapper(callback).map(Pending::new)
+    }
+
+    /// Registers a procedure whose callback can fail with any error that converts into a
+    /// `CallError`.  Strings and boxed errors convert into application errors, which reach the
+    /// caller with the default error URI and the error's message.
+    #[cfg(feature = "callee")]
+    pub fn register_fallible<F, E>(&mut self, procedure: URI, mut callback: F) -> WampResult<Pending<Registration>>
+        where F: FnMut(List, Dict) -> Result<(Option<List>, Option<Dict>), E> + 'static,
+              E: Into<CallError> {
+        self.register(procedure, Box::new(move |args, kwargs| callback(args, kwargs).map_err(Into::into)))
+    }
+
+    /// Sets the reason given to callers when a registered procedure fails with an application
+    /// error.  The default is `wamp.error.runtime_error`.
+    pub fn set_default_error_uri(&mut self, uri: URI) {
+        self.connection_info.lock().unwrap().default_error_reason = Reason::CustomReason(uri);
+    }
+
+    fn register_wrapper(&mut self, callback: RegistrationCallbackWrapper) -> WampResult<Future<Registration, CallError>> {
+        // Send a register messages
+        let request_id = self.get_next_session_id();
+        let (complete, future) = Future::<Registration, CallError>::pair();
+        let procedure = callback.procedure.clone();
+        let message = Message::Register(request_id, callback.options.clone(), procedure.clone());
+        debug!("Acquiring lock on connection info");
+        let mut info = self.connection_info.lock().unwrap();
+        debug!("Lock on connection info acquired");
+        try!(info.queue_message(message));
+        let key = info.registrations.add(callback);
+        info.note_request(request_id, RequestKind::Register, procedure.clone());
+        info.registration_requests.insert(request_id, RegistrationRequest::Register(Some(complete), key, procedure));
+        Ok(future)
+    }
+
+    /// Re-establishes the subscriptions and registrations recorded in a `SubscriptionCache`.
+    ///
+    /// The given functions are called with the handler name of each cached entry and should
+    /// return the callback to bind to it.  Entries whose handler can't be found are skipped.
+    #[cfg(all(feature = "subscriber", feature = "callee"))]
+    pub fn rehydrate<S, R>(&mut self, cache: &SubscriptionCache, mut subscription_handlers: S, mut registration_handlers: R) -> WampResult<(Vec<Pending<Subscription>>, Vec<Pending<Registration>>)>
+        where S: FnMut(&str) -> Option<Box<FnMut(List, Dict)>>,
+              R: FnMut(&str) -> Option<Box<FnMut(List, Dict) -> CallResult<(Option<List>, Option<Dict>)>>> {
+        let mut subscriptions = Vec::new();
+        for cached in cache.subscriptions.iter() {
+            match subscription_handlers(&cached.handler) {
+                Some(callback) => {
+                    subscriptions.push(try!(self.subscribe_with_options(cached.topic.clone(), callback, cached.options.clone())));
+                },
+                None => {
+                    warn!("No handler named {} for cached subscription to {}.  Skipping", cached.handler, cached.topic.uri);
+                }
+            }
+        }
+        let mut registrations = Vec::new();
+        for cached in cache.registrations.iter() {
+            match registration_handlers(&cached.handler) {
+                Some(callback) => {
+                    registrations.push(try!(self.register_with_options(cached.procedure.clone(), callback, cached.options.clone())));
+                },
+                None => {
+                    warn!("No handler named {} for cached registration of {}.  Skipping", cached.handler, cached.procedure.uri);
+                }
+            }
+        }
+        Ok((subscriptions, registrations))
+    }
+
+    /// Re-establishes the entries of a `SubscriptionCache`, binding each one to the handler with
+    /// the same name in the given registry.
+    ///
+    /// Handlers that haven't been added to the registry yet are still bound, and will start
+    /// receiving events and invocations as soon as they are added.
+    #[cfg(all(feature = "subscriber", feature = "callee"))]
+    pub fn rehydrate_with_registry(&mut self, cache: &SubscriptionCache, registry: &HandlerRegistry) -> WampResult<(Vec<Pending<Subscription>>, Vec<Pending<Registration>>)> {
+        self.rehydrate(cache, |name| Some(registry.event_callback(name)), |name| Some(registry.procedure_callback(name)))
+    }
+
+    #[cfg(feature = "subscriber")]
+    /// Removes a subscription.  It stops receiving events right away.  The router is only told
+    /// once no other subscription shares the subscription's ID.
+    pub fn unsubscribe(&mut self, subscription: Subscription) -> WampResult<Pending<()>> {
+        let request_id = self.get_next_session_id();
+        let mut info = self.connection_info.lock().unwrap();
+        debug!("Unsubscribing from {} ({})", subscription.topic.uri, subscription.subscription_id);
+        let subscription_id = match info.subscriptions.remove(subscription.key) {
+            Some((_, Some(subscription_id))) => subscription_id,
+            Some((_, None)) => return Ok(Pending::of(())),
+            None => return Ok(Pending::new(Future::error(CallError::new(Reason::NoSuchSubscription, None, None))))
+        };
+        try!(info.queue_message(Message::Unsubscribe(request_id, subscription_id)));
+        let (complete, future) = Future::<(), CallError>::pair();
+        info.note_request(request_id, RequestKind::Unsubscribe, subscription.topic);
+        info.unsubscription_requests.insert(request_id, (complete, subscription_id));
+        Ok(Pending::new(future))
+    }
+
+    /// Asks the router for the last `limit` events on the subscription's topics, or all that it
+    /// kept, using the `wamp.subscription.get_events` meta procedure.  The call's first argument
+    /// is the list of events, oldest first.
+    #[cfg(all(feature = "subscriber", feature = "caller"))]
+    pub fn get_events(&mut self, subscription: &Subscription, limit: Option<usize>) -> WampResult<CallHandle> {
+        let subscription_id = match self.connection_info.lock().unwrap().subscriptions.id_of(subscription.key) {
+            Some(subscription_id) => subscription_id,
+            None => return Err(Error::new(ErrorKind::InvalidState("The subscription isn't active")))
+        };
+        let mut args = vec![Value::UnsignedInteger(subscription_id)];
+        if let Some(limit) = limit {
+            args.push(Value::Integer(limit as i64));
+        }
+        self.call(URI::new(GET_EVENTS_PROCEDURE), Some(args), None)
+    }
+
+    #[cfg(feature = "callee")]
+    pub fn unregister(&mut self, registration: Registration) -> WampResult<Pending<()>> {
+        let request_id = self.get_next_session_id();
+        let mut info = self.connection_info.lock().unwrap();
+        debug!("Unregistering {} ({})", registration.procedure.uri, registration.registration_id);
+        let registration_id = match info.registrations.id_of(registration.key) {
+            Some(registration_id) => registration_id,
+            None => return Ok(Pending::new(Future::error(CallError::new(Reason::NoSuchRegistration, None, None))))
+        };
+        try!(info.queue_message(Message::Unregister(request_id, registration_id)));
+        let (complete, future) = Future::<(), CallError>::pair();
+
+        info.note_request(request_id, RequestKind::Unregister, registration.procedure);
+        info.registration_requests.insert(request_id, RegistrationRequest::Unregister(complete, registration_id));
+        Ok(Pending::new(future))
+    }
+
+
+
+    #[cfg(feature = "publisher")]
+    fn check_publish(&self, topic: &URI) -> WampResult<()> {
+        match self.allow_list {
+            Some(ref allow_list) if !allow_list.may_publish(&topic.uri) => Err(Error::new(ErrorKind::NotAllowed(topic.uri.to_string()))),
+            _ => Ok(())
+        }
+    }
+
+    #[cfg(feature = "caller")]
+    fn check_call(&self, procedure: &URI) -> WampResult<()> {
+        match self.allow_list {
+            Some(ref allow_list) if !allow_list.may_call(&procedure.uri) => Err(Error::new(ErrorKind::NotAllowed(procedure.uri.to_string()))),
+            _ => Ok(())
+        }
+    }
+
+    #[cfg(feature = "publisher")]
+    pub fn publish(&mut self, topic: URI, args: Option<List>, kwargs: Option<Dict>) -> WampResult<()> {
+        self.publish_with_options(topic, args, kwargs, PublishOptions::new(false))
+    }
+
+    #[cfg(feature = "publisher")]
+    pub fn publish_with_options(&mut self, topic: URI, args: Option<List>, kwargs: Option<Dict>, mut options: PublishOptions) -> WampResult<()> {
+        info!("Publishing to {:?} with {:?} | {:?}", topic, args, kwargs);
+        try!(self.check_publish(&topic));
+        let request_id = self.next_publication_id(&topic);
+        options.acknowledge = false;
+        let mut info = self.connection_info.lock().unwrap();
+        info.option_defaults.apply_to_publish(&mut options);
+        let (args, kwargs) = info.compress_payload(&topic, args, kwargs);
+        info.submit_publication(Message::Publish(request_id, options, topic, args, kwargs), None)
+    }
+
+    #[cfg(feature = "caller")]
+    pub fn call(&mut self, procedure: URI, args: Option<List>, kwargs: Option<Dict>) -> WampResult<CallHandle> {
+        self.call_with_options(procedure, args, kwargs, CallOptions::new())
+    }
+
+    #[cfg(feature = "caller")]
+    pub fn call_with_options(&mut self, procedure: URI, args: Option<List>, kwargs: Option<Dict>, options: CallOptions) -> WampResult<CallHandle> {
+        self.make_call(procedure, args, kwargs, options, None)
+    }
+
+    /// Calls a procedure that may send progressive results before its final one.  Each
+    /// progressive result is passed to `on_progress`, while the final one completes the call.
+    #[cfg(feature = "caller")]
+    pub fn call_with_progress(&mut self, procedure: URI, args: Option<List>, kwargs: Option<Dict>, options: CallOptions, on_progress: Box<FnMut(List, Dict)>) -> WampResult<CallHandle> {
+        self.make_call(procedure, args, kwargs, options.with_receive_progress(true), Some(on_progress))
+    }
+
+    /// Starts a progressive call, sending its arguments in chunks so that large inputs needn't
+    /// be held in memory at once.  `args` and `kwargs` are the first chunk; the returned
+    /// `ProgressiveCall` sends the rest.  The callee has to take progressive calls, see
+    /// `Client::register_progressive()`.
+    #[cfg(feature = "caller")]
+    pub fn call_progressive(&mut self, procedure: URI, args: Option<List>, kwargs: Option<Dict>, options: CallOptions) -> WampResult<ProgressiveCall> {
+        let handle = try!(self.make_call(procedure.clone(), args, kwargs, options.clone().with_progress(true), None));
+        Ok(ProgressiveCall::new(handle, procedure, options))
+    }
+
+    #[cfg(feature = "caller")]
+    fn make_call(&mut self, procedure: URI, args: Option<List>, kwargs: Option<Dict>, mut options: CallOptions, on_progress: Option<Box<FnMut(List, Dict)>>) -> WampResult<CallHandle> {
+        info!("Calling {:?} with {:?} | {:?}", procedure, args, kwargs);
+        try!(self.check_call(&procedure));
+        let request_id = self.get_next_session_id();
+        let (complete, future) = Future::<(List, Dict), CallError>::pair();
+        let connection_info = self.connection_info.clone();
+        let mut info = connection_info.lock().unwrap();
+        // The first chunk of a progressive call says little about its result
+        let cache = if options.is_progressive() { None } else { info.response_cache.as_ref() };
+        let cache_key = match cache {
+            Some(cache) => {
+                let key = canonical_key(&args, &kwargs);
+                if let Some(result) = cache.get(&procedure.uri, &key) {
+                    debug!("Answering call to {} from the response cache", procedure.uri);
+                    return Ok(CallHandle::new(Pending::of(result), request_id, connection_info.clone()));
+                }
+                Some(key)
+            },
+            None => None
+        };
+        info.option_defaults.apply_to_call(&mut options);
+        let (args, kwargs) = info.compress_payload(&procedure, args, kwargs);
+        try!(info.queue_message(Message::Call(request_id, options, procedure.clone(), args, kwargs)));
+        if let (Some(key), Some(cache)) = (cache_key, info.response_cache.as_mut()) {
+            cache.expect_result(request_id, procedure.uri.to_string(), key);
+        }
+        info.call_requests.insert(request_id, complete);
+        if let Some(on_progress) = on_progress {
+            info.progress_handlers.insert(request_id, on_progress);
+        }
+        info.note_request(request_id, RequestKind::Call, procedure);
+        self.track_request(&info, request_id);
+        Ok(CallHandle::new(Pending::new(future), request_id, connection_info.clone()))
+    }
+
+    #[cfg(feature = "publisher")]
+    pub fn publish_and_acknowledge(&mut self, topic: URI, args: Option<List>, kwargs: Option<Dict>) -> WampResult<Pending<ID>> {
+        self.publish_and_acknowledge_with_options(topic, args, kwargs, PublishOptions::new(true))
+    }
+
+    #[cfg(feature = "publisher")]
+    pub fn publish_and_acknowledge_with_options(&mut self, topic: URI, args: Option<List>, kwargs: Option<Dict>, mut options: PublishOptions) -> WampResult<Pending<ID>> {
+        info!("Publishing to {:?} with {:?} | {:?}", topic, args, kwargs);
+        try!(self.check_publish(&topic));
+        let request_id = self.next_publication_id(&topic);
+        let (complete, future) = Future::<ID, CallError>::pair();
+        options.acknowledge = true;
+        let connection_info = self.connection_info.clone();
+        let mut info = connection_info.lock().unwrap();
+        info.option_defaults.apply_to_publish(&mut options);
+        info.publish_requests.insert(request_id, complete);
+        info.note_request(request_id, RequestKind::Publish, topic.clone());
+        self.track_request(&info, request_id);
+        let (args, kwargs) = info.compress_payload(&topic, args, kwargs);
+        try!(info.submit_publication(Message::Publish(request_id, options, topic, args, kwargs), None));
+        Ok(Pending::new(future))
+    }
+
+    pub fn shutdown(&mut self) -> WampResult<Pending<()>> {
+        let mut info = self.connection_info.lock().unwrap();
+        if info.session.leave() {
+            info.disconnect_cause = Some(DisconnectCause::Shutdown);
+            info.cancellation.cancel();
+            let (complete, future) = Future::pair();
+            info.shutdown_complete = Some(complete);
+            // TODO add timeout in case server doesn't respond.
+            try!(info.queue_message(Message::Goodbye(ErrorDetails::new(), Reason::SystemShutdown)));
+            Ok(Pending::new(future))
+        } else {
+            Err(Error::new(ErrorKind::InvalidState("Tried to shut down a client that was already shutting down")))
+        }
+    }
+}
+
+impl fmt::Debug for ConnectionHandler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{{Connection id: {}}}", self.connection_info.lock().unwrap().session.session_id())
+    }
+}
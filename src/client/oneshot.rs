@@ -0,0 +1,113 @@
+//! Contains `publish_once` and `call_once`, which connect, make one request and leave again, for
+//! scripts and cron jobs that have no use for a long lived `Client`.
+//!
+//! Both block until the router has answered the request, so a publication isn't reported as
+//! sent until the router has accepted it, and then say goodbye and wait for the connection to
+//! close before returning.  Connecting, waiting for the answer and leaving each give up after
+//! ten seconds.
+use super::{Client, Connection};
+use messages::{URI, Dict, List};
+use std::time::Duration;
+use ::{WampResult, Error, ErrorKind, CallResult, ID};
+
+// How long each step of a one-shot request may take
+fn timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+/// Runs `request` in a new session on `realm`, and leaves the session whether or not the request
+/// succeeded
+fn once<T, F>(url: &str, realm: &str, request: F) -> WampResult<T> where F: FnOnce(&mut Client) -> WampResult<CallResult<T>> {
+    let mut connection = Connection::new(url, realm);
+    connection.set_connect_timeout(timeout());
+    let mut client = try!(connection.connect());
+    let result = request(&mut client);
+    match client.shutdown_and_wait(timeout()) {
+        Ok(true) => {},
+        Ok(false) => warn!("The session on {} didn't close within {:?}", realm, timeout()),
+        Err(e) => warn!("Could not leave the session on {}: {}", realm, e)
+    }
+    match try!(result) {
+        Ok(answer) => Ok(answer),
+        Err(e) => Err(Error::new(ErrorKind::CallFailed(e)))
+    }
+}
+
+/// Publishes `args` to `topic` in a new session, and returns the publication's ID once the
+/// router has acknowledged it.
+#[cfg(feature = "publisher")]
+pub fn publish_once(url: &str, realm: &str, topic: URI, args: Option<List>) -> WampResult<ID> {
+    once(url, realm, |client| {
+        let pending = try!(client.publish_and_acknowledge(topic, args, None));
+        Ok(pending.wait_timeout(timeout()))
+    })
+}
+
+/// Calls `procedure` with `args` in a new session, and returns its result.  A call the router
+/// or callee answers with an error fails with `ErrorKind::CallFailed`.
+#[cfg(feature = "caller")]
+pub fn call_once(url: &str, realm: &str, procedure: URI, args: Option<List>) -> WampResult<(List, Dict)> {
+    once(url, realm, |client| {
+        let handle = try!(client.call(procedure, args, None));
+        Ok(handle.wait_timeout(timeout()))
+    })
+}
+
+#[cfg(all(test, feature = "router", feature = "publisher", feature = "subscriber", feature = "caller", feature = "callee"))]
+mod test {
+    use super::{publish_once, call_once};
+    use messages::{URI, Reason};
+    use router::Router;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use testing::{start_router, join, REALM};
+    use ::{ErrorKind, Value};
+
+    // Waits for the one-shot sessions to be gone from the router
+    fn wait_for_sessions(router: &Router, count: usize) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while router.sessions().len() != count {
+            assert!(Instant::now() < deadline, "The router still has {} sessions", router.sessions().len());
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn publishing_once() {
+        let (router, url) = start_router();
+        let mut subscriber = join(&url);
+        let (events, received) = channel();
+        subscriber.subscribe(URI::new("ca.test.topic"), Box::new(move |args, _| {
+            events.send(args).unwrap();
+        })).unwrap().wait().unwrap();
+
+        publish_once(&url, REALM, URI::new("ca.test.topic"), Some(vec![Value::Integer(1)])).unwrap();
+        assert_eq!(received.recv_timeout(Duration::from_secs(5)).unwrap(), vec![Value::Integer(1)]);
+        wait_for_sessions(&router, 1);
+
+        assert!(publish_once(&url, "ca.missing", URI::new("ca.test.topic"), None).is_err());
+    }
+
+    #[test]
+    fn calling_once() {
+        let (router, url) = start_router();
+        let mut callee = join(&url);
+        callee.register(URI::new("ca.test.double"), Box::new(|args, _| {
+            match args.get(0) {
+                Some(&Value::Integer(n)) => Ok((Some(vec![Value::Integer(n * 2)]), None)),
+                _ => Ok((None, None))
+            }
+        })).unwrap().wait().unwrap();
+
+        let (args, _) = call_once(&url, REALM, URI::new("ca.test.double"), Some(vec![Value::Integer(21)])).unwrap();
+        assert_eq!(args, vec![Value::Integer(42)]);
+        wait_for_sessions(&router, 1);
+
+        match *call_once(&url, REALM, URI::new("ca.test.missing"), None).unwrap_err().kind() {
+            ErrorKind::CallFailed(ref e) => assert_eq!(*e.get_reason(), Reason::NoSuchProcedure),
+            ref kind => panic!("Unexpected error {:?}", kind)
+        }
+        wait_for_sessions(&router, 1);
+    }
+}
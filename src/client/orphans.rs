@@ -0,0 +1,120 @@
+//! Contains the `OrphanEvent` struct, which describes an event for a subscription the client
+//! doesn't have.
+//!
+//! An orphaned event means the router and the client disagree about the client's subscriptions,
+//! for instance because an unsubscribe crossed paths with an event, or a bug on either side.
+//! They are counted, and can be passed to a hook so that they can be reported.
+use super::{Client, ConnectionInfo};
+use messages::{URI, Dict, List};
+use ::ID;
+
+/// An event the router sent for a subscription ID the client doesn't know.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrphanEvent {
+    pub subscription_id: ID,
+    pub publication_id: ID,
+    /// The topic, if the router included it in the event's details
+    pub topic: Option<URI>,
+    /// When the router received the publication, if the router stamps events
+    pub timestamp: Option<u64>,
+    /// The publisher's session ID, if the publisher asked the router to disclose it
+    pub publisher: Option<ID>,
+    pub publisher_authid: Option<String>,
+    pub publisher_authrole: Option<String>,
+    pub args: List,
+    pub kwargs: Dict
+}
+
+/// Runs for every orphaned event.  See `Client::on_orphan_event`.
+pub type OrphanEventHook = Box<FnMut(&OrphanEvent)>;
+
+impl ConnectionInfo {
+    pub fn orphan_event(&mut self, event: OrphanEvent) {
+        warn!("Recieved an event for a subscription we don't have.  ID: {}", event.subscription_id);
+        self.orphan_events += 1;
+        if let Some(ref mut hook) = self.orphan_event_hook {
+            hook(&event);
+        }
+    }
+
+    /// Treats the events held for unknown subscriptions as orphaned once no subscription is
+    /// pending, since they can't belong to any subscription the client will make
+    pub fn discard_early_events(&mut self) {
+        if self.subscription_requests.is_empty() && !self.early_events.is_empty() {
+            let early_events: Vec<_> = self.early_events.drain().collect();
+            for (_, events) in early_events {
+                for event in events {
+                    self.orphan_event(event);
+                }
+            }
+        }
+    }
+}
+
+impl Client {
+    /// The number of events received for subscriptions the client doesn't have
+    pub fn orphan_events(&self) -> u64 {
+        self.connection_info.lock().unwrap().orphan_events
+    }
+
+    /// Passes every event for a subscription the client doesn't have to `hook`, replacing any
+    /// earlier hook.  The hook runs on the connection's event loop, so it shouldn't block or use
+    /// the client.
+    pub fn on_orphan_event(&mut self, hook: OrphanEventHook) {
+        self.connection_info.lock().unwrap().orphan_event_hook = Some(hook);
+    }
+}
+
+#[cfg(all(test, feature = "subscriber"))]
+mod test {
+    use messages::{Message, EventDetails, URI};
+    use serde_json;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+    use testing::{ScriptedRouter, join};
+    use ::{Value, ID};
+
+    // Reads the client's SUBSCRIBE, and returns its request ID
+    fn subscribe_request(router: &ScriptedRouter) -> ID {
+        match serde_json::from_str(&router.next()).unwrap() {
+            Message::Subscribe(request_id, ..) => request_id,
+            message => panic!("Unexpected message {:?}", message)
+        }
+    }
+
+    fn event(subscription_id: ID, publication_id: ID) -> Message {
+        Message::Event(subscription_id, publication_id, EventDetails::new(), Some(vec![Value::Integer(publication_id as i64)]), None)
+    }
+
+    #[test]
+    fn events_before_subscribed_are_delivered() {
+        let router = ScriptedRouter::start();
+        let mut client = join(&router.url);
+        let (events, received) = channel();
+        let subscription = client.subscribe(URI::new("ca.test.topic"), Box::new(move |args, _| {
+            events.send(args).unwrap();
+        })).unwrap();
+        let request_id = subscribe_request(&router);
+        router.send(&event(5, 1));
+        router.send(&event(5, 2));
+        router.send(&Message::Subscribed(request_id, 5));
+        router.send(&event(5, 3));
+        assert_eq!(subscription.wait().unwrap().subscription_id, 5);
+        for publication_id in 1..4 {
+            assert_eq!(received.recv_timeout(Duration::from_secs(5)).unwrap(), vec![Value::Integer(publication_id)]);
+        }
+        assert_eq!(client.orphan_events(), 0);
+    }
+
+    #[test]
+    fn early_events_for_other_subscriptions_are_orphaned() {
+        let router = ScriptedRouter::start();
+        let mut client = join(&router.url);
+        let subscription = client.subscribe(URI::new("ca.test.topic"), Box::new(|_, _| {})).unwrap();
+        let request_id = subscribe_request(&router);
+        router.send(&event(6, 1));
+        router.send(&Message::Subscribed(request_id, 5));
+        subscription.wait().unwrap();
+        assert_eq!(client.orphan_events(), 1);
+    }
+}
@@ -0,0 +1,89 @@
+//! Contains the `Pending` struct, which every request that waits for the router's answer
+//! returns.
+use messages::Reason;
+use eventual::{Async, AsyncError, Future};
+use std::sync::mpsc::channel;
+use std::time::Duration;
+use ::CallError;
+
+/// The router's eventual answer to a request: a subscription, a registration, the ID of an
+/// acknowledged publication, the result of a call, or just confirmation.
+///
+/// The answer can be waited for, with or without a time limit, or the handle can be turned into
+/// a future to combine it with others.  If the connection drops before the router answers, the
+/// request fails with `Reason::NetworkFailure`.
+pub struct Pending<T: Send + 'static> {
+    future: Future<T, CallError>
+}
+
+fn from_async_error(error: AsyncError<CallError>) -> CallError {
+    match error {
+        AsyncError::Failed(error) => error,
+        AsyncError::Aborted => CallError::new(Reason::NetworkFailure, None, None)
+    }
+}
+
+impl<T: Send + 'static> Pending<T> {
+    pub fn new(future: Future<T, CallError>) -> Pending<T> {
+        Pending {
+            future: future
+        }
+    }
+
+    /// A request that has already been answered
+    pub fn of(value: T) -> Pending<T> {
+        Pending::new(Future::of(value))
+    }
+
+    /// Blocks until the router answers
+    pub fn wait(self) -> Result<T, CallError> {
+        self.future.await().map_err(from_async_error)
+    }
+
+    /// Blocks until the router answers, or fails with `Reason::Timeout` after `timeout`.  The
+    /// request itself isn't cancelled, and its answer is dropped if it arrives later.
+    pub fn wait_timeout(self, timeout: Duration) -> Result<T, CallError> {
+        let (tx, rx) = channel();
+        self.future.receive(move |result| {
+            tx.send(result.map_err(from_async_error)).ok();
+        });
+        match rx.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(_) => Err(CallError::new(Reason::Timeout, None, None))
+        }
+    }
+
+    pub fn into_future(self) -> Future<T, CallError> {
+        self.future
+    }
+}
+
+impl<T: Send + 'static> From<Pending<T>> for Future<T, CallError> {
+    fn from(pending: Pending<T>) -> Future<T, CallError> {
+        pending.future
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Pending;
+    use messages::Reason;
+    use eventual::Future;
+    use std::time::Duration;
+    use ::CallError;
+
+    #[test]
+    fn wait_for_answers() {
+        assert_eq!(Pending::of(3).wait(), Ok(3));
+        let (complete, future) = Future::<u64, CallError>::pair();
+        complete.fail(CallError::new(Reason::NotAuthorized, None, None));
+        assert_eq!(Pending::new(future).wait().unwrap_err().get_reason(), &Reason::NotAuthorized);
+
+        let (complete, future) = Future::<u64, CallError>::pair();
+        assert_eq!(Pending::new(future).wait_timeout(Duration::from_millis(10)).unwrap_err().get_reason(), &Reason::Timeout);
+        drop(complete);
+        let (complete, future) = Future::<u64, CallError>::pair();
+        drop(complete);
+        assert_eq!(Pending::new(future).wait_timeout(Duration::from_millis(10)).unwrap_err().get_reason(), &Reason::NetworkFailure);
+    }
+}
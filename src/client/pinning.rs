@@ -0,0 +1,107 @@
+//! Contains the `ThreadHints` struct, which pins the connection's thread to a CPU core and raises
+//! its priority, for latency critical consumers.
+//!
+//! Each connection runs one thread, which both receives messages and writes the outbound queue.
+//! The hints are applied when that thread starts.  They need the crate's `pinning` feature and
+//! are only supported on Linux; elsewhere, or when the operating system refuses a hint (raising
+//! the priority usually needs `CAP_SYS_NICE`), a warning is logged and the thread runs
+//! unchanged.
+#[cfg(all(feature = "pinning", target_os = "linux"))]
+use libc;
+#[cfg(all(feature = "pinning", target_os = "linux"))]
+use std::{io, mem};
+
+/// Scheduling hints for the connection's thread.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ThreadHints {
+    core: Option<usize>,
+    niceness: Option<i32>
+}
+
+impl ThreadHints {
+    /// Leaves the thread wherever the operating system puts it, at the normal priority
+    pub fn new() -> ThreadHints {
+        ThreadHints {
+            core: None,
+            niceness: None
+        }
+    }
+
+    /// Pins the thread to the CPU core with the given index
+    pub fn with_core(mut self, core: usize) -> ThreadHints {
+        self.core = Some(core);
+        self
+    }
+
+    /// Sets the thread's niceness, from -20 for the highest priority to 19 for the lowest
+    pub fn with_niceness(mut self, niceness: i32) -> ThreadHints {
+        self.niceness = Some(niceness);
+        self
+    }
+
+    fn is_empty(&self) -> bool {
+        self.core.is_none() && self.niceness.is_none()
+    }
+
+    /// Applies the hints to the calling thread, logging the ones that can't be applied
+    pub fn apply(&self) {
+        if self.is_empty() {
+            return;
+        }
+        if let Some(core) = self.core {
+            match pin_to_core(core) {
+                Ok(()) => debug!("Pinned the connection thread to core {}", core),
+                Err(e) => warn!("Could not pin the connection thread to core {}: {}", core, e)
+            }
+        }
+        if let Some(niceness) = self.niceness {
+            match set_niceness(niceness) {
+                Ok(()) => debug!("Set the connection thread's niceness to {}", niceness),
+                Err(e) => warn!("Could not set the connection thread's niceness to {}: {}", niceness, e)
+            }
+        }
+    }
+}
+
+#[cfg(all(feature = "pinning", target_os = "linux"))]
+fn pin_to_core(core: usize) -> Result<(), String> {
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        if core >= mem::size_of::<libc::cpu_set_t>() * 8 {
+            return Err("there is no such core".to_string());
+        }
+        libc::CPU_SET(core, &mut set);
+        if libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(io::Error::last_os_error().to_string());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(feature = "pinning", target_os = "linux"))]
+fn set_niceness(niceness: i32) -> Result<(), String> {
+    // On Linux, niceness is per thread, and a `who` of 0 means the calling thread
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, niceness) } != 0 {
+        return Err(io::Error::last_os_error().to_string());
+    }
+    Ok(())
+}
+
+#[cfg(not(all(feature = "pinning", target_os = "linux")))]
+fn pin_to_core(_core: usize) -> Result<(), String> {
+    Err(unsupported())
+}
+
+#[cfg(not(all(feature = "pinning", target_os = "linux")))]
+fn set_niceness(_niceness: i32) -> Result<(), String> {
+    Err(unsupported())
+}
+
+#[cfg(not(all(feature = "pinning", target_os = "linux")))]
+fn unsupported() -> String {
+    if cfg!(feature = "pinning") {
+        "thread hints are only supported on Linux".to_string()
+    } else {
+        "thread hints need the pinning feature".to_string()
+    }
+}
@@ -0,0 +1,306 @@
+//! Contains the `HttpProxy` transport, which tunnels a connection's websocket through an HTTP
+//! proxy for networks that can't reach the router directly.
+//!
+//! The proxy is asked to open a tunnel to the router with a `CONNECT` request, authenticating
+//! with basic authentication if it was given credentials.  Once it agrees, the tunnel is handed
+//! to the client through a port on the loopback interface that accepts the one connection.  On
+//! Linux, connections that another process opened to the port are refused, so that it can't
+//! take the tunnel, and the proxy credentials it was opened with, in the client's place.  Other
+//! platforms hand the tunnel to the first connection.  Everything is carried through the tunnel unchanged, so the websocket handshake, and TLS for
+//! `wss://` URLs, are still between the client and the router.
+use super::transport::Transport;
+#[cfg(target_os = "linux")]
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, Instant};
+use url::Url;
+use utils::base64_encode;
+use ::{WampResult, Error, ErrorKind};
+
+/// A proxy that accepts HTTP `CONNECT` requests.  Only websocket URLs can be tunneled.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HttpProxy {
+    host: String,
+    port: u16,
+    credentials: Option<(String, String)>,
+    timeout: Duration
+}
+
+impl HttpProxy {
+    pub fn new(host: &str, port: u16) -> HttpProxy {
+        HttpProxy {
+            host: host.to_string(),
+            port: port,
+            credentials: None,
+            timeout: Duration::from_secs(5)
+        }
+    }
+
+    /// Authenticates with the proxy as `username` using basic authentication
+    pub fn with_basic_auth(mut self, username: &str, password: &str) -> HttpProxy {
+        self.credentials = Some((username.to_string(), password.to_string()));
+        self
+    }
+
+    /// Sets how long reaching the proxy and opening the tunnel may take.  The default is 5
+    /// seconds.
+    pub fn with_timeout(mut self, timeout: Duration) -> HttpProxy {
+        self.timeout = timeout;
+        self
+    }
+
+    fn connect_request(&self, target: &str) -> String {
+        let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+        if let Some((ref username, ref password)) = self.credentials {
+            let credentials = base64_encode(format!("{}:{}", username, password).as_bytes());
+            request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", credentials));
+        }
+        request.push_str("\r\n");
+        request
+    }
+
+    /// Asks the proxy for a tunnel to `target`
+    fn open_tunnel(&self, target: &str) -> Result<TcpStream, String> {
+        let proxy = format!("{}:{}", self.host, self.port);
+        let address = match try!(proxy.to_socket_addrs().map_err(|e| e.to_string())).next() {
+            Some(address) => address,
+            None => return Err(format!("{} has no address", self.host))
+        };
+        let mut stream = try!(TcpStream::connect_timeout(&address, self.timeout).map_err(|e| e.to_string()));
+        try!(stream.set_read_timeout(Some(self.timeout)).map_err(|e| e.to_string()));
+        try!(stream.write_all(self.connect_request(target).as_bytes()).map_err(|e| e.to_string()));
+        let response = try!(read_head(&mut stream).map_err(|e| e.to_string()));
+        try!(check_response(&response));
+        try!(stream.set_read_timeout(None).map_err(|e| e.to_string()));
+        Ok(stream)
+    }
+}
+
+impl Transport for HttpProxy {
+    fn open(&self, url: &str, _: &[String]) -> WampResult<String> {
+        let mut parsed = try!(Url::parse(url).map_err(|e| Error::new(ErrorKind::URLError(e))));
+        if parsed.scheme() != "ws" && parsed.scheme() != "wss" {
+            return Err(Error::new(ErrorKind::InvalidState("HTTP proxies can only tunnel ws:// and wss:// URLs")));
+        }
+        let target = match (parsed.host_str(), parsed.port_or_known_default()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            _ => return Err(Error::new(ErrorKind::InvalidState("Websocket URLs need a host")))
+        };
+        let tunnel = try!(self.open_tunnel(&target).map_err(|e| {
+            Error::new(ErrorKind::TransportError(format!("Could not tunnel to {} through the proxy at {}:{}: {}", target, self.host, self.port, e)))
+        }));
+        info!("Opened a tunnel to {} through the proxy at {}:{}", target, self.host, self.port);
+        let listener = try!(TcpListener::bind("127.0.0.1:0").map_err(|e| Error::new(ErrorKind::TransportError(e.to_string()))));
+        let port = try!(listener.local_addr().map_err(|e| Error::new(ErrorKind::TransportError(e.to_string())))).port();
+        let timeout = self.timeout;
+        thread::spawn(move || {
+            match accept(&listener, timeout) {
+                Ok(client) => relay(client, tunnel),
+                Err(e) => warn!("The client never used its tunnel to {}: {}", target, e)
+            }
+        });
+        parsed.set_host(Some("127.0.0.1")).ok();
+        parsed.set_port(Some(port)).ok();
+        Ok(parsed.into_string())
+    }
+}
+
+/// Reads the status line and headers of the proxy's response, without reading any further
+fn read_head(stream: &mut TcpStream) -> io::Result<String> {
+    let mut head = Vec::new();
+    let mut byte = [0];
+    while !head.ends_with(b"\r\n\r\n") {
+        if try!(stream.read(&mut byte)) == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the proxy closed the connection"));
+        }
+        head.push(byte[0]);
+        if head.len() > 16 * 1024 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "the proxy's response is too long"));
+        }
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+/// Checks that the proxy agreed to open the tunnel
+fn check_response(response: &str) -> Result<(), String> {
+    let status_line = response.lines().next().unwrap_or("");
+    let mut parts = status_line.splitn(3, ' ');
+    let version = parts.next().unwrap_or("");
+    let status = parts.next().unwrap_or("");
+    if !version.starts_with("HTTP/") {
+        return Err(format!("the proxy didn't answer with HTTP ({:?})", status_line));
+    }
+    match status {
+        "200" => Ok(()),
+        "407" => Err("the proxy needs credentials, or refused the ones it was given".to_string()),
+        _ => Err(format!("the proxy refused the tunnel ({})", status_line))
+    }
+}
+
+/// Waits for the client to connect to `listener`, ignoring connections from other processes
+fn accept(listener: &TcpListener, timeout: Duration) -> io::Result<TcpStream> {
+    try!(listener.set_nonblocking(true));
+    let local = try!(listener.local_addr());
+    let deadline = Instant::now() + timeout;
+    loop {
+        match listener.accept() {
+            Ok((stream, peer)) => {
+                if !opened_by_this_process(peer, local) {
+                    warn!("Refusing a connection to the tunnel from another process");
+                    continue;
+                }
+                try!(stream.set_nonblocking(false));
+                return Ok(stream);
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock && Instant::now() < deadline => {
+                thread::sleep(Duration::from_millis(10));
+            },
+            Err(e) => return Err(e)
+        }
+    }
+}
+
+/// Whether this process opened the connection from `peer` to `local`.  The connection's end at
+/// `peer` is listed in /proc/net/tcp with the inode of its socket, which this process only has a
+/// descriptor for if it opened it.
+#[cfg(target_os = "linux")]
+fn opened_by_this_process(peer: SocketAddr, local: SocketAddr) -> bool {
+    let inode = match socket_inode(peer, local) {
+        Some(inode) => inode,
+        None => return false
+    };
+    let socket = format!("socket:[{}]", inode);
+    let descriptors = match fs::read_dir("/proc/self/fd") {
+        Ok(descriptors) => descriptors,
+        Err(_) => return false
+    };
+    descriptors.filter_map(|entry| entry.ok()).any(|entry| {
+        fs::read_link(entry.path()).map(|target| target.to_string_lossy() == socket).unwrap_or(false)
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn opened_by_this_process(_: SocketAddr, _: SocketAddr) -> bool {
+    true
+}
+
+/// The inode of the socket connected from `from` to `to`, as listed in /proc/net/tcp
+#[cfg(target_os = "linux")]
+fn socket_inode(from: SocketAddr, to: SocketAddr) -> Option<String> {
+    let mut table = String::new();
+    if File::open("/proc/net/tcp").and_then(|mut file| file.read_to_string(&mut table)).is_err() {
+        return None;
+    }
+    let (from, to) = (proc_address(from), proc_address(to));
+    table.lines().skip(1).filter_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match (fields.get(1), fields.get(2), fields.get(9)) {
+            (Some(local), Some(remote), Some(inode)) if *local == from && *remote == to => Some(inode.to_string()),
+            _ => None
+        }
+    }).next()
+}
+
+/// How /proc/net/tcp writes an IPv4 address: its four octets read as a native endian integer, and
+/// the port, both in hex
+#[cfg(target_os = "linux")]
+fn proc_address(address: SocketAddr) -> String {
+    let ip = match address {
+        SocketAddr::V4(address) => u32::from_ne_bytes(address.ip().octets()),
+        SocketAddr::V6(_) => return String::new()
+    };
+    format!("{:08X}:{:04X}", ip, address.port())
+}
+
+/// Copies everything between the client and the tunnel until both sides are done
+fn relay(client: TcpStream, tunnel: TcpStream) {
+    let (client_reader, tunnel_reader) = match (client.try_clone(), tunnel.try_clone()) {
+        (Ok(client_reader), Ok(tunnel_reader)) => (client_reader, tunnel_reader),
+        _ => {
+            error!("Could not relay through the tunnel");
+            return;
+        }
+    };
+    let upstream = thread::spawn(move || copy(client_reader, tunnel));
+    copy(tunnel_reader, client);
+    upstream.join().ok();
+}
+
+fn copy(mut from: TcpStream, mut to: TcpStream) {
+    if let Err(e) = io::copy(&mut from, &mut to) {
+        debug!("Tunnel closed: {}", e);
+    }
+    to.shutdown(Shutdown::Write).ok();
+}
+
+#[cfg(test)]
+mod test {
+    use super::{HttpProxy, check_response};
+    #[cfg(target_os = "linux")]
+    use super::opened_by_this_process;
+    #[cfg(feature = "router")]
+    use super::{read_head, relay};
+    #[cfg(feature = "router")]
+    use client::Connection;
+    #[cfg(feature = "router")]
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    #[cfg(feature = "router")]
+    use std::thread;
+    #[cfg(feature = "router")]
+    use testing::{self, REALM};
+
+    #[test]
+    fn connect_requests_and_responses() {
+        let proxy = HttpProxy::new("proxy.example.com", 3128);
+        assert_eq!(proxy.connect_request("router:443"), "CONNECT router:443 HTTP/1.1\r\nHost: router:443\r\n\r\n");
+        let proxy = proxy.with_basic_auth("joe", "secret");
+        assert_eq!(proxy.connect_request("router:443"), "CONNECT router:443 HTTP/1.1\r\nHost: router:443\r\nProxy-Authorization: Basic am9lOnNlY3JldA==\r\n\r\n");
+
+        assert!(check_response("HTTP/1.1 200 Connection established\r\n\r\n").is_ok());
+        assert!(check_response("HTTP/1.0 200\r\n\r\n").is_ok());
+        assert!(check_response("HTTP/1.1 407 Proxy Authentication Required\r\n\r\n").unwrap_err().contains("credentials"));
+        assert!(check_response("HTTP/1.1 403 Forbidden\r\n\r\n").unwrap_err().contains("403 Forbidden"));
+        assert!(check_response("SSH-2.0-OpenSSH\r\n\r\n").is_err());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn only_this_process_can_take_a_tunnel() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let local = listener.local_addr().unwrap();
+        let _client = TcpStream::connect(local).unwrap();
+        let (_, peer) = listener.accept().unwrap();
+        assert!(opened_by_this_process(peer, local));
+        // Nothing in this process is connected from the listener's own address
+        assert!(!opened_by_this_process(local, peer));
+    }
+
+    // Opens one tunnel to wherever it is asked to
+    #[cfg(feature = "router")]
+    fn start_proxy() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut client, _) = listener.accept().unwrap();
+            let request = read_head(&mut client).unwrap();
+            let target = request.split_whitespace().nth(1).unwrap().to_string();
+            let router = TcpStream::connect(&target[..]).unwrap();
+            client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").unwrap();
+            relay(client, router);
+        });
+        port
+    }
+
+    #[test]
+    #[cfg(feature = "router")]
+    fn connections_join_through_the_tunnel() {
+        let (_router, url) = testing::start_router();
+        let mut connection = Connection::new(&url, REALM);
+        connection.set_proxy(HttpProxy::new("127.0.0.1", start_proxy()));
+        let mut client = connection.connect().unwrap();
+        client.shutdown().unwrap();
+    }
+}
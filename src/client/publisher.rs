@@ -0,0 +1,86 @@
+//! Contains the `Publisher` struct, a handle that can only publish and call, for handing to
+//! worker threads.
+use super::{Client, Pending, CallHandle};
+use messages::{URI, Dict, List, PublishOptions, CallOptions};
+use ::{WampResult, ID};
+
+/// A cloneable handle that publishes and calls through a client's connection.
+///
+/// Publishers can be sent to other threads, and have no way to subscribe, register or shut the
+/// session down.  They follow the allow list of the client they were made from.  Once the
+/// session ends, publishing and calling through a publisher fails.
+pub struct Publisher {
+    client: Client
+}
+
+impl Client {
+    /// Creates a publisher that shares this client's connection.
+    pub fn publisher(&self) -> Publisher {
+        Publisher {
+            client: self.share()
+        }
+    }
+
+    /// A client on the same connection, with the same owner and allow list, but none of this
+    /// client's pending requests
+    fn share(&self) -> Client {
+        Client {
+            connection_info: self.connection_info.clone(),
+            owner: self.owner,
+            pending_requests: Vec::new(),
+            allow_list: self.allow_list.clone(),
+            receive_thread: None
+        }
+    }
+}
+
+impl Clone for Publisher {
+    fn clone(&self) -> Publisher {
+        Publisher {
+            client: self.client.share()
+        }
+    }
+}
+
+impl Publisher {
+    #[cfg(feature = "publisher")]
+    pub fn publish(&mut self, topic: URI, args: Option<List>, kwargs: Option<Dict>) -> WampResult<()> {
+        self.client.publish(topic, args, kwargs)
+    }
+
+    #[cfg(feature = "publisher")]
+    pub fn publish_with_options(&mut self, topic: URI, args: Option<List>, kwargs: Option<Dict>, options: PublishOptions) -> WampResult<()> {
+        self.client.publish_with_options(topic, args, kwargs, options)
+    }
+
+    #[cfg(feature = "publisher")]
+    pub fn publish_and_acknowledge(&mut self, topic: URI, args: Option<List>, kwargs: Option<Dict>) -> WampResult<Pending<ID>> {
+        self.client.publish_and_acknowledge(topic, args, kwargs)
+    }
+
+    #[cfg(feature = "publisher")]
+    pub fn publish_and_acknowledge_with_options(&mut self, topic: URI, args: Option<List>, kwargs: Option<Dict>, options: PublishOptions) -> WampResult<Pending<ID>> {
+        self.client.publish_and_acknowledge_with_options(topic, args, kwargs, options)
+    }
+
+    #[cfg(feature = "caller")]
+    pub fn call(&mut self, procedure: URI, args: Option<List>, kwargs: Option<Dict>) -> WampResult<CallHandle> {
+        self.client.call(procedure, args, kwargs)
+    }
+
+    #[cfg(feature = "caller")]
+    pub fn call_with_options(&mut self, procedure: URI, args: Option<List>, kwargs: Option<Dict>, options: CallOptions) -> WampResult<CallHandle> {
+        self.client.call_with_options(procedure, args, kwargs, options)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Publisher;
+
+    #[test]
+    fn publishers_can_move_between_threads() {
+        fn assert_send_clone<T: Send + Clone>() {}
+        assert_send_clone::<Publisher>();
+    }
+}
@@ -0,0 +1,613 @@
+//! Contains the outbound message queue used by the client.
+//!
+//! Messages initiated by the client are placed in a queue and written to the websocket from the
+//! websocket's own event loop, rather than directly from whichever thread called the `Client`.
+//! This gives a single place where outgoing messages can be inspected before they are written,
+//! which is where expired messages are dropped.
+//!
+//! The queue can also hold messages back for a short coalescing window, so that messages sent
+//! in quick succession are written together.  The window is tuned automatically: it grows while
+//! messages keep arriving back to back, and drops back to nothing once the client goes idle, so
+//! occasional messages are never delayed.
+//!
+//! Publications to conflated topics are kept at most once in the queue: a new publication to
+//! the topic replaces the one still waiting to be written, so only the latest value is sent once
+//! the writer catches up.
+//!
+//! A message may be queued along with the frame it was already encoded into, in which case the
+//! frame is written instead of encoding the message.  The message itself then only describes the
+//! frame, for conflation and expiry.
+//!
+//! `Client::flush()` waits for the queue to be written out, for programs that exit right after
+//! sending their last messages.
+//!
+//! Messages are written in the order they reach the queue, so publications made one after
+//! another, from one thread or from threads that wait for each other, are written in that order.
+//! Publications made at the same time from different threads reach the queue in whichever order
+//! they get the connection's lock, which needn't be the order they were made in, since a
+//! publication takes the lock once for its request ID and again to be queued.  With
+//! `PublishOrdering::PerTopic`, publications to a topic are instead queued in the order of their
+//! request IDs, which are given out in the order the publications are made: a publication that
+//! overtakes an earlier one to the same topic is held back until the earlier one is queued.
+use super::{Client, ConnectionInfo, MessageSender, WRITE_QUEUE};
+use super::shutdown::wait_until;
+use codec::Frame;
+use messages::{Message, URI, Reason};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use utils::{as_millis, under_prefix};
+use ::{WampResult, Error, ErrorKind, ID, CallError};
+
+pub struct QueuedMessage {
+    message: Message,
+    frame: Option<Frame>,
+    queued_at: Instant,
+    ttl: Option<Duration>
+}
+
+/// Describes a message that was dropped because it spent longer than its time to live in the
+/// outbound queue.
+#[derive(Debug)]
+pub struct ExpiredMessage {
+    /// The request ID of the publish or call that expired
+    pub request_id: ID,
+    /// The topic or procedure the message was addressed to
+    pub uri: URI,
+    /// How long the message waited in the queue
+    pub age: Duration
+}
+
+/// Statistics about how the client has been writing messages.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WriterStats {
+    /// The number of messages written to the websocket
+    pub frames_written: u64,
+    /// The number of times the queue was written out
+    pub batches_written: u64,
+    /// The total time messages spent in the queue before being written
+    pub total_latency: Duration,
+    /// The longest time any message spent in the queue before being written
+    pub max_latency: Duration,
+    /// The coalescing window currently in use
+    pub coalescing_window: Duration,
+    /// The largest coalescing window the queue may choose, if coalescing is enabled
+    pub max_coalescing_window: Option<Duration>,
+    /// The number of publications that were replaced by a newer publication to the same topic
+    pub messages_conflated: u64,
+    /// The number of messages dropped after outliving the queue's time to live
+    pub messages_expired: u64
+}
+
+/// What `Client::flush()` managed to write out.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FlushSummary {
+    /// The messages that were waiting to be written, and were
+    pub flushed: usize,
+    /// The messages that weren't written in time, or were dropped from the queue because they
+    /// expired or a newer publication replaced them.  Messages still queued are written later,
+    /// unless the session ends first.
+    pub abandoned: usize,
+    /// Acknowledged publications that the router didn't acknowledge in time
+    pub unacknowledged: usize
+}
+
+/// How publications to the same topic are ordered.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PublishOrdering {
+    /// Publications are written in the order they reach the outbound queue.  This is the default.
+    BestEffort,
+    /// Publications to the same topic are written in the order they were made, even when they
+    /// are made from different threads
+    PerTopic
+}
+
+/// Holds back publications that overtook an earlier publication to the same topic.
+pub struct Sequencer<T> {
+    // The request IDs of publications that have been made but not queued yet, by topic
+    reserved: HashMap<String, BTreeSet<ID>>,
+    // Publications waiting for the ones made before them, by topic and request ID
+    parked: HashMap<String, BTreeMap<ID, T>>
+}
+
+impl<T> Sequencer<T> {
+    pub fn new() -> Sequencer<T> {
+        Sequencer {
+            reserved: HashMap::new(),
+            parked: HashMap::new()
+        }
+    }
+
+    /// Records that a publication to `topic` has been made with `request_id`
+    pub fn reserve(&mut self, topic: &str, request_id: ID) {
+        self.reserved.entry(topic.to_string()).or_insert_with(BTreeSet::new).insert(request_id);
+    }
+
+    /// Takes a publication that is ready to be queued, and returns the publications to the
+    /// topic that may now be queued, in order.  Publications that weren't reserved are returned
+    /// right away.
+    pub fn arrive(&mut self, topic: &str, request_id: ID, publication: T) -> Vec<T> {
+        if !self.unreserve(topic, request_id) {
+            return vec![publication];
+        }
+        self.parked.entry(topic.to_string()).or_insert_with(BTreeMap::new).insert(request_id, publication);
+        self.release(topic)
+    }
+
+    /// Forgets a publication that won't be queued after all, and returns the publications to the
+    /// topic that were waiting for it
+    pub fn abandon(&mut self, topic: &str, request_id: ID) -> Vec<T> {
+        self.unreserve(topic, request_id);
+        self.release(topic)
+    }
+
+    fn unreserve(&mut self, topic: &str, request_id: ID) -> bool {
+        let (reserved, emptied) = match self.reserved.get_mut(topic) {
+            Some(ids) => (ids.remove(&request_id), ids.is_empty()),
+            None => return false
+        };
+        if emptied {
+            self.reserved.remove(topic);
+        }
+        reserved
+    }
+
+    fn release(&mut self, topic: &str) -> Vec<T> {
+        let first_reserved = self.reserved.get(topic).and_then(|ids| ids.iter().next().cloned());
+        let (released, emptied) = match self.parked.get_mut(topic) {
+            Some(parked) => {
+                let ready: Vec<ID> = parked.keys().cloned().take_while(|id| first_reserved.map_or(true, |first| *id < first)).collect();
+                let released: Vec<T> = ready.iter().filter_map(|id| parked.remove(id)).collect();
+                (released, parked.is_empty())
+            },
+            None => return Vec::new()
+        };
+        if emptied {
+            self.parked.remove(topic);
+        }
+        released
+    }
+
+    /// The number of publications being held back
+    pub fn parked_count(&self) -> usize {
+        self.parked.values().map(|parked| parked.len()).sum()
+    }
+}
+
+pub struct OutboundQueue {
+    messages: VecDeque<QueuedMessage>,
+    write_scheduled: bool,
+    last_write: Option<Instant>,
+    pub ordering: PublishOrdering,
+    sequencer: Sequencer<(Message, Option<Frame>)>,
+    pub ttl: Option<Duration>,
+    /// Prefixes of the topics whose publications are conflated
+    pub conflated_topics: Vec<String>,
+    pub expiry_handler: Option<Box<FnMut(ExpiredMessage)>>,
+    pub stats: WriterStats
+}
+
+impl QueuedMessage {
+    fn is_expired(&self, now: Instant) -> bool {
+        match self.ttl {
+            Some(ttl) => now.duration_since(self.queued_at) > ttl,
+            None => false
+        }
+    }
+}
+
+impl WriterStats {
+    fn new() -> WriterStats {
+        WriterStats {
+            frames_written: 0,
+            batches_written: 0,
+            total_latency: Duration::from_millis(0),
+            max_latency: Duration::from_millis(0),
+            coalescing_window: Duration::from_millis(0),
+            max_coalescing_window: None,
+            messages_conflated: 0,
+            messages_expired: 0
+        }
+    }
+
+    /// The average number of messages written at a time
+    pub fn average_batch_size(&self) -> f64 {
+        if self.batches_written == 0 {
+            0.0
+        } else {
+            self.frames_written as f64 / self.batches_written as f64
+        }
+    }
+
+    /// The average time messages spent in the queue before being written
+    pub fn average_latency(&self) -> Duration {
+        if self.frames_written == 0 {
+            Duration::from_millis(0)
+        } else {
+            self.total_latency / self.frames_written as u32
+        }
+    }
+}
+
+impl OutboundQueue {
+    pub fn new() -> OutboundQueue {
+        OutboundQueue {
+            messages: VecDeque::new(),
+            write_scheduled: false,
+            last_write: None,
+            ordering: PublishOrdering::BestEffort,
+            sequencer: Sequencer::new(),
+            ttl: None,
+            conflated_topics: Vec::new(),
+            expiry_handler: None,
+            stats: WriterStats::new()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    fn is_conflated(&self, topic: &str) -> bool {
+        self.conflated_topics.iter().any(|prefix| under_prefix(topic, prefix))
+    }
+
+    /// Finds a publication to the same topic that is still waiting to be written
+    fn queued_publication(&self, topic: &str) -> Option<usize> {
+        self.messages.iter().position(|queued| match queued.message {
+            Message::Publish(_, _, ref queued_topic, _, _) => queued_topic.uri == topic,
+            _ => false
+        })
+    }
+
+    /// Adjusts the coalescing window when a message arrives at an empty queue.
+    fn tune_window(&mut self, now: Instant) {
+        let max_window = match self.stats.max_coalescing_window {
+            Some(max_window) => max_window,
+            None => return
+        };
+        let since_last_write = match self.last_write {
+            Some(last_write) => now.duration_since(last_write),
+            None => return
+        };
+        let window = self.stats.coalescing_window;
+        if since_last_write <= window + Duration::from_millis(1) {
+            // Messages are arriving back to back, so waiting a little longer would batch them
+            self.stats.coalescing_window = ::std::cmp::min(window + Duration::from_millis(1), max_window);
+        } else if since_last_write > max_window {
+            self.stats.coalescing_window = Duration::from_millis(0);
+        }
+    }
+}
+
+impl ConnectionInfo {
+    /// Adds a message to the outbound queue, and makes sure the queue will be written out on the
+    /// websocket event loop.
+    ///
+    /// Only publications and calls are subject to the queue's time to live.
+    pub fn queue_message(&mut self, message: Message) -> WampResult<()> {
+        self.enqueue(message, None)
+    }
+
+    /// Adds a message that has already been encoded to the outbound queue, like `queue_message`
+    pub fn queue_encoded(&mut self, message: Message, frame: Frame) -> WampResult<()> {
+        self.enqueue(message, Some(frame))
+    }
+
+    /// Gives out the request ID for a publication to `topic`.  With `PublishOrdering::PerTopic`,
+    /// the publication must then be passed to `submit_publication` or `abandon_publication`.
+    pub fn reserve_publication(&mut self, topic: &URI) -> ID {
+        self.max_request_id += 1;
+        let request_id = self.max_request_id;
+        if self.outbound.ordering == PublishOrdering::PerTopic {
+            self.outbound.sequencer.reserve(&topic.uri, request_id);
+        }
+        request_id
+    }
+
+    /// Queues a publication made with `reserve_publication`, along with the frame it was
+    /// already encoded into if there is one.  The publication is held back while publications
+    /// made before it to the same topic haven't been queued.
+    pub fn submit_publication(&mut self, message: Message, frame: Option<Frame>) -> WampResult<()> {
+        let (request_id, topic) = match message {
+            Message::Publish(request_id, _, ref topic, _, _) => (request_id, topic.uri.to_string()),
+            _ => return self.enqueue(message, frame)
+        };
+        let ready = self.outbound.sequencer.arrive(&topic, request_id, (message, frame));
+        self.queue_in_order(ready, request_id)
+    }
+
+    /// Forgets a publication made with `reserve_publication` that won't be submitted
+    pub fn abandon_publication(&mut self, topic: &URI, request_id: ID) {
+        let ready = self.outbound.sequencer.abandon(&topic.uri, request_id);
+        self.queue_in_order(ready, 0).ok();
+    }
+
+    // Returns the result of queueing the publication with `request_id`.  Other publications
+    // were submitted by calls that have returned already, so their failures are only logged.
+    fn queue_in_order(&mut self, publications: Vec<(Message, Option<Frame>)>, request_id: ID) -> WampResult<()> {
+        let mut result = Ok(());
+        for (message, frame) in publications {
+            let released = match message {
+                Message::Publish(released, ..) => released,
+                _ => 0
+            };
+            let queued = match frame {
+                Some(frame) => self.queue_encoded(message, frame),
+                None => self.queue_publication(message)
+            };
+            if released == request_id {
+                result = queued;
+            } else if let Err(e) = queued {
+                warn!("Could not queue publication {} after holding it back: {}", released, e);
+            }
+        }
+        result
+    }
+
+    fn enqueue(&mut self, message: Message, frame: Option<Frame>) -> WampResult<()> {
+        let ttl = match message {
+            Message::Publish(..) | Message::Call(..) => self.outbound.ttl,
+            _ => None
+        };
+        let now = Instant::now();
+        if let Message::Publish(_, _, ref topic, _, _) = message {
+            if self.outbound.is_conflated(&topic.uri) {
+                if let Some(index) = self.outbound.queued_publication(&topic.uri) {
+                    let replaced = ::std::mem::replace(&mut self.outbound.messages[index], QueuedMessage {
+                        message: message,
+                        frame: frame,
+                        queued_at: now,
+                        ttl: ttl
+                    });
+                    self.conflate(replaced);
+                    return Ok(());
+                }
+            }
+        }
+        self.outbound.messages.push_back(QueuedMessage {
+            message: message,
+            frame: frame,
+            queued_at: now,
+            ttl: ttl
+        });
+        if !self.outbound.write_scheduled {
+            self.outbound.tune_window(now);
+            let window = as_millis(self.outbound.stats.coalescing_window);
+            if let Err(e) = self.sender.timeout(window, WRITE_QUEUE) {
+                // Nothing would write the message, so callers can forget the request
+                self.outbound.messages.pop_back();
+                return Err(Error::new(ErrorKind::WSError(e)));
+            }
+            self.outbound.write_scheduled = true;
+        }
+        Ok(())
+    }
+
+    /// Writes every queued message to the websocket, dropping any that have expired.
+    pub fn write_queue(&mut self) -> WampResult<()> {
+        self.outbound.write_scheduled = false;
+        let now = Instant::now();
+        let mut frames = 0;
+        while let Some(queued) = self.outbound.messages.pop_front() {
+            if queued.is_expired(now) {
+                self.expire_message(queued, now);
+            } else {
+                let latency = now.duration_since(queued.queued_at);
+                try!(match queued.frame {
+                    Some(frame) => self.send_encoded(&queued.message, frame),
+                    None => self.send_message(queued.message)
+                });
+                frames += 1;
+                let stats = &mut self.outbound.stats;
+                stats.total_latency += latency;
+                if latency > stats.max_latency {
+                    stats.max_latency = latency;
+                }
+            }
+        }
+        if frames > 0 {
+            self.outbound.stats.frames_written += frames;
+            self.outbound.stats.batches_written += 1;
+        }
+        self.outbound.last_write = Some(now);
+        Ok(())
+    }
+
+    fn conflate(&mut self, replaced: QueuedMessage) {
+        if let Message::Publish(request_id, _, ref topic, _, _) = replaced.message {
+            debug!("Conflating publication {} to {}", request_id, topic.uri);
+            if let Some(promise) = self.publish_requests.remove(&request_id) {
+                promise.fail(CallError::new(Reason::Cancelled, None, None));
+            }
+        }
+        self.outbound.stats.messages_conflated += 1;
+    }
+
+    fn expire_message(&mut self, queued: QueuedMessage, now: Instant) {
+        let age = now.duration_since(queued.queued_at);
+        self.outbound.stats.messages_expired += 1;
+        let (request_id, uri) = match queued.message {
+            Message::Publish(request_id, _, topic, _, _) => {
+                if let Some(promise) = self.publish_requests.remove(&request_id) {
+                    promise.fail(CallError::new(Reason::Timeout, None, None));
+                }
+                (request_id, topic)
+            },
+            Message::Call(request_id, _, procedure, _, _) => {
+                if let Some(promise) = self.call_requests.remove(&request_id) {
+                    promise.fail(CallError::new(Reason::Timeout, None, None));
+                }
+                (request_id, procedure)
+            },
+            _ => return
+        };
+        warn!("Dropping message to {} after it spent {:?} in the outbound queue", uri.uri, age);
+        if let Some(ref mut handler) = self.outbound.expiry_handler {
+            handler(ExpiredMessage {
+                request_id: request_id,
+                uri: uri,
+                age: age
+            });
+        }
+    }
+}
+
+impl ConnectionInfo {
+    // The messages waiting to be written, including publications held back by rate limits or
+    // for earlier publications
+    fn unwritten(&self) -> usize {
+        self.outbound.len() + self.rate_limiter.held_count() + self.outbound.sequencer.parked_count()
+    }
+
+    fn dropped_from_queue(&self) -> u64 {
+        self.outbound.stats.messages_expired + self.outbound.stats.messages_conflated
+    }
+}
+
+impl Client {
+    /// Blocks until every message queued so far has been written to the websocket, and the
+    /// router has acknowledged every acknowledged publication waiting for it, or until `timeout`
+    /// passes.  Publications held back by rate limits are waited for too.
+    ///
+    /// Nothing is dropped when the timeout passes, so messages that weren't written in time may
+    /// still be written later.
+    pub fn flush(&mut self, timeout: Duration) -> WampResult<FlushSummary> {
+        let (unwritten, dropped, acknowledgements) = {
+            let info = self.connection_info.lock().unwrap();
+            let acknowledgements: Vec<ID> = info.publish_requests.keys().cloned().collect();
+            (info.unwritten(), info.dropped_from_queue(), acknowledgements)
+        };
+        debug!("Flushing {} messages and {} acknowledgements", unwritten, acknowledgements.len());
+        wait_until(&self.connection_info, timeout, |info| {
+            info.unwritten() == 0 && acknowledgements.iter().all(|id| !info.publish_requests.contains_key(id))
+        });
+        let info = self.connection_info.lock().unwrap();
+        let dropped = (info.dropped_from_queue() - dropped) as usize;
+        let abandoned = (info.unwritten() + dropped).min(unwritten);
+        Ok(FlushSummary {
+            flushed: unwritten - abandoned,
+            abandoned: abandoned,
+            unacknowledged: acknowledgements.iter().filter(|id| info.publish_requests.contains_key(id)).count()
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{OutboundQueue, QueuedMessage, Sequencer};
+    #[cfg(feature = "publisher")]
+    use client::{Overflow, RateLimit};
+    use messages::{Message, PublishOptions, URI};
+    #[cfg(feature = "publisher")]
+    use serde_json;
+    use std::time::{Duration, Instant};
+    #[cfg(feature = "publisher")]
+    use testing::{ScriptedRouter, join};
+    #[cfg(feature = "publisher")]
+    use ::{Value, ID};
+
+    #[test]
+    fn finding_conflated_publications() {
+        let mut queue = OutboundQueue::new();
+        queue.conflated_topics.push("ca.test.prices".to_string());
+        for &(request_id, topic) in [(1, "ca.test.prices.abc"), (2, "ca.test.volume")].iter() {
+            queue.messages.push_back(QueuedMessage {
+                message: Message::Publish(request_id, PublishOptions::new(false), URI::new(topic), None, None),
+                frame: None,
+                queued_at: Instant::now(),
+                ttl: None
+            });
+        }
+        assert!(queue.is_conflated("ca.test.prices.abc"));
+        assert!(!queue.is_conflated("ca.test.volume"));
+        assert_eq!(queue.queued_publication("ca.test.prices.abc"), Some(0));
+        assert_eq!(queue.queued_publication("ca.test.volume"), Some(1));
+        assert_eq!(queue.queued_publication("ca.test.prices.xyz"), None);
+    }
+
+    #[test]
+    fn coalescing_window_tuning() {
+        let mut queue = OutboundQueue::new();
+        let start = Instant::now();
+        queue.stats.max_coalescing_window = Some(Duration::from_millis(2));
+        for _ in 0..5 {
+            queue.last_write = Some(start);
+            queue.tune_window(start);
+        }
+        assert_eq!(queue.stats.coalescing_window, Duration::from_millis(2));
+
+        queue.last_write = Some(start);
+        queue.tune_window(start + Duration::from_millis(50));
+        assert_eq!(queue.stats.coalescing_window, Duration::from_millis(0));
+
+        queue.stats.max_coalescing_window = None;
+        queue.tune_window(start);
+        assert_eq!(queue.stats.coalescing_window, Duration::from_millis(0));
+    }
+
+    #[test]
+    fn sequencing_publications() {
+        let mut sequencer = Sequencer::new();
+        for request_id in 1..5 {
+            sequencer.reserve("ca.test.a", request_id);
+        }
+        sequencer.reserve("ca.test.b", 5);
+
+        // Publications that overtook an earlier one wait for it
+        assert_eq!(sequencer.arrive("ca.test.a", 2, "a2"), Vec::<&str>::new());
+        assert_eq!(sequencer.arrive("ca.test.a", 4, "a4"), Vec::<&str>::new());
+        assert_eq!(sequencer.arrive("ca.test.b", 5, "b5"), vec!["b5"]);
+        assert_eq!(sequencer.parked_count(), 2);
+        assert_eq!(sequencer.arrive("ca.test.a", 1, "a1"), vec!["a1", "a2"]);
+        assert_eq!(sequencer.abandon("ca.test.a", 3), vec!["a4"]);
+        assert_eq!(sequencer.parked_count(), 0);
+
+        // Publications made without a reservation aren't held back
+        sequencer.reserve("ca.test.a", 6);
+        assert_eq!(sequencer.arrive("ca.test.a", 7, "a7"), vec!["a7"]);
+    }
+
+    // Reads the client's next PUBLISH, and returns its request ID and arguments
+    #[cfg(feature = "publisher")]
+    fn publication(router: &ScriptedRouter) -> (ID, Option<Vec<Value>>) {
+        match serde_json::from_str(&router.next()).unwrap() {
+            Message::Publish(request_id, _, _, args, _) => (request_id, args),
+            message => panic!("Unexpected message {:?}", message)
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "publisher")]
+    fn flushing_waits_for_queued_messages() {
+        let router = ScriptedRouter::start();
+        let mut client = join(&router.url);
+        // The first publication goes straight out, and the rest are held for 50ms each
+        client.set_rate_limit("ca.test", Some(RateLimit::new(20.0, 1).with_overflow(Overflow::Queue)));
+        for i in 0..5 {
+            client.publish(URI::new("ca.test.readings"), Some(vec![Value::Integer(i)]), None).unwrap();
+        }
+        assert!(client.connection_info.lock().unwrap().unwritten() > 0);
+        let started = Instant::now();
+        let summary = client.flush(Duration::from_secs(5)).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(150));
+        assert_eq!((summary.abandoned, summary.unacknowledged), (0, 0));
+        assert!(summary.flushed >= 4);
+        assert_eq!(client.connection_info.lock().unwrap().unwritten(), 0);
+        for i in 0..5 {
+            assert_eq!(publication(&router).1, Some(vec![Value::Integer(i)]));
+        }
+
+        // Acknowledged publications are waited for until the router acknowledges them
+        let acknowledged = client.publish_and_acknowledge(URI::new("ca.other"), None, None).unwrap();
+        let (request_id, _) = publication(&router);
+        assert_eq!(client.flush(Duration::from_millis(100)).unwrap().unacknowledged, 1);
+        router.send(&Message::Published(request_id, 9));
+        let summary = client.flush(Duration::from_secs(5)).unwrap();
+        assert_eq!((summary.flushed, summary.abandoned, summary.unacknowledged), (0, 0, 0));
+        assert_eq!(acknowledged.wait().unwrap(), 9);
+    }
+}
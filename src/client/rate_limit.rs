@@ -0,0 +1,316 @@
+//! Contains the `RateLimit` struct, which limits how quickly the client publishes to a topic.
+//!
+//! Limits are configured for URI prefixes, and each topic under a prefix gets its own token
+//! bucket.  A bucket holds up to `burst` tokens and refills at a steady rate; every publication
+//! takes one token, and a publication that finds its bucket empty is handled according to the
+//! limit's `Overflow` policy.
+use super::{ConnectionInfo, RATE_LIMIT};
+use messages::{Message, Reason};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use utils::{as_millis, under_prefix};
+use ::{WampResult, Error, ErrorKind, CallError};
+
+/// What happens to a publication that exceeds its topic's rate limit
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Overflow {
+    /// Only the most recent excess publication to the topic is kept, and it is sent as soon as
+    /// a token is available.  This suits values like sensor readings, where only the latest one
+    /// matters.
+    #[serde(rename="coalesce")]
+    Coalesce,
+    /// Excess publications are held, in order, until tokens are available
+    #[serde(rename="queue")]
+    Queue,
+    /// Excess publications fail with `ErrorKind::RateLimited`
+    #[serde(rename="reject")]
+    Reject
+}
+
+/// A token bucket rate limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    per_second: f64,
+    burst: u32,
+    overflow: Overflow
+}
+
+struct Bucket {
+    limit: RateLimit,
+    tokens: f64,
+    refilled_at: Instant,
+    held: VecDeque<Message>
+}
+
+/// The result of asking the rate limiter whether a publication may be sent
+pub enum Admission {
+    Send(Message),
+    Held,
+    /// The publication was held in place of an older one, which will never be sent
+    Replaced(Message),
+    Rejected(Message)
+}
+
+pub struct RateLimiter {
+    limits: Vec<(String, RateLimit)>,
+    buckets: HashMap<String, Bucket>,
+    pub release_scheduled: bool
+}
+
+impl RateLimit {
+    /// Allows `per_second` publications a second to each topic, with bursts of up to `burst`
+    /// publications.  Excess publications are rejected.
+    pub fn new(per_second: f64, burst: u32) -> RateLimit {
+        RateLimit {
+            per_second: per_second,
+            burst: ::std::cmp::max(burst, 1),
+            overflow: Overflow::Reject
+        }
+    }
+
+    pub fn with_overflow(mut self, overflow: Overflow) -> RateLimit {
+        self.overflow = overflow;
+        self
+    }
+}
+
+impl Bucket {
+    fn new(limit: RateLimit, now: Instant) -> Bucket {
+        Bucket {
+            limit: limit,
+            tokens: limit.burst as f64,
+            refilled_at: now,
+            held: VecDeque::new()
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.refilled_at);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        self.tokens = (self.tokens + elapsed * self.limit.per_second).min(self.limit.burst as f64);
+        self.refilled_at = now;
+    }
+
+    fn take(&mut self) -> bool {
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// How long until the bucket has a token for the next held publication
+    fn wait(&self) -> Option<Duration> {
+        if self.held.is_empty() {
+            return None;
+        }
+        let seconds = (1.0 - self.tokens).max(0.0) / self.limit.per_second;
+        Some(Duration::from_millis((seconds * 1000.0).ceil() as u64))
+    }
+}
+
+fn topic_of(message: &Message) -> &str {
+    match *message {
+        Message::Publish(_, _, ref topic, _, _) => &topic.uri,
+        _ => ""
+    }
+}
+
+impl RateLimiter {
+    pub fn new() -> RateLimiter {
+        RateLimiter {
+            limits: Vec::new(),
+            buckets: HashMap::new(),
+            release_scheduled: false
+        }
+    }
+
+    /// Sets the limit for topics under `prefix`, or removes it if `limit` is `None`.  Topics
+    /// that are no longer limited still send the publications they were holding, at their old
+    /// rate.
+    pub fn set_limit(&mut self, prefix: &str, limit: Option<RateLimit>) {
+        self.limits.retain(|&(ref existing, _)| existing != prefix);
+        if let Some(limit) = limit {
+            self.limits.push((prefix.to_string(), limit));
+        }
+        let limits = &self.limits;
+        for (topic, bucket) in self.buckets.iter_mut() {
+            if let Some(limit) = limit_for(limits, topic) {
+                bucket.limit = limit;
+                bucket.tokens = bucket.tokens.min(limit.burst as f64);
+            }
+        }
+        self.buckets.retain(|topic, bucket| limit_for(limits, topic).is_some() || !bucket.held.is_empty());
+    }
+
+    /// Whether publications to the topic are subject to a rate limit
+    pub fn is_limited(&self, topic: &str) -> bool {
+        limit_for(&self.limits, topic).is_some()
+    }
+
+    pub fn admit(&mut self, message: Message, now: Instant) -> Admission {
+        let limit = match limit_for(&self.limits, topic_of(&message)) {
+            Some(limit) => limit,
+            None => return Admission::Send(message)
+        };
+        let bucket = self.buckets.entry(topic_of(&message).to_string()).or_insert_with(|| Bucket::new(limit, now));
+        bucket.refill(now);
+        if bucket.held.is_empty() && bucket.take() {
+            return Admission::Send(message);
+        }
+        match limit.overflow {
+            Overflow::Reject => Admission::Rejected(message),
+            Overflow::Queue => {
+                bucket.held.push_back(message);
+                Admission::Held
+            },
+            Overflow::Coalesce => {
+                let replaced = bucket.held.pop_front();
+                bucket.held.push_back(message);
+                match replaced {
+                    Some(replaced) => Admission::Replaced(replaced),
+                    None => Admission::Held
+                }
+            }
+        }
+    }
+
+    /// Takes every held publication that may now be sent
+    pub fn release(&mut self, now: Instant) -> Vec<Message> {
+        let mut released = Vec::new();
+        for bucket in self.buckets.values_mut() {
+            bucket.refill(now);
+            while !bucket.held.is_empty() && bucket.take() {
+                released.extend(bucket.held.pop_front());
+            }
+        }
+        released
+    }
+
+    /// Takes every held publication, whether or not it may be sent yet
+    pub fn take_held(&mut self) -> Vec<Message> {
+        self.buckets.values_mut().flat_map(|bucket| bucket.held.drain(..)).collect()
+    }
+
+    /// How many publications are held
+    pub fn held_count(&self) -> usize {
+        self.buckets.values().map(|bucket| bucket.held.len()).sum()
+    }
+
+    /// How long until the next held publication may be sent, if any are held
+    pub fn next_release(&self) -> Option<Duration> {
+        self.buckets.values().filter_map(Bucket::wait).min()
+    }
+}
+
+/// Finds the limit for the longest prefix that matches the topic.  Like an allow list, a prefix
+/// matches the topic itself and any topic below it.
+fn limit_for(limits: &[(String, RateLimit)], topic: &str) -> Option<RateLimit> {
+    limits.iter().filter(|&&(ref prefix, _)| under_prefix(topic, prefix)).max_by_key(|&&(ref prefix, _)| prefix.len()).map(|&(_, limit)| limit)
+}
+
+impl ConnectionInfo {
+    /// Queues a publication, subject to any rate limit on its topic.
+    pub fn queue_publication(&mut self, message: Message) -> WampResult<()> {
+        match self.rate_limiter.admit(message, Instant::now()) {
+            Admission::Send(message) => self.queue_message(message),
+            Admission::Held => self.schedule_release(),
+            Admission::Replaced(message) => {
+                if let Message::Publish(request_id, _, ref topic, _, _) = message {
+                    debug!("Coalescing publication {} to {}", request_id, topic.uri);
+                    if let Some(promise) = self.publish_requests.remove(&request_id) {
+                        promise.fail(CallError::new(Reason::Cancelled, None, None));
+                    }
+                }
+                self.schedule_release()
+            },
+            Admission::Rejected(message) => {
+                if let Message::Publish(request_id, _, topic, _, _) = message {
+                    self.publish_requests.remove(&request_id);
+                    return Err(Error::new(ErrorKind::RateLimited(topic.uri.to_string())));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Moves held publications whose tokens have arrived into the outbound queue.
+    pub fn release_publications(&mut self) -> WampResult<()> {
+        self.rate_limiter.release_scheduled = false;
+        for message in self.rate_limiter.release(Instant::now()) {
+            try!(self.queue_message(message));
+        }
+        self.schedule_release()
+    }
+
+    fn schedule_release(&mut self) -> WampResult<()> {
+        if self.rate_limiter.release_scheduled {
+            return Ok(());
+        }
+        if let Some(wait) = self.rate_limiter.next_release() {
+            try!(self.sender.timeout(::std::cmp::max(as_millis(wait), 1), RATE_LIMIT).map_err(|e| Error::new(ErrorKind::WSError(e))));
+            self.rate_limiter.release_scheduled = true;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RateLimiter, RateLimit, Overflow, Admission};
+    use messages::{Message, PublishOptions, URI};
+    use std::time::{Duration, Instant};
+
+    fn publication(request_id: u64, topic: &str) -> Message {
+        Message::Publish(request_id, PublishOptions::new(false), URI::new(topic), None, None)
+    }
+
+    fn outcome(admission: Admission) -> String {
+        match admission {
+            Admission::Send(_) => "send".to_string(),
+            Admission::Held => "held".to_string(),
+            Admission::Replaced(message) => format!("replaced {}", request_ids(vec![message])[0]),
+            Admission::Rejected(_) => "rejected".to_string()
+        }
+    }
+
+    fn request_ids(messages: Vec<Message>) -> Vec<u64> {
+        messages.into_iter().map(|message| match message {
+            Message::Publish(request_id, ..) => request_id,
+            _ => 0
+        }).collect()
+    }
+
+    #[test]
+    fn token_buckets() {
+        let mut limiter = RateLimiter::new();
+        let start = Instant::now();
+        limiter.set_limit("ca.test.sensor", Some(RateLimit::new(10.0, 2)));
+        limiter.set_limit("ca.test.sensor.log", Some(RateLimit::new(10.0, 1).with_overflow(Overflow::Queue)));
+        limiter.set_limit("ca.test.sensor.temperature", Some(RateLimit::new(10.0, 1).with_overflow(Overflow::Coalesce)));
+
+        assert_eq!(outcome(limiter.admit(publication(1, "ca.test.sensors"), start)), "send");
+        assert_eq!(outcome(limiter.admit(publication(2, "ca.test.sensor.humidity"), start)), "send");
+        assert_eq!(outcome(limiter.admit(publication(3, "ca.test.sensor.humidity"), start)), "send");
+        assert_eq!(outcome(limiter.admit(publication(4, "ca.test.sensor.humidity"), start)), "rejected");
+        // Other topics under the prefix have their own buckets
+        assert_eq!(outcome(limiter.admit(publication(5, "ca.test.sensor.pressure"), start)), "send");
+
+        assert_eq!(outcome(limiter.admit(publication(6, "ca.test.sensor.log"), start)), "send");
+        assert_eq!(outcome(limiter.admit(publication(7, "ca.test.sensor.log"), start)), "held");
+        assert_eq!(outcome(limiter.admit(publication(8, "ca.test.sensor.log"), start)), "held");
+
+        assert_eq!(outcome(limiter.admit(publication(9, "ca.test.sensor.temperature"), start)), "send");
+        assert_eq!(outcome(limiter.admit(publication(10, "ca.test.sensor.temperature"), start)), "held");
+        assert_eq!(outcome(limiter.admit(publication(11, "ca.test.sensor.temperature"), start)), "replaced 10");
+
+        assert_eq!(limiter.next_release(), Some(Duration::from_millis(100)));
+        assert!(limiter.release(start).is_empty());
+        let mut released = request_ids(limiter.release(start + Duration::from_millis(100)));
+        released.sort();
+        assert_eq!(released, vec![7, 11]);
+        assert_eq!(request_ids(limiter.release(start + Duration::from_millis(200))), vec![8]);
+        assert_eq!(limiter.next_release(), None);
+    }
+}
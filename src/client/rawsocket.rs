@@ -0,0 +1,364 @@
+//! Contains the client side of the WAMP raw socket transport, which reaches routers over plain
+//! TCP instead of a websocket.  Connections use it for `tcp://host:port` URLs.
+//!
+//! A raw socket starts with a four octet handshake, in which the client asks for a serializer
+//! and each side says how long the messages it accepts may be, and then carries every message
+//! in a frame with a four octet header giving its type and length.  The client asks for the
+//! first of its serializers that raw sockets support, which are JSON, MsgPack and CBOR, and
+//! tries the next if the router refuses it.  Custom codecs can't be used.
+//!
+//! Like the long-poll transport, the raw socket is bridged to a websocket server on a loopback
+//! port for the one connection, which has to ask for the bridge's random path.  The bridge
+//! answers the router's pings itself, so the client's own pings only reach the bridge.  A
+//! message longer than the router accepts closes the connection, as does one longer than the
+//! client accepts.
+use super::transport::{self, Transport};
+use serializer::{WAMP_JSON, WAMP_MSGPACK, WAMP_CBOR};
+use std::cmp;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use url::Url;
+use ws::{CloseCode, Factory, Handler, Handshake, Message as WSMessage, Request, Response, Result as WSResult, Sender};
+use ::{WampResult, Error, ErrorKind};
+
+const MAGIC: u8 = 0x7f;
+
+// The frame types, in the low three bits of a frame header's first octet
+const REGULAR: u8 = 0;
+const PING: u8 = 1;
+const PONG: u8 = 2;
+
+// Lengths are given as exponents, for lengths from 2^9 to 2^24 octets
+const MIN_LENGTH_EXPONENT: u32 = 9;
+const MAX_LENGTH_EXPONENT: u32 = 24;
+
+/// The raw socket transport.  By default the client accepts messages of up to 16 MiB, the most
+/// raw sockets allow.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RawSocketTransport {
+    max_length_exponent: u32,
+    handshake_timeout: Duration
+}
+
+/// Why the router refused a handshake
+#[derive(Debug, PartialEq)]
+enum Refusal {
+    SerializerUnsupported,
+    Other(String)
+}
+
+impl RawSocketTransport {
+    pub fn new() -> RawSocketTransport {
+        RawSocketTransport {
+            max_length_exponent: MAX_LENGTH_EXPONENT,
+            handshake_timeout: Duration::from_secs(5)
+        }
+    }
+
+    /// Sets the longest message the client accepts, which is rounded up to a power of two
+    /// between 512 octets and 16 MiB
+    pub fn with_max_length(mut self, max_length: usize) -> RawSocketTransport {
+        let mut exponent = MIN_LENGTH_EXPONENT;
+        while exponent < MAX_LENGTH_EXPONENT && (1 << exponent) < max_length {
+            exponent += 1;
+        }
+        self.max_length_exponent = exponent;
+        self
+    }
+
+    /// Sets how long connecting and the handshake may take, for each serializer tried
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> RawSocketTransport {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Connects to `address` and asks for `serializer`.  Returns the socket and the longest
+    /// message the router accepts.
+    fn handshake(&self, address: &str, serializer: u8) -> Result<(TcpStream, usize), Refusal> {
+        let addresses = try!(address.to_socket_addrs().map_err(|e| Refusal::Other(e.to_string())));
+        let mut last_error = format!("{} has no addresses", address);
+        for socket_address in addresses {
+            let stream = match TcpStream::connect_timeout(&socket_address, self.handshake_timeout) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    last_error = e.to_string();
+                    continue;
+                }
+            };
+            try!(stream.set_read_timeout(Some(self.handshake_timeout)).map_err(|e| Refusal::Other(e.to_string())));
+            let mut reply = [0; 4];
+            let exchange = (&stream).write_all(&handshake_request(self.max_length_exponent, serializer)).and_then(|_| (&stream).read_exact(&mut reply));
+            try!(exchange.map_err(|e| Refusal::Other(format!("the handshake failed: {}", e))));
+            let max_length = try!(parse_handshake_reply(reply, serializer));
+            try!(stream.set_read_timeout(None).map_err(|e| Refusal::Other(e.to_string())));
+            return Ok((stream, max_length));
+        }
+        Err(Refusal::Other(last_error))
+    }
+}
+
+impl Default for RawSocketTransport {
+    fn default() -> RawSocketTransport {
+        RawSocketTransport::new()
+    }
+}
+
+impl Transport for RawSocketTransport {
+    fn open(&self, url: &str, protocols: &[String]) -> WampResult<String> {
+        let parsed = try!(Url::parse(url).map_err(|e| Error::new(ErrorKind::URLError(e))));
+        let address = match (parsed.host_str(), parsed.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            _ => return Err(Error::new(ErrorKind::InvalidState("Raw socket URLs need a host and a port")))
+        };
+        for protocol in protocols {
+            let serializer = match serializer_id(protocol) {
+                Some(serializer) => serializer,
+                None => continue
+            };
+            match self.handshake(&address, serializer) {
+                Ok((stream, router_max_length)) => {
+                    info!("Opened a raw socket to {} using {}", address, protocol);
+                    let path = try!(transport::bridge_path().map_err(|e| Error::new(ErrorKind::TransportError(e))));
+                    let bridge = BridgeFactory {
+                        stream: Arc::new(Mutex::new(stream)),
+                        protocol: protocol.clone(),
+                        max_length: 1 << self.max_length_exponent,
+                        router_max_length: router_max_length,
+                        path: path.clone(),
+                        claimed: Arc::new(AtomicBool::new(false))
+                    };
+                    return transport::start_bridge("Raw socket", &path, bridge).map_err(|e| Error::new(ErrorKind::TransportError(e)));
+                },
+                Err(Refusal::SerializerUnsupported) => debug!("The router at {} doesn't support {} over raw sockets", address, protocol),
+                Err(Refusal::Other(e)) => return Err(Error::new(ErrorKind::TransportError(format!("Could not open a raw socket to {}: {}", address, e))))
+            }
+        }
+        Err(Error::new(ErrorKind::TransportError(format!("The router at {} doesn't support any of the client's serializers over raw sockets", address))))
+    }
+}
+
+fn serializer_id(protocol: &str) -> Option<u8> {
+    match protocol {
+        WAMP_JSON => Some(1),
+        WAMP_MSGPACK => Some(2),
+        WAMP_CBOR => Some(3),
+        _ => None
+    }
+}
+
+fn handshake_request(max_length_exponent: u32, serializer: u8) -> [u8; 4] {
+    [MAGIC, ((max_length_exponent - MIN_LENGTH_EXPONENT) as u8) << 4 | serializer, 0, 0]
+}
+
+/// Reads the router's reply to the handshake, returning the longest message it accepts
+fn parse_handshake_reply(reply: [u8; 4], serializer: u8) -> Result<usize, Refusal> {
+    if reply[0] != MAGIC {
+        return Err(Refusal::Other("the router didn't answer with a raw socket handshake".to_string()));
+    }
+    match (reply[1] >> 4, reply[1] & 0x0f) {
+        (1, 0) => Err(Refusal::SerializerUnsupported),
+        (2, 0) => Err(Refusal::Other("the router refused the maximum message length".to_string())),
+        (3, 0) => Err(Refusal::Other("the router refused the use of reserved bits".to_string())),
+        (4, 0) => Err(Refusal::Other("the router has reached its maximum number of connections".to_string())),
+        (error, 0) => Err(Refusal::Other(format!("the router refused the handshake with error {}", error))),
+        (exponent, chosen) if chosen == serializer => Ok(1 << (exponent as u32 + MIN_LENGTH_EXPONENT)),
+        (_, chosen) => Err(Refusal::Other(format!("the router chose serializer {} instead of {}", chosen, serializer)))
+    }
+}
+
+fn frame_header(frame_type: u8, length: usize) -> [u8; 4] {
+    [frame_type, (length >> 16) as u8, (length >> 8) as u8, length as u8]
+}
+
+/// The type and length of the frame with this header, or `None` if it uses reserved bits
+fn parse_frame_header(header: [u8; 4]) -> Option<(u8, usize)> {
+    if header[0] & 0xf8 != 0 {
+        return None;
+    }
+    Some((header[0], (header[1] as usize) << 16 | (header[2] as usize) << 8 | header[3] as usize))
+}
+
+fn write_frame(stream: &Mutex<TcpStream>, frame_type: u8, payload: &[u8]) -> ::std::io::Result<()> {
+    let mut stream = stream.lock().unwrap();
+    try!(stream.write_all(&frame_header(frame_type, payload.len())));
+    stream.write_all(payload)
+}
+
+struct BridgeFactory {
+    stream: Arc<Mutex<TcpStream>>,
+    protocol: String,
+    max_length: usize,
+    router_max_length: usize,
+    path: String,
+    // Set once a connection has been bridged, since the socket can't be shared
+    claimed: Arc<AtomicBool>
+}
+
+impl Factory for BridgeFactory {
+    type Handler = BridgeHandler;
+
+    fn connection_made(&mut self, out: Sender) -> BridgeHandler {
+        BridgeHandler {
+            out: out,
+            stream: self.stream.clone(),
+            protocol: self.protocol.clone(),
+            max_length: self.max_length,
+            router_max_length: self.router_max_length,
+            path: self.path.clone(),
+            claimed: self.claimed.clone(),
+            bridged: false,
+            closed: Arc::new(AtomicBool::new(false))
+        }
+    }
+
+    fn connection_lost(&mut self, handler: BridgeHandler) {
+        // As with long-poll, the bridge only stops once the bridged connection is gone
+        if handler.bridged {
+            handler.out.shutdown().ok();
+        }
+    }
+}
+
+struct BridgeHandler {
+    out: Sender,
+    stream: Arc<Mutex<TcpStream>>,
+    protocol: String,
+    max_length: usize,
+    router_max_length: usize,
+    path: String,
+    claimed: Arc<AtomicBool>,
+    bridged: bool,
+    closed: Arc<AtomicBool>
+}
+
+impl BridgeHandler {
+    /// Relays the router's messages to the client until the socket closes
+    fn relay(out: Sender, stream: Arc<Mutex<TcpStream>>, mut reader: TcpStream, text: bool, max_length: usize, closed: Arc<AtomicBool>) {
+        loop {
+            let mut header = [0; 4];
+            if let Err(e) = reader.read_exact(&mut header) {
+                if !closed.load(Ordering::SeqCst) {
+                    info!("The raw socket closed: {}", e);
+                    out.close(CloseCode::Away).ok();
+                }
+                return;
+            }
+            let (frame_type, length) = match parse_frame_header(header) {
+                Some((frame_type, length)) if length <= max_length => (frame_type, length),
+                Some((_, length)) => {
+                    warn!("The router sent a {} octet message over the raw socket, which is longer than the client accepts", length);
+                    out.close(CloseCode::Size).ok();
+                    return;
+                },
+                None => {
+                    warn!("The router sent a raw socket frame that uses reserved bits");
+                    out.close(CloseCode::Protocol).ok();
+                    return;
+                }
+            };
+            let mut payload = vec![0; length];
+            if reader.read_exact(&mut payload).is_err() {
+                out.close(CloseCode::Away).ok();
+                return;
+            }
+            let sent = match frame_type {
+                REGULAR if text => match String::from_utf8(payload) {
+                    Ok(text) => out.send(WSMessage::Text(text)).is_ok(),
+                    Err(_) => {
+                        warn!("The router sent a JSON message that isn't UTF-8 over the raw socket");
+                        out.close(CloseCode::Invalid).is_ok()
+                    }
+                },
+                REGULAR => out.send(WSMessage::Binary(payload)).is_ok(),
+                PING => write_frame(&stream, PONG, &payload).is_ok(),
+                PONG => true,
+                other => {
+                    debug!("Ignoring a raw socket frame of type {}", other);
+                    true
+                }
+            };
+            if !sent {
+                return;
+            }
+        }
+    }
+}
+
+impl Handler for BridgeHandler {
+    fn on_request(&mut self, request: &Request) -> WSResult<Response> {
+        transport::accept_bridge_request(request, &self.path, &self.protocol)
+    }
+
+    fn on_open(&mut self, _: Handshake) -> WSResult<()> {
+        if self.claimed.swap(true, Ordering::SeqCst) {
+            return self.out.close(CloseCode::Normal);
+        }
+        self.bridged = true;
+        let reader = match self.stream.lock().unwrap().try_clone() {
+            Ok(reader) => reader,
+            Err(e) => {
+                warn!("Could not read from the raw socket: {}", e);
+                return self.out.close(CloseCode::Away);
+            }
+        };
+        let out = self.out.clone();
+        let stream = self.stream.clone();
+        let text = self.protocol == WAMP_JSON;
+        let max_length = self.max_length;
+        let closed = self.closed.clone();
+        thread::spawn(move || BridgeHandler::relay(out, stream, reader, text, max_length, closed));
+        Ok(())
+    }
+
+    fn on_message(&mut self, message: WSMessage) -> WSResult<()> {
+        let payload = match message {
+            WSMessage::Text(text) => text.into_bytes(),
+            WSMessage::Binary(data) => data
+        };
+        // Frame lengths have three octets, so even a router that accepts 2^24 octets can't be
+        // sent quite that many
+        if payload.len() > cmp::min(self.router_max_length, (1 << MAX_LENGTH_EXPONENT) - 1) {
+            warn!("Closing the raw socket, since a {} octet message is longer than the router accepts", payload.len());
+            return self.out.close(CloseCode::Size);
+        }
+        if let Err(e) = write_frame(&self.stream, REGULAR, &payload) {
+            warn!("Could not send a message over the raw socket: {}", e);
+            try!(self.out.close(CloseCode::Away));
+        }
+        Ok(())
+    }
+
+    fn on_close(&mut self, _: CloseCode, _: &str) {
+        if !self.bridged {
+            return;
+        }
+        self.closed.store(true, Ordering::SeqCst);
+        self.stream.lock().unwrap().shutdown(Shutdown::Both).ok();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RawSocketTransport, Refusal, handshake_request, parse_handshake_reply, frame_header, parse_frame_header, PING};
+
+    #[test]
+    fn handshakes_and_frame_headers() {
+        // The client asks for MsgPack and accepts up to 16 MiB
+        assert_eq!(handshake_request(RawSocketTransport::new().max_length_exponent, 2), [0x7f, 0xf2, 0, 0]);
+        assert_eq!(RawSocketTransport::new().with_max_length(1000).max_length_exponent, 10);
+        assert_eq!(RawSocketTransport::new().with_max_length(1).max_length_exponent, 9);
+        assert_eq!(parse_handshake_reply([0x7f, 0x32, 0, 0], 2), Ok(4096));
+        assert_eq!(parse_handshake_reply([0x7f, 0x10, 0, 0], 2), Err(Refusal::SerializerUnsupported));
+        assert!(parse_handshake_reply([0x7f, 0x40, 0, 0], 2).is_err());
+        assert!(parse_handshake_reply([0x7f, 0x31, 0, 0], 2).is_err());
+        assert!(parse_handshake_reply([0x48, 0x54, 0x54, 0x50], 2).is_err());
+
+        assert_eq!(frame_header(PING, 0x010203), [1, 1, 2, 3]);
+        assert_eq!(parse_frame_header([1, 1, 2, 3]), Some((PING, 0x010203)));
+        assert_eq!(parse_frame_header([0x08, 0, 0, 1]), None);
+    }
+}
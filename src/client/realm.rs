@@ -0,0 +1,136 @@
+//! Contains the `RealmPolicy` struct, which says how the realm a connection joins is normalized
+//! before it is checked.
+//!
+//! Realms are checked when the `Connection` is made, so that a misconfigured realm is reported
+//! as such rather than as the router's abort once the client tries to join.  A realm must be a
+//! WAMP URI: dot separated components that are neither empty nor contain whitespace or `#`.
+use super::Connection;
+use messages::URI;
+use ::{WampResult, Error, ErrorKind};
+
+/// How a realm is normalized before it is checked.  By default it is used exactly as given.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub struct RealmPolicy {
+    trim: bool,
+    lowercase: bool
+}
+
+impl RealmPolicy {
+    pub fn new() -> RealmPolicy {
+        RealmPolicy::default()
+    }
+
+    /// Removes whitespace around the realm, such as a newline left at the end of a file
+    pub fn with_trim(mut self, trim: bool) -> RealmPolicy {
+        self.trim = trim;
+        self
+    }
+
+    /// Lowercases the realm, for routers whose realm names are all lowercase
+    pub fn with_lowercase(mut self, lowercase: bool) -> RealmPolicy {
+        self.lowercase = lowercase;
+        self
+    }
+
+    /// Normalizes `realm`, and checks that the result is a valid realm
+    pub fn apply(&self, realm: &str) -> WampResult<String> {
+        self.normalize(realm).map_err(|problem| Error::new(ErrorKind::InvalidRealm(problem)))
+    }
+
+    // Describes what is wrong with the normalized realm, if anything
+    fn normalize(&self, realm: &str) -> Result<String, String> {
+        let mut realm = if self.trim { realm.trim().to_string() } else { realm.to_string() };
+        if self.lowercase {
+            realm = realm.to_lowercase();
+        }
+        match problem_with(&realm) {
+            Ok(()) => Ok(realm),
+            Err(problem) => Err(format!("The realm {:?} {}", realm, problem))
+        }
+    }
+}
+
+fn problem_with(realm: &str) -> Result<(), &'static str> {
+    if realm.trim().is_empty() {
+        return Err("is empty");
+    }
+    if realm.chars().any(char::is_whitespace) {
+        return Err("contains whitespace");
+    }
+    if realm.contains('#') {
+        return Err("contains '#'");
+    }
+    if realm.split('.').any(str::is_empty) {
+        return Err("has an empty component");
+    }
+    Ok(())
+}
+
+impl Connection {
+    /// Makes a connection like `Connection::new`, but normalizes the realm with `policy` and
+    /// fails straight away if it isn't valid
+    pub fn try_new(url: &str, realm: &str, policy: RealmPolicy) -> WampResult<Connection> {
+        let mut connection = Connection::new(url, realm);
+        try!(connection.set_realm_policy(policy));
+        Ok(connection)
+    }
+
+    /// Normalizes the realm the connection already has with `policy`, and checks the result.
+    /// If it isn't valid, the realm is left as it was and `connect()` fails with the same
+    /// error.  Use `Connection::try_new` to have an invalid realm fail where it is given.
+    pub fn set_realm_policy(&mut self, policy: RealmPolicy) -> WampResult<()> {
+        match policy.normalize(&self.realm.uri) {
+            Ok(realm) => {
+                self.realm = URI::new(&realm);
+                self.realm_error = None;
+                Ok(())
+            },
+            Err(problem) => {
+                self.realm_error = Some(problem.clone());
+                Err(Error::new(ErrorKind::InvalidRealm(problem)))
+            }
+        }
+    }
+
+    /// Fails if the realm isn't valid
+    pub fn check_realm(&self) -> WampResult<()> {
+        match self.realm_error {
+            Some(ref problem) => Err(Error::new(ErrorKind::InvalidRealm(problem.clone()))),
+            None => Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RealmPolicy;
+    use client::Connection;
+
+    #[test]
+    fn realms_are_normalized_then_checked() {
+        assert_eq!(RealmPolicy::new().apply("realm1").unwrap(), "realm1");
+        assert_eq!(RealmPolicy::new().apply("ca.test.Realm").unwrap(), "ca.test.Realm");
+        for realm in &["", "  ", " realm1\n", "realm 1", "ca..test", "ca.test.", "ca.#"] {
+            assert!(RealmPolicy::new().apply(realm).is_err(), "{:?} was accepted", realm);
+        }
+        let policy = RealmPolicy::new().with_trim(true).with_lowercase(true);
+        assert_eq!(policy.apply(" Ca.Test\n").unwrap(), "ca.test");
+        assert!(policy.apply(" \t").is_err());
+    }
+
+    #[test]
+    fn connections_check_the_realm_they_have() {
+        assert!(Connection::try_new("ws://127.0.0.1:8090/ws", "realm 1", RealmPolicy::new()).is_err());
+        assert_eq!(Connection::try_new("ws://127.0.0.1:8090/ws", " Realm1\n", RealmPolicy::new().with_trim(true).with_lowercase(true)).unwrap().realm.uri, "realm1");
+
+        let mut connection = Connection::new("ws://127.0.0.1:8090/ws", " realm1\n");
+        assert!(connection.check_realm().is_err());
+        assert!(connection.set_realm_policy(RealmPolicy::new().with_lowercase(true)).is_err());
+        assert_eq!(connection.realm.uri, " realm1\n");
+        connection.set_realm_policy(RealmPolicy::new().with_trim(true)).unwrap();
+        assert!(connection.check_realm().is_ok());
+        // Later policies apply to the realm as the earlier ones left it
+        connection.set_realm_policy(RealmPolicy::new()).unwrap();
+        assert_eq!(connection.realm.uri, "realm1");
+    }
+}
@@ -0,0 +1,288 @@
+//! Contains `Client::leave_and_rejoin`, which moves a client to a new session, for instance so
+//! that new credentials or a new authentication role take effect.
+//!
+//! The client says goodbye, connects again, and makes each of its subscriptions and
+//! registrations again in the new session, with the same callbacks and options.  They are made
+//! from the client's record of what it wants rather than from what the old session had, so each
+//! is made exactly once, in the order it was first made, and subscriptions or registrations the
+//! old session was still waiting for are made too.  Subscriptions that share a topic and options
+//! share one subscription again.  Shutdown hooks, the connection hooks, the orphaned event hook,
+//! the invocation authorizer and the credentials refreshed handler are moved to the new session
+//! too, as are the receivers of the client's events.  Session handles made from the client stay
+//! with the old session, which has ended.
+//!
+//! Nothing else carries over.  Calls and acknowledged publications the old session was waiting
+//! for fail when it closes, and invocations it was running can no longer be answered.
+//! Subscriptions and registrations the router refuses in the new session, for instance because
+//! the new credentials don't allow them, are listed in the `RejoinSummary`.
+use super::{Client, ClientEvent, Connection, SessionState, DisconnectCause, Subscription, Registration, SubscriptionRequest, RegistrationRequest, RequestKind};
+use client::events::EventBus;
+use client::interests::{Interests, InterestKey};
+use client::shutdown::{run_shutdown_hooks, wait_until};
+use eventual::Complete;
+use messages::{Message, URI};
+use std::collections::HashMap;
+use std::mem;
+use std::time::Duration;
+use ::{WampResult, Error, ErrorKind, CallError, ID};
+
+/// What happened when a client rejoined.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RejoinSummary {
+    /// The ID of the new session
+    pub session_id: ID,
+    /// Whether the router said goodbye in time when the client left the old session
+    pub goodbye_acknowledged: bool,
+    pub subscriptions_restored: usize,
+    /// The topics of the subscriptions the router refused, or didn't confirm in time
+    pub subscriptions_not_restored: Vec<URI>,
+    pub registrations_restored: usize,
+    /// The procedures of the registrations the router refused, or didn't confirm in time
+    pub registrations_not_restored: Vec<URI>
+}
+
+impl Client {
+    /// Leaves the realm, then joins again through `connection`, which may have different
+    /// authentication settings.  Each step waits for up to `timeout`.
+    ///
+    /// If the client can't connect again, the old session's shutdown hooks run and the error is
+    /// returned.
+    pub fn leave_and_rejoin(&mut self, connection: &Connection, timeout: Duration) -> WampResult<RejoinSummary> {
+        {
+            let mut info = self.connection_info.lock().unwrap();
+            if info.session.state() != SessionState::Joined {
+                return Err(Error::new(ErrorKind::InvalidState("Tried to rejoin with a client that wasn't connected")));
+            }
+            // Leaving isn't the end of the client, so the shutdown hooks are kept for later
+            info.graceful_shutdown = true;
+            let session_id = info.session.session_id();
+            info.event_bus.emit(ClientEvent::Reconnecting { session_id: session_id });
+        }
+        let (subscribing, registering) = self.take_interest_requests();
+        try!(self.shutdown());
+        let goodbye_acknowledged = wait_until(&self.connection_info, timeout, |info| info.session.state() == SessionState::Closed);
+        if !goodbye_acknowledged {
+            warn!("Router didn't say goodbye within {:?}, closing the connection", timeout);
+            self.connection_info.lock().unwrap().sender.shutdown().ok();
+        }
+
+        let client = match connection.connect() {
+            Ok(client) => client,
+            Err(e) => {
+                let (hooks, end) = {
+                    let mut info = self.connection_info.lock().unwrap();
+                    info.graceful_shutdown = false;
+                    info.record_session_end(DisconnectCause::Shutdown);
+                    (mem::replace(&mut info.shutdown_hooks, Vec::new()), info.session_end.clone())
+                };
+                if let Some(end) = end {
+                    run_shutdown_hooks(hooks, &end);
+                }
+                return Err(e);
+            }
+        };
+
+        let (subscriptions, registrations) = {
+            let mut old = self.connection_info.lock().unwrap();
+            let mut new = client.connection_info.lock().unwrap();
+            new.shutdown_hooks = mem::replace(&mut old.shutdown_hooks, Vec::new());
+            new.orphan_event_hook = old.orphan_event_hook.take();
+            new.invocation_authorizer = old.invocation_authorizer.take();
+            new.credentials_refreshed = old.credentials_refreshed.take();
+            new.hooks = old.hooks.clone();
+            new.event_bus = mem::replace(&mut old.event_bus, EventBus::new());
+            new.subscriptions = mem::replace(&mut old.subscriptions, Interests::new());
+            new.registrations = mem::replace(&mut old.registrations, Interests::new());
+            new.subscriptions.unbind_all();
+            new.registrations.unbind_all();
+            for (_, subscription) in new.subscriptions.iter_mut() {
+                subscription.owner = self.owner;
+            }
+            for (_, registration) in new.registrations.iter_mut() {
+                registration.owner = self.owner;
+            }
+            let subscriptions: Vec<(InterestKey, URI)> = new.subscriptions.iter().map(|(key, subscription)| (*key, subscription.topic.clone())).collect();
+            let registrations: Vec<(InterestKey, URI)> = new.registrations.iter().map(|(key, registration)| (*key, registration.procedure.clone())).collect();
+            (subscriptions, registrations)
+        };
+        self.connection_info = client.connection_info;
+        self.receive_thread = client.receive_thread;
+        self.pending_requests.clear();
+
+        try!(self.restore_interests(subscribing, registering));
+        wait_until(&self.connection_info, timeout, |info| info.subscription_requests.is_empty() && info.registration_requests.is_empty());
+
+        let mut info = self.connection_info.lock().unwrap();
+        let subscriptions_not_restored: Vec<URI> = subscriptions.iter().filter(|&&(key, _)| info.subscriptions.id_of(key).is_none()).map(|&(_, ref topic)| topic.clone()).collect();
+        let registrations_not_restored: Vec<URI> = registrations.iter().filter(|&&(key, _)| info.registrations.id_of(key).is_none()).map(|&(_, ref procedure)| procedure.clone()).collect();
+        info!("Rejoined the realm as session {}", info.session.session_id());
+        let session_id = info.session.session_id();
+        for topic in subscriptions_not_restored.iter() {
+            info.event_bus.emit(ClientEvent::SubscriptionLost { topic: topic.clone() });
+        }
+        for &(key, ref procedure) in registrations.iter() {
+            if let Some(registration_id) = info.registrations.id_of(key) {
+                info.event_bus.emit(ClientEvent::RegistrationRestored { procedure: procedure.clone(), registration_id: registration_id });
+            }
+        }
+        info.event_bus.emit(ClientEvent::Rejoined { session_id: session_id });
+        let hooks = info.hooks.clone();
+        drop(info);
+        hooks.reconnected(session_id);
+        Ok(RejoinSummary {
+            session_id: session_id,
+            goodbye_acknowledged: goodbye_acknowledged,
+            subscriptions_restored: subscriptions.len() - subscriptions_not_restored.len(),
+            subscriptions_not_restored: subscriptions_not_restored,
+            registrations_restored: registrations.len() - registrations_not_restored.len(),
+            registrations_not_restored: registrations_not_restored
+        })
+    }
+
+    /// Takes the subscribe and register requests the old session is waiting for, so that they
+    /// are made again instead of failing with it, and settles its unsubscribe and unregister
+    /// requests, which the new session won't need.
+    fn take_interest_requests(&mut self) -> (HashMap<InterestKey, Complete<Subscription, CallError>>, HashMap<InterestKey, Complete<Registration, CallError>>) {
+        let mut info = self.connection_info.lock().unwrap();
+        let mut subscribing = HashMap::new();
+        for (_, request) in info.subscription_requests.drain() {
+            for (key, promise) in request.waiting {
+                if let Some(promise) = promise {
+                    subscribing.insert(key, promise);
+                }
+            }
+        }
+        for (_, (promise, _)) in info.unsubscription_requests.drain() {
+            promise.complete(());
+        }
+        let mut registering = HashMap::new();
+        let requests: Vec<_> = info.registration_requests.drain().collect();
+        for (_, request) in requests {
+            match request {
+                RegistrationRequest::Register(promise, key, _) => {
+                    if let Some(promise) = promise {
+                        registering.insert(key, promise);
+                    }
+                },
+                RegistrationRequest::Unregister(promise, registration_id) => {
+                    info.registrations.remove_id(registration_id);
+                    promise.complete(());
+                }
+            }
+        }
+        (subscribing, registering)
+    }
+
+    /// Makes every subscription and registration the client wants in the current session.
+    /// Subscriptions with the same topic and options are made together.
+    fn restore_interests(&mut self, mut subscribing: HashMap<InterestKey, Complete<Subscription, CallError>>, mut registering: HashMap<InterestKey, Complete<Registration, CallError>>) -> WampResult<()> {
+        let (subscriptions, registrations) = {
+            let info = self.connection_info.lock().unwrap();
+            let mut subscriptions: Vec<SubscriptionRequest> = Vec::new();
+            for (key, subscription) in info.subscriptions.iter() {
+                let waiting = (*key, subscribing.remove(key));
+                match subscriptions.iter().position(|request| request.topic == subscription.topic && request.options == subscription.options) {
+                    Some(index) => subscriptions[index].waiting.push(waiting),
+                    None => subscriptions.push(SubscriptionRequest {
+                        topic: subscription.topic.clone(),
+                        options: subscription.options.clone(),
+                        waiting: vec![waiting]
+                    })
+                }
+            }
+            let registrations: Vec<_> = info.registrations.iter().map(|(key, registration)| (*key, registration.procedure.clone(), registration.options.clone())).collect();
+            (subscriptions, registrations)
+        };
+        debug!("Restoring {} subscriptions and {} registrations", subscriptions.len(), registrations.len());
+        for request in subscriptions {
+            let request_id = self.get_next_session_id();
+            let mut info = self.connection_info.lock().unwrap();
+            try!(info.queue_message(Message::Subscribe(request_id, request.options.clone(), request.topic.clone())));
+            info.note_request(request_id, RequestKind::Subscribe, request.topic.clone());
+            info.subscription_requests.insert(request_id, request);
+        }
+        for (key, procedure, options) in registrations {
+            let request_id = self.get_next_session_id();
+            let mut info = self.connection_info.lock().unwrap();
+            try!(info.queue_message(Message::Register(request_id, options, procedure.clone())));
+            info.note_request(request_id, RequestKind::Register, procedure.clone());
+            info.registration_requests.insert(request_id, RegistrationRequest::Register(registering.remove(&key), key, procedure));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "router", feature = "publisher", feature = "subscriber", feature = "caller", feature = "callee"))]
+mod test {
+    use client::{Client, Connection};
+    use messages::{URI, Reason};
+    use router::{Action, AuthorizationRule};
+    use std::sync::mpsc::{channel, Receiver};
+    use std::time::Duration;
+    use testing::{start_router, join, REALM};
+    use ::{List, Value};
+
+    // Subscribes to ca.test.topic and registers ca.test.double, and returns the events received
+    fn take_interests(client: &mut Client) -> Receiver<List> {
+        let (events, received) = channel();
+        client.subscribe(URI::new("ca.test.topic"), Box::new(move |args, _| {
+            events.send(args).unwrap();
+        })).unwrap().wait().unwrap();
+        client.register(URI::new("ca.test.double"), Box::new(|args, _| {
+            match args.get(0) {
+                Some(&Value::Integer(n)) => Ok((Some(vec![Value::Integer(n * 2)]), None)),
+                _ => Ok((None, None))
+            }
+        })).unwrap().wait().unwrap();
+        received
+    }
+
+    fn double(client: &mut Client, n: i64) -> Result<List, Reason> {
+        let call = client.call(URI::new("ca.test.double"), Some(vec![Value::Integer(n)]), None).unwrap();
+        call.wait_timeout(Duration::from_secs(5)).map(|(args, _)| args).map_err(|e| e.get_reason().clone())
+    }
+
+    #[test]
+    fn rejoining_restores_subscriptions_and_registrations() {
+        let (_router, url) = start_router();
+        let mut client = join(&url);
+        let mut other = join(&url);
+        let events = take_interests(&mut client);
+        let old_session = client.session_id();
+
+        let summary = client.leave_and_rejoin(&Connection::new(&url, REALM), Duration::from_secs(5)).unwrap();
+        assert!(summary.goodbye_acknowledged);
+        assert!(summary.session_id != old_session);
+        assert_eq!(client.session_id(), summary.session_id);
+        assert_eq!((summary.subscriptions_restored, summary.registrations_restored), (1, 1));
+        assert!(summary.subscriptions_not_restored.is_empty() && summary.registrations_not_restored.is_empty());
+
+        // The restored subscription and registration work in the new session
+        other.publish_and_acknowledge(URI::new("ca.test.topic"), Some(vec![Value::Integer(1)]), None).unwrap().wait().unwrap();
+        assert_eq!(events.recv_timeout(Duration::from_secs(5)).unwrap(), vec![Value::Integer(1)]);
+        assert_eq!(double(&mut other, 21), Ok(vec![Value::Integer(42)]));
+    }
+
+    #[test]
+    fn refused_interests_are_reported() {
+        let (router, url) = start_router();
+        let mut client = join(&url);
+        let mut other = join(&url);
+        let events = take_interests(&mut client);
+        router.set_authorization_rules(vec![AuthorizationRule {
+            prefix: "ca.test.double".to_string(),
+            action: Some(Action::Register),
+            authid: None,
+            allow: false
+        }]);
+
+        let summary = client.leave_and_rejoin(&Connection::new(&url, REALM), Duration::from_secs(5)).unwrap();
+        assert_eq!((summary.subscriptions_restored, summary.registrations_restored), (1, 0));
+        assert!(summary.subscriptions_not_restored.is_empty());
+        assert_eq!(summary.registrations_not_restored, vec![URI::new("ca.test.double")]);
+
+        other.publish_and_acknowledge(URI::new("ca.test.topic"), Some(vec![Value::Integer(2)]), None).unwrap().wait().unwrap();
+        assert_eq!(events.recv_timeout(Duration::from_secs(5)).unwrap(), vec![Value::Integer(2)]);
+        assert_eq!(double(&mut other, 21), Err(Reason::NoSuchProcedure));
+    }
+}
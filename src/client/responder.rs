@@ -0,0 +1,477 @@
+//! Contains the `Responder` struct, which answers an invocation after its handler has returned,
+//! so that handlers can pass slow work on to other threads instead of holding up the session.
+use super::{ConnectionInfo, MessageSender};
+use super::cancel::CancellationToken;
+use messages::{Message, ErrorType, YieldOptions, CancelMode, Reason, Dict, List, URI};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, ThreadId};
+use ::{CallResult, CallError, WampResult, Error, ErrorKind, ID};
+
+/// What a handler registered with `Client::register_deferred()` returns.
+pub enum Reply {
+    /// Answers the invocation right away, like the handlers of `Client::register()` do
+    Done(CallResult<(Option<List>, Option<Dict>)>),
+    /// Leaves the invocation to the handler's `Responder`
+    Deferred
+}
+
+/// Answers one invocation of a procedure registered with `Client::register_deferred()`.
+///
+/// Answering takes the connection's lock, which the session holds while it runs callbacks, so
+/// responders only answer from threads other than the one running the session's callbacks.  A
+/// responder dropped without answering fails the invocation with an application error.
+///
+/// If the caller cancels the call, the responder's cancellation token fires and anything it
+/// sends afterwards is dropped.  Callers that cancel with the `kill` mode are answered with
+/// `wamp.error.canceled` straight away.
+pub struct Responder {
+    request_id: ID,
+    procedure: URI,
+    receive_progress: bool,
+    connection_info: Arc<Mutex<ConnectionInfo>>,
+    dispatch: Arc<DispatchState>,
+    // Set once this responder has answered, or tried to
+    finished: bool
+}
+
+/// Shared between a responder and the dispatch of its invocation.
+pub struct DispatchState {
+    // The thread running the session's callbacks
+    event_loop: ThreadId,
+    // Set while the handler runs
+    dispatching: AtomicBool,
+    // Set when the responder is dropped unanswered while the handler runs
+    dropped: AtomicBool,
+    // Set when the responder is dropped unanswered on the session's thread after the handler
+    // has returned, so the session fails the invocation once it can
+    abandoned: AtomicBool,
+    answered: AtomicBool,
+    // Fired when the router interrupts the invocation
+    cancellation: CancellationToken
+}
+
+impl DispatchState {
+    pub fn is_answered(&self) -> bool {
+        self.answered.load(Ordering::SeqCst)
+    }
+
+    /// Ends the dispatch with what the handler returned, and returns what to answer the
+    /// invocation with now, if anything.  `more_chunks` is set for the chunks of a progressive
+    /// call that aren't the last, which needn't be answered.
+    pub fn finish(&self, reply: Reply, more_chunks: bool) -> Option<CallResult<(Option<List>, Option<Dict>)>> {
+        self.dispatching.store(false, Ordering::SeqCst);
+        match reply {
+            Reply::Done(result) => {
+                self.answered.store(true, Ordering::SeqCst);
+                Some(result)
+            },
+            Reply::Deferred => {
+                if !more_chunks && self.dropped.load(Ordering::SeqCst) && !self.answered.swap(true, Ordering::SeqCst) {
+                    Some(Err(dropped_error()))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+fn dropped_error() -> CallError {
+    CallError::application("The procedure dropped its responder without answering")
+}
+
+impl Responder {
+    pub(crate) fn new(request_id: ID, procedure: URI, receive_progress: bool, connection_info: Arc<Mutex<ConnectionInfo>>) -> Responder {
+        Responder {
+            request_id: request_id,
+            procedure: procedure,
+            receive_progress: receive_progress,
+            connection_info: connection_info,
+            dispatch: Arc::new(DispatchState {
+                event_loop: thread::current().id(),
+                dispatching: AtomicBool::new(true),
+                dropped: AtomicBool::new(false),
+                abandoned: AtomicBool::new(false),
+                answered: AtomicBool::new(false),
+                cancellation: CancellationToken::new()
+            }),
+            finished: false
+        }
+    }
+
+    pub(crate) fn dispatch(&self) -> Arc<DispatchState> {
+        self.dispatch.clone()
+    }
+
+    pub fn request_id(&self) -> ID {
+        self.request_id
+    }
+
+    /// Fires if the caller cancels the call
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.dispatch.cancellation.clone()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.dispatch.cancellation.is_cancelled()
+    }
+
+    /// Whether the caller accepts progressive results.  If it doesn't, `progress()` fails.
+    pub fn receives_progress(&self) -> bool {
+        self.receive_progress
+    }
+
+    /// Sends a progressive result, which more results follow.  Fails if the invocation has
+    /// already been answered, or the caller doesn't accept progressive results.
+    pub fn progress(&self, args: Option<List>, kwargs: Option<Dict>) -> WampResult<()> {
+        try!(self.check_thread());
+        let info = self.connection_info.lock().unwrap();
+        if self.is_cancelled() {
+            debug!("Not sending progress for invocation {}, which was cancelled", self.request_id);
+            return Ok(());
+        }
+        if self.dispatch.answered.load(Ordering::SeqCst) {
+            return Err(Error::new(ErrorKind::InvalidState("The invocation has already been answered")));
+        }
+        if !self.receive_progress {
+            return Err(Error::new(ErrorKind::InvalidState("The caller doesn't accept progressive results")));
+        }
+        let (args, kwargs) = info.compress_payload(&self.procedure, args, kwargs);
+        info.send_message(Message::Yield(self.request_id, YieldOptions::progressive(), args, kwargs))
+    }
+
+    /// Answers the invocation with its final result
+    pub fn respond(mut self, args: Option<List>, kwargs: Option<Dict>) -> WampResult<()> {
+        self.answer(Ok((args, kwargs)))
+    }
+
+    /// Fails the invocation.  Application errors are given the client's default error URI, as
+    /// they are when a handler returns them.
+    pub fn fail(mut self, error: CallError) -> WampResult<()> {
+        self.answer(Err(error))
+    }
+
+    fn check_thread(&self) -> WampResult<()> {
+        if thread::current().id() == self.dispatch.event_loop {
+            Err(Error::new(ErrorKind::InvalidState("Responders can't answer from the thread running the session's callbacks")))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn answer(&mut self, result: CallResult<(Option<List>, Option<Dict>)>) -> WampResult<()> {
+        try!(self.check_thread());
+        self.finished = true;
+        let mut info = self.connection_info.lock().unwrap();
+        if self.is_cancelled() {
+            debug!("Not answering invocation {}, which was cancelled", self.request_id);
+            return Ok(());
+        }
+        if self.dispatch.answered.swap(true, Ordering::SeqCst) {
+            return Err(Error::new(ErrorKind::InvalidState("The invocation has already been answered")));
+        }
+        info.deferred_invocations.remove(&self.request_id);
+        info.answer_invocation(self.request_id, &self.procedure, result)
+    }
+}
+
+impl Drop for Responder {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        if thread::current().id() == self.dispatch.event_loop {
+            if self.dispatch.dispatching.load(Ordering::SeqCst) {
+                self.dispatch.dropped.store(true, Ordering::SeqCst);
+            } else if !self.dispatch.answered.load(Ordering::SeqCst) {
+                warn!("The responder for invocation {} was dropped unanswered on the session's thread", self.request_id);
+                self.dispatch.abandoned.store(true, Ordering::SeqCst);
+            }
+            return;
+        }
+        self.answer(Err(dropped_error())).ok();
+    }
+}
+
+impl ConnectionInfo {
+    /// Stops an invocation the router interrupted because its call was cancelled.  Invocations
+    /// answered while their handlers ran are already finished, so only deferred ones are found.
+    pub fn interrupt_invocation(&mut self, request_id: ID, mode: Option<CancelMode>) {
+        let (procedure, dispatch) = match self.deferred_invocations.remove(&request_id) {
+            Some(invocation) => invocation,
+            None => {
+                debug!("Ignoring an interrupt for invocation {}, which has already been answered", request_id);
+                return;
+            }
+        };
+        info!("Invocation {} of {} was interrupted", request_id, procedure.uri);
+        dispatch.cancellation.cancel();
+        if dispatch.answered.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let cancelled = Err(CallError::new(Reason::Cancelled, None, None));
+        // The router only waits for an answer in the kill mode
+        if mode.unwrap_or(CancelMode::Kill) == CancelMode::Kill {
+            self.answer_invocation(request_id, &procedure, cancelled).ok();
+        } else {
+            self.answer_retries(request_id, &procedure, &cancelled);
+        }
+    }
+
+    /// Fails the invocations whose responders were dropped on the session's thread, which
+    /// couldn't answer while the session held the connection's lock
+    pub fn fail_abandoned_invocations(&mut self) {
+        let abandoned: Vec<ID> = self.deferred_invocations.iter().filter(|&(_, &(_, ref dispatch))| dispatch.abandoned.load(Ordering::SeqCst)).map(|(&request_id, _)| request_id).collect();
+        for request_id in abandoned {
+            let (procedure, dispatch) = self.deferred_invocations.remove(&request_id).unwrap();
+            if !dispatch.answered.swap(true, Ordering::SeqCst) {
+                self.answer_invocation(request_id, &procedure, Err(dropped_error())).ok();
+            }
+        }
+    }
+
+    /// The YIELD or ERROR answering an invocation of `procedure`
+    pub fn invocation_reply(&self, request_id: ID, procedure: &URI, result: CallResult<(Option<List>, Option<Dict>)>) -> Message {
+        match result {
+            Ok((args, kwargs)) => {
+                let (args, kwargs) = self.compress_payload(procedure, args, kwargs);
+                Message::Yield(request_id, YieldOptions::new(), args, kwargs)
+            },
+            Err(error) => {
+                let error = if error.is_application_error() {
+                    error.with_reason(self.default_error_reason.clone())
+                } else {
+                    error
+                };
+                let details = error.get_details().clone();
+                let (reason, args, kwargs) = error.to_tuple();
+                Message::Error(ErrorType::Invocation, request_id, details, reason, args, kwargs)
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "callee"))]
+mod test {
+    use super::{Reply, Responder};
+    use client::{Client, Connection, IDEMPOTENCY_KEY};
+    use messages::{Message, WelcomeDetails, InvocationDetails, InterruptOptions, RegisterOptions, RouterRoles, CancelMode, MatchingPolicy, URI};
+    use serde_json;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc::{channel, Sender, Receiver};
+    use std::thread;
+    use std::time::Duration;
+    use testing::{ScriptedRouter, join};
+    use ws;
+    use ::{ErrorKind, Value, ID};
+
+    // Joins the client, registers its procedure and invokes it as request 7, and passes on
+    // whatever else the client sends
+    struct FakeRouter {
+        out: ws::Sender,
+        receive_progress: bool,
+        received: Sender<String>
+    }
+
+    impl ws::Handler for FakeRouter {
+        fn on_request(&mut self, request: &ws::Request) -> ws::Result<ws::Response> {
+            let mut response = try!(ws::Response::from_request(request));
+            response.set_protocol("wamp.2.json");
+            Ok(response)
+        }
+
+        fn on_message(&mut self, message: ws::Message) -> ws::Result<()> {
+            let text = try!(message.into_text());
+            match serde_json::from_str(&text) {
+                Ok(Message::Hello(..)) => {
+                    self.out.send(serde_json::to_string(&Message::Welcome(1, WelcomeDetails::new(RouterRoles::new()))).unwrap())
+                },
+                Ok(Message::Register(request_id, ..)) => {
+                    try!(self.out.send(serde_json::to_string(&Message::Registered(request_id, 5)).unwrap()));
+                    let mut details = InvocationDetails::new();
+                    details.receive_progress = Some(self.receive_progress);
+                    self.out.send(serde_json::to_string(&Message::Invocation(7, 5, details, None, None)).unwrap())
+                },
+                _ => {
+                    self.received.send(text).ok();
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    fn fake_router(port: u16, receive_progress: bool) -> Receiver<String> {
+        let (received, messages) = channel();
+        thread::spawn(move || {
+            ws::listen(("127.0.0.1", port), |out| FakeRouter {
+                out: out,
+                receive_progress: receive_progress,
+                received: received.clone()
+            }).unwrap();
+        });
+        thread::sleep(Duration::from_millis(200));
+        messages
+    }
+
+    fn sent(messages: &Receiver<String>) -> Vec<String> {
+        thread::sleep(Duration::from_millis(200));
+        messages.try_iter().collect()
+    }
+
+    #[test]
+    fn deferred_replies_are_sent_once() {
+        let messages = fake_router(18471, false);
+        let mut client = Connection::new("ws://127.0.0.1:18471/ws", "ca.test").connect().unwrap();
+        let (responders, responder) = channel();
+        client.register_deferred(URI::new("ca.test.slow"), Box::new(move |_, _, responder| {
+            responders.send(responder).unwrap();
+            Reply::Deferred
+        })).unwrap().wait().unwrap();
+
+        let responder = responder.recv_timeout(Duration::from_secs(5)).unwrap();
+        match *responder.progress(None, None).unwrap_err().kind() {
+            ErrorKind::InvalidState(_) => {},
+            ref kind => panic!("Unexpected error {:?}", kind)
+        }
+        responder.respond(Some(vec![Value::Integer(1)]), None).unwrap();
+        assert_eq!(sent(&messages), vec!["[70,7,{},[1]]".to_string()]);
+    }
+
+    #[test]
+    fn progress_after_the_reply_fails() {
+        let messages = fake_router(18472, true);
+        let mut client = Connection::new("ws://127.0.0.1:18472/ws", "ca.test").connect().unwrap();
+        let (responders, responder) = channel();
+        client.register_deferred(URI::new("ca.test.quick"), Box::new(move |_, _, responder| {
+            responders.send(responder).unwrap();
+            Reply::Done(Ok((Some(vec![Value::Integer(2)]), None)))
+        })).unwrap().wait().unwrap();
+
+        let responder = responder.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(responder.progress(Some(vec![Value::Integer(1)]), None).is_err());
+        assert!(responder.respond(None, None).is_err());
+        assert_eq!(sent(&messages), vec!["[70,7,{},[2]]".to_string()]);
+    }
+
+    fn deduplicating_client(router: &ScriptedRouter) -> Client {
+        let mut client = join(&router.url);
+        client.enable_invocation_dedup(Duration::from_secs(60), 10);
+        client
+    }
+
+    // An invocation of the registration `registration_id` by session 3, marked with `key`
+    fn invoke(router: &ScriptedRouter, request_id: ID, registration_id: ID, procedure: Option<&str>, key: &str) {
+        let mut details = InvocationDetails::new();
+        details.caller = Some(3);
+        details.procedure = procedure.map(URI::new);
+        let mut kwargs = HashMap::new();
+        kwargs.insert(IDEMPOTENCY_KEY.to_string(), Value::String(key.to_string()));
+        router.send(&Message::Invocation(request_id, registration_id, details, None, Some(kwargs)));
+    }
+
+    // Registers a deferred procedure whose responders are passed on
+    fn register_deferred(client: &mut Client) -> (ID, Receiver<Responder>) {
+        let (responders, responder) = channel();
+        let registration = client.register_deferred(URI::new("ca.test.charge"), Box::new(move |_, _, responder| {
+            responders.send(responder).unwrap();
+            Reply::Deferred
+        })).unwrap().wait().unwrap();
+        (registration.registration_id, responder)
+    }
+
+    #[test]
+    fn interrupted_calls_are_run_again() {
+        let router = ScriptedRouter::start();
+        let mut client = deduplicating_client(&router);
+        let (registration_id, responders) = register_deferred(&mut client);
+
+        invoke(&router, 7, registration_id, None, "a");
+        invoke(&router, 8, registration_id, None, "a");
+        let interrupted = responders.recv_timeout(Duration::from_secs(5)).unwrap();
+        // The router doesn't wait for an answer to a killnowait interrupt, but the retry
+        // waiting for the call does
+        router.send(&Message::Interrupt(7, InterruptOptions::new(CancelMode::KillNoWait)));
+        assert_eq!(router.next(), "[8,68,8,{},\"wamp.error.canceled\"]");
+        interrupted.respond(None, None).unwrap();
+        router.assert_quiet();
+
+        invoke(&router, 9, registration_id, None, "a");
+        responders.recv_timeout(Duration::from_secs(5)).unwrap().respond(Some(vec![Value::Integer(1)]), None).unwrap();
+        assert_eq!(router.next(), "[70,9,{},[1]]");
+        assert!(responders.try_recv().is_err());
+    }
+
+    #[test]
+    fn abandoned_calls_are_run_again() {
+        let router = ScriptedRouter::start();
+        let mut client = deduplicating_client(&router);
+        // The handler keeps the responder of a call, and drops it on the session's thread
+        // during the next one
+        let mut kept = None;
+        let runs = Arc::new(AtomicUsize::new(0));
+        let handler_runs = runs.clone();
+        let registration = client.register_deferred(URI::new("ca.test.charge"), Box::new(move |_, _, responder| {
+            handler_runs.fetch_add(1, Ordering::SeqCst);
+            match kept.take() {
+                Some(_) => Reply::Done(Ok((Some(vec![Value::Integer(2)]), None))),
+                None => {
+                    kept = Some(responder);
+                    Reply::Deferred
+                }
+            }
+        })).unwrap().wait().unwrap();
+        let registration_id = registration.registration_id;
+
+        invoke(&router, 7, registration_id, None, "a");
+        invoke(&router, 8, registration_id, None, "a");
+        invoke(&router, 9, registration_id, None, "b");
+        assert_eq!(router.next(), "[70,9,{},[2]]");
+        let dropped = "{},\"wamp.error.runtime_error\",[\"The procedure dropped its responder without answering\"]]";
+        assert_eq!(router.next(), format!("[8,68,8,{}", dropped));
+        assert_eq!(router.next(), format!("[8,68,7,{}", dropped));
+
+        // Failures aren't kept, so the call runs again
+        invoke(&router, 10, registration_id, None, "a");
+        router.assert_quiet();
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn pattern_registrations_are_deduplicated_by_the_called_procedure() {
+        let router = ScriptedRouter::start();
+        let mut client = deduplicating_client(&router);
+        let mut runs = 0;
+        let options = RegisterOptions::new().with_pattern_match(MatchingPolicy::Prefix);
+        let registration = client.register_with_options(URI::new("ca.test"), Box::new(move |_, _| {
+            runs += 1;
+            Ok((Some(vec![Value::Integer(runs)]), None))
+        }), options).unwrap().wait().unwrap();
+
+        invoke(&router, 7, registration.registration_id, Some("ca.test.a"), "a");
+        assert_eq!(router.next(), "[70,7,{},[1]]");
+        invoke(&router, 8, registration.registration_id, Some("ca.test.b"), "a");
+        assert_eq!(router.next(), "[70,8,{},[2]]");
+        invoke(&router, 9, registration.registration_id, Some("ca.test.a"), "a");
+        assert_eq!(router.next(), "[70,9,{},[1]]");
+    }
+
+    #[test]
+    fn progressive_procedures_are_not_deduplicated() {
+        let router = ScriptedRouter::start();
+        let mut client = deduplicating_client(&router);
+        let mut runs = 0;
+        let registration = client.register_progressive(URI::new("ca.test.sum"), Box::new(move |_, _, _| {
+            runs += 1;
+            Reply::Done(Ok((Some(vec![Value::Integer(runs)]), None)))
+        })).unwrap().wait().unwrap();
+
+        // Each is the last chunk of a call, which may have different chunks before it
+        invoke(&router, 7, registration.registration_id, None, "a");
+        assert_eq!(router.next(), "[70,7,{},[1]]");
+        invoke(&router, 8, registration.registration_id, None, "a");
+        assert_eq!(router.next(), "[70,8,{},[2]]");
+    }
+}
@@ -0,0 +1,119 @@
+//! Contains the `ResponseCache` struct, which lets a client answer repeated calls to slowly
+//! changing procedures without going to the router.
+//!
+//! A callee can say how long its result stays valid by including a keyword argument named
+//! `_cache_ttl` in the result, holding the number of milliseconds the result may be reused for.
+//! A TTL of 0 means the result must not be cached.  Results without the hint are cached for the
+//! cache's default TTL, if it has one.
+use messages::{Dict, List, Value};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use ::ID;
+
+pub static CACHE_TTL_KEY: &'static str = "_cache_ttl";
+
+struct CachedResponse {
+    expires: Instant,
+    args: List,
+    kwargs: Dict
+}
+
+pub struct ResponseCache {
+    default_ttl: Option<Duration>,
+    // Keyed by procedure and the canonical form of the call's arguments
+    entries: HashMap<(String, String), CachedResponse>,
+    // Calls that are waiting for a result to cache, keyed by request id
+    pending: HashMap<ID, (String, String)>
+}
+
+/// Gets the TTL a callee asked for in the keyword arguments of its result.
+fn ttl_hint(kwargs: &Dict) -> Option<Duration> {
+    match kwargs.get(CACHE_TTL_KEY) {
+        Some(&Value::Integer(ttl)) if ttl >= 0 => Some(Duration::from_millis(ttl as u64)),
+        _ => None
+    }
+}
+
+impl ResponseCache {
+    pub fn new(default_ttl: Option<Duration>) -> ResponseCache {
+        ResponseCache {
+            default_ttl: default_ttl,
+            entries: HashMap::new(),
+            pending: HashMap::new()
+        }
+    }
+
+    pub fn get(&self, procedure: &str, key: &str) -> Option<(List, Dict)> {
+        match self.entries.get(&(procedure.to_string(), key.to_string())) {
+            Some(cached) if cached.expires > Instant::now() => Some((cached.args.clone(), cached.kwargs.clone())),
+            _ => None
+        }
+    }
+
+    /// Remembers that the result of the given call should be considered for caching
+    pub fn expect_result(&mut self, request_id: ID, procedure: String, key: String) {
+        self.pending.insert(request_id, (procedure, key));
+    }
+
+    /// Forgets a call that failed or was cancelled
+    pub fn forget(&mut self, request_id: ID) {
+        self.pending.remove(&request_id);
+    }
+
+    /// Caches the result of a call, if it was expected and the TTL allows it.
+    pub fn insert_result(&mut self, request_id: ID, args: &List, kwargs: &Dict) {
+        let (procedure, key) = match self.pending.remove(&request_id) {
+            Some(pending) => pending,
+            None => return
+        };
+        let ttl = match ttl_hint(kwargs).or(self.default_ttl) {
+            Some(ttl) if ttl > Duration::from_millis(0) => ttl,
+            _ => return
+        };
+        let now = Instant::now();
+        self.entries.retain(|_, cached| cached.expires > now);
+        self.entries.insert((procedure, key), CachedResponse {
+            expires: now + ttl,
+            args: args.clone(),
+            kwargs: kwargs.clone()
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ResponseCache, CACHE_TTL_KEY};
+    use messages::Value;
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    #[test]
+    fn ttl_hints() {
+        let mut cache = ResponseCache::new(None);
+        let mut hinted = HashMap::new();
+        hinted.insert(CACHE_TTL_KEY.to_string(), Value::Integer(60000));
+        let mut uncacheable = HashMap::new();
+        uncacheable.insert(CACHE_TTL_KEY.to_string(), Value::Integer(0));
+
+        cache.expect_result(1, "ca.test.hinted".to_string(), "[]{}".to_string());
+        cache.insert_result(1, &vec![Value::Integer(1)], &hinted);
+        cache.expect_result(2, "ca.test.plain".to_string(), "[]{}".to_string());
+        cache.insert_result(2, &vec![Value::Integer(2)], &HashMap::new());
+        cache.insert_result(3, &vec![Value::Integer(3)], &hinted);
+
+        assert_eq!(cache.get("ca.test.hinted", "[]{}"), Some((vec![Value::Integer(1)], hinted)));
+        assert_eq!(cache.get("ca.test.plain", "[]{}"), None);
+
+        let mut cache = ResponseCache::new(Some(Duration::from_secs(60)));
+        cache.expect_result(1, "ca.test.plain".to_string(), "[]{}".to_string());
+        cache.insert_result(1, &vec![Value::Integer(2)], &HashMap::new());
+        cache.expect_result(2, "ca.test.uncacheable".to_string(), "[]{}".to_string());
+        cache.insert_result(2, &Vec::new(), &uncacheable);
+        assert_eq!(cache.get("ca.test.plain", "[]{}"), Some((vec![Value::Integer(2)], HashMap::new())));
+        assert_eq!(cache.get("ca.test.uncacheable", "[]{}"), None);
+    }
+}
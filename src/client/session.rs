@@ -0,0 +1,163 @@
+//! Contains the `SessionHandle` struct, which lets several independent parts of an application
+//! share one client connection.
+use super::{Client, Subscription, Registration, RegistrationRequest, RequestKind, SessionState, AllowList, Pending, CallHandle};
+use messages::{URI, Dict, List, Message, Reason, SubscribeOptions, RegisterOptions, MatchingPolicy};
+use eventual::{self, Future};
+use ::{WampResult, Error, ErrorKind, CallResult, CallError, ID};
+
+/// A lightweight handle onto a shared client connection.
+///
+/// Every handle uses the same websocket and WAMP session as the client it was created from, but
+/// keeps track of its own subscriptions, registrations and pending requests.  Shutting a handle
+/// down only removes what that handle set up, so plugins that each hold a handle can come and go
+/// without affecting each other.  Shutting down the original `Client` still ends the session for
+/// every handle.
+pub struct SessionHandle {
+    client: Client,
+    closed: bool
+}
+
+impl Client {
+    /// Creates a new handle that shares this client's connection.
+    pub fn session_handle(&self) -> SessionHandle {
+        self.new_session_handle(None)
+    }
+
+    /// Creates a new handle that may only publish to and call the URIs on the allow list.  The
+    /// handle can't change its own allow list, so this is a way to sandbox plugins.
+    pub fn restricted_session_handle(&self, allow_list: AllowList) -> SessionHandle {
+        self.new_session_handle(Some(allow_list))
+    }
+
+    fn new_session_handle(&self, allow_list: Option<AllowList>) -> SessionHandle {
+        let mut info = self.connection_info.lock().unwrap();
+        info.max_owner_id += 1;
+        SessionHandle {
+            client: Client {
+                connection_info: self.connection_info.clone(),
+                owner: info.max_owner_id,
+                pending_requests: Vec::new(),
+                allow_list: allow_list,
+                receive_thread: None
+            },
+            closed: false
+        }
+    }
+}
+
+impl SessionHandle {
+    fn client(&mut self) -> WampResult<&mut Client> {
+        if self.closed {
+            Err(Error::new(ErrorKind::InvalidState("Tried to use a session handle that was already shut down")))
+        } else {
+            Ok(&mut self.client)
+        }
+    }
+
+    #[cfg(feature = "subscriber")]
+    pub fn subscribe(&mut self, topic: URI, callback: Box<FnMut(List, Dict)>) -> WampResult<Pending<Subscription>> {
+        try!(self.client()).subscribe(topic, callback)
+    }
+
+    #[cfg(feature = "subscriber")]
+    pub fn subscribe_with_pattern(&mut self, topic_pattern: URI, callback: Box<FnMut(List, Dict)>, policy: MatchingPolicy) -> WampResult<Pending<Subscription>> {
+        try!(self.client()).subscribe_with_pattern(topic_pattern, callback, policy)
+    }
+
+    #[cfg(feature = "subscriber")]
+    pub fn subscribe_with_options(&mut self, topic_pattern: URI, callback: Box<FnMut(List, Dict)>, options: SubscribeOptions) -> WampResult<Pending<Subscription>> {
+        try!(self.client()).subscribe_with_options(topic_pattern, callback, options)
+    }
+
+    #[cfg(feature = "subscriber")]
+    pub fn unsubscribe(&mut self, subscription: Subscription) -> WampResult<Pending<()>> {
+        try!(self.client()).unsubscribe(subscription)
+    }
+
+    #[cfg(feature = "callee")]
+    pub fn register(&mut self, procedure: URI, callback: Box<FnMut(List, Dict) -> CallResult<(Option<List>, Option<Dict>)>>) -> WampResult<Pending<Registration>> {
+        try!(self.client()).register(procedure, callback)
+    }
+
+    #[cfg(feature = "callee")]
+    pub fn register_with_options(&mut self, procedure_pattern: URI, callback: Box<FnMut(List, Dict) -> CallResult<(Option<List>, Option<Dict>)>>, options: RegisterOptions) -> WampResult<Pending<Registration>> {
+        try!(self.client()).register_with_options(procedure_pattern, callback, options)
+    }
+
+    #[cfg(feature = "callee")]
+    pub fn unregister(&mut self, registration: Registration) -> WampResult<Pending<()>> {
+        try!(self.client()).unregister(registration)
+    }
+
+    #[cfg(feature = "publisher")]
+    pub fn publish(&mut self, topic: URI, args: Option<List>, kwargs: Option<Dict>) -> WampResult<()> {
+        try!(self.client()).publish(topic, args, kwargs)
+    }
+
+    #[cfg(feature = "publisher")]
+    pub fn publish_and_acknowledge(&mut self, topic: URI, args: Option<List>, kwargs: Option<Dict>) -> WampResult<Pending<ID>> {
+        try!(self.client()).publish_and_acknowledge(topic, args, kwargs)
+    }
+
+    #[cfg(feature = "caller")]
+    pub fn call(&mut self, procedure: URI, args: Option<List>, kwargs: Option<Dict>) -> WampResult<CallHandle> {
+        try!(self.client()).call(procedure, args, kwargs)
+    }
+
+    /// Removes every subscription and registration made through this handle, and cancels its
+    /// pending calls and publications.  The connection itself stays open.
+    ///
+    /// The returned future completes once the router has confirmed every removal.  Subscriptions
+    /// and registrations that the router hadn't confirmed yet when the handle was shut down are
+    /// not removed.
+    pub fn shutdown(&mut self) -> WampResult<Pending<()>> {
+        if self.closed {
+            return Err(Error::new(ErrorKind::InvalidState("Tried to shut down a session handle that was already shut down")));
+        }
+        self.closed = true;
+        let owner = self.client.owner;
+        let (subscription_ids, registration_ids) = {
+            let mut info = self.client.connection_info.lock().unwrap();
+            for request_id in self.client.pending_requests.drain(..) {
+                if let Some(promise) = info.call_requests.remove(&request_id) {
+                    promise.fail(CallError::new(Reason::Cancelled, None, None));
+                }
+                if let Some(promise) = info.publish_requests.remove(&request_id) {
+                    promise.fail(CallError::new(Reason::Cancelled, None, None));
+                }
+            }
+            if info.session.state() != SessionState::Joined {
+                return Ok(Pending::of(()));
+            }
+            // The handle's subscriptions stop receiving events right away, as with unsubscribe()
+            let subscription_keys: Vec<_> = info.subscriptions.iter().filter(|&(_, subscription)| subscription.owner == owner).map(|(key, _)| *key).collect();
+            let subscription_ids: Vec<(ID, URI)> = subscription_keys.into_iter().filter_map(|key| info.subscriptions.remove(key)).filter_map(|(subscription, id)| id.map(|id| (id, subscription.topic))).collect();
+            let registration_ids: Vec<ID> = info.registrations.iter().filter(|&(_, registration)| registration.owner == owner).filter_map(|(key, _)| info.registrations.id_of(*key)).collect();
+            (subscription_ids, registration_ids)
+        };
+        debug!("Shutting down session handle {} ({} subscriptions, {} registrations)", owner, subscription_ids.len(), registration_ids.len());
+
+        let mut futures = Vec::new();
+        for (subscription_id, topic) in subscription_ids {
+            let request_id = self.client.get_next_session_id();
+            let (complete, future) = Future::<(), CallError>::pair();
+            let mut info = self.client.connection_info.lock().unwrap();
+            try!(info.queue_message(Message::Unsubscribe(request_id, subscription_id)));
+            info.note_request(request_id, RequestKind::Unsubscribe, topic);
+            info.unsubscription_requests.insert(request_id, (complete, subscription_id));
+            futures.push(future);
+        }
+        for registration_id in registration_ids {
+            let request_id = self.client.get_next_session_id();
+            let (complete, future) = Future::<(), CallError>::pair();
+            let mut info = self.client.connection_info.lock().unwrap();
+            try!(info.queue_message(Message::Unregister(request_id, registration_id)));
+            if let Some(procedure) = info.registrations.first(registration_id).map(|registration| registration.procedure.clone()) {
+                info.note_request(request_id, RequestKind::Unregister, procedure);
+            }
+            info.registration_requests.insert(request_id, RegistrationRequest::Unregister(complete, registration_id));
+            futures.push(future);
+        }
+        Ok(Pending::new(eventual::join(futures).map(|_| ())))
+    }
+}
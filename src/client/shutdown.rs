@@ -0,0 +1,309 @@
+//! Contains the `ShutdownPlan` struct, which describes how `Client::shutdown_graceful` winds a
+//! session down.
+//!
+//! A graceful shutdown goes through the session in stages, so that nothing is cut off halfway:
+//!
+//! 1. Every registration is removed, so that the router stops sending invocations.  Invocations
+//!    are answered on the connection's event loop as soon as they arrive, so once the router has
+//!    confirmed the removals, every invocation it sent has been answered.
+//! 2. Every subscription is removed.
+//! 3. Publications held back by rate limits are sent or dropped, the outbound queue is written
+//!    out, and the client waits for the router to acknowledge acknowledged publications.
+//! 4. The client says goodbye, and waits for the router to say goodbye back.
+//!
+//! Each stage waits for at most the plan's timeout before moving on to the next.
+//!
+//! Shutdown hooks run exactly once when a session ends, however it ends, so that applications
+//! can flush their own state.  After a graceful shutdown they run once it is over, and are given
+//! its summary.
+use super::{Client, ConnectionInfo, SessionState, RegistrationRequest, RequestKind, WriterStats};
+use messages::{URI, Message, Reason, ErrorDetails};
+use eventual::Future;
+use std::mem;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use ::{WampResult, Error, ErrorKind, CallError, ID};
+
+/// Configures a graceful shutdown.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShutdownPlan {
+    timeout: Duration,
+    send_held_publications: bool
+}
+
+/// Describes what happened during a graceful shutdown, and in particular what was dropped.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShutdownSummary {
+    pub registrations_removed: usize,
+    /// The procedures the router didn't confirm unregistering in time
+    pub registrations_not_removed: Vec<URI>,
+    pub subscriptions_removed: usize,
+    /// The topics the router didn't confirm unsubscribing from in time
+    pub subscriptions_not_removed: Vec<URI>,
+    /// Publications that were held back by rate limits, and dropped instead of being sent
+    pub publications_dropped: usize,
+    /// Acknowledged publications that the router didn't acknowledge in time
+    pub publications_unacknowledged: usize,
+    /// Calls that were still waiting for a result when the session ended
+    pub calls_cancelled: usize,
+    /// Whether the router said goodbye back and closed the connection in time
+    pub goodbye_acknowledged: bool
+}
+
+/// Why a session ended.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DisconnectCause {
+    /// The client said goodbye
+    Shutdown,
+    /// The router said goodbye
+    RouterGoodbye(Reason),
+    /// The router aborted the session
+    Aborted(Reason),
+    /// The client aborted the session because the router broke the protocol.  Holds what the
+    /// router did.
+    ProtocolViolation(String),
+    /// The connection closed without either side saying goodbye.  Holds the close code and
+    /// reason.
+    ConnectionLost(String)
+}
+
+/// How a session ended, and its statistics at the time, as given to shutdown hooks.
+#[derive(Debug, Clone)]
+pub struct SessionEnd {
+    pub cause: DisconnectCause,
+    pub session_id: ID,
+    pub writer_stats: WriterStats,
+    pub protocol_violations: u64,
+    /// Calls that were still waiting for a result
+    pub calls_cancelled: usize,
+    /// Acknowledged publications the router hadn't acknowledged
+    pub publications_unacknowledged: usize,
+    /// The summary of the graceful shutdown, if the session ended with one
+    pub summary: Option<ShutdownSummary>
+}
+
+/// Runs when a session ends.  See `Client::on_shutdown`.
+pub type ShutdownHook = Box<FnMut(&SessionEnd)>;
+
+impl ShutdownPlan {
+    /// Waits up to five seconds for each stage, and sends publications held back by rate limits
+    pub fn new() -> ShutdownPlan {
+        ShutdownPlan {
+            timeout: Duration::from_secs(5),
+            send_held_publications: true
+        }
+    }
+
+    /// Sets how long to wait for the router at each stage
+    pub fn with_timeout(mut self, timeout: Duration) -> ShutdownPlan {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets whether publications held back by rate limits are sent, ignoring the limits, or
+    /// dropped.  Acknowledged publications that are dropped fail with `Reason::Cancelled`.
+    pub fn with_held_publications(mut self, send: bool) -> ShutdownPlan {
+        self.send_held_publications = send;
+        self
+    }
+}
+
+impl Client {
+    /// Ends the session in stages, as described by the plan, and blocks until it is over.
+    ///
+    /// Like `shutdown`, this ends the session for every session handle sharing the connection.
+    pub fn shutdown_graceful(&mut self, plan: ShutdownPlan) -> WampResult<ShutdownSummary> {
+        {
+            let mut info = self.connection_info.lock().unwrap();
+            if info.session.state() != SessionState::Joined {
+                return Err(Error::new(ErrorKind::InvalidState("Tried to shut down a client that was already shutting down")));
+            }
+            info.graceful_shutdown = true;
+        }
+        let result = self.shutdown_in_stages(plan);
+
+        // The connection may have closed during the shutdown, in which case the hooks were left
+        // for now, so that they could be given the summary
+        let (hooks, end) = {
+            let mut info = self.connection_info.lock().unwrap();
+            info.graceful_shutdown = false;
+            if result.is_ok() {
+                info.record_session_end(DisconnectCause::Shutdown);
+            }
+            let mut end = match info.session_end.clone() {
+                Some(end) => end,
+                None => return result
+            };
+            end.summary = result.as_ref().ok().cloned();
+            (mem::replace(&mut info.shutdown_hooks, Vec::new()), end)
+        };
+        run_shutdown_hooks(hooks, &end);
+        result
+    }
+
+    fn shutdown_in_stages(&mut self, plan: ShutdownPlan) -> WampResult<ShutdownSummary> {
+        let mut summary = ShutdownSummary {
+            registrations_removed: 0,
+            registrations_not_removed: Vec::new(),
+            subscriptions_removed: 0,
+            subscriptions_not_removed: Vec::new(),
+            publications_dropped: 0,
+            publications_unacknowledged: 0,
+            calls_cancelled: 0,
+            goodbye_acknowledged: false
+        };
+
+        debug!("Graceful shutdown: removing registrations");
+        let registration_ids: Vec<_> = self.connection_info.lock().unwrap().registrations.ids();
+        for registration_id in registration_ids.iter() {
+            let request_id = self.get_next_session_id();
+            let (complete, _) = Future::<(), CallError>::pair();
+            let mut info = self.connection_info.lock().unwrap();
+            try!(info.queue_message(Message::Unregister(request_id, *registration_id)));
+            if let Some(procedure) = info.registrations.first(*registration_id).map(|registration| registration.procedure.clone()) {
+                info.note_request(request_id, RequestKind::Unregister, procedure);
+            }
+            info.registration_requests.insert(request_id, RegistrationRequest::Unregister(complete, *registration_id));
+        }
+        wait_until(&self.connection_info, plan.timeout, |info| registration_ids.iter().all(|id| !info.registrations.is_live(*id)));
+        {
+            let info = self.connection_info.lock().unwrap();
+            summary.registrations_not_removed = registration_ids.iter().filter_map(|id| info.registrations.first(*id)).map(|registration| registration.procedure.clone()).collect();
+            summary.registrations_removed = registration_ids.len() - summary.registrations_not_removed.len();
+        }
+
+        debug!("Graceful shutdown: removing subscriptions");
+        let subscription_ids: Vec<_> = self.connection_info.lock().unwrap().subscriptions.ids();
+        for subscription_id in subscription_ids.iter() {
+            let request_id = self.get_next_session_id();
+            let (complete, _) = Future::<(), CallError>::pair();
+            let mut info = self.connection_info.lock().unwrap();
+            try!(info.queue_message(Message::Unsubscribe(request_id, *subscription_id)));
+            if let Some(topic) = info.subscriptions.first(*subscription_id).map(|subscription| subscription.topic.clone()) {
+                info.note_request(request_id, RequestKind::Unsubscribe, topic);
+            }
+            info.unsubscription_requests.insert(request_id, (complete, *subscription_id));
+        }
+        wait_until(&self.connection_info, plan.timeout, |info| subscription_ids.iter().all(|id| !info.subscriptions.is_live(*id)));
+        {
+            let info = self.connection_info.lock().unwrap();
+            summary.subscriptions_not_removed = subscription_ids.iter().filter_map(|id| info.subscriptions.first(*id)).map(|subscription| subscription.topic.clone()).collect();
+            summary.subscriptions_removed = subscription_ids.len() - summary.subscriptions_not_removed.len();
+        }
+
+        debug!("Graceful shutdown: flushing publications");
+        {
+            let mut info = self.connection_info.lock().unwrap();
+            for message in info.rate_limiter.take_held() {
+                if plan.send_held_publications {
+                    try!(info.queue_message(message));
+                } else if let Message::Publish(request_id, ..) = message {
+                    if let Some(promise) = info.publish_requests.remove(&request_id) {
+                        promise.fail(CallError::new(Reason::Cancelled, None, None));
+                    }
+                    summary.publications_dropped += 1;
+                }
+            }
+        }
+        wait_until(&self.connection_info, plan.timeout, |info| info.outbound.is_empty() && info.publish_requests.is_empty());
+        summary.publications_unacknowledged = self.connection_info.lock().unwrap().publish_requests.len();
+
+        debug!("Graceful shutdown: saying goodbye");
+        {
+            let mut info = self.connection_info.lock().unwrap();
+            summary.calls_cancelled = info.call_requests.len();
+            info.session.leave();
+            info.disconnect_cause = Some(DisconnectCause::Shutdown);
+            try!(info.queue_message(Message::Goodbye(ErrorDetails::new(), Reason::SystemShutdown)));
+        }
+        summary.goodbye_acknowledged = wait_until(&self.connection_info, plan.timeout, |info| info.session.state() == SessionState::Closed);
+        if !summary.goodbye_acknowledged {
+            warn!("Router didn't say goodbye within {:?}, closing the connection", plan.timeout);
+            self.connection_info.lock().unwrap().sender.shutdown().ok();
+        }
+        Ok(summary)
+    }
+
+    /// Says goodbye like `shutdown`, and blocks until the router has said goodbye back and the
+    /// connection's thread has finished, or until `timeout` passes.  Returns whether both
+    /// happened in time.  If the router doesn't say goodbye in time, the connection is closed
+    /// without waiting for it.
+    ///
+    /// Only the client that made the connection owns its thread, so for clients shared through a
+    /// `Publisher` this only waits for the session to close.
+    pub fn shutdown_and_wait(&mut self, timeout: Duration) -> WampResult<bool> {
+        let deadline = Instant::now() + timeout;
+        try!(self.shutdown());
+        let closed = wait_until(&self.connection_info, timeout, |info| info.session.state() == SessionState::Closed);
+        if !closed {
+            warn!("Router didn't say goodbye within {:?}, closing the connection", timeout);
+            self.connection_info.lock().unwrap().sender.shutdown().ok();
+        }
+        let thread = match self.receive_thread.take() {
+            Some(thread) => thread,
+            None => return Ok(closed)
+        };
+        while !thread.is_finished() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        if !thread.is_finished() {
+            warn!("The connection's thread didn't finish within {:?}", timeout);
+            return Ok(false);
+        }
+        Ok(thread.join().is_ok() && closed)
+    }
+
+    /// Adds a hook that runs exactly once when the session ends, whether it is shut down or the
+    /// connection is lost.  It runs on the connection's event loop, or on the thread that called
+    /// `shutdown_graceful`.
+    pub fn on_shutdown(&mut self, hook: ShutdownHook) {
+        self.connection_info.lock().unwrap().shutdown_hooks.push(hook);
+    }
+}
+
+impl ConnectionInfo {
+    /// Records how the session ended, unless that has already been recorded
+    pub fn record_session_end(&mut self, cause: DisconnectCause) {
+        self.cancellation.cancel();
+        if self.session_end.is_some() {
+            return;
+        }
+        self.session_end = Some(SessionEnd {
+            cause: cause,
+            session_id: self.session.session_id(),
+            writer_stats: self.outbound.stats.clone(),
+            protocol_violations: self.protocol_violations,
+            calls_cancelled: self.call_requests.len(),
+            publications_unacknowledged: self.publish_requests.len(),
+            summary: None
+        });
+    }
+}
+
+pub fn run_shutdown_hooks(hooks: Vec<ShutdownHook>, end: &SessionEnd) {
+    for mut hook in hooks {
+        hook(end);
+    }
+}
+
+/// Waits for up to `timeout` until `done` returns true, and returns whether it did.  Gives up
+/// early if the connection closes first.
+pub fn wait_until<F>(connection_info: &Mutex<ConnectionInfo>, timeout: Duration, done: F) -> bool where F: Fn(&ConnectionInfo) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        {
+            let info = connection_info.lock().unwrap();
+            if done(&info) {
+                return true;
+            }
+            if info.session.state() == SessionState::Closed {
+                return false;
+            }
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
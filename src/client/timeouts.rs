@@ -0,0 +1,120 @@
+//! Contains the `ConnectionConfig` struct, which limits how long `Connection::connect` waits for
+//! each step of joining a realm.
+//!
+//! Joining takes two steps: the websocket handshake, which includes making the TCP connection
+//! and setting up TLS, and then waiting for the router to welcome the client once it has said
+//! hello.  The overall timeout applies on top of both.  When any of them runs out, `connect`
+//! fails with `ErrorKind::Timeout`.
+//!
+//! There is no separate timeout for making the TCP connection, because ws doesn't say when it
+//! has been made.  A router that doesn't accept the connection at all is caught by the
+//! handshake timeout, or by the overall timeout if there is no handshake timeout; the
+//! operating system's own connect timeout is usually much longer than either.
+use super::{ConnectionHandler, SessionState, DisconnectCause};
+use std::time::Duration;
+use ::{Error, ErrorKind};
+
+/// How long connecting may take.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConnectionConfig {
+    timeout: Duration,
+    handshake_timeout: Option<Duration>,
+    welcome_timeout: Option<Duration>
+}
+
+impl ConnectionConfig {
+    /// Waits up to five seconds for the whole connection, with no limit on either step
+    pub fn new() -> ConnectionConfig {
+        ConnectionConfig {
+            timeout: Duration::from_secs(5),
+            handshake_timeout: None,
+            welcome_timeout: None
+        }
+    }
+
+    /// Sets how long the whole connection may take, until the router welcomes the client
+    pub fn with_timeout(mut self, timeout: Duration) -> ConnectionConfig {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets how long the websocket handshake may take, including making the TCP connection and
+    /// setting up TLS
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> ConnectionConfig {
+        self.handshake_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets how long to wait for the router's welcome once the websocket is open
+    pub fn with_welcome_timeout(mut self, timeout: Duration) -> ConnectionConfig {
+        self.welcome_timeout = Some(timeout);
+        self
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn handshake_timeout(&self) -> Option<Duration> {
+        self.handshake_timeout
+    }
+
+    pub fn welcome_timeout(&self) -> Option<Duration> {
+        self.welcome_timeout
+    }
+}
+
+impl ConnectionHandler {
+    /// Gives up on connecting, if the client is still connecting
+    pub fn connection_timed_out(&mut self, step: &str) {
+        let mut info = self.connection_info.lock().unwrap();
+        if info.session.state() != SessionState::Joining {
+            return;
+        }
+        warn!("Timed out waiting for the {}", step);
+        info.session.close();
+        info.disconnect_cause = Some(DisconnectCause::ConnectionLost(format!("Timed out waiting for the {}", step)));
+        info.sender.shutdown().ok();
+        drop(info);
+        self.state_transmission.send(Err(Error::new(ErrorKind::Timeout))).ok();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ConnectionConfig;
+    use client::Connection;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use ws;
+    use ::ErrorKind;
+
+    // Opens the websocket, then never answers the client's hello
+    struct SilentRouter;
+
+    impl ws::Handler for SilentRouter {
+        fn on_request(&mut self, request: &ws::Request) -> ws::Result<ws::Response> {
+            let mut response = try!(ws::Response::from_request(request));
+            response.set_protocol("wamp.2.json");
+            Ok(response)
+        }
+    }
+
+    #[test]
+    fn welcome_timeout_expires() {
+        thread::spawn(|| ws::listen("127.0.0.1:18491", |_| SilentRouter).unwrap());
+        thread::sleep(Duration::from_millis(200));
+        let mut connection = Connection::new("ws://127.0.0.1:18491/ws", "ca.test");
+        connection.set_connection_config(ConnectionConfig::new().with_welcome_timeout(Duration::from_millis(300)));
+        let started = Instant::now();
+        match connection.connect() {
+            Err(e) => match *e.kind() {
+                ErrorKind::Timeout => {},
+                ref kind => panic!("Unexpected error {:?}", kind)
+            },
+            Ok(_) => panic!("Joined a router that never welcomed the client")
+        }
+        // Well before the overall timeout
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,180 @@
+//! Contains the `TlsPolicy` struct, which configures how the client sets up TLS for `wss://`
+//! URLs.
+//!
+//! TLS needs the crate's `ssl` feature.  Without it, connecting to a `wss://` URL fails.  By
+//! default the router's certificate is checked against the system's root certificates; a
+//! different bundle can be given instead, or checking can be turned off for development.
+//!
+//! Besides the certificate chain, the router's certificate must have been issued for the host in
+//! the URL.  Its DNS and IP subject alternative names are compared with the host, or its common
+//! name if it has none, and a leading `*` label matches any one label.  Since openssl 0.7 has no
+//! hostname checks of its own, this is done in a verify callback.
+#[cfg(feature = "ssl")]
+use openssl::nid::Nid;
+#[cfg(feature = "ssl")]
+use openssl::ssl::{Ssl, SslContext, SslMethod, SSL_VERIFY_NONE, SSL_VERIFY_PEER};
+#[cfg(feature = "ssl")]
+use openssl::x509::{X509, X509FileType, X509StoreContext};
+#[cfg(feature = "ssl")]
+use std::io;
+#[cfg(any(feature = "ssl", test))]
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "ssl")]
+use ws::{Error as WSError, Result as WSResult};
+
+/// How the client sets up TLS connections.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsPolicy {
+    ca_file: Option<PathBuf>,
+    accept_invalid_certs: bool,
+    // The client's certificate and private key, both PEM files
+    client_certificate: Option<(PathBuf, PathBuf)>
+}
+
+impl TlsPolicy {
+    /// Checks that the router's certificate was issued for its host by one of the system's root
+    /// certificates, and presents no client certificate
+    pub fn new() -> TlsPolicy {
+        TlsPolicy {
+            ca_file: None,
+            accept_invalid_certs: false,
+            client_certificate: None
+        }
+    }
+
+    /// Checks the router's certificate against the root certificates in `path`, a PEM file,
+    /// instead of the system's
+    pub fn with_ca_file<P: AsRef<Path>>(mut self, path: P) -> TlsPolicy {
+        self.ca_file = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Whether to accept any certificate the router presents.  This is only safe for
+    /// development, since anyone can pose as the router.
+    pub fn with_accept_invalid_certs(mut self, accept: bool) -> TlsPolicy {
+        self.accept_invalid_certs = accept;
+        self
+    }
+
+    /// Presents the certificate in `certificate` to the router, for routers that authenticate
+    /// clients by certificate.  Both files are PEM, and the key must match the certificate.
+    pub fn with_client_certificate<P: AsRef<Path>, Q: AsRef<Path>>(mut self, certificate: P, private_key: Q) -> TlsPolicy {
+        self.client_certificate = Some((certificate.as_ref().to_path_buf(), private_key.as_ref().to_path_buf()));
+        self
+    }
+
+    /// Makes the `Ssl` for a connection to `host`, which is sent to the router so that it can
+    /// choose a certificate
+    #[cfg(feature = "ssl")]
+    pub fn build_ssl(&self, host: Option<&str>) -> WSResult<Ssl> {
+        let mut context = try!(SslContext::new(SslMethod::Sslv23));
+        if self.accept_invalid_certs {
+            context.set_verify(SSL_VERIFY_NONE, None);
+        } else {
+            context.set_verify(SSL_VERIFY_PEER, None);
+            match self.ca_file {
+                Some(ref ca_file) => try!(context.set_CA_file(ca_file)),
+                None => try!(context.set_default_verify_paths())
+            }
+        }
+        if let Some((ref certificate, ref private_key)) = self.client_certificate {
+            try!(context.set_certificate_file(certificate, X509FileType::PEM));
+            try!(context.set_private_key_file(private_key, X509FileType::PEM));
+            try!(context.check_private_key());
+        }
+        let mut ssl = try!(Ssl::new(&context));
+        if let Some(host) = host {
+            try!(ssl.set_hostname(host));
+        }
+        if !self.accept_invalid_certs {
+            // Without a host there is nothing to check the certificate against
+            let host = match host {
+                Some(host) => host.to_string(),
+                None => return Err(WSError::from(io::Error::new(io::ErrorKind::InvalidInput, "The router's certificate can't be checked without a host")))
+            };
+            ssl.set_verify_callback(SSL_VERIFY_PEER, move |chain_ok, chain: &X509StoreContext| {
+                // Only the router's own certificate, at depth 0, names the host
+                if !chain_ok || chain.error_depth() > 0 {
+                    return chain_ok;
+                }
+                match chain.get_current_cert() {
+                    Some(certificate) => {
+                        let issued_for = certificate_matches(&certificate, &host);
+                        if !issued_for {
+                            warn!("The router's certificate wasn't issued for {}", host);
+                        }
+                        issued_for
+                    },
+                    None => false
+                }
+            });
+        }
+        Ok(ssl)
+    }
+}
+
+#[cfg(feature = "ssl")]
+fn certificate_matches<'a>(certificate: &X509<'a>, host: &str) -> bool {
+    if let Some(names) = certificate.subject_alt_names() {
+        let names: Vec<_> = (&names).into_iter().collect();
+        if !names.is_empty() {
+            return names.iter().any(|name| {
+                name.dnsname().map_or(false, |name| name_matches(name, host)) || name.ipaddress().map_or(false, |address| address_matches(address, host))
+            });
+        }
+    }
+    certificate.subject_name().text_by_nid(Nid::CN).map_or(false, |name| name_matches(&name, host))
+}
+
+/// Whether the DNS name `pattern` from a certificate names `host`
+#[cfg(any(feature = "ssl", test))]
+fn name_matches(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.trim_end_matches('.').to_lowercase();
+    let host = host.trim_end_matches('.').to_lowercase();
+    if host.parse::<IpAddr>().is_ok() {
+        return false;
+    }
+    if pattern.starts_with("*.") {
+        // The wildcard stands for exactly one label, and not the host's last two
+        match host.find('.') {
+            Some(dot) => dot > 0 && host[dot..] == pattern[1..] && pattern[2..].contains('.'),
+            None => false
+        }
+    } else {
+        pattern == host
+    }
+}
+
+/// Whether the IP address from a certificate, as 4 or 16 bytes, is `host`
+#[cfg(any(feature = "ssl", test))]
+fn address_matches(address: &[u8], host: &str) -> bool {
+    match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(IpAddr::V4(host)) => address == &host.octets()[..],
+        Ok(IpAddr::V6(host)) => address == &host.octets()[..],
+        Err(_) => false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{name_matches, address_matches};
+
+    #[test]
+    fn certificate_names_match_the_host() {
+        assert!(name_matches("router.example.com", "Router.Example.com"));
+        assert!(name_matches("*.example.com", "router.example.com"));
+        assert!(!name_matches("*.example.com", "example.com"));
+        assert!(!name_matches("*.example.com", "a.router.example.com"));
+        assert!(!name_matches("*.com", "example.com"));
+        assert!(!name_matches("other.example.com", "router.example.com"));
+        assert!(!name_matches("127.0.0.1", "127.0.0.1"));
+
+        assert!(address_matches(&[127, 0, 0, 1], "127.0.0.1"));
+        assert!(!address_matches(&[127, 0, 0, 2], "127.0.0.1"));
+        let mut loopback = [0; 16];
+        loopback[15] = 1;
+        assert!(address_matches(&loopback, "[::1]"));
+        assert!(!address_matches(&[127, 0, 0, 1], "localhost"));
+    }
+}
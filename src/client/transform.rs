@@ -0,0 +1,188 @@
+//! Contains `EventChain` and `ProcedureChain`, which run transformers around subscription and
+//! registration callbacks, so that decoding, validation and the like stay out of the handlers.
+//!
+//! Like `HandlerRegistry`, a chain doesn't change how subscribing or registering works: it wraps
+//! a callback, and the wrapped callback is passed to `Client::subscribe()`, `Client::register()`
+//! or any of their variants.  Transformers run in the order they were added.
+use super::EventContext;
+use messages::{Dict, List, Reason, Value};
+use ::{CallResult, CallError};
+
+/// Rewrites the arguments of an event or invocation before its handler sees them.  Failing
+/// stops the chain.
+pub type ArgumentTransformer = Box<FnMut(List, Dict) -> CallResult<(List, Dict)>>;
+
+/// Rewrites the result of a procedure handler before it is sent to the caller.  Failing stops
+/// the chain, and the call fails instead.
+pub type ResultTransformer = Box<FnMut(Option<List>, Option<Dict>) -> CallResult<(Option<List>, Option<Dict>)>>;
+
+/// The transformers run on each event of a subscription.  Events that a transformer fails on are
+/// dropped, since there is nobody to tell.
+pub struct EventChain {
+    transformers: Vec<ArgumentTransformer>
+}
+
+/// The transformers run on each invocation of a registration, and on its results.  An invocation
+/// that a transformer fails on is answered with the transformer's error, without calling the
+/// handler.  Handler errors are passed on untouched.
+pub struct ProcedureChain {
+    arguments: Vec<ArgumentTransformer>,
+    results: Vec<ResultTransformer>
+}
+
+fn run_arguments(transformers: &mut [ArgumentTransformer], mut args: List, mut kwargs: Dict) -> CallResult<(List, Dict)> {
+    for transformer in transformers.iter_mut() {
+        let (new_args, new_kwargs) = try!(transformer(args, kwargs));
+        args = new_args;
+        kwargs = new_kwargs;
+    }
+    Ok((args, kwargs))
+}
+
+impl EventChain {
+    pub fn new() -> EventChain {
+        EventChain {
+            transformers: Vec::new()
+        }
+    }
+
+    pub fn with_transformer(mut self, transformer: ArgumentTransformer) -> EventChain {
+        self.transformers.push(transformer);
+        self
+    }
+
+    /// Runs every transformer on an event
+    pub fn transform(&mut self, args: List, kwargs: Dict) -> CallResult<(List, Dict)> {
+        run_arguments(&mut self.transformers, args, kwargs)
+    }
+
+    /// Constructs a subscription callback that runs the chain before `callback`
+    pub fn wrap(self, mut callback: Box<FnMut(List, Dict)>) -> Box<FnMut(List, Dict)> {
+        let mut chain = self;
+        Box::new(move |args, kwargs| {
+            match chain.transform(args, kwargs) {
+                Ok((args, kwargs)) => callback(args, kwargs),
+                Err(e) => warn!("Dropping an event that could not be transformed: {:?}", e.get_reason())
+            }
+        })
+    }
+
+    /// Like `wrap()`, for the callbacks of `Client::subscribe_with_details()`
+    pub fn wrap_with_details(self, mut callback: Box<FnMut(List, Dict, &EventContext)>) -> Box<FnMut(List, Dict, &EventContext)> {
+        let mut chain = self;
+        Box::new(move |args, kwargs, context: &EventContext| {
+            match chain.transform(args, kwargs) {
+                Ok((args, kwargs)) => callback(args, kwargs, context),
+                Err(e) => warn!("Dropping publication {} that could not be transformed: {:?}", context.publication_id, e.get_reason())
+            }
+        })
+    }
+}
+
+impl ProcedureChain {
+    pub fn new() -> ProcedureChain {
+        ProcedureChain {
+            arguments: Vec::new(),
+            results: Vec::new()
+        }
+    }
+
+    /// Adds a transformer for the arguments of invocations
+    pub fn with_argument_transformer(mut self, transformer: ArgumentTransformer) -> ProcedureChain {
+        self.arguments.push(transformer);
+        self
+    }
+
+    /// Adds a transformer for the results of the handler
+    pub fn with_result_transformer(mut self, transformer: ResultTransformer) -> ProcedureChain {
+        self.results.push(transformer);
+        self
+    }
+
+    /// Runs the chain around one invocation of `handler`
+    pub fn invoke<F>(&mut self, args: List, kwargs: Dict, handler: F) -> CallResult<(Option<List>, Option<Dict>)>
+        where F: FnOnce(List, Dict) -> CallResult<(Option<List>, Option<Dict>)> {
+        let (args, kwargs) = try!(run_arguments(&mut self.arguments, args, kwargs));
+        let (mut args, mut kwargs) = try!(handler(args, kwargs));
+        for transformer in self.results.iter_mut() {
+            let (new_args, new_kwargs) = try!(transformer(args, kwargs));
+            args = new_args;
+            kwargs = new_kwargs;
+        }
+        Ok((args, kwargs))
+    }
+
+    /// Constructs a registration callback that runs the chain around `callback`
+    pub fn wrap(self, mut callback: Box<FnMut(List, Dict) -> CallResult<(Option<List>, Option<Dict>)>>) -> Box<FnMut(List, Dict) -> CallResult<(Option<List>, Option<Dict>)>> {
+        let mut chain = self;
+        Box::new(move |args, kwargs| chain.invoke(args, kwargs, &mut callback))
+    }
+}
+
+/// A transformer that renames keyword arguments, for instance to accept the field names of an
+/// older version of an API.  Arguments that already use the new name are left alone.
+pub fn rename_kwargs(renames: &[(&str, &str)]) -> ArgumentTransformer {
+    let renames: Vec<(String, String)> = renames.iter().map(|&(from, to)| (from.to_string(), to.to_string())).collect();
+    Box::new(move |args, mut kwargs| {
+        for &(ref from, ref to) in &renames {
+            if !kwargs.contains_key(to) {
+                if let Some(value) = kwargs.remove(from) {
+                    kwargs.insert(to.clone(), value);
+                }
+            }
+        }
+        Ok((args, kwargs))
+    })
+}
+
+/// A transformer that fails with `Reason::InvalidArgument` unless every one of `keys` is given
+pub fn require_kwargs(keys: &[&str]) -> ArgumentTransformer {
+    let keys: Vec<String> = keys.iter().map(|key| key.to_string()).collect();
+    Box::new(move |args, kwargs: Dict| {
+        match keys.iter().find(|key| !kwargs.contains_key(*key)) {
+            Some(key) => Err(CallError::new(Reason::InvalidArgument, Some(vec![Value::String(format!("Missing argument {}", key))]), None)),
+            None => Ok((args, kwargs))
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{EventChain, ProcedureChain, rename_kwargs, require_kwargs};
+    use messages::{Reason, Value};
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    #[test]
+    fn event_chains_run_before_the_handler() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let handler_seen = seen.clone();
+        let mut callback = EventChain::new()
+            .with_transformer(rename_kwargs(&[("temp", "temperature")]))
+            .with_transformer(require_kwargs(&["temperature"]))
+            .wrap(Box::new(move |_, kwargs| handler_seen.borrow_mut().push(kwargs)));
+
+        let mut legacy = HashMap::new();
+        legacy.insert("temp".to_string(), Value::Integer(21));
+        callback(Vec::new(), legacy);
+        callback(Vec::new(), HashMap::new());
+
+        let mut expected = HashMap::new();
+        expected.insert("temperature".to_string(), Value::Integer(21));
+        assert_eq!(*seen.borrow(), vec![expected]);
+    }
+
+    #[test]
+    fn procedure_chains_run_around_the_handler() {
+        let mut callback = ProcedureChain::new()
+            .with_argument_transformer(require_kwargs(&["name"]))
+            .with_result_transformer(Box::new(|args, kwargs| Ok((args.map(|mut args| { args.push(Value::Boolean(true)); args }), kwargs))))
+            .wrap(Box::new(|_, kwargs| Ok((Some(vec![kwargs["name"].clone()]), None))));
+
+        let mut kwargs = HashMap::new();
+        kwargs.insert("name".to_string(), Value::String("joe".to_string()));
+        assert_eq!(callback(Vec::new(), kwargs).unwrap().0, Some(vec![Value::String("joe".to_string()), Value::Boolean(true)]));
+        assert_eq!(callback(Vec::new(), HashMap::new()).unwrap_err().get_reason(), &Reason::InvalidArgument);
+    }
+}
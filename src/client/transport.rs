@@ -0,0 +1,152 @@
+//! Contains the `Transport` trait, which decides how a connection reaches the router, and the
+//! websocket transport that connections use unless they are told otherwise.
+//!
+//! The client itself always speaks to a websocket.  A websocket transport hands it the router's
+//! URL unchanged, while other transports, like the raw socket transport, start a bridge on a
+//! loopback port that carries the client's messages over their own protocol, and hand it the
+//! bridge's URL instead.  A bridge only accepts websockets for a random path in that URL, so
+//! other local processes can't connect to it in the client's place.  The HTTP proxy transport
+//! hands it a tunnel to the router in the same way.  Tests can implement the trait to point a connection somewhere else.  Wherever the
+//! websocket ends up, its handshake, and TLS for `wss://` URLs, still name the router.
+use super::RawSocketTransport;
+use rand::{OsRng, Rng};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use ws::{Factory, Request, Response, Result as WSResult, WebSocket};
+use ::WampResult;
+
+/// Opens connections to routers.
+pub trait Transport: Send + Sync {
+    /// Opens a transport to the router at `url`, for a client that offers `protocols`, most
+    /// preferred first.  Returns the URL of the websocket the client should connect to.
+    fn open(&self, url: &str, protocols: &[String]) -> WampResult<String>;
+}
+
+/// Connects to the router's websocket directly.
+pub struct WebSocketTransport;
+
+impl Transport for WebSocketTransport {
+    fn open(&self, url: &str, _: &[String]) -> WampResult<String> {
+        Ok(url.to_string())
+    }
+}
+
+/// The transport for connections that haven't been given one, which is the raw socket
+/// transport for `tcp://` URLs and the websocket transport otherwise
+pub fn for_url(url: &str) -> Arc<Transport> {
+    if url.starts_with("tcp:") {
+        Arc::new(RawSocketTransport::new())
+    } else {
+        Arc::new(WebSocketTransport)
+    }
+}
+
+/// A path that can't be guessed, for a bridge's websocket
+pub fn bridge_path() -> Result<String, String> {
+    let mut rng = try!(OsRng::new().map_err(|e| format!("could not generate a bridge path: {}", e)));
+    let token: String = (0..16).map(|_| format!("{:02x}", rng.gen::<u8>())).collect();
+    Ok(format!("/{}", token))
+}
+
+/// Answers a websocket handshake for a bridge at `path`, choosing `protocol` if the client
+/// offers it.  Handshakes for any other path are refused.
+pub fn accept_bridge_request(request: &Request, path: &str, protocol: &str) -> WSResult<Response> {
+    let mut response = try!(Response::from_request(request));
+    if request.resource() != path {
+        warn!("Refusing a websocket for the wrong bridge path");
+        response.set_status(403);
+        response.set_reason("Forbidden");
+        return Ok(response);
+    }
+    if try!(request.protocols()).iter().any(|offered| *offered == protocol) {
+        response.set_protocol(protocol);
+    }
+    Ok(response)
+}
+
+/// Starts a websocket server for a bridge on a free loopback port, and returns its URL, ending
+/// in `path`, once it is listening.  The factory's handlers should answer handshakes with
+/// `accept_bridge_request`.
+pub fn start_bridge<F>(name: &'static str, path: &str, factory: F) -> Result<String, String> where F: Factory + Send + 'static {
+    // ws doesn't say which port it listens on, so a free one is found first
+    let port = try!(TcpListener::bind("127.0.0.1:0").and_then(|listener| listener.local_addr()).map_err(|e| e.to_string())).port();
+    let address = format!("127.0.0.1:{}", port);
+    let listen_address = address.clone();
+    thread::spawn(move || {
+        let result = WebSocket::new(factory).and_then(|socket| socket.listen(&listen_address[..]));
+        if let Err(e) = result {
+            error!("{} bridge stopped: {}", name, e);
+        }
+    });
+    // Give the bridge a moment to start listening.  The probe isn't a websocket handshake, so
+    // the bridge drops it rather than keeping it around as a connection.
+    for _ in 0..50 {
+        if let Ok(mut probe) = TcpStream::connect(&address[..]) {
+            probe.set_read_timeout(Some(Duration::from_secs(1))).ok();
+            probe.write_all(b"GET / HTTP/1.1\r\n\r\n").ok();
+            probe.read_to_end(&mut Vec::new()).ok();
+            return Ok(format!("ws://{}{}", address, path));
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    Err(format!("the {} bridge didn't start", name))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{accept_bridge_request, bridge_path, start_bridge};
+    use std::sync::mpsc::{channel, Sender};
+    use std::thread;
+    use std::time::Duration;
+    use ws::{self, CloseCode, Handshake, Request, Response};
+
+    struct Bridge {
+        path: String
+    }
+
+    impl ws::Handler for Bridge {
+        fn on_request(&mut self, request: &Request) -> ws::Result<Response> {
+            accept_bridge_request(request, &self.path, "wamp.2.json")
+        }
+    }
+
+    struct Probe {
+        out: ws::Sender,
+        opened: Sender<bool>
+    }
+
+    impl ws::Handler for Probe {
+        fn on_open(&mut self, _: Handshake) -> ws::Result<()> {
+            self.opened.send(true).ok();
+            self.out.close(CloseCode::Normal)
+        }
+
+        fn on_error(&mut self, _: ws::Error) {
+            self.opened.send(false).ok();
+        }
+    }
+
+    // Whether a websocket for `url` opens
+    fn opens(url: String) -> bool {
+        let (opened, result) = channel();
+        thread::spawn(move || ws::connect(url, |out| Probe { out: out, opened: opened.clone() }).ok());
+        result.recv_timeout(Duration::from_secs(5)).unwrap_or(false)
+    }
+
+    #[test]
+    fn bridges_only_accept_their_path() {
+        let path = bridge_path().unwrap();
+        assert_eq!(path.len(), 33);
+        assert!(path != bridge_path().unwrap());
+        let bridge_path = path.clone();
+        let url = start_bridge("Test", &path, move |_| Bridge { path: bridge_path.clone() }).unwrap();
+        assert!(url.ends_with(&path));
+        let root = url[..url.len() - path.len()].to_string();
+        assert!(!opens(format!("{}/", root)));
+        assert!(!opens(format!("{}/{}", root, "0".repeat(32))));
+        assert!(opens(url));
+    }
+}
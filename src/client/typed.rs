@@ -0,0 +1,275 @@
+//! Contains the typed variants of publishing, subscribing and calling, which take and give any
+//! payload that implements `Serialize` or `Deserialize` instead of `List`s and `Dict`s.
+//!
+//! Publications are normally made of `List` and `Dict` values, so a typed payload would first be
+//! converted into a tree of `Value`s, only for that tree to be serialized again.  When nothing
+//! needs to look at the payload before it is written, it is instead serialized straight into
+//! the frame with the negotiated serializer, as soon as it is published.  That is the case when
+//!
+//! * the connection uses one of WAMP's serializations, rather than a custom codec,
+//! * payload compression is off, and
+//! * the topic has no rate limit.
+//!
+//! Otherwise the payload is converted to values and published like any other publication.
+//! Events and call results are always converted from the values they were received as.
+//!
+//! Payloads that can't be converted fail with `ErrorKind::ConversionError`.  An event that can't
+//! be converted has nowhere to report that, so it is logged and dropped.
+use super::{Client, ConnectionInfo};
+#[cfg(feature = "subscriber")]
+use super::{Subscription, Pending};
+#[cfg(feature = "caller")]
+use super::CallHandle;
+use codec::Frame;
+use messages::{URI, Dict, List, Message, PublishOptions, CancelMode};
+use rmp_serde::Serializer;
+use serde_cbor::ser::Serializer as CBORSerializer;
+use serde::{Serialize, Deserialize};
+use serde_json;
+use serializer::Serialization;
+use std::fmt;
+use std::marker::PhantomData;
+use std::time::Duration;
+use utils::StructMapWriter;
+use ::{WampResult, Error, ErrorKind, ID};
+
+const PUBLISH: u64 = 16;
+
+/// A payload that couldn't be converted to or from the type it was published, subscribed or
+/// called with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConversionError {
+    /// The topic or procedure the payload was for
+    pub uri: String,
+    pub message: String
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The payload for {} could not be converted: {}", self.uri, self.message)
+    }
+}
+
+fn conversion_error<E: fmt::Display>(uri: &URI, error: E) -> Error {
+    Error::new(ErrorKind::ConversionError(ConversionError {
+        uri: uri.uri.to_string(),
+        message: error.to_string()
+    }))
+}
+
+/// A typed call the router hasn't answered yet, made with `Client::call_typed()`.  Waiting works
+/// like `CallHandle`, except that a call that fails, including one that times out, fails with
+/// `ErrorKind::CallFailed`.
+#[cfg(feature = "caller")]
+pub struct TypedCall<R> {
+    handle: CallHandle,
+    procedure: URI,
+    result: PhantomData<R>
+}
+
+#[cfg(feature = "caller")]
+impl<R: Deserialize> TypedCall<R> {
+    pub fn request_id(&self) -> ID {
+        self.handle.request_id()
+    }
+
+    /// Asks the router to cancel the call, see `CallHandle::cancel()`
+    pub fn cancel(&self, mode: CancelMode) -> WampResult<()> {
+        self.handle.cancel(mode)
+    }
+
+    /// Blocks until the router answers, and converts the result's arguments
+    pub fn wait(self) -> WampResult<R> {
+        let (args, _) = try!(self.handle.wait().map_err(|e| Error::new(ErrorKind::CallFailed(e))));
+        from_values(&self.procedure, args)
+    }
+
+    /// Blocks until the router answers or `timeout` passes, and converts the result's arguments
+    pub fn wait_timeout(self, timeout: Duration) -> WampResult<R> {
+        let (args, _) = try!(self.handle.wait_timeout(timeout).map_err(|e| Error::new(ErrorKind::CallFailed(e))));
+        from_values(&self.procedure, args)
+    }
+}
+
+impl Client {
+    /// Publishes `args`, which must serialize as a sequence, such as a tuple or a `Vec`
+    #[cfg(feature = "publisher")]
+    pub fn publish_typed<A: Serialize>(&mut self, topic: URI, args: &A) -> WampResult<()> {
+        self.publish_typed_with_options::<A, Dict>(topic, args, None, PublishOptions::new(false))
+    }
+
+    /// Publishes `args` and `kwargs`, which must serialize as a sequence and a map respectively.
+    /// The router isn't asked to acknowledge the publication.
+    ///
+    /// The payload is serialized by its own `Serialize` implementation, so byte strings aren't
+    /// written the way WAMP represents binary values in JSON.
+    #[cfg(feature = "publisher")]
+    pub fn publish_typed_with_options<A, K>(&mut self, topic: URI, args: &A, kwargs: Option<&K>, mut options: PublishOptions) -> WampResult<()> where A: Serialize, K: Serialize {
+        info!("Publishing a typed payload to {:?}", topic);
+        try!(self.check_publish(&topic));
+        let request_id = self.next_publication_id(&topic);
+        options.acknowledge = false;
+        let mut info = self.connection_info.lock().unwrap();
+        match typed_publication(&info, request_id, options, topic.clone(), args, kwargs) {
+            Ok((message, frame)) => info.submit_publication(message, frame),
+            Err(e) => {
+                info.abandon_publication(&topic, request_id);
+                Err(e)
+            }
+        }
+    }
+
+    /// Subscribes to `topic`, passing each event's arguments to `callback` as a `T`, which should
+    /// deserialize from a sequence, such as a tuple or a `Vec`.  Keyword arguments are ignored.
+    #[cfg(feature = "subscriber")]
+    pub fn subscribe_typed<T>(&mut self, topic: URI, mut callback: Box<FnMut(T)>) -> WampResult<Pending<Subscription>> where T: Deserialize + 'static {
+        let subscribed = topic.clone();
+        self.subscribe(topic, Box::new(move |args, _| {
+            match from_values(&subscribed, args) {
+                Ok(args) => callback(args),
+                Err(e) => warn!("Dropping an event: {}", e)
+            }
+        }))
+    }
+
+    /// Calls `procedure` with `args`, which must serialize as a sequence.  The result's arguments
+    /// are converted to an `R` once the router answers, so a procedure that returns one value is
+    /// read as a one element tuple.
+    #[cfg(feature = "caller")]
+    pub fn call_typed<A, R>(&mut self, procedure: URI, args: &A) -> WampResult<TypedCall<R>> where A: Serialize, R: Deserialize {
+        let args = try!(to_values(&procedure, args));
+        let handle = try!(self.call(procedure.clone(), Some(args), None));
+        Ok(TypedCall {
+            handle: handle,
+            procedure: procedure,
+            result: PhantomData
+        })
+    }
+}
+
+/// The publication of a typed payload, along with the frame it was encoded into if it could be
+/// encoded directly
+#[cfg(feature = "publisher")]
+fn typed_publication<A, K>(info: &ConnectionInfo, request_id: ID, options: PublishOptions, topic: URI, args: &A, kwargs: Option<&K>) -> WampResult<(Message, Option<Frame>)> where A: Serialize, K: Serialize {
+    let serialization = match info.serializer.serialization() {
+        Some(serialization) if info.compression.is_none() && !info.rate_limiter.is_limited(&topic.uri) => serialization,
+        _ => {
+            let args = try!(to_values(&topic, args));
+            let kwargs = match kwargs {
+                Some(kwargs) => Some(try!(to_values(&topic, kwargs))),
+                None => None
+            };
+            let (args, kwargs) = info.compress_payload(&topic, Some(args), kwargs);
+            return Ok((Message::Publish(request_id, options, topic, args, kwargs), None));
+        }
+    };
+    let frame = try!(encode_publish(serialization, request_id, &options, &topic, args, kwargs).map_err(|e| conversion_error(&topic, e)));
+    Ok((Message::Publish(request_id, options, topic, None, None), Some(frame)))
+}
+
+/// Converts a typed payload into values, by way of JSON
+fn to_values<T, V>(uri: &URI, payload: &T) -> WampResult<V> where T: Serialize, V: Deserialize {
+    let json = try!(serde_json::to_vec(payload).map_err(|e| conversion_error(uri, e)));
+    serde_json::from_slice(&json).map_err(|e| conversion_error(uri, e))
+}
+
+/// Converts received values into a typed payload, by way of JSON
+fn from_values<T>(uri: &URI, values: List) -> WampResult<T> where T: Deserialize {
+    to_values(uri, &values)
+}
+
+#[cfg(feature = "publisher")]
+fn encode_publish<A, K>(serialization: Serialization, request_id: ID, options: &PublishOptions, topic: &URI, args: &A, kwargs: Option<&K>) -> WampResult<Frame> where A: Serialize, K: Serialize {
+    match serialization {
+        Serialization::Json => {
+            let text = match kwargs {
+                Some(kwargs) => serde_json::to_string(&(PUBLISH, request_id, options, topic, args, kwargs)),
+                None => serde_json::to_string(&(PUBLISH, request_id, options, topic, args))
+            };
+            text.map(Frame::Text).map_err(|e| Error::new(ErrorKind::JSONError(e)))
+        },
+        Serialization::MsgPack => {
+            let mut buf: Vec<u8> = Vec::new();
+            {
+                let mut serializer = Serializer::with(&mut buf, StructMapWriter);
+                let result = match kwargs {
+                    Some(kwargs) => (PUBLISH, request_id, options, topic, args, kwargs).serialize(&mut serializer),
+                    None => (PUBLISH, request_id, options, topic, args).serialize(&mut serializer)
+                };
+                try!(result.map_err(|e| Error::new(ErrorKind::MsgPackEncodeError(e))));
+            }
+            Ok(Frame::Binary(buf))
+        },
+        Serialization::Cbor => {
+            let mut buf: Vec<u8> = Vec::new();
+            {
+                let mut serializer = CBORSerializer::new(&mut buf);
+                let result = match kwargs {
+                    Some(kwargs) => (PUBLISH, request_id, options, topic, args, kwargs).serialize(&mut serializer),
+                    None => (PUBLISH, request_id, options, topic, args).serialize(&mut serializer)
+                };
+                try!(result.map_err(|e| Error::new(ErrorKind::CBORError(e))));
+            }
+            Ok(Frame::Binary(buf))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::from_values;
+    use messages::{URI, Value};
+    use ::ErrorKind;
+
+    #[test]
+    #[cfg(feature = "publisher")]
+    fn typed_publications_match_value_publications() {
+        use super::encode_publish;
+        use codec::Frame;
+        use messages::{Message, PublishOptions};
+        use rmp_serde::Deserializer;
+        use serde_cbor;
+        use serializer::Serialization;
+        use serde::Deserialize;
+        use serde_json;
+        use std::collections::HashMap;
+
+        #[derive(Serialize)]
+        struct Reading {
+            sensor: String,
+            value: i64
+        }
+
+        let reading = Reading { sensor: "a".to_string(), value: 3 };
+        let mut kwargs = HashMap::new();
+        kwargs.insert("sensor".to_string(), Value::String("a".to_string()));
+        kwargs.insert("value".to_string(), Value::Integer(3));
+        let message = Message::Publish(7, PublishOptions::new(false), URI::new("t.a"), Some(vec![Value::Integer(1)]), Some(kwargs));
+
+        let json = encode_publish(Serialization::Json, 7, &PublishOptions::new(false), &URI::new("t.a"), &(1,), Some(&reading)).unwrap();
+        match json {
+            Frame::Text(text) => assert_eq!(serde_json::from_str::<Message>(&text).unwrap(), message),
+            Frame::Binary(_) => panic!("JSON publications should be text")
+        }
+        let msgpack = encode_publish(Serialization::MsgPack, 7, &PublishOptions::new(false), &URI::new("t.a"), &(1,), Some(&reading)).unwrap();
+        match msgpack {
+            Frame::Binary(data) => assert_eq!(Message::deserialize(&mut Deserializer::new(&data[..])).unwrap(), message),
+            Frame::Text(_) => panic!("MsgPack publications should be binary")
+        }
+        let cbor = encode_publish(Serialization::Cbor, 7, &PublishOptions::new(false), &URI::new("t.a"), &(1,), Some(&reading)).unwrap();
+        match cbor {
+            Frame::Binary(data) => assert_eq!(serde_cbor::from_slice::<Message>(&data).unwrap(), message),
+            Frame::Text(_) => panic!("CBOR publications should be binary")
+        }
+    }
+
+    #[test]
+    fn received_values_are_converted() {
+        let args = vec![Value::String("a".to_string()), Value::Integer(3)];
+        assert_eq!(from_values::<(String, i64)>(&URI::new("t.a"), args.clone()).unwrap(), ("a".to_string(), 3));
+        match from_values::<(i64, i64)>(&URI::new("t.a"), args).unwrap_err().kind() {
+            &ErrorKind::ConversionError(ref e) => assert_eq!(e.uri, "t.a"),
+            kind => panic!("Unexpected error {:?}", kind)
+        }
+    }
+}
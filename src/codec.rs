@@ -0,0 +1,30 @@
+//! Contains the `Codec` trait, which lets clients and routers speak WAMP over serializations
+//! other than JSON and MsgPack.
+//!
+//! A codec is identified by the websocket subprotocol it implements, such as
+//! `wamp.2.flatbuffers`.  Codecs added to a `Connection` are offered to the router ahead of the
+//! built in serializations, and codecs added to a `Router` are accepted alongside them, so a
+//! custom codec is only used when both ends know about it.  Since the subprotocol is chosen
+//! during the websocket handshake, before the client has said which realm it wants to join, a
+//! router's codecs are available in every realm.
+use messages::Message;
+
+/// A single websocket frame holding an encoded message
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>)
+}
+
+/// Converts WAMP messages to and from websocket frames.
+///
+/// `Message` implements `Serialize` and `Deserialize`, so codecs for serde based formats only
+/// need to call that format's serializer.
+pub trait Codec: Send + Sync {
+    /// The websocket subprotocol the codec implements
+    fn protocol(&self) -> &str;
+
+    fn encode(&self, message: &Message) -> Result<Frame, String>;
+
+    fn decode(&self, frame: Frame) -> Result<Message, String>;
+}
@@ -0,0 +1,342 @@
+//! Generates typed client stubs and callee traits from an interface description file.
+//!
+//! This is intended to be called from a build script, so that the procedures and topics that an
+//! application uses are described once in a language neutral file that can be shared with peers
+//! written in other languages.  The description is JSON:
+//!
+//! ```text
+//! {
+//!     "procedures": [
+//!         {"name": "add", "uri": "ca.test.add",
+//!          "args": [{"name": "a", "type": "integer"}, {"name": "b", "type": "integer"}],
+//!          "returns": [{"name": "sum", "type": "integer"}]}
+//!     ],
+//!     "topics": [
+//!         {"name": "status", "uri": "ca.test.status", "args": [{"name": "message", "type": "string"}]}
+//!     ]
+//! }
+//! ```
+//!
+//! The supported types are `integer`, `string`, `boolean`, `list`, `dict` and `any`.
+//!
+//! In `build.rs`:
+//!
+//! ```text
+//! wamp::codegen::generate("calculator.json", Path::new(&env::var("OUT_DIR").unwrap()).join("calculator.rs")).unwrap();
+//! ```
+//!
+//! And in the application:
+//!
+//! ```text
+//! mod calculator {
+//!     include!(concat!(env!("OUT_DIR"), "/calculator.rs"));
+//! }
+//! ```
+//!
+//! The generated module contains a `Stub` struct with a method for each procedure and topic, a
+//! `Callee` trait with a method for each procedure, and a `register()` function that registers
+//! every procedure of a `Callee` with a client.  The generated code uses `eventual`, so the
+//! application needs to depend on it.
+use messages::{Dict, List, Value, Reason, CallError};
+use serde_json;
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use ::{CallResult, WampResult, Error, ErrorKind};
+
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct InterfaceDescription {
+    #[serde(default)]
+    pub procedures: Vec<ProcedureDescription>,
+    #[serde(default)]
+    pub topics: Vec<TopicDescription>
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct ProcedureDescription {
+    pub name: String,
+    pub uri: String,
+    #[serde(default)]
+    pub args: Vec<ArgDescription>,
+    #[serde(default)]
+    pub returns: Vec<ArgDescription>
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct TopicDescription {
+    pub name: String,
+    pub uri: String,
+    #[serde(default)]
+    pub args: Vec<ArgDescription>
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct ArgDescription {
+    pub name: String,
+    #[serde(rename="type")]
+    pub arg_type: String
+}
+
+/// Conversion from an argument `Value`, used by generated code
+pub trait FromValue: Sized {
+    fn from_value(value: &Value) -> CallResult<Self>;
+}
+
+/// Conversion into an argument `Value`, used by generated code
+pub trait IntoValue {
+    fn into_value(self) -> Value;
+}
+
+fn invalid_argument(expected: &str, value: &Value) -> CallError {
+    CallError::new(Reason::InvalidArgument, Some(vec![Value::String(format!("Expected {}, got {}", expected, value.summarize()))]), None)
+}
+
+macro_rules! value_conversion {
+    ($t: ty, $variant: ident, $name: expr) => {
+        impl FromValue for $t {
+            fn from_value(value: &Value) -> CallResult<$t> {
+                match value {
+                    &Value::$variant(ref value) => Ok(value.clone()),
+                    _ => Err(invalid_argument($name, value))
+                }
+            }
+        }
+
+        impl IntoValue for $t {
+            fn into_value(self) -> Value {
+                Value::$variant(self)
+            }
+        }
+    }
+}
+
+value_conversion!(i64, Integer, "integer");
+value_conversion!(String, String, "string");
+value_conversion!(bool, Boolean, "boolean");
+value_conversion!(List, List, "list");
+value_conversion!(Dict, Dict, "dict");
+
+impl FromValue for Value {
+    fn from_value(value: &Value) -> CallResult<Value> {
+        Ok(value.clone())
+    }
+}
+
+impl IntoValue for Value {
+    fn into_value(self) -> Value {
+        self
+    }
+}
+
+/// Gets the required argument at `index`, converted to the type the interface declares
+pub fn get_arg<T: FromValue>(args: &List, index: usize) -> CallResult<T> {
+    match args.get(index) {
+        Some(value) => T::from_value(value),
+        None => Err(CallError::new(Reason::InvalidArgument, Some(vec![Value::String(format!("Missing argument {}", index))]), None))
+    }
+}
+
+fn invalid_interface(message: String) -> Error {
+    Error::new(ErrorKind::InvalidInterface(message))
+}
+
+fn rust_type(arg_type: &str) -> WampResult<&'static str> {
+    match arg_type {
+        "integer" => Ok("i64"),
+        "string" => Ok("String"),
+        "boolean" => Ok("bool"),
+        "list" => Ok("::wamp::List"),
+        "dict" => Ok("::wamp::Dict"),
+        "any" => Ok("::wamp::Value"),
+        _ => Err(invalid_interface(format!("Unknown type {}", arg_type)))
+    }
+}
+
+fn check_identifier(name: &str) -> WampResult<()> {
+    let valid = match name.chars().next() {
+        Some(c) => (c.is_ascii_alphabetic() || c == '_') && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        None => false
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(invalid_interface(format!("{} is not a valid identifier", name)))
+    }
+}
+
+/// Produces `name: type, ...` for a parameter list
+fn parameters(args: &[ArgDescription]) -> WampResult<String> {
+    let mut result = Vec::new();
+    for arg in args {
+        try!(check_identifier(&arg.name));
+        result.push(format!("{}: {}", arg.name, try!(rust_type(&arg.arg_type))));
+    }
+    Ok(result.join(", "))
+}
+
+/// Produces the type of a return value, which is a tuple unless there is exactly one value
+fn return_type(returns: &[ArgDescription]) -> WampResult<String> {
+    let mut types = Vec::new();
+    for arg in returns {
+        types.push(try!(rust_type(&arg.arg_type)));
+    }
+    if types.len() == 1 {
+        Ok(types[0].to_string())
+    } else {
+        Ok(format!("({})", types.join(", ")))
+    }
+}
+
+/// Produces an expression that extracts each of the described values from `args`
+fn extract(args: &[ArgDescription]) -> String {
+    let values: Vec<String> = (0..args.len()).map(|i| format!("try!(::wamp::codegen::get_arg(&args, {}))", i)).collect();
+    if values.len() == 1 {
+        values[0].clone()
+    } else {
+        format!("({})", values.join(", "))
+    }
+}
+
+/// The name to give the argument list in a closure, so that unused lists don't cause warnings
+fn args_name(args: &[ArgDescription]) -> &'static str {
+    if args.is_empty() { "_args" } else { "args" }
+}
+
+/// Produces an expression that builds an argument list from the named variables
+fn build_list(names: &[String]) -> String {
+    let values: Vec<String> = names.iter().map(|name| format!("::wamp::codegen::IntoValue::into_value({})", name)).collect();
+    format!("vec![{}]", values.join(", "))
+}
+
+/// Generates the Rust source for the given interface.
+pub fn generate_source(interface: &InterfaceDescription) -> WampResult<String> {
+    let mut out = String::new();
+    let mut callee = String::new();
+    let mut register = String::new();
+    writeln!(out, "// Generated by wamp::codegen.  Do not edit.").unwrap();
+    writeln!(out, "").unwrap();
+    writeln!(out, "pub struct Stub<'a> {{\n    client: &'a mut ::wamp::Client\n}}\n").unwrap();
+    writeln!(out, "impl<'a> Stub<'a> {{").unwrap();
+    writeln!(out, "    pub fn new(client: &'a mut ::wamp::Client) -> Stub<'a> {{\n        Stub {{ client: client }}\n    }}").unwrap();
+
+    for procedure in &interface.procedures {
+        try!(check_identifier(&procedure.name));
+        let params = try!(parameters(&procedure.args));
+        let returns = try!(return_type(&procedure.returns));
+        let names: Vec<String> = procedure.args.iter().map(|arg| arg.name.clone()).collect();
+        let separator = if params.is_empty() { "" } else { ", " };
+
+        writeln!(out, "").unwrap();
+        writeln!(out, "    /// Calls `{}`", procedure.uri).unwrap();
+        writeln!(out, "    pub fn {}(&mut self{}{}) -> ::wamp::WampResult<::eventual::Future<{}, ::wamp::CallError>> {{", procedure.name, separator, params, returns).unwrap();
+        writeln!(out, "        let future = try!(self.client.call(::wamp::URI::new({:?}), Some({}), None));", procedure.uri, build_list(&names)).unwrap();
+        writeln!(out, "        Ok(::eventual::Async::and_then(future, |({}, _kwargs): (::wamp::List, ::wamp::Dict)| -> ::wamp::CallResult<{}> {{", args_name(&procedure.returns), returns).unwrap();
+        writeln!(out, "            Ok({})", extract(&procedure.returns)).unwrap();
+        writeln!(out, "        }}))").unwrap();
+        writeln!(out, "    }}").unwrap();
+
+        writeln!(callee, "    /// Handles calls to `{}`", procedure.uri).unwrap();
+        writeln!(callee, "    fn {}(&mut self{}{}) -> ::wamp::CallResult<{}>;", procedure.name, separator, params, returns).unwrap();
+
+        let result_names: Vec<String> = (0..procedure.returns.len()).map(|i| format!("result_{}", i)).collect();
+        let result_pattern = if result_names.len() == 1 { result_names[0].clone() } else { format!("({})", result_names.join(", ")) };
+        let call_args: Vec<String> = (0..procedure.args.len()).map(|i| format!("try!(::wamp::codegen::get_arg(&args, {}))", i)).collect();
+        writeln!(register, "    let handler = callee.clone();").unwrap();
+        writeln!(register, "    futures.push(try!(client.register(::wamp::URI::new({:?}), Box::new(move |{}: ::wamp::List, _kwargs: ::wamp::Dict| {{", procedure.uri, args_name(&procedure.args)).unwrap();
+        writeln!(register, "        let {} = try!(handler.borrow_mut().{}({}));", result_pattern, procedure.name, call_args.join(", ")).unwrap();
+        writeln!(register, "        Ok((Some({}), None))", build_list(&result_names)).unwrap();
+        writeln!(register, "    }}))));").unwrap();
+    }
+
+    for topic in &interface.topics {
+        try!(check_identifier(&topic.name));
+        let params = try!(parameters(&topic.args));
+        let names: Vec<String> = topic.args.iter().map(|arg| arg.name.clone()).collect();
+        let separator = if params.is_empty() { "" } else { ", " };
+        let mut callback_types = Vec::new();
+        for arg in &topic.args {
+            callback_types.push(try!(rust_type(&arg.arg_type)));
+        }
+
+        writeln!(out, "").unwrap();
+        writeln!(out, "    /// Publishes to `{}`", topic.uri).unwrap();
+        writeln!(out, "    pub fn publish_{}(&mut self{}{}) -> ::wamp::WampResult<()> {{", topic.name, separator, params).unwrap();
+        writeln!(out, "        self.client.publish(::wamp::URI::new({:?}), Some({}), None)", topic.uri, build_list(&names)).unwrap();
+        writeln!(out, "    }}").unwrap();
+        writeln!(out, "").unwrap();
+        writeln!(out, "    /// Subscribes to `{}`.  Events whose arguments don't match the interface are dropped.", topic.uri).unwrap();
+        writeln!(out, "    pub fn subscribe_{}<F: FnMut({}) + 'static>(&mut self, mut callback: F) -> ::wamp::WampResult<::eventual::Future<::wamp::client::Subscription, ::wamp::CallError>> {{", topic.name, callback_types.join(", ")).unwrap();
+        writeln!(out, "        self.client.subscribe(::wamp::URI::new({:?}), Box::new(move |{}: ::wamp::List, _kwargs: ::wamp::Dict| {{", topic.uri, args_name(&topic.args)).unwrap();
+        for (i, name) in names.iter().enumerate() {
+            writeln!(out, "            let {} = match ::wamp::codegen::get_arg(&args, {}) {{ Ok(value) => value, Err(_) => return }};", name, i).unwrap();
+        }
+        writeln!(out, "            callback({});", names.join(", ")).unwrap();
+        writeln!(out, "        }}))").unwrap();
+        writeln!(out, "    }}").unwrap();
+    }
+    writeln!(out, "}}\n").unwrap();
+
+    writeln!(out, "pub trait Callee {{").unwrap();
+    out.push_str(&callee);
+    writeln!(out, "}}\n").unwrap();
+
+    writeln!(out, "/// Registers every procedure in the interface, dispatching invocations to `callee`").unwrap();
+    writeln!(out, "pub fn register<T: Callee + 'static>(client: &mut ::wamp::Client, callee: T) -> ::wamp::WampResult<Vec<::eventual::Future<::wamp::client::Registration, ::wamp::CallError>>> {{").unwrap();
+    writeln!(out, "    let callee = ::std::rc::Rc::new(::std::cell::RefCell::new(callee));").unwrap();
+    writeln!(out, "    let mut futures = Vec::new();").unwrap();
+    out.push_str(&register);
+    writeln!(out, "    Ok(futures)").unwrap();
+    writeln!(out, "}}").unwrap();
+    Ok(out)
+}
+
+/// Reads the interface description at `input` and writes the generated source to `output`.
+pub fn generate<P: AsRef<Path>, Q: AsRef<Path>>(input: P, output: Q) -> WampResult<()> {
+    let mut contents = String::new();
+    let mut file = try!(File::open(input).map_err(|e| Error::new(ErrorKind::IOError(e))));
+    try!(file.read_to_string(&mut contents).map_err(|e| Error::new(ErrorKind::IOError(e))));
+    let interface: InterfaceDescription = try!(serde_json::from_str(&contents).map_err(|e| Error::new(ErrorKind::JSONError(e))));
+    let source = try!(generate_source(&interface));
+    let mut file = try!(File::create(output).map_err(|e| Error::new(ErrorKind::IOError(e))));
+    file.write_all(source.as_bytes()).map_err(|e| Error::new(ErrorKind::IOError(e)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{generate_source, get_arg, InterfaceDescription};
+    use messages::{Value, Reason};
+    use serde_json;
+
+    #[test]
+    fn generate_from_description() {
+        let interface: InterfaceDescription = serde_json::from_str(r#"{
+            "procedures": [{"name": "add", "uri": "ca.test.add",
+                            "args": [{"name": "a", "type": "integer"}, {"name": "b", "type": "integer"}],
+                            "returns": [{"name": "sum", "type": "integer"}]}],
+            "topics": [{"name": "status", "uri": "ca.test.status", "args": [{"name": "message", "type": "string"}]}]
+        }"#).unwrap();
+        let source = generate_source(&interface).unwrap();
+        assert!(source.contains("pub fn add(&mut self, a: i64, b: i64) -> ::wamp::WampResult<::eventual::Future<i64, ::wamp::CallError>>"));
+        assert!(source.contains("fn add(&mut self, a: i64, b: i64) -> ::wamp::CallResult<i64>;"));
+        assert!(source.contains("pub fn publish_status(&mut self, message: String)"));
+        assert!(source.contains("pub fn subscribe_status<F: FnMut(String) + 'static>"));
+    }
+
+    #[test]
+    fn invalid_descriptions() {
+        let interface: InterfaceDescription = serde_json::from_str(r#"{"procedures": [{"name": "add", "uri": "ca.test.add", "args": [{"name": "a", "type": "float"}]}]}"#).unwrap();
+        assert!(generate_source(&interface).is_err());
+        let interface: InterfaceDescription = serde_json::from_str(r#"{"topics": [{"name": "not-an-ident", "uri": "ca.test.status"}]}"#).unwrap();
+        assert!(generate_source(&interface).is_err());
+    }
+
+    #[test]
+    fn typed_arguments() {
+        let args = vec![Value::Integer(3), Value::String("three".to_string())];
+        assert_eq!(get_arg::<i64>(&args, 0).unwrap(), 3);
+        assert_eq!(get_arg::<String>(&args, 1).unwrap(), "three");
+        assert_eq!(get_arg::<i64>(&args, 1).unwrap_err().get_reason(), &Reason::InvalidArgument);
+        assert_eq!(get_arg::<i64>(&args, 2).unwrap_err().get_reason(), &Reason::InvalidArgument);
+    }
+}
@@ -0,0 +1,182 @@
+//! WAMP-CRA challenge-response authentication, along with the SHA-256, HMAC and PBKDF2
+//! functions it is built on.
+//!
+//! The router's challenge is a string, which the client signs with HMAC-SHA256 keyed by its
+//! secret.  When the challenge includes a salt, the key is derived from the secret with PBKDF2
+//! first, so that the router only needs to store the derived key.
+use messages::{Dict, Value};
+use utils::base64_encode;
+
+pub const WAMPCRA_AUTH: &'static str = "wampcra";
+
+// The iteration count and key length Autobahn uses when a salted challenge doesn't give them
+const DEFAULT_ITERATIONS: u64 = 1000;
+const DEFAULT_KEY_LENGTH: u64 = 32;
+
+const BLOCK_SIZE: usize = 64;
+
+const ROUND_CONSTANTS: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2
+];
+
+/// Hashes `data` with SHA-256
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut state: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19
+    ];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % BLOCK_SIZE != 56 {
+        message.push(0);
+    }
+    let bit_length = (data.len() as u64).wrapping_mul(8);
+    for shift in (0..8).rev() {
+        message.push((bit_length >> (shift * 8)) as u8);
+    }
+
+    for block in message.chunks(BLOCK_SIZE) {
+        let mut schedule = [0u32; 64];
+        for (i, word) in block.chunks(4).enumerate() {
+            schedule[i] = (word[0] as u32) << 24 | (word[1] as u32) << 16 | (word[2] as u32) << 8 | word[3] as u32;
+        }
+        for i in 16..64 {
+            let s0 = schedule[i - 15].rotate_right(7) ^ schedule[i - 15].rotate_right(18) ^ (schedule[i - 15] >> 3);
+            let s1 = schedule[i - 2].rotate_right(17) ^ schedule[i - 2].rotate_right(19) ^ (schedule[i - 2] >> 10);
+            schedule[i] = schedule[i - 16].wrapping_add(s0).wrapping_add(schedule[i - 7]).wrapping_add(s1);
+        }
+
+        let mut working = state;
+        for i in 0..64 {
+            let [a, b, c, d, e, f, g, h] = working;
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let choice = (e & f) ^ (!e & g);
+            let temp1 = h.wrapping_add(s1).wrapping_add(choice).wrapping_add(ROUND_CONSTANTS[i]).wrapping_add(schedule[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let majority = (a & b) ^ (a & c) ^ (b & c);
+            let temp2 = s0.wrapping_add(majority);
+            working = [temp1.wrapping_add(temp2), a, b, c, d.wrapping_add(temp1), e, f, g];
+        }
+        for (word, added) in state.iter_mut().zip(working.iter()) {
+            *word = word.wrapping_add(*added);
+        }
+    }
+
+    let mut digest = [0u8; 32];
+    for (i, word) in state.iter().enumerate() {
+        for j in 0..4 {
+            digest[i * 4 + j] = (word >> (24 - j * 8)) as u8;
+        }
+    }
+    digest
+}
+
+/// Computes the HMAC-SHA256 of `data` with `key`
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut block_key = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block_key[..32].copy_from_slice(&sha256(key));
+    } else {
+        block_key[..key.len()].copy_from_slice(key);
+    }
+    let mut inner: Vec<u8> = block_key.iter().map(|byte| byte ^ 0x36).collect();
+    inner.extend_from_slice(data);
+    let mut outer: Vec<u8> = block_key.iter().map(|byte| byte ^ 0x5c).collect();
+    outer.extend_from_slice(&sha256(&inner));
+    sha256(&outer)
+}
+
+/// Derives a key of `key_length` bytes from `password` with PBKDF2, using HMAC-SHA256
+pub fn pbkdf2_hmac_sha256(password: &[u8], salt: &[u8], iterations: u64, key_length: usize) -> Vec<u8> {
+    let mut key = Vec::with_capacity(key_length);
+    let mut block_index: u32 = 1;
+    while key.len() < key_length {
+        let mut salted = salt.to_vec();
+        salted.extend_from_slice(&[(block_index >> 24) as u8, (block_index >> 16) as u8, (block_index >> 8) as u8, block_index as u8]);
+        let mut round = hmac_sha256(password, &salted);
+        let mut block = round;
+        for _ in 1..iterations {
+            round = hmac_sha256(password, &round);
+            for (byte, next) in block.iter_mut().zip(round.iter()) {
+                *byte ^= *next;
+            }
+        }
+        key.extend_from_slice(&block);
+        block_index += 1;
+    }
+    key.truncate(key_length);
+    key
+}
+
+fn get_integer(extra: &Dict, key: &str, default: u64) -> Result<u64, String> {
+    match extra.get(key) {
+        None => Ok(default),
+        Some(&Value::Integer(value)) if value > 0 => Ok(value as u64),
+        Some(&Value::UnsignedInteger(value)) if value > 0 => Ok(value),
+        Some(_) => Err(format!("the challenge's {} is not a positive integer", key))
+    }
+}
+
+/// Answers a WAMP-CRA challenge, given the challenge's extra details, by signing it with
+/// `secret`.  Returns the base64 signature.
+pub fn sign_challenge(secret: &str, extra: &Dict) -> Result<String, String> {
+    let challenge = match extra.get("challenge") {
+        Some(&Value::String(ref challenge)) => challenge,
+        _ => return Err("the challenge has no challenge string to sign".to_string())
+    };
+    let key = match extra.get("salt") {
+        Some(&Value::String(ref salt)) => {
+            let iterations = try!(get_integer(extra, "iterations", DEFAULT_ITERATIONS));
+            let key_length = try!(get_integer(extra, "keylen", DEFAULT_KEY_LENGTH));
+            // The derived key is used in its base64 form, as other WAMP implementations do
+            base64_encode(&pbkdf2_hmac_sha256(secret.as_bytes(), salt.as_bytes(), iterations, key_length as usize)).into_bytes()
+        },
+        Some(_) => return Err("the challenge's salt is not a string".to_string()),
+        None => secret.as_bytes().to_vec()
+    };
+    Ok(base64_encode(&hmac_sha256(&key, challenge.as_bytes())))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{sha256, hmac_sha256, pbkdf2_hmac_sha256, sign_challenge};
+    use messages::Value;
+    use std::collections::HashMap;
+    use utils::base64_encode;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn known_digests() {
+        assert_eq!(hex(&sha256(b"")), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(hex(&sha256(b"abc")), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(hex(&sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")), "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+        assert_eq!(hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")), "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+        assert_eq!(hex(&hmac_sha256(&[0xaa; 131], b"Test Using Larger Than Block-Size Key - Hash Key First")), "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54");
+        assert_eq!(hex(&pbkdf2_hmac_sha256(b"password", b"salt", 1, 32)), "120fb6cffcf8b32c43e7225256c4f837a86548c92ccc35480805987cb70be17b");
+        assert_eq!(hex(&pbkdf2_hmac_sha256(b"password", b"salt", 2, 32)), "ae4d0c95af6b46d32d0adff928f06dd02a303f8ef3c251dfd6e2d85a95474c43");
+    }
+
+    #[test]
+    fn sign_challenges() {
+        let mut extra = HashMap::new();
+        assert!(sign_challenge("secret", &extra).is_err());
+        extra.insert("challenge".to_string(), Value::String("{\"nonce\": \"abc\"}".to_string()));
+        assert_eq!(sign_challenge("secret", &extra), Ok(base64_encode(&hmac_sha256(b"secret", b"{\"nonce\": \"abc\"}"))));
+
+        extra.insert("salt".to_string(), Value::String("salt".to_string()));
+        extra.insert("iterations".to_string(), Value::Integer(2));
+        let key = base64_encode(&pbkdf2_hmac_sha256(b"secret", b"salt", 2, 32));
+        assert_eq!(sign_challenge("secret", &extra), Ok(base64_encode(&hmac_sha256(key.as_bytes(), b"{\"nonce\": \"abc\"}"))));
+        extra.insert("keylen".to_string(), Value::String("32".to_string()));
+        assert!(sign_challenge("secret", &extra).is_err());
+    }
+}
@@ -0,0 +1,207 @@
+//! A C compatible interface to the client, for embedding it in applications written in other
+//! languages.  Requires the `ffi` feature.
+//!
+//! Arguments and keyword arguments cross the boundary as JSON strings: a JSON array for the
+//! arguments and a JSON object for the keyword arguments.  Either may be passed as a null
+//! pointer to leave it out.  Strings returned by these functions must be released with
+//! `wamp_string_free()`.
+//!
+//! Every pointer passed to these functions must either be null or valid: strings must be nul
+//! terminated, and clients must have come from `wamp_connect()` and not yet been passed to
+//! `wamp_shutdown()`.  Functions given a string that isn't UTF-8 fail.
+//!
+//! To produce a shared library, build the crate with the `cdylib` crate type, e.g.
+//! `cargo rustc --release --features ffi --crate-type cdylib`.
+use client::{Client, Connection};
+use messages::{URI, Dict, List};
+use eventual::Async;
+use serde_json;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+/// Called with the user data pointer given to `wamp_subscribe()`, and the event's arguments and
+/// keyword arguments as JSON.  The strings are only valid for the duration of the call.
+pub type WampEventCallback = extern "C" fn(user_data: *mut c_void, args: *const c_char, kwargs: *const c_char);
+
+/// An opaque handle to a connected client
+pub struct WampClient {
+    client: Client
+}
+
+/// Reads a string argument, which is `None` if the pointer is null.  Fails if the string isn't
+/// UTF-8, so that a garbled payload isn't mistaken for a missing one.
+unsafe fn to_str<'a>(value: *const c_char) -> Result<Option<&'a str>, ()> {
+    if value.is_null() {
+        Ok(None)
+    } else {
+        CStr::from_ptr(value).to_str().map(Some).map_err(|_| ())
+    }
+}
+
+/// Reads and parses the JSON payload of a publish or call
+unsafe fn read_payload(args: *const c_char, kwargs: *const c_char) -> Option<(Option<List>, Option<Dict>)> {
+    match (to_str(args), to_str(kwargs)) {
+        (Ok(args), Ok(kwargs)) => parse_payload(args, kwargs),
+        _ => None
+    }
+}
+
+/// Parses the JSON payload of a publish or call.  Returns `None` if either part is malformed.
+fn parse_payload(args: Option<&str>, kwargs: Option<&str>) -> Option<(Option<List>, Option<Dict>)> {
+    let args = match args {
+        Some(args) => match serde_json::from_str(args) {
+            Ok(args) => Some(args),
+            Err(_) => return None
+        },
+        None => None
+    };
+    let kwargs = match kwargs {
+        Some(kwargs) => match serde_json::from_str(kwargs) {
+            Ok(kwargs) => Some(kwargs),
+            Err(_) => return None
+        },
+        None => None
+    };
+    Some((args, kwargs))
+}
+
+fn to_c_string<T: ::serde::Serialize>(value: &T) -> CString {
+    // Serialized JSON never contains a nul byte, since they are escaped in strings
+    CString::new(serde_json::to_string(value).unwrap()).unwrap()
+}
+
+/// Connects to the router at `url` and joins `realm`.  Returns null if the connection fails.
+#[no_mangle]
+pub unsafe extern "C" fn wamp_connect(url: *const c_char, realm: *const c_char) -> *mut WampClient {
+    let (url, realm) = match (to_str(url), to_str(realm)) {
+        (Ok(Some(url)), Ok(Some(realm))) => (url, realm),
+        _ => return ptr::null_mut()
+    };
+    match Connection::new(url, realm).connect() {
+        Ok(client) => Box::into_raw(Box::new(WampClient { client: client })),
+        Err(e) => {
+            error!("Could not connect to {}: {}", url, e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Subscribes to `topic`, waiting until the router has confirmed the subscription.  Returns 0 on
+/// success and -1 on failure.
+#[no_mangle]
+#[cfg(feature = "subscriber")]
+pub unsafe extern "C" fn wamp_subscribe(client: *mut WampClient, topic: *const c_char, callback: WampEventCallback, user_data: *mut c_void) -> c_int {
+    let topic = match (client.is_null(), to_str(topic)) {
+        (false, Ok(Some(topic))) => topic,
+        _ => return -1
+    };
+    let uri = URI::new(topic);
+    let result = (*client).client.subscribe(uri.clone(), Box::new(move |args, kwargs| {
+        // Unwinding into the session's thread would take the connection down with it
+        let delivered = panic::catch_unwind(AssertUnwindSafe(|| {
+            let args = to_c_string(&args);
+            let kwargs = to_c_string(&kwargs);
+            callback(user_data, args.as_ptr(), kwargs.as_ptr());
+        }));
+        if delivered.is_err() {
+            error!("Could not pass an event on {} to its callback", uri.uri);
+        }
+    }));
+    match result {
+        Ok(pending) => if pending.wait().is_ok() { 0 } else { -1 },
+        Err(_) => -1
+    }
+}
+
+/// Publishes an event to `topic`.  Returns 0 on success and -1 on failure.
+#[no_mangle]
+#[cfg(feature = "publisher")]
+pub unsafe extern "C" fn wamp_publish(client: *mut WampClient, topic: *const c_char, args: *const c_char, kwargs: *const c_char) -> c_int {
+    let topic = match (client.is_null(), to_str(topic)) {
+        (false, Ok(Some(topic))) => topic,
+        _ => return -1
+    };
+    let (args, kwargs) = match read_payload(args, kwargs) {
+        Some(payload) => payload,
+        None => return -1
+    };
+    match (*client).client.publish(URI::new(topic), args, kwargs) {
+        Ok(()) => 0,
+        Err(_) => -1
+    }
+}
+
+/// Calls `procedure` and waits for the result, which is returned as a JSON array of the form
+/// `[args, kwargs]`.  Returns null if the call fails.
+#[no_mangle]
+#[cfg(feature = "caller")]
+pub unsafe extern "C" fn wamp_call(client: *mut WampClient, procedure: *const c_char, args: *const c_char, kwargs: *const c_char) -> *mut c_char {
+    let procedure = match (client.is_null(), to_str(procedure)) {
+        (false, Ok(Some(procedure))) => procedure,
+        _ => return ptr::null_mut()
+    };
+    let (args, kwargs) = match read_payload(args, kwargs) {
+        Some(payload) => payload,
+        None => return ptr::null_mut()
+    };
+    let result = match (*client).client.call(URI::new(procedure), args, kwargs) {
+        Ok(pending) => pending.wait(),
+        Err(_) => return ptr::null_mut()
+    };
+    match result {
+        Ok(result) => to_c_string(&result).into_raw(),
+        Err(_) => ptr::null_mut()
+    }
+}
+
+/// Releases a string returned by one of these functions
+#[no_mangle]
+pub unsafe extern "C" fn wamp_string_free(value: *mut c_char) {
+    if !value.is_null() {
+        drop(CString::from_raw(value));
+    }
+}
+
+/// Leaves the realm, waits for the router to acknowledge it and releases the client.
+#[no_mangle]
+pub unsafe extern "C" fn wamp_shutdown(client: *mut WampClient) {
+    if client.is_null() {
+        return;
+    }
+    let mut client = Box::from_raw(client);
+    if let Ok(pending) = client.client.shutdown() {
+        pending.wait().ok();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_payload, read_payload, to_str};
+    use messages::Value;
+    use std::ffi::CString;
+    use std::ptr;
+
+    #[test]
+    fn payload_parsing() {
+        let (args, kwargs) = parse_payload(Some("[1, \"two\"]"), None).unwrap();
+        assert_eq!(args, Some(vec![Value::Integer(1), Value::String("two".to_string())]));
+        assert_eq!(kwargs, None);
+        assert!(parse_payload(Some("[1, 2"), None).is_none());
+        assert!(parse_payload(None, Some("[]")).is_none());
+    }
+
+    #[test]
+    fn strings_must_be_utf8() {
+        let args = CString::new("[1]").unwrap();
+        let garbled = CString::new(vec![b'[', 0xff, b']']).unwrap();
+        unsafe {
+            assert_eq!(to_str(ptr::null()), Ok(None));
+            assert_eq!(to_str(args.as_ptr()), Ok(Some("[1]")));
+            assert!(to_str(garbled.as_ptr()).is_err());
+            assert_eq!(read_payload(args.as_ptr(), ptr::null()), Some((Some(vec![Value::Integer(1)]), None)));
+            assert!(read_payload(args.as_ptr(), garbled.as_ptr()).is_none());
+        }
+    }
+}
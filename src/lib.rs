@@ -6,27 +6,58 @@ extern crate ws;
 extern crate url;
 extern crate rmp;
 extern crate rmp_serde;
+extern crate serde_cbor;
 extern crate rand;
 extern crate eventual;
+#[cfg(feature = "gzip")]
+extern crate flate2;
+#[cfg(feature = "ssl")]
+extern crate openssl;
+#[cfg(feature = "pinning")]
+extern crate libc;
 
 #[macro_use]
 extern crate log;
 
 mod messages;
 mod utils;
+mod cra;
 pub mod client;
+#[cfg(feature = "router")]
 pub mod router;
+pub mod codec;
+pub mod serializer;
+pub mod codegen;
+pub mod matching;
+pub mod logging;
+pub mod store;
+pub mod transcode;
+#[cfg(all(feature = "publisher", feature = "subscriber"))]
+pub mod loadtest;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(test)]
+mod testing;
 
 use ws::Error as WSError;
 use std::fmt;
 use url::ParseError;
 use std::sync::mpsc::SendError;
+use std::io::Error as IOError;
 use serde_json::Error as JSONError;
 use rmp_serde::decode::Error as MsgPackError;
+use rmp_serde::encode::Error as MsgPackEncodeError;
+use serde_cbor::Error as CBORError;
 
-pub use messages::{URI, Dict, List, Value, Reason, MatchingPolicy, InvocationPolicy, CallError, ArgList, ArgDict};
-use messages::{ErrorType, Message};
+pub use messages::{URI, SharedStr, Dict, List, Value, Reason, MatchingPolicy, InvocationPolicy, CallError, ArgList, ArgDict, PublishOptions, SubscribeOptions, EventDetails, RegisterOptions, CallOptions, CancelMode, InvocationDetails, Message};
+pub use messages::validation::{ValidationMode, ProtocolViolation};
+use messages::ErrorType;
 pub use client::{Client, Connection};
+#[cfg(feature = "publisher")]
+pub use client::publish_once;
+#[cfg(feature = "caller")]
+pub use client::call_once;
+#[cfg(feature = "router")]
 pub use router::Router;
 
 pub type CallResult<T> = Result<T, CallError>;
@@ -48,11 +79,23 @@ pub enum ErrorKind {
     Closing(String),
     JSONError(JSONError),
     MsgPackError(MsgPackError),
+    MsgPackEncodeError(MsgPackEncodeError),
+    CBORError(CBORError),
     MalformedData,
     InvalidMessageType(Message),
     InvalidState(&'static str),
     Timeout,
     ErrorReason(ErrorType, ID, Reason),
+    IOError(IOError),
+    InvalidInterface(String),
+    CodecError(String),
+    ProtocolViolation(ProtocolViolation),
+    NotAllowed(String),
+    RateLimited(String),
+    CallFailed(CallError),
+    ConversionError(client::ConversionError),
+    InvalidRealm(String),
+    TransportError(String),
 }
 impl Error {
     fn new(kind: ErrorKind) -> Error {
@@ -69,6 +112,11 @@ impl Error {
     fn get_kind(self) -> ErrorKind{
         self.kind
     }
+
+    /// What went wrong, for callers that handle some errors differently
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
 }
 
 
@@ -89,11 +137,23 @@ impl ErrorKind {
             &ErrorKind::Closing(ref s) => s.clone(),
             &ErrorKind::JSONError(ref e) => e.to_string(),
             &ErrorKind::MsgPackError(ref e) => e.to_string(),
+            &ErrorKind::MsgPackEncodeError(ref e) => e.to_string(),
+            &ErrorKind::CBORError(ref e) => e.to_string(),
             &ErrorKind::MalformedData => "Malformed Data".to_string(),
             &ErrorKind::InvalidMessageType(ref t) => format!("Invalid Message Type: {:?}", t),
             &ErrorKind::InvalidState(ref s) => s.to_string(),
             &ErrorKind::Timeout => "Connection timed out".to_string(),
             &ErrorKind::ErrorReason(_, _, ref s) => s.to_string(),
+            &ErrorKind::IOError(ref e) => e.to_string(),
+            &ErrorKind::InvalidInterface(ref s) => s.clone(),
+            &ErrorKind::CodecError(ref s) => s.clone(),
+            &ErrorKind::ProtocolViolation(ref v) => v.to_string(),
+            &ErrorKind::NotAllowed(ref uri) => format!("{} is not on the allow list", uri),
+            &ErrorKind::RateLimited(ref uri) => format!("Publications to {} are being rate limited", uri),
+            &ErrorKind::CallFailed(ref e) => e.to_string(),
+            &ErrorKind::ConversionError(ref e) => e.to_string(),
+            &ErrorKind::InvalidRealm(ref s) => s.clone(),
+            &ErrorKind::TransportError(ref s) => s.clone(),
         }
     }
 }
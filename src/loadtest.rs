@@ -0,0 +1,203 @@
+//! Generates load on a router and measures how quickly it delivers events, to size router
+//! deployments.
+//!
+//! A load test opens a number of sessions, each subscribed to the same topic, and publishes to
+//! that topic from each session in turn at a target rate.  Every publication carries the time it
+//! was published, so each session that receives it can tell how long delivery took.  Routers
+//! don't send publications back to their publisher, so each publication is expected to reach
+//! every session but one.
+//!
+//! The latencies include the time the client spends serializing and deserializing, since both
+//! ends of the measurement run in this process.  The `loadtest` example runs a load test from the
+//! command line.
+use client::Connection;
+use messages::{URI, Value};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use ::{WampResult, Error, ErrorKind};
+
+/// The settings of a load test.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadTest {
+    url: String,
+    realm: String,
+    topic: String,
+    sessions: usize,
+    rate: u32,
+    duration: Duration,
+    payload_size: usize
+}
+
+/// What a load test measured.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadReport {
+    pub sessions: usize,
+    pub published: u64,
+    /// How many events should have been delivered, given how many were published
+    pub expected_deliveries: u64,
+    pub delivered: u64,
+    /// How long publishing took, which may be longer than asked if the target rate couldn't be
+    /// kept up
+    pub elapsed: Duration,
+    pub latency_p50: Duration,
+    pub latency_p90: Duration,
+    pub latency_p99: Duration,
+    pub latency_max: Duration
+}
+
+impl LoadTest {
+    /// A load test of the router at `url` that publishes 100 events a second from two sessions
+    /// for ten seconds
+    pub fn new(url: &str, realm: &str) -> LoadTest {
+        LoadTest {
+            url: url.to_string(),
+            realm: realm.to_string(),
+            topic: "wamp.loadtest".to_string(),
+            sessions: 2,
+            rate: 100,
+            duration: Duration::from_secs(10),
+            payload_size: 0
+        }
+    }
+
+    /// Sets the topic to publish to
+    pub fn with_topic(mut self, topic: &str) -> LoadTest {
+        self.topic = topic.to_string();
+        self
+    }
+
+    /// Sets how many sessions to open.  At least two are needed.
+    pub fn with_sessions(mut self, sessions: usize) -> LoadTest {
+        self.sessions = sessions;
+        self
+    }
+
+    /// Sets how many events to publish each second, across all sessions
+    pub fn with_rate(mut self, rate: u32) -> LoadTest {
+        self.rate = rate;
+        self
+    }
+
+    /// Sets how long to publish for
+    pub fn with_duration(mut self, duration: Duration) -> LoadTest {
+        self.duration = duration;
+        self
+    }
+
+    /// Pads each publication with a string of `size` bytes
+    pub fn with_payload_size(mut self, size: usize) -> LoadTest {
+        self.payload_size = size;
+        self
+    }
+
+    /// Runs the load test, blocking until it has finished.  Events still in flight when
+    /// publishing stops are given a second to arrive.
+    pub fn run(&self) -> WampResult<LoadReport> {
+        if self.sessions < 2 {
+            return Err(Error::new(ErrorKind::InvalidState("A load test needs at least two sessions")));
+        }
+        if self.rate == 0 {
+            return Err(Error::new(ErrorKind::InvalidState("A load test needs a publishing rate")));
+        }
+        let epoch = Instant::now();
+        let latencies = Arc::new(Mutex::new(Vec::new()));
+        let mut clients = Vec::with_capacity(self.sessions);
+        for _ in 0..self.sessions {
+            let mut client = try!(Connection::new(&self.url, &self.realm).connect());
+            let latencies = latencies.clone();
+            let subscription = try!(client.subscribe(URI::new(&self.topic), Box::new(move |args, _| {
+                if let Some(&Value::Integer(sent)) = args.get(0) {
+                    let received = as_micros(epoch.elapsed()) as i64;
+                    latencies.lock().unwrap().push((received - sent).max(0) as u64);
+                }
+            })));
+            try!(subscription.wait().map_err(|_| Error::new(ErrorKind::InvalidState("The router refused the load test's subscription"))));
+            clients.push(client);
+        }
+        info!("Opened {} sessions, publishing {} events a second to {}", self.sessions, self.rate, self.topic);
+
+        let padding = Value::String(String::from_utf8(vec![b'x'; self.payload_size]).unwrap());
+        let total = (self.rate as u64 * as_micros(self.duration)) / 1_000_000;
+        let interval = 1_000_000 / self.rate as u64;
+        let start = Instant::now();
+        for sequence in 0..total {
+            let due = sequence * interval;
+            let now = as_micros(start.elapsed());
+            if due > now {
+                thread::sleep(Duration::from_micros(due - now));
+            }
+            let sent = as_micros(epoch.elapsed()) as i64;
+            let client = &mut clients[sequence as usize % self.sessions];
+            try!(client.publish(URI::new(&self.topic), Some(vec![Value::Integer(sent), padding.clone()]), None));
+        }
+        let elapsed = start.elapsed();
+        let expected = total * (self.sessions as u64 - 1);
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while (latencies.lock().unwrap().len() as u64) < expected && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        for client in clients.iter_mut() {
+            client.shutdown().ok();
+        }
+
+        let mut latencies = latencies.lock().unwrap().clone();
+        latencies.sort();
+        Ok(LoadReport {
+            sessions: self.sessions,
+            published: total,
+            expected_deliveries: expected,
+            delivered: latencies.len() as u64,
+            elapsed: elapsed,
+            latency_p50: Duration::from_micros(percentile(&latencies, 50.0)),
+            latency_p90: Duration::from_micros(percentile(&latencies, 90.0)),
+            latency_p99: Duration::from_micros(percentile(&latencies, 99.0)),
+            latency_max: Duration::from_micros(latencies.last().cloned().unwrap_or(0))
+        })
+    }
+}
+
+fn as_micros(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000 + duration.subsec_nanos() as u64 / 1_000
+}
+
+/// The nearest-rank percentile of sorted values
+fn percentile(sorted: &[u64], percent: f64) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = ((percent / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.max(1).min(sorted.len()) - 1]
+}
+
+impl fmt::Display for LoadReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let seconds = self.elapsed.as_secs() as f64 + self.elapsed.subsec_nanos() as f64 / 1e9;
+        let rate = if seconds > 0.0 { self.published as f64 / seconds } else { 0.0 };
+        try!(writeln!(f, "sessions:   {}", self.sessions));
+        try!(writeln!(f, "published:  {} in {:.2}s ({:.0}/s)", self.published, seconds, rate));
+        try!(writeln!(f, "delivered:  {} of {}", self.delivered, self.expected_deliveries));
+        write!(f, "latency:    p50 {:?}, p90 {:?}, p99 {:?}, max {:?}", self.latency_p50, self.latency_p90, self.latency_p99, self.latency_max)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{percentile, LoadTest};
+
+    #[test]
+    fn nearest_rank_percentiles() {
+        let values: Vec<u64> = (1..101).collect();
+        assert_eq!(percentile(&values, 50.0), 50);
+        assert_eq!(percentile(&values, 99.0), 99);
+        assert_eq!(percentile(&values, 100.0), 100);
+        assert_eq!(percentile(&[7], 90.0), 7);
+        assert_eq!(percentile(&[], 50.0), 0);
+    }
+
+    #[test]
+    fn load_tests_need_two_sessions() {
+        assert!(LoadTest::new("ws://127.0.0.1:8090/ws", "realm1").with_sessions(1).run().is_err());
+    }
+}
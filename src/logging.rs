@@ -0,0 +1,148 @@
+//! Lets the verbosity of the crate's logging be changed at runtime, separately for each part of
+//! the crate.
+//!
+//! Everything the crate logs belongs to one of four subsystems, which is recorded in the log
+//! target:
+//!
+//! * `Transport` (`wamp::transport`): websocket connections opening and closing, and the raw
+//!   frames sent and received on them
+//! * `Protocol` (`wamp::protocol`): messages that can't be parsed, or that break the protocol
+//! * `Dispatch` (`wamp::client`): the client handling messages from the router and running the
+//!   application's callbacks
+//! * `Router` (`wamp::router`): the router handling messages from its clients
+//!
+//! The `log` crate can only switch logging off globally, so to filter by subsystem the
+//! application's logger has to be installed through `init`, which wraps it in a filter.  Every
+//! subsystem starts out logging everything the wrapped logger accepts.
+use log::{self, Log, LogLevel, LogLevelFilter, LogMetadata, LogRecord, SetLoggerError};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub const TRANSPORT_TARGET: &'static str = "wamp::transport";
+pub const PROTOCOL_TARGET: &'static str = "wamp::protocol";
+
+/// A part of the crate whose logging can be filtered separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subsystem {
+    Transport,
+    Protocol,
+    Dispatch,
+    Router
+}
+
+static TRANSPORT_LEVEL: AtomicUsize = AtomicUsize::new(LogLevelFilter::Trace as usize);
+static PROTOCOL_LEVEL: AtomicUsize = AtomicUsize::new(LogLevelFilter::Trace as usize);
+static DISPATCH_LEVEL: AtomicUsize = AtomicUsize::new(LogLevelFilter::Trace as usize);
+static ROUTER_LEVEL: AtomicUsize = AtomicUsize::new(LogLevelFilter::Trace as usize);
+
+static LEVELS: [LogLevelFilter; 6] = [LogLevelFilter::Off, LogLevelFilter::Error, LogLevelFilter::Warn, LogLevelFilter::Info, LogLevelFilter::Debug, LogLevelFilter::Trace];
+
+impl Subsystem {
+    fn level_cell(&self) -> &'static AtomicUsize {
+        match *self {
+            Subsystem::Transport => &TRANSPORT_LEVEL,
+            Subsystem::Protocol => &PROTOCOL_LEVEL,
+            Subsystem::Dispatch => &DISPATCH_LEVEL,
+            Subsystem::Router => &ROUTER_LEVEL
+        }
+    }
+
+    /// Finds the subsystem a log target belongs to, or `None` if it doesn't come from this crate
+    pub fn for_target(target: &str) -> Option<Subsystem> {
+        if target.starts_with(TRANSPORT_TARGET) {
+            Some(Subsystem::Transport)
+        } else if target.starts_with(PROTOCOL_TARGET) || target.starts_with("wamp::messages") || target.starts_with("wamp::codec") {
+            Some(Subsystem::Protocol)
+        } else if target.starts_with("wamp::router") {
+            Some(Subsystem::Router)
+        } else if target == "wamp" || target.starts_with("wamp::") {
+            Some(Subsystem::Dispatch)
+        } else {
+            None
+        }
+    }
+}
+
+impl FromStr for Subsystem {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Subsystem, String> {
+        match &*name.to_lowercase() {
+            "transport" => Ok(Subsystem::Transport),
+            "protocol" => Ok(Subsystem::Protocol),
+            "dispatch" => Ok(Subsystem::Dispatch),
+            "router" => Ok(Subsystem::Router),
+            _ => Err(format!("Unknown subsystem: {}", name))
+        }
+    }
+}
+
+/// Sets the most verbose level the subsystem logs at
+pub fn set_level(subsystem: Subsystem, level: LogLevelFilter) {
+    subsystem.level_cell().store(level as usize, Ordering::Relaxed);
+}
+
+pub fn level(subsystem: Subsystem) -> LogLevelFilter {
+    LEVELS[subsystem.level_cell().load(Ordering::Relaxed)]
+}
+
+/// Sets a subsystem's level from their names, such as `"router"` and `"debug"`.
+pub fn set_level_by_name(subsystem: &str, level: &str) -> Result<LogLevelFilter, String> {
+    let subsystem = try!(subsystem.parse::<Subsystem>());
+    let level = try!(level.parse::<LogLevelFilter>().map_err(|_| format!("Unknown log level: {}", level)));
+    set_level(subsystem, level);
+    Ok(level)
+}
+
+fn enabled(target: &str, level: LogLevel) -> bool {
+    match Subsystem::for_target(target) {
+        Some(subsystem) => level <= self::level(subsystem),
+        None => true
+    }
+}
+
+struct SubsystemFilter {
+    logger: Box<Log>
+}
+
+impl Log for SubsystemFilter {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        enabled(metadata.target(), metadata.level()) && self.logger.enabled(metadata)
+    }
+
+    fn log(&self, record: &LogRecord) {
+        if enabled(record.target(), record.level()) {
+            self.logger.log(record);
+        }
+    }
+}
+
+/// Installs `logger` as the global logger, behind a filter that applies the subsystem levels.
+///
+/// Since the levels can be raised at any time, the global maximum level is set to `Trace`, and
+/// `logger` is relied on to discard whatever it isn't interested in.
+pub fn init(logger: Box<Log>) -> Result<(), SetLoggerError> {
+    log::set_logger(|max_level| {
+        max_level.set(LogLevelFilter::Trace);
+        Box::new(SubsystemFilter {
+            logger: logger
+        })
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::Subsystem;
+
+    #[test]
+    fn targets() {
+        assert_eq!(Subsystem::for_target("wamp::transport"), Some(Subsystem::Transport));
+        assert_eq!(Subsystem::for_target("wamp::messages::validation"), Some(Subsystem::Protocol));
+        assert_eq!(Subsystem::for_target("wamp::router::messaging"), Some(Subsystem::Router));
+        assert_eq!(Subsystem::for_target("wamp::client::queue"), Some(Subsystem::Dispatch));
+        assert_eq!(Subsystem::for_target("wampire"), None);
+        assert_eq!(Subsystem::for_target("ws::handler"), None);
+        assert_eq!("Router".parse::<Subsystem>(), Ok(Subsystem::Router));
+        assert!("network".parse::<Subsystem>().is_err());
+    }
+}
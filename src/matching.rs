@@ -0,0 +1,48 @@
+//! Contains the URI matching rules used for subscriptions and registrations, so that
+//! applications can check which topics a pattern covers the same way the router does.
+use messages::MatchingPolicy;
+
+/// Checks whether a subscription or registration with the given pattern and policy applies to
+/// `uri`.
+///
+/// Strict patterns only match the URI itself, and prefix patterns match any URI that starts
+/// with them.  In wildcard patterns, empty components match any single component, so
+/// `com..status` matches `com.myapp.status` but not `com.myapp.user.status`.
+pub fn matches(pattern: &str, uri: &str, policy: MatchingPolicy) -> bool {
+    match policy {
+        MatchingPolicy::Strict => pattern == uri,
+        MatchingPolicy::Prefix => uri.starts_with(pattern),
+        MatchingPolicy::Wildcard => {
+            let mut pattern_bits = pattern.split('.');
+            let mut uri_bits = uri.split('.');
+            loop {
+                match (pattern_bits.next(), uri_bits.next()) {
+                    (Some(p), Some(u)) => if !p.is_empty() && p != u {
+                        return false;
+                    },
+                    (None, None) => return true,
+                    _ => return false
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::matches;
+    use messages::MatchingPolicy;
+
+    #[test]
+    fn matching_policies() {
+        assert!(matches("ca.test.status", "ca.test.status", MatchingPolicy::Strict));
+        assert!(!matches("ca.test", "ca.test.status", MatchingPolicy::Strict));
+        assert!(matches("ca.test", "ca.test.status", MatchingPolicy::Prefix));
+        assert!(matches("ca.test", "ca.test", MatchingPolicy::Prefix));
+        assert!(!matches("ca.test.status", "ca.test", MatchingPolicy::Prefix));
+        assert!(matches("ca..status", "ca.test.status", MatchingPolicy::Wildcard));
+        assert!(!matches("ca..status", "ca.test.other", MatchingPolicy::Wildcard));
+        assert!(!matches("ca..", "ca.test.status.extra", MatchingPolicy::Wildcard));
+        assert!(!matches("ca..status.extra", "ca.test.status", MatchingPolicy::Wildcard));
+    }
+}
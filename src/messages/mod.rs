@@ -1,9 +1,27 @@
+//! Contains the WAMP message model and its serialization.
+//!
+//! The message model depends on `std`: `Dict` is a `HashMap`, and the serde, serde_json and
+//! rmp-serde versions this crate is built against all require `std`.  Supporting `no_std`
+//! targets would first need those dependencies upgraded to versions with `alloc` support, and
+//! `Dict` replaced with a map type that is available without `std`.
 use std::fmt;
 
-use serde;
+use serde::{self, Serialize};
+use rmp_serde::Serializer;
+use rmp_serde::Deserializer as RMPDeserializer;
+use rmp_serde::encode::Error as MsgPackEncodeError;
+use serde_cbor::Error as CBORError;
+use serde_cbor::ser::Serializer as CBORSerializer;
+use serde_cbor::de::Deserializer as CBORDeserializer;
+use utils::StructMapWriter;
+use transcode::{transcode, BinaryConversion};
 pub use messages::types::*;
 use ::ID;
 mod types;
+pub mod validation;
+
+/// The router's meta procedure for fetching a subscription's event history
+pub const GET_EVENTS_PROCEDURE: &'static str = "wamp.subscription.get_events";
 
 macro_rules! try_or {
     ($e: expr, $msg: expr) => (
@@ -19,6 +37,8 @@ pub enum Message {
     Hello(URI, HelloDetails),
     Welcome(ID, WelcomeDetails),
     Abort(ErrorDetails, Reason),
+    Challenge(String, Dict),
+    Authenticate(String, Dict),
     Goodbye(ErrorDetails, Reason),
     Error(ErrorType, ID, Dict, Reason, Option<List>, Option<Dict>),
     Subscribe(ID, SubscribeOptions, URI),
@@ -36,6 +56,8 @@ pub enum Message {
     Invocation(ID, ID, InvocationDetails, Option<List>, Option<Dict>),
     Yield(ID, YieldOptions, Option<List>, Option<Dict>),
     Result(ID, ResultDetails, Option<List>, Option<Dict>),
+    Cancel(ID, CancelOptions),
+    Interrupt(ID, InterruptOptions),
 }
 
 macro_rules! serialize_with_args {
@@ -71,6 +93,12 @@ impl serde::Serialize for Message {
             Message::Abort(ref details, ref reason) => {
                 (3, details, reason).serialize(serializer)
             },
+            Message::Challenge(ref authmethod, ref extra) => {
+                (4, authmethod, extra).serialize(serializer)
+            },
+            Message::Authenticate(ref signature, ref extra) => {
+                (5, signature, extra).serialize(serializer)
+            },
             Message::Goodbye(ref details, ref reason) => {
                 (6, details, reason).serialize(serializer)
             },
@@ -121,6 +149,12 @@ impl serde::Serialize for Message {
             },
             Message::Result(id, ref details, ref args, ref kwargs) => {
                 serialize_with_args!(args, kwargs, serializer, 50, id, details)
+            },
+            Message::Cancel(request_id, ref options) => {
+                (49, request_id, options).serialize(serializer)
+            },
+            Message::Interrupt(request_id, ref options) => {
+                (69, request_id, options).serialize(serializer)
             }
         }
     }
@@ -133,6 +167,80 @@ impl serde::Deserialize for Message {
         }
 }
 
+impl Message {
+    /// The message's type, as the WAMP specification spells it
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Message::Hello(..) => "HELLO",
+            Message::Welcome(..) => "WELCOME",
+            Message::Abort(..) => "ABORT",
+            Message::Challenge(..) => "CHALLENGE",
+            Message::Authenticate(..) => "AUTHENTICATE",
+            Message::Goodbye(..) => "GOODBYE",
+            Message::Error(..) => "ERROR",
+            Message::Subscribe(..) => "SUBSCRIBE",
+            Message::Subscribed(..) => "SUBSCRIBED",
+            Message::Unsubscribe(..) => "UNSUBSCRIBE",
+            Message::Unsubscribed(..) => "UNSUBSCRIBED",
+            Message::Publish(..) => "PUBLISH",
+            Message::Published(..) => "PUBLISHED",
+            Message::Event(..) => "EVENT",
+            Message::Register(..) => "REGISTER",
+            Message::Registered(..) => "REGISTERED",
+            Message::Unregister(..) => "UNREGISTER",
+            Message::Unregistered(..) => "UNREGISTERED",
+            Message::Call(..) => "CALL",
+            Message::Invocation(..) => "INVOCATION",
+            Message::Yield(..) => "YIELD",
+            Message::Result(..) => "RESULT",
+            Message::Cancel(..) => "CANCEL",
+            Message::Interrupt(..) => "INTERRUPT"
+        }
+    }
+
+    /// Whether the message's arguments or keyword arguments hold binary values
+    pub fn has_binary_payload(&self) -> bool {
+        let (args, kwargs) = match *self {
+            Message::Error(_, _, _, _, ref args, ref kwargs) |
+            Message::Publish(_, _, _, ref args, ref kwargs) |
+            Message::Event(_, _, _, ref args, ref kwargs) |
+            Message::Call(_, _, _, ref args, ref kwargs) |
+            Message::Invocation(_, _, _, ref args, ref kwargs) |
+            Message::Yield(_, _, ref args, ref kwargs) |
+            Message::Result(_, _, ref args, ref kwargs) => (args, kwargs),
+            _ => return false
+        };
+        args.as_ref().map_or(false, |args| args.iter().any(Value::contains_binary)) ||
+            kwargs.as_ref().map_or(false, |kwargs| kwargs.values().any(Value::contains_binary))
+    }
+}
+
+/// Writes a message as MsgPack.  Binary values serialize as the base64 strings WAMP uses in
+/// JSON, so a message with binary values in its payload is transcoded, which turns them into
+/// MsgPack binary values.
+pub fn to_msgpack(message: &Message) -> Result<Vec<u8>, MsgPackEncodeError> {
+    let mut buf: Vec<u8> = Vec::new();
+    try!(message.serialize(&mut Serializer::with(&mut buf, StructMapWriter)));
+    if !message.has_binary_payload() {
+        return Ok(buf);
+    }
+    let mut converted = Vec::with_capacity(buf.len());
+    try!(transcode(&mut RMPDeserializer::new(&buf[..]), &mut Serializer::with(&mut converted, StructMapWriter), BinaryConversion::FromBase64));
+    Ok(converted)
+}
+
+/// Writes a message as CBOR, turning binary values into CBOR byte strings like `to_msgpack`
+pub fn to_cbor(message: &Message) -> Result<Vec<u8>, CBORError> {
+    let mut buf: Vec<u8> = Vec::new();
+    try!(message.serialize(&mut CBORSerializer::new(&mut buf)));
+    if !message.has_binary_payload() {
+        return Ok(buf);
+    }
+    let mut converted = Vec::with_capacity(buf.len());
+    try!(transcode(&mut CBORDeserializer::new(&buf[..]), &mut CBORSerializer::new(&mut converted), BinaryConversion::FromBase64));
+    Ok(converted)
+}
+
 struct MessageVisitor;
 
 
@@ -156,6 +264,18 @@ impl MessageVisitor {
         Ok( Message::Abort(details, reason))
     }
 
+    fn visit_challenge<V>(&self,  mut visitor:V) -> Result<Message, V::Error> where V: serde::de::SeqVisitor {
+        let authmethod = try_or!(visitor.visit(), "Challenge message ended before authentication method");
+        let extra = try_or!(visitor.visit(), "Challenge message ended before extra dict");
+        Ok( Message::Challenge(authmethod, extra))
+    }
+
+    fn visit_authenticate<V>(&self,  mut visitor:V) -> Result<Message, V::Error> where V: serde::de::SeqVisitor {
+        let signature = try_or!(visitor.visit(), "Authenticate message ended before signature");
+        let extra = try_or!(visitor.visit(), "Authenticate message ended before extra dict");
+        Ok( Message::Authenticate(signature, extra))
+    }
+
     fn visit_goodbye<V>(&self,  mut visitor:V) -> Result<Message, V::Error> where V: serde::de::SeqVisitor {
         let details = try_or!(visitor.visit(), "Goodbye message ended before details dict");
         let reason = try_or!(visitor.visit(), "Goodbye message ended before reason uri");
@@ -271,6 +391,18 @@ impl MessageVisitor {
         Ok(Message::Yield(id, options, args, kwargs))
     }
 
+    fn visit_cancel<V>(&self,  mut visitor:V) -> Result<Message, V::Error> where V: serde::de::SeqVisitor {
+        let id = try_or!(visitor.visit(), "Cancel message ended before request id");
+        let options = try_or!(visitor.visit(), "Cancel message ended before options dict");
+        Ok(Message::Cancel(id, options))
+    }
+
+    fn visit_interrupt<V>(&self,  mut visitor:V) -> Result<Message, V::Error> where V: serde::de::SeqVisitor {
+        let id = try_or!(visitor.visit(), "Interrupt message ended before request id");
+        let options = try_or!(visitor.visit(), "Interrupt message ended before options dict");
+        Ok(Message::Interrupt(id, options))
+    }
+
     fn visit_result<V>(&self,  mut visitor:V) -> Result<Message, V::Error> where V: serde::de::SeqVisitor {
         let id = try_or!(visitor.visit(), "Result message ended before session id");
         let details = try_or!(visitor.visit(), "Result message ended before details dict");
@@ -293,6 +425,8 @@ impl serde::de::Visitor for MessageVisitor {
             1  => self.visit_hello(visitor),
             2  => self.visit_welcome(visitor),
             3  => self.visit_abort(visitor),
+            4  => self.visit_challenge(visitor),
+            5  => self.visit_authenticate(visitor),
             6  => self.visit_goodbye(visitor),
             8  => self.visit_error(visitor),
             32 => self.visit_subscribe(visitor),
@@ -310,6 +444,8 @@ impl serde::de::Visitor for MessageVisitor {
             68 => self.visit_invocation(visitor),
             70 => self.visit_yield(visitor),
             50 => self.visit_result(visitor),
+            49 => self.visit_cancel(visitor),
+            69 => self.visit_interrupt(visitor),
             _  => Err(serde::de::Error::custom("Unknown message type"))
         }
     }
@@ -317,7 +453,7 @@ impl serde::de::Visitor for MessageVisitor {
 
 #[cfg(test)]
 mod test {
-    use super::{Message};
+    use super::{Message, to_msgpack};
     use super::types::{
         URI,
         ClientRoles,
@@ -335,7 +471,10 @@ mod test {
         Value,
         EventDetails,
         InvocationDetails,
-        ResultDetails
+        ResultDetails,
+        CancelOptions,
+        InterruptOptions,
+        CancelMode
     };
     use utils::StructMapWriter;
     use std::collections::{HashMap};
@@ -361,6 +500,7 @@ mod test {
     }
 
     #[test]
+    #[cfg(all(feature = "publisher", feature = "subscriber", feature = "caller", feature = "callee"))]
     fn serialize_hello() {
         two_way_test!(
             Message::Hello(URI::new("ca.dal.wamp.test"), HelloDetails::new(ClientRoles::new_basic())),
@@ -368,8 +508,24 @@ mod test {
         );
         two_way_test!(
             Message::Hello(URI::new("ca.dal.wamp.test"), HelloDetails::new_with_agent(ClientRoles::new(), "dal_wamp")),
-            "[1,\"ca.dal.wamp.test\",{\"agent\":\"dal_wamp\",\"roles\":{\"publisher\":{\"features\":{}},\"subscriber\":{\"features\":{\"pattern_based_subscription\":true}},\"caller\":{\"features\":{}},\"callee\":{\"features\":{}}}}]"
-        )
+            "[1,\"ca.dal.wamp.test\",{\"agent\":\"dal_wamp\",\"roles\":{\"publisher\":{\"features\":{\"publisher_identification\":true}},\"subscriber\":{\"features\":{\"pattern_based_subscription\":true,\"publisher_identification\":true}},\"caller\":{\"features\":{\"call_canceling\":true,\"progressive_call_invocations\":true,\"progressive_call_results\":true}},\"callee\":{\"features\":{\"call_canceling\":true,\"progressive_call_invocations\":true,\"progressive_call_results\":true,\"shared_registration\":true}}}}]"
+        );
+        two_way_test!(
+            Message::Hello(URI::new("ca.dal.wamp.test"), HelloDetails::new_with_authentication(ClientRoles::new_basic(), "joe", vec!["ticket".to_string()])),
+            "[1,\"ca.dal.wamp.test\",{\"authid\":\"joe\",\"authmethods\":[\"ticket\"],\"roles\":{\"publisher\":{\"features\":{}},\"subscriber\":{\"features\":{}},\"caller\":{\"features\":{}},\"callee\":{\"features\":{}}}}]"
+        );
+        let mut authextra = HashMap::new();
+        authextra.insert("pubkey".to_string(), Value::String("545efb0a".to_string()));
+        two_way_test!(
+            Message::Hello(URI::new("ca.dal.wamp.test"), HelloDetails::new_with_authentication(ClientRoles::new_basic(), "joe", vec!["cryptosign".to_string()]).with_authextra(authextra)),
+            "[1,\"ca.dal.wamp.test\",{\"authid\":\"joe\",\"authmethods\":[\"cryptosign\"],\"authextra\":{\"pubkey\":\"545efb0a\"},\"roles\":{\"publisher\":{\"features\":{}},\"subscriber\":{\"features\":{}},\"caller\":{\"features\":{}},\"callee\":{\"features\":{}}}}]"
+        );
+        // Clients built with only some roles only announce those
+        let roles = ClientRoles {publisher: None, subscriber: None, caller: None, callee: None};
+        two_way_test!(
+            Message::Hello(URI::new("ca.dal.wamp.test"), HelloDetails::new(roles)),
+            "[1,\"ca.dal.wamp.test\",{\"roles\":{}}]"
+        );
     }
 
     #[test]
@@ -380,7 +536,11 @@ mod test {
         );
         two_way_test!(
             Message::Welcome(493782, WelcomeDetails::new_with_agent(RouterRoles::new(), "dal_wamp")),
-            "[2,493782,{\"agent\":\"dal_wamp\",\"roles\":{\"dealer\":{\"features\":{\"pattern_based_registration\":true}},\"broker\":{\"features\":{\"pattern_based_subscription\":true}}}}]"
+            "[2,493782,{\"agent\":\"dal_wamp\",\"roles\":{\"dealer\":{\"features\":{\"pattern_based_registration\":true,\"caller_identification\":true,\"call_canceling\":true,\"shared_registration\":true,\"progressive_call_results\":true,\"progressive_call_invocations\":true}},\"broker\":{\"features\":{\"pattern_based_subscription\":true,\"subscriber_blackwhite_listing\":true,\"publisher_exclusion\":true,\"publisher_identification\":true,\"event_retention\":true}}}}]"
+        );
+        two_way_test!(
+            Message::Welcome(493782, WelcomeDetails::new_with_authentication(RouterRoles::new_basic(), "joe", "user", "ticket")),
+            "[2,493782,{\"authid\":\"joe\",\"authrole\":\"user\",\"authmethod\":\"ticket\",\"roles\":{\"dealer\":{},\"broker\":{}}}]"
         );
     }
 
@@ -397,6 +557,28 @@ mod test {
         );
     }
 
+    #[test]
+    fn serialize_challenge() {
+        two_way_test!(
+            Message::Challenge("ticket".to_string(), HashMap::new()),
+            "[4,\"ticket\",{}]"
+        );
+        let mut extra = HashMap::new();
+        extra.insert("challenge".to_string(), Value::String("nonce".to_string()));
+        two_way_test!(
+            Message::Challenge("wampcra".to_string(), extra),
+            "[4,\"wampcra\",{\"challenge\":\"nonce\"}]"
+        );
+    }
+
+    #[test]
+    fn serialize_authenticate() {
+        two_way_test!(
+            Message::Authenticate("secret-ticket".to_string(), HashMap::new()),
+            "[5,\"secret-ticket\",{}]"
+        );
+    }
+
     #[test]
     fn serialize_goodbye() {
         two_way_test!(
@@ -433,6 +615,12 @@ mod test {
         two_way_test!(
             Message::Subscribe(58944, SubscribeOptions::new(), URI::new("ca.dal.test.the_sub")),
             "[32,58944,{},\"ca.dal.test.the_sub\"]"
+        );
+        let mut options = SubscribeOptions::new();
+        options.shard_group = Some("workers".to_string());
+        two_way_test!(
+            Message::Subscribe(58945, options, URI::new("ca.dal.test.the_sub")),
+            "[32,58945,{\"shard_group\":\"workers\"},\"ca.dal.test.the_sub\"]"
         )
     }
 
@@ -476,9 +664,41 @@ mod test {
         two_way_test!(
             Message::Publish(3243542, PublishOptions::new(true), URI::new("ca.dal.test.topic3"), Some(Vec::new()), Some(kwargs)),
             "[16,3243542,{\"acknowledge\":true},\"ca.dal.test.topic3\",[],{\"key1\":[5]}]"
+        );
+        two_way_test!(
+            Message::Publish(3243543, PublishOptions::new_with_shard_key(false, "order-17"), URI::new("ca.dal.test.topic4"), None, None),
+            "[16,3243543,{\"shard_key\":\"order-17\"},\"ca.dal.test.topic4\"]"
+        );
+        two_way_test!(
+            Message::Publish(3243544, PublishOptions::new(false).with_exclude(vec![7]).with_eligible_authrole(vec!["sensor".to_string()]), URI::new("ca.dal.test.topic5"), None, None),
+            "[16,3243544,{\"exclude\":[7],\"eligible_authrole\":[\"sensor\"]},\"ca.dal.test.topic5\"]"
+        );
+        two_way_test!(
+            Message::Publish(3243545, PublishOptions::new(false).with_exclude_me(false), URI::new("ca.dal.test.topic6"), None, None),
+            "[16,3243545,{\"exclude_me\":false},\"ca.dal.test.topic6\"]"
+        );
+        two_way_test!(
+            Message::Publish(3243546, PublishOptions::new(false).with_disclose_me(true), URI::new("ca.dal.test.topic7"), None, None),
+            "[16,3243546,{\"disclose_me\":true},\"ca.dal.test.topic7\"]"
+        );
+        two_way_test!(
+            Message::Publish(3243547, PublishOptions::new(false).with_retain(true), URI::new("ca.dal.test.topic8"), None, None),
+            "[16,3243547,{\"retain\":true},\"ca.dal.test.topic8\"]"
         )
     }
 
+    #[test]
+    fn publish_recipient_lists() {
+        let options = PublishOptions::new(false).with_exclude(vec![3]).with_exclude_authid(vec!["mallory".to_string()]).with_eligible_authrole(vec!["sensor".to_string()]);
+        assert!(options.admits(1, Some("alice"), Some("sensor")));
+        assert!(!options.admits(3, Some("alice"), Some("sensor")));
+        assert!(!options.admits(1, Some("mallory"), Some("sensor")));
+        assert!(!options.admits(1, Some("alice"), Some("operator")));
+        assert!(!options.admits(1, None, None));
+        assert!(PublishOptions::new(false).admits(1, None, None));
+        assert!(!PublishOptions::new(false).with_eligible(vec![2]).admits(1, None, None));
+    }
+
     #[test]
     fn serialize_published() {
           two_way_test!(
@@ -503,6 +723,10 @@ mod test {
         two_way_test!(
             Message::Event(65675, 587495, EventDetails::new(), Some(Vec::new()), Some(kwargs)),
             "[36,65675,587495,{},[],{\"key1\":[5]}]"
+        );
+        two_way_test!(
+            Message::Event(65676, 587496, EventDetails::new().with_publisher(3335656, Some("joe".to_string()), Some("user".to_string())), None, None),
+            "[36,65676,587496,{\"publisher\":3335656,\"publisher_authid\":\"joe\",\"publisher_authrole\":\"user\"}]"
         )
     }
 
@@ -512,6 +736,10 @@ mod test {
             Message::Register(25349185, RegisterOptions::new(), URI::new("ca.test.proc")),
             "[64,25349185,{},\"ca.test.proc\"]"
         );
+        two_way_test!(
+            Message::Register(25349185, RegisterOptions::new().with_disclose_caller(true), URI::new("ca.test.proc")),
+            "[64,25349185,{\"disclose_caller\":true},\"ca.test.proc\"]"
+        );
     }
 
     #[test]
@@ -554,6 +782,14 @@ mod test {
         two_way_test!(
             Message::Call(764346, CallOptions::new(), URI::new("com.myapp.compute"), Some(Vec::new()), Some(kwargs)),
             "[48,764346,{},\"com.myapp.compute\",[],{\"key1\":[5]}]"
+        );
+        two_way_test!(
+            Message::Call(764347, CallOptions::new().with_timeout(2000).with_disclose_me(true), URI::new("com.myapp.ping"), None, None),
+            "[48,764347,{\"timeout\":2000,\"disclose_me\":true},\"com.myapp.ping\"]"
+        );
+        two_way_test!(
+            Message::Call(764348, CallOptions::new().with_progress(true), URI::new("com.myapp.upload"), Some(vec![Value::Integer(1)]), None),
+            "[48,764348,{\"progress\":true},\"com.myapp.upload\",[1]]"
         )
     }
 
@@ -573,6 +809,13 @@ mod test {
         two_way_test!(
             Message::Invocation(764346, 9823526, InvocationDetails::new(), Some(Vec::new()), Some(kwargs)),
             "[68,764346,9823526,{},[],{\"key1\":[5]}]"
+        );
+        let mut details = InvocationDetails::new();
+        details.caller = Some(3335656);
+        details.caller_authid = Some("joe".to_string());
+        two_way_test!(
+            Message::Invocation(764346, 9823526, details, None, None),
+            "[68,764346,9823526,{\"caller\":3335656,\"caller_authid\":\"joe\"}]"
         )
     }
 
@@ -592,6 +835,11 @@ mod test {
         two_way_test!(
             Message::Yield(6131533, YieldOptions::new(), Some(Vec::new()), Some(kwargs)),
             "[70,6131533,{},[],{\"key1\":[5]}]"
+        );
+
+        two_way_test!(
+            Message::Yield(6131533, YieldOptions::progressive(), Some(vec![Value::Integer(1)]), None),
+            "[70,6131533,{\"progress\":true},[1]]"
         )
     }
 
@@ -611,7 +859,53 @@ mod test {
         two_way_test!(
             Message::Result(764346, ResultDetails::new(), Some(Vec::new()), Some(kwargs)),
             "[50,764346,{},[],{\"key1\":[5]}]"
+        );
+
+        two_way_test!(
+            Message::Result(764346, ResultDetails::progressive(), Some(vec![Value::Integer(1)]), None),
+            "[50,764346,{\"progress\":true},[1]]"
         )
     }
 
+    #[test]
+    fn serialize_cancel() {
+        two_way_test!(
+            Message::Cancel(7814135, CancelOptions::new(CancelMode::KillNoWait)),
+            "[49,7814135,{\"mode\":\"killnowait\"}]"
+        );
+        two_way_test!(
+            Message::Interrupt(764346, InterruptOptions::new(CancelMode::Kill)),
+            "[69,764346,{\"mode\":\"kill\"}]"
+        );
+        let options: CancelOptions = serde_json::from_str("{}").unwrap();
+        assert_eq!(options.mode(), CancelMode::Kill);
+    }
+
+    #[test]
+    fn payloads_cross_serializers() {
+        let mut kwargs = HashMap::new();
+        kwargs.insert("größe".to_string(), Value::UnsignedInteger(18446744073709551615));
+        kwargs.insert("raw".to_string(), Value::Bytes(vec![0, 159, 146, 150]));
+        let message = Message::Event(1, 2, EventDetails::new(), Some(vec![Value::String("日本語 ✓".to_string()), Value::Integer(-9223372036854775808), Value::Bytes(Vec::new())]), Some(kwargs));
+        assert!(message.has_binary_payload());
+
+        // JSON to MsgPack
+        let json = serde_json::to_string(&message).unwrap();
+        assert!(json.contains("\"\\u0000AJ+Slg==\""));
+        let from_json: Message = serde_json::from_str(&json).unwrap();
+        assert_eq!(from_json, message);
+        let buf = to_msgpack(&from_json).unwrap();
+        assert!(buf.windows(6).any(|bytes| bytes == [0xc4, 0x04, 0, 159, 146, 150]));
+
+        // MsgPack to JSON
+        let mut de = RMPDeserializer::new(&buf[..]);
+        let from_msgpack: Message = Deserialize::deserialize(&mut de).unwrap();
+        assert_eq!(from_msgpack, message);
+        assert_eq!(serde_json::from_str::<Message>(&serde_json::to_string(&from_msgpack).unwrap()).unwrap(), message);
+
+        // Strings that merely start with a NUL character stay strings
+        let message = Message::Event(1, 2, EventDetails::new(), Some(vec![Value::String("\0not base64!".to_string())]), None);
+        assert!(!message.has_binary_payload());
+        assert_eq!(serde_json::from_str::<Message>(&serde_json::to_string(&message).unwrap()).unwrap(), message);
+    }
 }
@@ -1,9 +1,12 @@
 use URI;
+use std::collections::HashMap;
 use std::fmt;
 use serde;
-use super::{List, Dict};
+use serde_json;
+use super::{List, Dict, Value};
+use std::error::Error;
 
-#[derive(Hash, Eq, PartialEq, Debug)]
+#[derive(Hash, Eq, PartialEq, Debug, Clone)]
 pub enum Reason {
     InvalidURI,
     NoSuchProcedure,
@@ -24,16 +27,28 @@ pub enum Reason {
     OptionDisallowedDiscloseMe,
     NetworkFailure,
     NormalClose,
+    Timeout,
+    ProtocolViolation,
     CustomReason(URI)
 }
 
-#[derive(Debug)]
+/// An error answering a request, with everything the peer sent along with it.  Application
+/// errors often describe themselves in their arguments, which `arg()` and `kwarg()` extract.
+#[derive(Debug, Clone, PartialEq)]
 pub struct CallError {
     reason: Reason,
     args: Option<List>,
-    kwargs: Option<Dict>
+    kwargs: Option<Dict>,
+    details: Dict,
+    // Set for errors converted from application errors, so that the client can give them its
+    // default error URI
+    application: bool
 }
 
+/// The reason given to errors converted from application errors, unless the client has been
+/// given another one.  It is the URI other WAMP implementations use for unhandled errors.
+pub const DEFAULT_ERROR_URI: &'static str = "wamp.error.runtime_error";
+
 #[derive(Hash, Eq, PartialEq, Debug)]
 pub enum ErrorType {
     Subscribe,
@@ -51,10 +66,45 @@ impl CallError {
         CallError {
             reason: reason,
             args: args,
-            kwargs: kwargs
+            kwargs: kwargs,
+            details: HashMap::new(),
+            application: false
+        }
+    }
+
+    /// An error raised by application code, carrying `message` as its only argument.  When a
+    /// registered procedure fails with it, the caller gets the client's default error URI as the
+    /// reason.  See `Client::set_default_error_uri`.
+    pub fn application(message: &str) -> CallError {
+        CallError {
+            reason: Reason::CustomReason(URI::new(DEFAULT_ERROR_URI)),
+            args: Some(vec![Value::String(message.to_string())]),
+            kwargs: None,
+            details: HashMap::new(),
+            application: true
         }
     }
 
+    /// Whether the error was made by `CallError::application`, or converted from a string or
+    /// another error type
+    #[inline]
+    pub fn is_application_error(&self) -> bool {
+        self.application
+    }
+
+    /// Replaces the error's reason
+    pub fn with_reason(mut self, reason: Reason) -> CallError {
+        self.reason = reason;
+        self.application = false;
+        self
+    }
+
+    /// Sets the details sent along with the error
+    pub fn with_details(mut self, details: Dict) -> CallError {
+        self.details = details;
+        self
+    }
+
     pub fn to_tuple(self) -> (Reason, Option<List>, Option<Dict>) {
         (self.reason, self.args, self.kwargs)
     }
@@ -73,6 +123,60 @@ impl CallError {
     pub fn get_kwargs(&self) -> &Option<Dict> {
         &self.kwargs
     }
+
+    #[inline]
+    pub fn get_details(&self) -> &Dict {
+        &self.details
+    }
+
+    /// The URI of the error's reason, such as `wamp.error.no_such_procedure` or the URI an
+    /// application error was raised with
+    pub fn uri(&self) -> &str {
+        self.reason.get_string()
+    }
+
+    /// The argument at `index`, converted to `T`.  `None` if there is no such argument, or it
+    /// isn't a `T`.
+    pub fn arg<T: serde::Deserialize>(&self, index: usize) -> Option<T> {
+        self.args.as_ref().and_then(|args| args.get(index)).and_then(convert)
+    }
+
+    /// The keyword argument `key`, converted to `T`.  `None` if there is no such argument, or
+    /// it isn't a `T`.
+    pub fn kwarg<T: serde::Deserialize>(&self, key: &str) -> Option<T> {
+        self.kwargs.as_ref().and_then(|kwargs| kwargs.get(key)).and_then(convert)
+    }
+}
+
+fn convert<T: serde::Deserialize>(value: &Value) -> Option<T> {
+    serde_json::to_vec(value).ok().and_then(|json| serde_json::from_slice(&json).ok())
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.arg::<String>(0) {
+            Some(message) => write!(f, "{}: {}", self.reason, message),
+            None => write!(f, "{}", self.reason)
+        }
+    }
+}
+
+impl From<String> for CallError {
+    fn from(message: String) -> CallError {
+        CallError::application(&message)
+    }
+}
+
+impl<'a> From<&'a str> for CallError {
+    fn from(message: &'a str) -> CallError {
+        CallError::application(message)
+    }
+}
+
+impl From<Box<Error + Send + Sync>> for CallError {
+    fn from(error: Box<Error + Send + Sync>) -> CallError {
+        CallError::application(&error.to_string())
+    }
 }
 
 struct ErrorTypeVisitor;
@@ -95,12 +199,14 @@ impl Reason {
             Reason::AuthorizationFailed => "wamp.error.authorization_failed",
             Reason::NoSuchRealm => "wamp.error.no_such_realm",
             Reason::NoSuchRole => "wamp.error.no_such_role",
-            Reason::Cancelled => "wamp.error.cancelled",
+            Reason::Cancelled => "wamp.error.canceled",
             Reason::OptionNotAllowed => "wamp.error.option_not_allowed",
             Reason::NoEligibleCallee => "wamp.error.no_eligible_callee",
             Reason::OptionDisallowedDiscloseMe => "wamp.error.option-disallowed.disclose_me",
             Reason::NetworkFailure => "wamp.error.network_failure",
             Reason::NormalClose => "wamp.close.normal",
+            Reason::Timeout => "wamp.error.timeout",
+            Reason::ProtocolViolation => "wamp.error.protocol_violation",
             Reason::CustomReason(ref reason) => &reason.uri
         }
     }
@@ -157,12 +263,15 @@ impl serde::de::Visitor for ReasonVisitor {
              "wamp.error.authorization_failed" => Ok(Reason::AuthorizationFailed),
              "wamp.error.no_such_realm" => Ok(Reason::NoSuchRealm),
              "wamp.error.no_such_role" => Ok(Reason::NoSuchRole),
-             "wamp.error.cancelled" => Ok(Reason::Cancelled),
+             // Older versions of this crate sent the British spelling
+             "wamp.error.canceled" | "wamp.error.cancelled" => Ok(Reason::Cancelled),
              "wamp.error.option_not_allowed" => Ok(Reason::OptionNotAllowed),
              "wamp.error.no_eligible_callee" => Ok(Reason::NoEligibleCallee),
              "wamp.error.option-disallowed.disclose_me" => Ok(Reason::OptionDisallowedDiscloseMe),
              "wamp.error.network_failure" => Ok(Reason::NetworkFailure),
              "wamp.close.normal" => Ok(Reason::NormalClose),
+             "wamp.error.timeout" => Ok(Reason::Timeout),
+             "wamp.error.protocol_violation" => Ok(Reason::ProtocolViolation),
              x => Ok(Reason::CustomReason(URI::new(x)))
         }
     }
@@ -223,3 +332,47 @@ impl serde::de::Visitor for ErrorTypeVisitor {
     }
 
 }
+
+#[cfg(test)]
+mod test {
+    use super::{CallError, Reason, DEFAULT_ERROR_URI};
+    use messages::{URI, Value};
+    use std::collections::HashMap;
+    use std::error::Error;
+    use std::num::ParseIntError;
+
+    fn parse(text: &str) -> Result<i64, Box<Error + Send + Sync>> {
+        let value: Result<i64, ParseIntError> = text.parse();
+        Ok(try!(value))
+    }
+
+    #[test]
+    fn application_errors() {
+        let error: CallError = parse("ten").unwrap_err().into();
+        assert!(error.is_application_error());
+        assert_eq!(error.get_reason(), &Reason::CustomReason(URI::new(DEFAULT_ERROR_URI)));
+        assert_eq!(error.get_args(), &Some(vec![Value::String("invalid digit found in string".to_string())]));
+        assert_eq!(CallError::from("bad input"), CallError::from("bad input".to_string()));
+
+        let error = error.with_reason(Reason::InvalidArgument);
+        assert!(!error.is_application_error());
+        assert_eq!(error.get_reason(), &Reason::InvalidArgument);
+        assert!(!CallError::new(Reason::InvalidArgument, None, None).is_application_error());
+    }
+
+    #[test]
+    fn structured_errors() {
+        let mut kwargs = HashMap::new();
+        kwargs.insert("code".to_string(), Value::Integer(404));
+        kwargs.insert("missing".to_string(), Value::List(vec![Value::String("sku-1".to_string())]));
+        let error = CallError::new(Reason::CustomReason(URI::new("com.shop.not_found")), Some(vec![Value::String("No such item".to_string())]), Some(kwargs));
+        assert_eq!(error.uri(), "com.shop.not_found");
+        assert_eq!(error.kwarg::<i64>("code"), Some(404));
+        assert_eq!(error.kwarg::<Vec<String>>("missing"), Some(vec!["sku-1".to_string()]));
+        assert_eq!(error.kwarg::<String>("code"), None);
+        assert_eq!(error.kwarg::<i64>("retry_after"), None);
+        assert_eq!(error.arg::<String>(0), Some("No such item".to_string()));
+        assert_eq!(error.to_string(), "com.shop.not_found: No such item");
+        assert_eq!(CallError::new(Reason::Timeout, None, None).to_string(), "wamp.error.timeout");
+    }
+}
@@ -1,57 +1,3 @@
-// All this is necessary because Serde decided that the best way
-// to serialize an empty struct was as a null value, and the best way
-// to deserialize it was as an empty list.  So instead we serialize it as a map.
-macro_rules! serialize_empty {
-    ($name: tt) => (
-        serialize_empty_workaround!($name, $name);
-    );
-}
-
-macro_rules! serialize_empty_workaround {
-    ($name: ty, $obj: expr) => (
-        impl serde::Serialize for $name {
-            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-                where S: serde::Serializer,
-            {
-
-                let struc = try!(serializer.serialize_struct("", 0));
-                struc.end()
-            }
-        }
-
-        impl serde::de::Deserialize for $name {
-
-
-            fn deserialize<D>(deserializer:  D) -> Result<$name, D::Error> where
-             D: serde::de::Deserializer {
-                {
-                    struct Visitor;
-                    impl serde::de::Visitor for Visitor {
-                        type Value = $name;
-
-                        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-                            formatter.write_str("empty struct")
-                        }
-
-                        #[inline]
-                        fn visit_unit<E>(self) -> Result<$name,E> where
-                         E: serde::de::Error {
-                            Ok($obj)
-                        }
-                        #[inline]
-                        fn visit_map<V>(self,  _visitor: V) -> Result<$name,V::Error>
-                         where V: serde::de::MapVisitor {
-                            self.visit_unit()
-                        }
-                    }
-                    deserializer.deserialize_unit_struct("",Visitor)
-                }
-            }
-        }
-    );
-}
-
-
 mod options;
 mod value;
 mod error;
@@ -103,6 +49,17 @@ pub enum InvocationPolicy {
     Last
 }
 
+/// How the router cancels a call.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum CancelMode {
+    /// Fails the call straight away, leaving the callee to finish it
+    Skip,
+    /// Asks the callee to stop, and fails the call once the callee has
+    Kill,
+    /// Asks the callee to stop, and fails the call straight away
+    KillNoWait
+}
+
 
 /**************************
         Visitors
@@ -110,6 +67,7 @@ pub enum InvocationPolicy {
 
 struct MatchingPolicyVisitor;
 struct InvocationPolicyVisitor;
+struct CancelModeVisitor;
 
 
 
@@ -235,3 +193,45 @@ impl serde::de::Visitor for InvocationPolicyVisitor {
     }
 
 }
+
+impl serde::Serialize for CancelMode {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: serde::Serializer,
+    {
+        let ser_str = match *self {
+             CancelMode::Skip => "skip",
+             CancelMode::Kill => "kill",
+             CancelMode::KillNoWait => "killnowait"
+        };
+        serializer.serialize_str(ser_str)
+    }
+}
+
+impl serde::Deserialize for CancelMode {
+    fn deserialize<D>(deserializer: D) -> Result<CancelMode, D::Error>
+        where D: serde::Deserializer,
+    {
+        deserializer.deserialize(CancelModeVisitor)
+    }
+}
+
+impl serde::de::Visitor for CancelModeVisitor {
+    type Value = CancelMode;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("cancellation mode for a call")
+    }
+
+    #[inline]
+    fn visit_str<E>(self, value: &str) -> Result<CancelMode, E>
+        where E: serde::de::Error,
+    {
+        match value {
+            "skip" => Ok(CancelMode::Skip),
+            "kill" => Ok(CancelMode::Kill),
+            "killnowait" => Ok(CancelMode::KillNoWait),
+            x => Err(serde::de::Error::custom(format!("Invalid cancellation mode: {}", x)))
+        }
+    }
+
+}
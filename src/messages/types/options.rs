@@ -1,12 +1,16 @@
-use super::{ClientRoles, RouterRoles, MatchingPolicy, InvocationPolicy, is_not, URI};
-use serde;
-use std::fmt;
-use serde::ser::SerializeStruct;
+use super::{ClientRoles, RouterRoles, MatchingPolicy, InvocationPolicy, CancelMode, is_not, URI, Dict};
+use ::ID;
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct HelloDetails {
     #[serde(default, skip_serializing_if="Option::is_none")]
     agent: Option<String>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    authid: Option<String>,
+    #[serde(default, skip_serializing_if="Vec::is_empty")]
+    authmethods: Vec<String>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    authextra: Option<Dict>,
     roles: ClientRoles
 }
 
@@ -14,6 +18,14 @@ pub struct HelloDetails {
 pub struct WelcomeDetails {
     #[serde(default, skip_serializing_if="Option::is_none")]
     agent: Option<String>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    authid: Option<String>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    authrole: Option<String>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    authmethod: Option<String>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    authprovider: Option<String>,
     roles:  RouterRoles
 }
 
@@ -23,37 +35,128 @@ pub struct ErrorDetails {
     message: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct SubscribeOptions {
     #[serde(default, rename="match", skip_serializing_if="MatchingPolicy::is_strict")]
-    pub pattern_match: MatchingPolicy
+    pub pattern_match: MatchingPolicy,
+
+    /// Subscribers that share a shard group compete for events, with only one of them receiving each publication
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub shard_group: Option<String>
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct PublishOptions {
     #[serde(default, skip_serializing_if="is_not")]
-    acknowledge: bool
+    pub acknowledge: bool,
+
+    /// Publications with the same shard key are delivered to the same member of each shard group
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub shard_key: Option<String>,
+
+    /// Sessions that don't receive the event, even if they are eligible
+    #[serde(default, skip_serializing_if="Vec::is_empty")]
+    pub exclude: Vec<ID>,
+
+    #[serde(default, skip_serializing_if="Vec::is_empty")]
+    pub exclude_authid: Vec<String>,
+
+    #[serde(default, skip_serializing_if="Vec::is_empty")]
+    pub exclude_authrole: Vec<String>,
+
+    /// When set, only these sessions may receive the event
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub eligible: Option<Vec<ID>>,
+
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub eligible_authid: Option<Vec<String>>,
+
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub eligible_authrole: Option<Vec<String>>,
+
+    /// Whether the publisher is left out of the event's recipients, even if it is subscribed.
+    /// Routers leave it out unless this is `Some(false)`.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub exclude_me: Option<bool>,
+
+    /// Whether the router should tell subscribers who published the event.  Routers don't
+    /// unless this is `Some(true)`.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub disclose_me: Option<bool>,
+
+    /// Whether the router should keep the event and give it to sessions that subscribe later
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub retain: Option<bool>
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct RegisterOptions {
     #[serde(default, rename="match", skip_serializing_if="MatchingPolicy::is_strict")]
     pub pattern_match: MatchingPolicy,
 
     #[serde(default, rename="invoke", skip_serializing_if="InvocationPolicy::is_single")]
-    pub invocation_policy: InvocationPolicy
+    pub invocation_policy: InvocationPolicy,
+
+    /// How long, in milliseconds, the router may answer identical calls to the procedure with
+    /// the result of an earlier one
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub cache_ttl: Option<u64>,
+
+    /// Whether the router should tell the callee who is calling, whatever the caller asks
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub disclose_caller: Option<bool>
 }
 
-#[derive(PartialEq, Debug)]
-pub struct CallOptions;
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct CallOptions {
+    /// How long, in milliseconds, the router lets the callee take before it cancels the call
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub timeout: Option<u64>,
 
-#[derive(PartialEq, Debug)]
-pub struct YieldOptions;
+    /// Whether the router should tell the callee who is calling.  Routers don't unless this is
+    /// `Some(true)`.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub disclose_me: Option<bool>,
+
+    /// Whether the caller accepts progressive results before the final one
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub receive_progress: Option<bool>,
+
+    /// Set on the chunks of a progressive call, which more chunks follow
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub progress: Option<bool>
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct YieldOptions {
+    /// Set on progressive results, which more results follow
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub progress: Option<bool>
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct CancelOptions {
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub mode: Option<CancelMode>
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct InterruptOptions {
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub mode: Option<CancelMode>
+}
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct EventDetails {
+    /// The publisher's session ID, if the publisher asked the router to disclose it
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub publisher: Option<ID>,
+
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub publisher_authid: Option<String>,
+
     #[serde(default, skip_serializing_if="Option::is_none")]
-    publisher: Option<String>,
+    pub publisher_authrole: Option<String>,
 
     #[serde(default, skip_serializing_if="Option::is_none")]
     trustlevel: Option<u64>,
@@ -61,49 +164,151 @@ pub struct EventDetails {
     #[serde(default, skip_serializing_if="Option::is_none")]
     pub topic: Option<URI>,
 
+    /// When the router received the publication, in milliseconds since the Unix epoch, if the
+    /// router stamps events
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub timestamp: Option<u64>
+
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct InvocationDetails {
     #[serde(default, skip_serializing_if="Option::is_none")]
     pub procedure: Option<URI>,
+
+    /// The caller's session ID, if the router disclosed it
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub caller: Option<ID>,
+
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub caller_authid: Option<String>,
+
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub caller_authrole: Option<String>,
+
+    /// Set when the caller accepts progressive results
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub receive_progress: Option<bool>,
+
+    /// Set on the chunks of a progressive call, which more chunks follow
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub progress: Option<bool>
 }
 
-#[derive(PartialEq, Debug)]
-pub struct ResultDetails;
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct ResultDetails {
+    /// Set on progressive results, which more results follow
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub progress: Option<bool>
+}
 
 impl HelloDetails {
     pub fn new(roles: ClientRoles) -> HelloDetails {
         HelloDetails {
             roles: roles,
-            agent: None
+            agent: None,
+            authid: None,
+            authmethods: Vec::new(),
+            authextra: None
         }
     }
 
     pub fn new_with_agent(roles: ClientRoles, agent: &str) -> HelloDetails {
         HelloDetails {
             roles: roles,
-            agent: Some(agent.to_string())
+            agent: Some(agent.to_string()),
+            authid: None,
+            authmethods: Vec::new(),
+            authextra: None
         }
     }
 
+    /// Asks the router to authenticate the client as `authid`, using one of `authmethods`
+    pub fn new_with_authentication(roles: ClientRoles, authid: &str, authmethods: Vec<String>) -> HelloDetails {
+        HelloDetails {
+            roles: roles,
+            agent: None,
+            authid: Some(authid.to_string()),
+            authmethods: authmethods,
+            authextra: None
+        }
+    }
+
+    /// Tells the router which software the client is running
+    pub fn with_agent(mut self, agent: &str) -> HelloDetails {
+        self.agent = Some(agent.to_string());
+        self
+    }
+
+    /// Gives the router extra details for authenticating the client, such as a public key
+    pub fn with_authextra(mut self, authextra: Dict) -> HelloDetails {
+        self.authextra = Some(authextra);
+        self
+    }
+
+    pub fn agent(&self) -> Option<&str> {
+        self.agent.as_ref().map(|agent| agent.as_str())
+    }
+
+    pub fn authid(&self) -> Option<&str> {
+        self.authid.as_ref().map(|authid| authid.as_str())
+    }
+
+    pub fn authmethods(&self) -> &[String] {
+        &self.authmethods
+    }
+
+    pub fn authextra(&self) -> Option<&Dict> {
+        self.authextra.as_ref()
+    }
+
 }
 
 impl WelcomeDetails {
     pub fn new(roles: RouterRoles) -> WelcomeDetails {
         WelcomeDetails {
             roles: roles,
-            agent: None
+            agent: None,
+            authid: None,
+            authrole: None,
+            authmethod: None,
+            authprovider: None
         }
     }
 
     pub fn new_with_agent(roles: RouterRoles, agent: &str) -> WelcomeDetails {
         WelcomeDetails {
-            roles: roles,
-            agent: Some(agent.to_string())
+            agent: Some(agent.to_string()),
+            ..WelcomeDetails::new(roles)
         }
     }
 
+    /// Tells the client who it was authenticated as, and how
+    pub fn new_with_authentication(roles: RouterRoles, authid: &str, authrole: &str, authmethod: &str) -> WelcomeDetails {
+        WelcomeDetails {
+            authid: Some(authid.to_string()),
+            authrole: Some(authrole.to_string()),
+            authmethod: Some(authmethod.to_string()),
+            ..WelcomeDetails::new(roles)
+        }
+    }
+
+    pub fn authid(&self) -> Option<&str> {
+        self.authid.as_ref().map(|authid| authid.as_str())
+    }
+
+    pub fn authrole(&self) -> Option<&str> {
+        self.authrole.as_ref().map(|authrole| authrole.as_str())
+    }
+
+    pub fn authmethod(&self) -> Option<&str> {
+        self.authmethod.as_ref().map(|authmethod| authmethod.as_str())
+    }
+
+    pub fn authprovider(&self) -> Option<&str> {
+        self.authprovider.as_ref().map(|authprovider| authprovider.as_str())
+    }
+
 }
 
 
@@ -125,7 +330,8 @@ impl ErrorDetails {
 impl SubscribeOptions {
     pub fn new() -> SubscribeOptions {
         SubscribeOptions {
-            pattern_match: MatchingPolicy::Strict
+            pattern_match: MatchingPolicy::Strict,
+            shard_group: None
         }
     }
 }
@@ -133,33 +339,246 @@ impl SubscribeOptions {
 impl PublishOptions {
     pub fn new(acknowledge: bool) -> PublishOptions {
         PublishOptions {
-            acknowledge: acknowledge
+            acknowledge: acknowledge,
+            shard_key: None,
+            exclude: Vec::new(),
+            exclude_authid: Vec::new(),
+            exclude_authrole: Vec::new(),
+            eligible: None,
+            eligible_authid: None,
+            eligible_authrole: None,
+            exclude_me: None,
+            disclose_me: None,
+            retain: None
         }
     }
 
+    pub fn new_with_shard_key(acknowledge: bool, shard_key: &str) -> PublishOptions {
+        let mut options = PublishOptions::new(acknowledge);
+        options.shard_key = Some(shard_key.to_string());
+        options
+    }
+
+    /// Keeps the event from the sessions with the given IDs
+    pub fn with_exclude(mut self, sessions: Vec<ID>) -> PublishOptions {
+        self.exclude = sessions;
+        self
+    }
+
+    /// Keeps the event from sessions authenticated as any of `authids`
+    pub fn with_exclude_authid(mut self, authids: Vec<String>) -> PublishOptions {
+        self.exclude_authid = authids;
+        self
+    }
+
+    /// Keeps the event from sessions with any of `authroles`
+    pub fn with_exclude_authrole(mut self, authroles: Vec<String>) -> PublishOptions {
+        self.exclude_authrole = authroles;
+        self
+    }
+
+    /// Only delivers the event to the sessions with the given IDs
+    pub fn with_eligible(mut self, sessions: Vec<ID>) -> PublishOptions {
+        self.eligible = Some(sessions);
+        self
+    }
+
+    /// Only delivers the event to sessions authenticated as one of `authids`
+    pub fn with_eligible_authid(mut self, authids: Vec<String>) -> PublishOptions {
+        self.eligible_authid = Some(authids);
+        self
+    }
+
+    /// Only delivers the event to sessions with one of `authroles`
+    pub fn with_eligible_authrole(mut self, authroles: Vec<String>) -> PublishOptions {
+        self.eligible_authrole = Some(authroles);
+        self
+    }
+
+    /// Sets whether the publisher is left out of the event's recipients
+    pub fn with_exclude_me(mut self, exclude_me: bool) -> PublishOptions {
+        self.exclude_me = Some(exclude_me);
+        self
+    }
+
+    /// Sets whether subscribers are told the publisher's session ID, authid and authrole
+    pub fn with_disclose_me(mut self, disclose_me: bool) -> PublishOptions {
+        self.disclose_me = Some(disclose_me);
+        self
+    }
+
+    /// Sets whether the router keeps the event for sessions that subscribe later
+    pub fn with_retain(mut self, retain: bool) -> PublishOptions {
+        self.retain = Some(retain);
+        self
+    }
+
     pub fn should_acknowledge(&self) -> bool {
         self.acknowledge
     }
+
+    pub fn discloses_publisher(&self) -> bool {
+        self.disclose_me.unwrap_or(false)
+    }
+
+    pub fn should_retain(&self) -> bool {
+        self.retain.unwrap_or(false)
+    }
+
+    pub fn excludes_publisher(&self) -> bool {
+        self.exclude_me.unwrap_or(true)
+    }
+
+    /// Whether only some of the subscribers may receive the event, because of an eligible or
+    /// exclude list
+    pub fn is_restricted(&self) -> bool {
+        self.eligible.is_some() || self.eligible_authid.is_some() || self.eligible_authrole.is_some() ||
+            !self.exclude.is_empty() || !self.exclude_authid.is_empty() || !self.exclude_authrole.is_empty()
+    }
+
+    /// Whether a subscriber may receive the event.  A subscriber must pass every eligible list
+    /// that is set, and be on none of the exclude lists.
+    pub fn admits(&self, session: ID, authid: Option<&str>, authrole: Option<&str>) -> bool {
+        fn listed(list: &[String], value: Option<&str>) -> bool {
+            value.map_or(false, |value| list.iter().any(|entry| entry == value))
+        }
+        if let Some(ref eligible) = self.eligible {
+            if !eligible.contains(&session) {
+                return false;
+            }
+        }
+        if let Some(ref eligible) = self.eligible_authid {
+            if !listed(eligible, authid) {
+                return false;
+            }
+        }
+        if let Some(ref eligible) = self.eligible_authrole {
+            if !listed(eligible, authrole) {
+                return false;
+            }
+        }
+        !self.exclude.contains(&session) && !listed(&self.exclude_authid, authid) && !listed(&self.exclude_authrole, authrole)
+    }
 }
 
 impl RegisterOptions {
     pub fn new() -> RegisterOptions {
         RegisterOptions {
             pattern_match: MatchingPolicy::Strict,
-            invocation_policy: InvocationPolicy::Single
+            invocation_policy: InvocationPolicy::Single,
+            cache_ttl: None,
+            disclose_caller: None
         }
     }
+
+    /// Sets whether the callee is told the session ID, authid and authrole of every caller
+    pub fn with_disclose_caller(mut self, disclose_caller: bool) -> RegisterOptions {
+        self.disclose_caller = Some(disclose_caller);
+        self
+    }
+
+    pub fn discloses_caller(&self) -> bool {
+        self.disclose_caller.unwrap_or(false)
+    }
+
+    /// Sets how the router matches the procedures called against the registered URI
+    pub fn with_pattern_match(mut self, policy: MatchingPolicy) -> RegisterOptions {
+        self.pattern_match = policy;
+        self
+    }
+
+    /// Sets how the router picks between the sessions that register the procedure.  Every
+    /// session has to ask for the same policy, and `InvocationPolicy::Single` doesn't share.
+    pub fn with_invocation_policy(mut self, policy: InvocationPolicy) -> RegisterOptions {
+        self.invocation_policy = policy;
+        self
+    }
 }
 
 impl CallOptions {
     pub fn new() -> CallOptions {
-        CallOptions{}
+        CallOptions {
+            timeout: None,
+            disclose_me: None,
+            receive_progress: None,
+            progress: None
+        }
+    }
+
+    /// Sets how long, in milliseconds, the callee may take
+    pub fn with_timeout(mut self, timeout: u64) -> CallOptions {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sets whether the callee is told the caller's session ID, authid and authrole
+    pub fn with_disclose_me(mut self, disclose_me: bool) -> CallOptions {
+        self.disclose_me = Some(disclose_me);
+        self
+    }
+
+    pub fn discloses_caller(&self) -> bool {
+        self.disclose_me.unwrap_or(false)
+    }
+
+    /// Sets whether the router may pass on the callee's progressive results
+    pub fn with_receive_progress(mut self, receive_progress: bool) -> CallOptions {
+        self.receive_progress = Some(receive_progress);
+        self
+    }
+
+    pub fn receives_progress(&self) -> bool {
+        self.receive_progress.unwrap_or(false)
+    }
+
+    /// Sets whether more chunks of the call follow this one
+    pub fn with_progress(mut self, progress: bool) -> CallOptions {
+        self.progress = Some(progress);
+        self
+    }
+
+    pub fn is_progressive(&self) -> bool {
+        self.progress.unwrap_or(false)
+    }
+}
+
+impl CancelOptions {
+    pub fn new(mode: CancelMode) -> CancelOptions {
+        CancelOptions {
+            mode: Some(mode)
+        }
+    }
+
+    /// The mode to cancel with.  Routers kill calls unless told otherwise.
+    pub fn mode(&self) -> CancelMode {
+        self.mode.unwrap_or(CancelMode::Kill)
+    }
+}
+
+impl InterruptOptions {
+    pub fn new(mode: CancelMode) -> InterruptOptions {
+        InterruptOptions {
+            mode: Some(mode)
+        }
     }
 }
 
 impl YieldOptions {
     pub fn new() -> YieldOptions {
-        YieldOptions{}
+        YieldOptions {
+            progress: None
+        }
+    }
+
+    /// The options of a progressive result
+    pub fn progressive() -> YieldOptions {
+        YieldOptions {
+            progress: Some(true)
+        }
+    }
+
+    pub fn is_progressive(&self) -> bool {
+        self.progress.unwrap_or(false)
     }
 }
 
@@ -167,34 +586,67 @@ impl EventDetails {
     pub fn new() -> EventDetails {
         EventDetails {
             publisher: None,
+            publisher_authid: None,
+            publisher_authrole: None,
             trustlevel: None,
-            topic: None
+            topic: None,
+            timestamp: None
         }
     }
 
     pub fn new_with_topic(topic: URI) -> EventDetails {
         EventDetails {
             publisher: None,
+            publisher_authid: None,
+            publisher_authrole: None,
             trustlevel: None,
-            topic: Some(topic)
+            topic: Some(topic),
+            timestamp: None
         }
     }
+
+    pub fn with_timestamp(mut self, timestamp: u64) -> EventDetails {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// Discloses the publisher of the event
+    pub fn with_publisher(mut self, session_id: ID, authid: Option<String>, authrole: Option<String>) -> EventDetails {
+        self.publisher = Some(session_id);
+        self.publisher_authid = authid;
+        self.publisher_authrole = authrole;
+        self
+    }
 }
 
 impl InvocationDetails {
     pub fn new() -> InvocationDetails {
         InvocationDetails{
-            procedure: None
+            procedure: None,
+            caller: None,
+            caller_authid: None,
+            caller_authrole: None,
+            receive_progress: None,
+            progress: None
         }
     }
 }
 
 impl ResultDetails {
     pub fn new() -> ResultDetails {
-        ResultDetails{}
+        ResultDetails {
+            progress: None
+        }
     }
-}
 
-serialize_empty!(CallOptions);
-serialize_empty!(YieldOptions);
-serialize_empty!(ResultDetails);
+    /// The details of a progressive result
+    pub fn progressive() -> ResultDetails {
+        ResultDetails {
+            progress: Some(true)
+        }
+    }
+
+    pub fn is_progressive(&self) -> bool {
+        self.progress.unwrap_or(false)
+    }
+}
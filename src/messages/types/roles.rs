@@ -1,12 +1,17 @@
 use super::{is_not};
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 
+/// The roles a client announces.  Clients only announce the roles they were built with.
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct ClientRoles {
-    pub publisher: PublisherRole,
-    pub subscriber: SubscriberRole,
-    pub caller: CallerRole,
-    pub callee: CalleeRole,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub publisher: Option<PublisherRole>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub subscriber: Option<SubscriberRole>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub caller: Option<CallerRole>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub callee: Option<CalleeRole>,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
@@ -21,19 +26,19 @@ pub struct RouterRoles {
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct PublisherRole {
     #[serde(default, skip_serializing_if="Option::is_none")]
-    features: Option<HashMap<String, bool>>
+    features: Option<BTreeMap<String, bool>>
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct CallerRole {
     #[serde(default, skip_serializing_if="Option::is_none")]
-    features: Option<HashMap<String, bool>>
+    features: Option<BTreeMap<String, bool>>
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct CalleeRole {
     #[serde(default, skip_serializing_if="Option::is_none")]
-    features: Option<HashMap<String, bool>>
+    features: Option<BTreeMap<String, bool>>
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
@@ -44,7 +49,9 @@ pub struct SubscriberRole {
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct SubscriberFeatures {
     #[serde(skip_serializing_if="is_not", default)]
-    pattern_based_subscription: bool
+    pattern_based_subscription: bool,
+    #[serde(skip_serializing_if="is_not", default)]
+    publisher_identification: bool
 }
 
 
@@ -63,13 +70,31 @@ pub struct BrokerRole {
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct DealerFeatures {
     #[serde(skip_serializing_if="is_not", default)]
-    pattern_based_registration: bool
+    pattern_based_registration: bool,
+    #[serde(skip_serializing_if="is_not", default)]
+    caller_identification: bool,
+    #[serde(skip_serializing_if="is_not", default)]
+    call_canceling: bool,
+    #[serde(skip_serializing_if="is_not", default)]
+    shared_registration: bool,
+    #[serde(skip_serializing_if="is_not", default)]
+    progressive_call_results: bool,
+    #[serde(skip_serializing_if="is_not", default)]
+    progressive_call_invocations: bool
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct BrokerFeatures {
     #[serde(skip_serializing_if="is_not", default)]
-    pattern_based_subscription: bool
+    pattern_based_subscription: bool,
+    #[serde(skip_serializing_if="is_not", default)]
+    subscriber_blackwhite_listing: bool,
+    #[serde(skip_serializing_if="is_not", default)]
+    publisher_exclusion: bool,
+    #[serde(skip_serializing_if="is_not", default)]
+    publisher_identification: bool,
+    #[serde(skip_serializing_if="is_not", default)]
+    event_retention: bool
 }
 
 /**************************
@@ -82,12 +107,21 @@ impl RouterRoles {
         RouterRoles {
             broker: BrokerRole {
                 features: Some(BrokerFeatures {
-                    pattern_based_subscription: true
+                    pattern_based_subscription: true,
+                    subscriber_blackwhite_listing: true,
+                    publisher_exclusion: true,
+                    publisher_identification: true,
+                    event_retention: true
                 })
             },
             dealer: DealerRole {
                 features: Some(DealerFeatures {
-                    pattern_based_registration: true
+                    pattern_based_registration: true,
+                    caller_identification: true,
+                    call_canceling: true,
+                    shared_registration: true,
+                    progressive_call_results: true,
+                    progressive_call_invocations: true
                 })
             }
         }
@@ -109,21 +143,68 @@ impl RouterRoles {
 impl ClientRoles {
     #[inline]
     pub fn new() -> ClientRoles {
-        ClientRoles {
-            publisher: PublisherRole{features: Some(HashMap::new())},
-            subscriber: SubscriberRole{features: Some(SubscriberFeatures{pattern_based_subscription: true})},
-            caller: CallerRole{features: Some(HashMap::new())},
-            callee: CalleeRole{features: Some(HashMap::new())}
-        }
+        let mut publisher_features = BTreeMap::new();
+        publisher_features.insert("publisher_identification".to_string(), true);
+        let mut caller_features = BTreeMap::new();
+        caller_features.insert("progressive_call_results".to_string(), true);
+        caller_features.insert("call_canceling".to_string(), true);
+        caller_features.insert("progressive_call_invocations".to_string(), true);
+        let mut callee_features = BTreeMap::new();
+        callee_features.insert("shared_registration".to_string(), true);
+        callee_features.insert("call_canceling".to_string(), true);
+        callee_features.insert("progressive_call_results".to_string(), true);
+        callee_features.insert("progressive_call_invocations".to_string(), true);
+        AllClientRoles {
+            publisher: PublisherRole{features: Some(publisher_features)},
+            subscriber: SubscriberRole{features: Some(SubscriberFeatures{pattern_based_subscription: true, publisher_identification: true})},
+            caller: CallerRole{features: Some(caller_features)},
+            callee: CalleeRole{features: Some(callee_features)}
+        }.enabled()
     }
 
     #[inline]
     pub fn new_basic() -> ClientRoles {
+        AllClientRoles {
+            publisher: PublisherRole{features: Some(BTreeMap::new())},
+            subscriber: SubscriberRole{features: Some(SubscriberFeatures{pattern_based_subscription: false, publisher_identification: false})},
+            caller: CallerRole{features: Some(BTreeMap::new())},
+            callee: CalleeRole{features: Some(BTreeMap::new())}
+        }.enabled()
+    }
+}
+
+/// Every client role, before dropping the ones this build leaves out
+struct AllClientRoles {
+    publisher: PublisherRole,
+    subscriber: SubscriberRole,
+    caller: CallerRole,
+    callee: CalleeRole
+}
+
+impl AllClientRoles {
+    fn enabled(self) -> ClientRoles {
         ClientRoles {
-            publisher: PublisherRole{features: Some(HashMap::new())},
-            subscriber: SubscriberRole{features: Some(SubscriberFeatures{pattern_based_subscription: false})},
-            caller: CallerRole{features: Some(HashMap::new())},
-            callee: CalleeRole{features: Some(HashMap::new())}
+            publisher: if cfg!(feature = "publisher") { Some(self.publisher) } else { None },
+            subscriber: if cfg!(feature = "subscriber") { Some(self.subscriber) } else { None },
+            caller: if cfg!(feature = "caller") { Some(self.caller) } else { None },
+            callee: if cfg!(feature = "callee") { Some(self.callee) } else { None }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ClientRoles;
+    use serde_json;
+
+    #[test]
+    #[cfg(all(feature = "caller", feature = "callee"))]
+    fn client_roles_announce_features() {
+        let roles = serde_json::to_value(&ClientRoles::new()).unwrap();
+        for feature in &["/caller/features/call_canceling", "/callee/features/call_canceling", "/callee/features/progressive_call_results", "/caller/features/progressive_call_invocations", "/callee/features/progressive_call_invocations"] {
+            assert_eq!(roles.pointer(feature).and_then(|value| value.as_bool()), Some(true), "{} isn't announced", feature);
         }
+        let basic = serde_json::to_value(&ClientRoles::new_basic()).unwrap();
+        assert_eq!(basic.pointer("/caller/features/call_canceling"), None);
     }
 }
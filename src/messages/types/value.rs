@@ -2,7 +2,12 @@ use std::collections::HashMap;
 use CallResult;
 use serde;
 use super::{Reason, CallError};
+use std::borrow::Borrow;
 use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::sync::Arc;
+use utils::{base64_encode, base64_decode};
 
 pub type Dict = HashMap<String, Value>;
 pub type List = Vec<Value>;
@@ -10,26 +15,119 @@ pub type List = Vec<Value>;
 // TODO properly implement Hash and Eq
 #[derive(Debug, PartialEq, Clone, Hash, Eq)]
 pub struct URI {
-    pub uri: String
+    pub uri: SharedStr
 }
 
+/// An immutable string that is shared rather than copied when it is cloned.  URIs are cloned
+/// into every event, invocation and routing table entry, so sharing them saves an allocation
+/// and a copy each time.
+///
+/// It dereferences to `str`, and compares equal to strings with the same contents.
+#[derive(Clone, Eq)]
+pub struct SharedStr(Arc<str>);
+
 impl URI {
     pub fn new(uri: &str) -> URI {
         URI {
-            uri: uri.to_string()
+            uri: SharedStr::from(uri)
         }
     }
 }
 
+impl SharedStr {
+    #[inline]
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl<'a> From<&'a str> for SharedStr {
+    fn from(text: &'a str) -> SharedStr {
+        SharedStr(Arc::from(text))
+    }
+}
+
+impl From<String> for SharedStr {
+    fn from(text: String) -> SharedStr {
+        SharedStr(Arc::from(text))
+    }
+}
+
+impl Deref for SharedStr {
+    type Target = str;
+
+    #[inline]
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Borrow<str> for SharedStr {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl PartialEq for SharedStr {
+    #[inline]
+    fn eq(&self, other: &SharedStr) -> bool {
+        // Clones of the same string can be compared without looking at their contents
+        Arc::ptr_eq(&self.0, &other.0) || self.0 == other.0
+    }
+}
+
+impl PartialEq<str> for SharedStr {
+    fn eq(&self, other: &str) -> bool {
+        &*self.0 == other
+    }
+}
+
+impl<'a> PartialEq<&'a str> for SharedStr {
+    fn eq(&self, other: &&'a str) -> bool {
+        &*self.0 == *other
+    }
+}
+
+impl PartialEq<String> for SharedStr {
+    fn eq(&self, other: &String) -> bool {
+        &*self.0 == other.as_str()
+    }
+}
+
+// Hashes the same way as `str`, so that maps keyed by `SharedStr` can be searched with a `&str`
+impl Hash for SharedStr {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state)
+    }
+}
+
+impl fmt::Display for SharedStr {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str(&self.0)
+    }
+}
+
+impl fmt::Debug for SharedStr {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&*self.0, formatter)
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Value {
     // The ID and URI types cannot be distinguished from string and integer types respectively.
     // So, we just ignore them here
     Dict(Dict),
     Integer(i64),
+    // Only used for integers too large to be an `Integer`
+    UnsignedInteger(u64),
     String(String),
     List(List),
-    Boolean(bool)
+    Boolean(bool),
+    // Serialized as a NUL character followed by base64, which is how WAMP sends binary values in
+    // JSON.  MsgPack and CBOR messages are transcoded when they are sent, to use their own binary
+    // values.
+    Bytes(Vec<u8>)
 }
 
 struct URIVisitor;
@@ -135,6 +233,9 @@ impl Value {
             &Value::Integer(i) => {
                 i.to_string()
             },
+            &Value::UnsignedInteger(i) => {
+                i.to_string()
+            },
             &Value::String(ref s) => {
                 if s.len() > 50 {
                     s[..50].to_string()
@@ -152,6 +253,19 @@ impl Value {
             &Value::Boolean(b) => {
                 b.to_string()
             }
+            &Value::Bytes(ref b) => {
+                format!("<{} bytes>", b.len())
+            }
+        }
+    }
+
+    /// Whether the value is or contains a binary value
+    pub fn contains_binary(&self) -> bool {
+        match self {
+            &Value::Bytes(_) => true,
+            &Value::List(ref l) => l.iter().any(Value::contains_binary),
+            &Value::Dict(ref d) => d.values().any(Value::contains_binary),
+            _ => false
         }
     }
 }
@@ -168,9 +282,26 @@ impl serde::de::Visitor for ValueVisitor {
     #[inline]
     fn visit_str<E>(self, value: &str) -> Result<Value, E>
         where E: serde::de::Error {
+            if value.starts_with('\0') {
+                if let Some(bytes) = base64_decode(&value[1..]) {
+                    return Ok(Value::Bytes(bytes));
+                }
+            }
             Ok(Value::String(value.to_string()))
     }
 
+    #[inline]
+    fn visit_bytes<E>(self, value: &[u8]) -> Result<Value, E>
+        where E: serde::de::Error {
+            Ok(Value::Bytes(value.to_vec()))
+    }
+
+    #[inline]
+    fn visit_byte_buf<E>(self, value: Vec<u8>) -> Result<Value, E>
+        where E: serde::de::Error {
+            Ok(Value::Bytes(value))
+    }
+
 
     #[inline]
     fn visit_i64<E>(self, value: i64) -> Result<Value, E>
@@ -181,7 +312,11 @@ impl serde::de::Visitor for ValueVisitor {
     #[inline]
     fn visit_u64<E>(self, value: u64) -> Result<Value, E>
     where E: serde::de::Error {
-        Ok(Value::Integer(value as i64))
+        if value > i64::max_value() as u64 {
+            Ok(Value::UnsignedInteger(value))
+        } else {
+            Ok(Value::Integer(value as i64))
+        }
     }
 
     #[inline]
@@ -233,8 +368,10 @@ impl serde::Serialize for Value {
             &Value::Dict(ref dict) => dict.serialize(serializer),
             &Value::String(ref s) => serializer.serialize_str(s),
             &Value::Integer(i) => serializer.serialize_i64(i),
+            &Value::UnsignedInteger(i) => serializer.serialize_u64(i),
             &Value::List(ref list) => list.serialize(serializer),
-            &Value::Boolean(b) => serializer.serialize_bool(b)
+            &Value::Boolean(b) => serializer.serialize_bool(b),
+            &Value::Bytes(ref b) => serializer.serialize_str(&format!("\0{}", base64_encode(b)))
         }
     }
 }
@@ -278,8 +415,32 @@ impl serde::de::Visitor for URIVisitor {
         where E: serde::de::Error,
     {
         Ok(URI {
-            uri: value.to_string()
+            uri: SharedStr::from(value)
         })
     }
 
 }
+
+#[cfg(test)]
+mod test {
+    use super::{URI, SharedStr};
+    use std::collections::HashMap;
+
+    #[test]
+    fn shared_uris_compare_as_strings() {
+        let uri = URI::new("ca.test.topic");
+        let copy = uri.clone();
+        assert_eq!(copy, uri);
+        assert_eq!(URI::new("ca.test.topic"), uri);
+        assert!(uri.uri == "ca.test.topic");
+        assert!(uri.uri != "ca.test.other");
+        assert_eq!(uri.uri.as_str(), "ca.test.topic");
+        assert_eq!(uri.uri.split('.').count(), 3);
+
+        let mut map: HashMap<SharedStr, u32> = HashMap::new();
+        map.insert(copy.uri, 1);
+        assert_eq!(map.get("ca.test.topic"), Some(&1));
+        assert_eq!(map.get(&uri.uri), Some(&1));
+        assert_eq!(format!("{} {:?}", uri.uri, uri.uri), "ca.test.topic \"ca.test.topic\"");
+    }
+}
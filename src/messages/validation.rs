@@ -0,0 +1,284 @@
+//! Checks the shape of incoming messages before they are deserialized.
+//!
+//! Deserializing a malformed message only reports that something didn't match, which makes
+//! misbehaving peers hard to debug.  These checks say exactly which message and field were
+//! wrong, and what was expected instead.
+use rmp_serde::Deserializer as RMPDeserializer;
+use serde_cbor::de::Deserializer as CBORDeserializer;
+use transcode::{Transcoder, BinaryConversion};
+use serde_json::{self, Value as JSONValue};
+use std::fmt;
+use std::io::Cursor;
+
+/// How strictly a peer checks the messages it recieves.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ValidationMode {
+    /// Every message is checked before it is deserialized, and any problem is treated as a
+    /// protocol error
+    #[serde(rename="strict")]
+    Strict,
+    /// Messages are only checked when they fail to deserialize, to explain what was wrong with
+    /// them
+    #[serde(rename="lenient")]
+    Lenient
+}
+
+/// A description of what was wrong with a message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProtocolViolation {
+    /// The name of the message type, if it could be determined
+    pub message: Option<&'static str>,
+    /// The field that was wrong, or `None` if the message as a whole was wrong
+    pub field: Option<&'static str>,
+    pub expected: String,
+    pub got: String
+}
+
+#[derive(Clone, Copy)]
+enum Field {
+    ID,
+    Integer,
+    URI,
+    Str,
+    Dict,
+    OptionalList,
+    OptionalDict
+}
+
+type Schema = (&'static str, &'static [(&'static str, Field)]);
+
+static HELLO: Schema = ("HELLO", &[("realm", Field::URI), ("details", Field::Dict)]);
+static WELCOME: Schema = ("WELCOME", &[("session", Field::ID), ("details", Field::Dict)]);
+static ABORT: Schema = ("ABORT", &[("details", Field::Dict), ("reason", Field::URI)]);
+static CHALLENGE: Schema = ("CHALLENGE", &[("authentication method", Field::Str), ("extra", Field::Dict)]);
+static AUTHENTICATE: Schema = ("AUTHENTICATE", &[("signature", Field::Str), ("extra", Field::Dict)]);
+static GOODBYE: Schema = ("GOODBYE", &[("details", Field::Dict), ("reason", Field::URI)]);
+static ERROR: Schema = ("ERROR", &[("request type", Field::Integer), ("request id", Field::ID), ("details", Field::Dict), ("error", Field::URI), ("arguments", Field::OptionalList), ("keyword arguments", Field::OptionalDict)]);
+static PUBLISH: Schema = ("PUBLISH", &[("request id", Field::ID), ("options", Field::Dict), ("topic", Field::URI), ("arguments", Field::OptionalList), ("keyword arguments", Field::OptionalDict)]);
+static PUBLISHED: Schema = ("PUBLISHED", &[("request id", Field::ID), ("publication id", Field::ID)]);
+static SUBSCRIBE: Schema = ("SUBSCRIBE", &[("request id", Field::ID), ("options", Field::Dict), ("topic", Field::URI)]);
+static SUBSCRIBED: Schema = ("SUBSCRIBED", &[("request id", Field::ID), ("subscription id", Field::ID)]);
+static UNSUBSCRIBE: Schema = ("UNSUBSCRIBE", &[("request id", Field::ID), ("subscription id", Field::ID)]);
+static UNSUBSCRIBED: Schema = ("UNSUBSCRIBED", &[("request id", Field::ID)]);
+static EVENT: Schema = ("EVENT", &[("subscription id", Field::ID), ("publication id", Field::ID), ("details", Field::Dict), ("arguments", Field::OptionalList), ("keyword arguments", Field::OptionalDict)]);
+static CALL: Schema = ("CALL", &[("request id", Field::ID), ("options", Field::Dict), ("procedure", Field::URI), ("arguments", Field::OptionalList), ("keyword arguments", Field::OptionalDict)]);
+static RESULT: Schema = ("RESULT", &[("request id", Field::ID), ("details", Field::Dict), ("arguments", Field::OptionalList), ("keyword arguments", Field::OptionalDict)]);
+static REGISTER: Schema = ("REGISTER", &[("request id", Field::ID), ("options", Field::Dict), ("procedure", Field::URI)]);
+static REGISTERED: Schema = ("REGISTERED", &[("request id", Field::ID), ("registration id", Field::ID)]);
+static UNREGISTER: Schema = ("UNREGISTER", &[("request id", Field::ID), ("registration id", Field::ID)]);
+static UNREGISTERED: Schema = ("UNREGISTERED", &[("request id", Field::ID)]);
+static INVOCATION: Schema = ("INVOCATION", &[("request id", Field::ID), ("registration id", Field::ID), ("details", Field::Dict), ("arguments", Field::OptionalList), ("keyword arguments", Field::OptionalDict)]);
+static CANCEL: Schema = ("CANCEL", &[("request id", Field::ID), ("options", Field::Dict)]);
+static INTERRUPT: Schema = ("INTERRUPT", &[("request id", Field::ID), ("options", Field::Dict)]);
+static YIELD: Schema = ("YIELD", &[("request id", Field::ID), ("options", Field::Dict), ("arguments", Field::OptionalList), ("keyword arguments", Field::OptionalDict)]);
+
+fn schema_for(message_type: u64) -> Option<&'static Schema> {
+    match message_type {
+        1  => Some(&HELLO),
+        2  => Some(&WELCOME),
+        3  => Some(&ABORT),
+        4  => Some(&CHALLENGE),
+        5  => Some(&AUTHENTICATE),
+        6  => Some(&GOODBYE),
+        8  => Some(&ERROR),
+        16 => Some(&PUBLISH),
+        17 => Some(&PUBLISHED),
+        32 => Some(&SUBSCRIBE),
+        33 => Some(&SUBSCRIBED),
+        34 => Some(&UNSUBSCRIBE),
+        35 => Some(&UNSUBSCRIBED),
+        36 => Some(&EVENT),
+        48 => Some(&CALL),
+        49 => Some(&CANCEL),
+        50 => Some(&RESULT),
+        64 => Some(&REGISTER),
+        65 => Some(&REGISTERED),
+        66 => Some(&UNREGISTER),
+        67 => Some(&UNREGISTERED),
+        68 => Some(&INVOCATION),
+        69 => Some(&INTERRUPT),
+        70 => Some(&YIELD),
+        _  => None
+    }
+}
+
+fn describe(value: &JSONValue) -> String {
+    match *value {
+        JSONValue::Null => "null".to_string(),
+        JSONValue::Bool(_) => "a boolean".to_string(),
+        JSONValue::Number(ref n) if n.is_u64() || n.is_i64() => format!("the integer {}", n),
+        JSONValue::Number(ref n) => format!("the number {}", n),
+        JSONValue::String(_) => "a string".to_string(),
+        JSONValue::Array(ref a) => format!("a list of {} elements", a.len()),
+        JSONValue::Object(_) => "a dictionary".to_string()
+    }
+}
+
+impl Field {
+    fn expected(&self) -> &'static str {
+        match *self {
+            Field::ID => "a non-negative integer ID",
+            Field::Integer => "an integer",
+            Field::URI => "a URI string",
+            Field::Str => "a string",
+            Field::Dict | Field::OptionalDict => "a dictionary",
+            Field::OptionalList => "a list"
+        }
+    }
+
+    fn accepts(&self, value: &JSONValue) -> bool {
+        match *self {
+            Field::ID => value.is_u64(),
+            Field::Integer => value.is_u64() || value.is_i64(),
+            Field::URI | Field::Str => value.is_string(),
+            Field::Dict | Field::OptionalDict => value.is_object(),
+            Field::OptionalList => value.is_array()
+        }
+    }
+
+    fn is_optional(&self) -> bool {
+        match *self {
+            Field::OptionalList | Field::OptionalDict => true,
+            _ => false
+        }
+    }
+}
+
+/// Checks a message that has already been parsed into a generic document
+pub fn validate(document: &JSONValue) -> Result<(), ProtocolViolation> {
+    let elements = match document.as_array() {
+        Some(elements) => elements,
+        None => return Err(ProtocolViolation {
+            message: None,
+            field: None,
+            expected: "a list".to_string(),
+            got: describe(document)
+        })
+    };
+    let message_type = match elements.first() {
+        Some(message_type) => message_type,
+        None => return Err(ProtocolViolation {
+            message: None,
+            field: Some("message type"),
+            expected: "a message type".to_string(),
+            got: "an empty list".to_string()
+        })
+    };
+    let &(name, fields) = match message_type.as_u64().and_then(schema_for) {
+        Some(schema) => schema,
+        None => return Err(ProtocolViolation {
+            message: None,
+            field: Some("message type"),
+            expected: "a known message type".to_string(),
+            got: describe(message_type)
+        })
+    };
+    let required = fields.iter().filter(|&&(_, field)| !field.is_optional()).count();
+    let arguments = elements.len() - 1;
+    if arguments < required || arguments > fields.len() {
+        let expected = if required == fields.len() {
+            format!("{} elements", required + 1)
+        } else {
+            format!("between {} and {} elements", required + 1, fields.len() + 1)
+        };
+        return Err(ProtocolViolation {
+            message: Some(name),
+            field: None,
+            expected: expected,
+            got: format!("{} elements", elements.len())
+        });
+    }
+    for (&(field_name, field), value) in fields.iter().zip(elements[1..].iter()) {
+        if !field.accepts(value) {
+            return Err(ProtocolViolation {
+                message: Some(name),
+                field: Some(field_name),
+                expected: field.expected().to_string(),
+                got: describe(value)
+            });
+        }
+    }
+    Ok(())
+}
+
+pub fn validate_json(payload: &str) -> Result<(), ProtocolViolation> {
+    match serde_json::from_str(payload) {
+        Ok(document) => validate(&document),
+        Err(e) => Err(ProtocolViolation {
+            message: None,
+            field: None,
+            expected: "valid JSON".to_string(),
+            got: e.to_string()
+        })
+    }
+}
+
+pub fn validate_msgpack(payload: &[u8]) -> Result<(), ProtocolViolation> {
+    // Binary values are turned into the strings JSON uses for them, since a JSON document can't
+    // hold them directly
+    let mut de = RMPDeserializer::new(Cursor::new(payload));
+    match serde_json::to_value(Transcoder::new(&mut de).with_binary(BinaryConversion::ToBase64)) {
+        Ok(document) => validate(&document),
+        Err(e) => Err(ProtocolViolation {
+            message: None,
+            field: None,
+            expected: "valid MsgPack".to_string(),
+            got: e.to_string()
+        })
+    }
+}
+
+pub fn validate_cbor(payload: &[u8]) -> Result<(), ProtocolViolation> {
+    let mut de = CBORDeserializer::new(payload);
+    match serde_json::to_value(Transcoder::new(&mut de).with_binary(BinaryConversion::ToBase64)) {
+        Ok(document) => validate(&document),
+        Err(e) => Err(ProtocolViolation {
+            message: None,
+            field: None,
+            expected: "valid CBOR".to_string(),
+            got: e.to_string()
+        })
+    }
+}
+
+impl fmt::Display for ProtocolViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.message, self.field) {
+            (Some(message), Some(field)) => write!(f, "{} message has an invalid {}: expected {}, got {}", message, field, self.expected, self.got),
+            (Some(message), None) => write!(f, "{} message should have {}, but has {}", message, self.expected, self.got),
+            (None, Some(field)) => write!(f, "Message has an invalid {}: expected {}, got {}", field, self.expected, self.got),
+            (None, None) => write!(f, "Expected a message to be {}, got {}", self.expected, self.got)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{validate_json, validate_msgpack};
+
+    #[test]
+    fn valid_messages() {
+        assert!(validate_json("[50,7814135,{}]").is_ok());
+        assert!(validate_json("[50,7814135,{},[\"a\"],{\"b\":1}]").is_ok());
+        assert!(validate_json("[8,48,7814135,{},\"wamp.error.no_such_procedure\"]").is_ok());
+        assert!(validate_msgpack(&[0x93, 0x21, 0x01, 0x02]).is_ok());
+    }
+
+    #[test]
+    fn invalid_messages() {
+        let violation = validate_json("[50,7814135]").unwrap_err();
+        assert_eq!(violation.to_string(), "RESULT message should have between 3 and 5 elements, but has 2 elements");
+
+        let violation = validate_json("[50,\"7814135\",{}]").unwrap_err();
+        assert_eq!((violation.message, violation.field), (Some("RESULT"), Some("request id")));
+        assert_eq!(violation.to_string(), "RESULT message has an invalid request id: expected a non-negative integer ID, got a string");
+
+        let violation = validate_json("[32,1,{},\"ca.test\",\"extra\"]").unwrap_err();
+        assert_eq!(violation.expected, "4 elements");
+
+        let violation = validate_json("[99,1]").unwrap_err();
+        assert_eq!(violation.field, Some("message type"));
+        assert!(validate_json("{\"type\": 50}").is_err());
+        assert!(validate_json("[50,").is_err());
+    }
+}
@@ -0,0 +1,329 @@
+//! Contains the router's admin realm, whose procedures manage the router while it runs.
+//!
+//! Sessions must authenticate with a ticket to join the admin realm, and are given the `admin`
+//! authentication role when they do.  Only admin sessions can call the management procedures:
+//!
+//! * `wamp.admin.list_sessions` returns a dictionary describing each session.
+//! * `wamp.admin.kill_session` ends the session whose ID it is given.
+//! * `wamp.admin.add_realm` and `wamp.admin.remove_realm` add and remove the realm whose name
+//!   they are given.  Sessions in a realm that is removed are told goodbye.
+//! * `wamp.admin.get_rules` and `wamp.admin.set_rules` return and replace the list of
+//!   authorization rules.  See `AuthorizationRule`.
+//! * `wamp.admin.stats` returns the number of sessions, protocol violations and authorization
+//!   cache statistics, along with the connections, subscriptions and registrations in each realm.
+use super::{Router, ConnectionHandler, ConnectionState, AuthorizationRule, SessionSummary};
+use router::messaging::send_message;
+use messages::{Message, URI, Dict, List, Value, Reason, ErrorDetails, HelloDetails, WelcomeDetails, RouterRoles};
+use serde_json;
+use std::collections::HashMap;
+use ws::CloseCode;
+use ::{WampResult, ID};
+
+pub const ADMIN_ROLE: &'static str = "admin";
+const TICKET_AUTH: &'static str = "ticket";
+const ADMIN_PREFIX: &'static str = "wamp.admin.";
+const KILLED: &'static str = "wamp.close.killed";
+
+/// The realm that admin sessions join, and the ticket for each of their authentication IDs
+pub struct AdminRealm {
+    pub name: String,
+    tickets: HashMap<String, String>
+}
+
+impl Router {
+    /// Adds a realm for managing the router.  Sessions that join it must authenticate as one of
+    /// the authentication IDs in `tickets` with ticket authentication, and can then call the
+    /// procedures described in the `admin` module.  Replaces any admin realm added before.
+    ///
+    /// Tickets are sent in the clear, so the router should only be reachable over `wss://` or a
+    /// trusted network.
+    pub fn add_admin_realm(&mut self, realm: &str, tickets: HashMap<String, String>) {
+        self.add_realm(realm);
+        *self.info.admin.lock().unwrap() = Some(AdminRealm {
+            name: realm.to_string(),
+            tickets: tickets
+        });
+    }
+
+    /// Says goodbye to the session with the given ID, and returns whether it was found
+    pub fn kill_session(&self, session_id: ID) -> bool {
+        for realm in self.info.realms.lock().unwrap().values() {
+            for connection in realm.connections.lock().unwrap().iter() {
+                {
+                    let info = connection.lock().unwrap();
+                    if info.id != session_id || info.state != ConnectionState::Connected {
+                        continue;
+                    }
+                }
+                info!("Killing session {}", session_id);
+                send_message(connection, &Message::Goodbye(ErrorDetails::new_with_message("killed by an administrator"), Reason::CustomReason(URI::new(KILLED)))).ok();
+                connection.lock().unwrap().state = ConnectionState::ShuttingDown;
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Removes a realm, saying goodbye to the sessions in it, and returns whether it existed.
+    /// The admin realm can't be removed.
+    pub fn remove_realm(&mut self, realm: &str) -> bool {
+        if self.info.admin.lock().unwrap().as_ref().map_or(false, |admin| admin.name == realm) {
+            warn!("Refusing to remove the admin realm {}", realm);
+            return false;
+        }
+        let removed = match self.info.realms.lock().unwrap().remove(realm) {
+            Some(removed) => removed,
+            None => return false
+        };
+        info!("Removed realm {}", realm);
+        for connection in removed.connections.lock().unwrap().iter() {
+            send_message(connection, &Message::Goodbye(ErrorDetails::new(), Reason::CloseRealm)).ok();
+            connection.lock().unwrap().state = ConnectionState::ShuttingDown;
+        }
+        true
+    }
+
+    /// Replaces the authorization rules, which are checked in order before the authorizer
+    pub fn set_authorization_rules(&self, rules: Vec<AuthorizationRule>) {
+        let mut authorization = self.info.authorization.lock().unwrap();
+        authorization.rules = rules;
+    }
+
+    pub fn authorization_rules(&self) -> Vec<AuthorizationRule> {
+        self.info.authorization.lock().unwrap().rules.clone()
+    }
+
+    fn stats(&self) -> Dict {
+        let mut realms = HashMap::new();
+        for (name, realm) in self.info.realms.lock().unwrap().iter() {
+            realms.insert(name.clone(), Value::Dict(realm.stats()));
+        }
+        let authorization = self.authorization_stats();
+        let mut cache = HashMap::new();
+        cache.insert("hits".to_string(), Value::Integer(authorization.hits as i64));
+        cache.insert("misses".to_string(), Value::Integer(authorization.misses as i64));
+        cache.insert("entries".to_string(), Value::Integer(authorization.entries as i64));
+
+        let mut stats = HashMap::new();
+        stats.insert("sessions".to_string(), Value::Integer(self.sessions().len() as i64));
+        stats.insert("protocol_violations".to_string(), Value::Integer(self.protocol_violations() as i64));
+        stats.insert("authorization".to_string(), Value::Dict(cache));
+        stats.insert("realms".to_string(), Value::Dict(realms));
+        stats
+    }
+}
+
+fn insert_optional(description: &mut Dict, key: &str, value: Option<String>) {
+    if let Some(value) = value {
+        description.insert(key.to_string(), Value::String(value));
+    }
+}
+
+fn describe_session(session: SessionSummary) -> Value {
+    let mut description = HashMap::new();
+    description.insert("session".to_string(), Value::Integer(session.session_id as i64));
+    description.insert("realm".to_string(), Value::String(session.realm));
+    insert_optional(&mut description, "authid", session.authid);
+    insert_optional(&mut description, "authrole", session.authrole);
+    insert_optional(&mut description, "agent", session.agent);
+    description.insert("transport".to_string(), Value::String(session.transport));
+    insert_optional(&mut description, "peer", session.peer);
+    description.insert("messages_received".to_string(), Value::Integer(session.messages_received as i64));
+    description.insert("messages_sent".to_string(), Value::Integer(session.messages_sent as i64));
+    description.insert("dropped_events".to_string(), Value::Integer(session.dropped_events as i64));
+    Value::Dict(description)
+}
+
+fn string_argument(args: &Option<List>) -> Result<&str, Reason> {
+    match args.as_ref().map(|args| &args[..]) {
+        Some(&[Value::String(ref name)]) => Ok(name),
+        _ => Err(Reason::InvalidArgument)
+    }
+}
+
+fn id_argument(args: &Option<List>) -> Result<ID, Reason> {
+    match args.as_ref().map(|args| &args[..]) {
+        Some(&[Value::Integer(id)]) if id >= 0 => Ok(id as ID),
+        Some(&[Value::UnsignedInteger(id)]) => Ok(id),
+        _ => Err(Reason::InvalidArgument)
+    }
+}
+
+fn parse_rules(args: &Option<List>) -> Result<Vec<AuthorizationRule>, Reason> {
+    let rules = match args.as_ref().map(|args| &args[..]) {
+        Some(&[ref rules]) => rules,
+        _ => return Err(Reason::InvalidArgument)
+    };
+    let json = try!(serde_json::to_string(rules).map_err(|_| Reason::InvalidArgument));
+    serde_json::from_str(&json).map_err(|e| {
+        info!("Could not parse authorization rules: {}", e);
+        Reason::InvalidArgument
+    })
+}
+
+fn rules_to_value(rules: &[AuthorizationRule]) -> Result<Value, Reason> {
+    let json = try!(serde_json::to_string(rules).map_err(|_| Reason::InvalidArgument));
+    serde_json::from_str(&json).map_err(|_| Reason::InvalidArgument)
+}
+
+impl ConnectionHandler {
+    /// Whether `realm` is the admin realm, which needs ticket authentication to join
+    pub fn is_admin_realm(&self, realm: &str) -> bool {
+        self.router.admin.lock().unwrap().as_ref().map_or(false, |admin| admin.name == realm)
+    }
+
+    /// Challenges a session that wants to join the admin realm for its ticket
+    pub fn challenge_admin(&mut self, realm: URI, details: HelloDetails) -> WampResult<()> {
+        let authid = match details.authid() {
+            Some(authid) if details.authmethods().iter().any(|authmethod| authmethod == TICKET_AUTH) => authid.to_string(),
+            _ => return self.abort(Reason::AuthorizationFailed, "the admin realm needs ticket authentication")
+        };
+        {
+            let mut info = self.info.lock().unwrap();
+            info.authid = Some(authid);
+            info.agent = details.agent().map(|agent| agent.to_string());
+        }
+        self.challenged_realm = Some(realm);
+        send_message(&self.info, &Message::Challenge(TICKET_AUTH.to_string(), HashMap::new()))
+    }
+
+    /// Checks the ticket a session sent to join the admin realm, and welcomes it as an admin if
+    /// the ticket is right
+    pub fn handle_authenticate(&mut self, ticket: String) -> WampResult<()> {
+        let realm = match self.challenged_realm.take() {
+            Some(realm) => realm,
+            None => return self.abort(Reason::ProtocolViolation, "the session wasn't challenged")
+        };
+        let authid = self.info.lock().unwrap().authid.clone().unwrap_or_default();
+        let valid = self.router.admin.lock().unwrap().as_ref().map_or(false, |admin| {
+            realm.uri == admin.name && admin.tickets.get(&authid) == Some(&ticket)
+        });
+        if !valid {
+            info!("[{}] Refusing {} access to the admin realm", self.tracking_id, authid);
+            return self.abort(Reason::AuthorizationFailed, "wrong ticket");
+        }
+        let id = {
+            let mut info = self.info.lock().unwrap();
+            info.state = ConnectionState::Connected;
+            info.authrole = Some(ADMIN_ROLE.to_string());
+            info.id
+        };
+        info!("[{}] Session {} joining the admin realm {} as {}", self.tracking_id, id, realm.uri, authid);
+        try!(self.set_realm(realm.uri.to_string()));
+        send_message(&self.info, &Message::Welcome(id, WelcomeDetails::new_with_authentication(RouterRoles::new(), &authid, ADMIN_ROLE, TICKET_AUTH)))
+    }
+
+    /// Ends a session that hasn't joined a realm yet
+    pub fn abort(&mut self, reason: Reason, message: &str) -> WampResult<()> {
+        info!("[{}] Aborting session: {}", self.tracking_id, message);
+        try!(send_message(&self.info, &Message::Abort(ErrorDetails::new_with_message(message), reason)));
+        let mut info = self.info.lock().unwrap();
+        info.state = ConnectionState::Disconnected;
+        info.close(CloseCode::Normal).ok();
+        Ok(())
+    }
+
+    /// Whether a call should be answered by the admin procedures
+    pub fn is_admin_call(&self, procedure: &URI) -> bool {
+        procedure.uri.starts_with(ADMIN_PREFIX) && self.info.lock().unwrap().authrole.as_ref().map_or(false, |role| role == ADMIN_ROLE)
+    }
+
+    /// Produces the result of a call to one of the admin procedures
+    pub fn call_admin(&self, procedure: &URI, args: Option<List>) -> Result<(Option<List>, Option<Dict>), Reason> {
+        let mut router = Router {
+            info: self.router.clone()
+        };
+        info!("[{}] Admin call to {}", self.tracking_id, procedure.uri);
+        Ok(match &procedure.uri[ADMIN_PREFIX.len()..] {
+            "list_sessions" => (Some(router.sessions().into_iter().map(describe_session).collect()), None),
+            "kill_session" => {
+                let session_id = try!(id_argument(&args));
+                if !router.kill_session(session_id) {
+                    return Err(Reason::InvalidArgument);
+                }
+                (None, None)
+            },
+            "add_realm" => {
+                router.add_realm(try!(string_argument(&args)));
+                (None, None)
+            },
+            "remove_realm" => {
+                if !router.remove_realm(try!(string_argument(&args))) {
+                    return Err(Reason::NoSuchRealm);
+                }
+                (None, None)
+            },
+            "get_rules" => (Some(vec![try!(rules_to_value(&router.authorization_rules()))]), None),
+            "set_rules" => {
+                router.set_authorization_rules(try!(parse_rules(&args)));
+                (None, None)
+            },
+            "stats" => (None, Some(router.stats())),
+            _ => return Err(Reason::NoSuchProcedure)
+        })
+    }
+}
+
+#[cfg(all(test, feature = "caller"))]
+mod test {
+    use client::{Client, Connection};
+    use messages::{URI, Reason, Value};
+    use std::collections::HashMap;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+    use testing::{start_router, join};
+    use ::{List, ID};
+
+    fn join_admin(url: &str, ticket: &str) -> Option<Client> {
+        let mut connection = Connection::new(url, "ca.admin");
+        connection.set_ticket("ops", ticket);
+        connection.connect().ok()
+    }
+
+    fn call(client: &mut Client, procedure: &str, args: List) -> Result<List, Reason> {
+        let call = client.call(URI::new(procedure), Some(args), None).unwrap();
+        call.wait_timeout(Duration::from_secs(5)).map(|(args, _)| args).map_err(|e| e.get_reason().clone())
+    }
+
+    fn session_ids(admin: &mut Client) -> Vec<ID> {
+        call(admin, "wamp.admin.list_sessions", Vec::new()).unwrap().into_iter().map(|session| match session {
+            Value::Dict(ref description) => match description.get("session") {
+                Some(&Value::Integer(id)) => id as ID,
+                other => panic!("Unexpected session ID {:?}", other)
+            },
+            other => panic!("Unexpected session {:?}", other)
+        }).collect()
+    }
+
+    #[test]
+    fn admin_sessions_manage_the_router() {
+        let (mut router, url) = start_router();
+        let mut tickets = HashMap::new();
+        tickets.insert("ops".to_string(), "s3cret".to_string());
+        router.add_admin_realm("ca.admin", tickets);
+
+        assert!(Connection::new(&url, "ca.admin").connect().is_err());
+        assert!(join_admin(&url, "wrong").is_none());
+        let mut admin = join_admin(&url, "s3cret").unwrap();
+        assert_eq!(admin.authrole(), Some("admin".to_string()));
+
+        // Other sessions can't use the admin procedures
+        let mut user = join(&url);
+        assert_eq!(call(&mut user, "wamp.admin.stats", Vec::new()), Err(Reason::NoSuchProcedure));
+        assert!(user.authrole() != Some("admin".to_string()));
+
+        let user_id = user.session_id();
+        assert!(session_ids(&mut admin).contains(&user_id));
+        let (goodbyes, goodbye) = channel();
+        user.on_goodbye(Box::new(move |reason| goodbyes.send(reason.clone()).unwrap()));
+        assert_eq!(call(&mut admin, "wamp.admin.kill_session", vec![Value::Integer(user_id as i64)]), Ok(Vec::new()));
+        assert_eq!(goodbye.recv_timeout(Duration::from_secs(5)).unwrap(), Reason::CustomReason(URI::new("wamp.close.killed")));
+        assert_eq!(call(&mut admin, "wamp.admin.kill_session", vec![Value::Integer(user_id as i64)]), Err(Reason::InvalidArgument));
+
+        assert!(Connection::new(&url, "ca.new").connect().is_err());
+        call(&mut admin, "wamp.admin.add_realm", vec![Value::String("ca.new".to_string())]).unwrap();
+        Connection::new(&url, "ca.new").connect().unwrap();
+        call(&mut admin, "wamp.admin.remove_realm", vec![Value::String("ca.new".to_string())]).unwrap();
+        assert_eq!(call(&mut admin, "wamp.admin.remove_realm", vec![Value::String("ca.admin".to_string())]), Err(Reason::NoSuchRealm));
+    }
+}
@@ -0,0 +1,260 @@
+//! Contains the router's authorization hook, along with the cache that saves it from being
+//! consulted for every single message.
+use messages::URI;
+use std::collections::{BTreeMap, HashMap};
+use ::ID;
+
+/// The actions a session can be authorized to perform on a URI
+#[derive(Hash, Eq, PartialEq, Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum Action {
+    #[serde(rename="publish")]
+    Publish,
+    #[serde(rename="subscribe")]
+    Subscribe,
+    #[serde(rename="call")]
+    Call,
+    #[serde(rename="register")]
+    Register
+}
+
+/// Allows or denies an action on every URI that starts with a prefix.  Rules are checked in
+/// order before the authorizer, and the first one that matches decides.
+///
+/// ```text
+/// {"prefix": "ca.test.private", "action": "subscribe", "authid": "joe", "allow": false}
+/// ```
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct AuthorizationRule {
+    pub prefix: String,
+    /// The action the rule applies to, or every action if there isn't one
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub action: Option<Action>,
+    /// The authentication ID the rule applies to, or every session if there isn't one
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub authid: Option<String>,
+    pub allow: bool
+}
+
+/// Decides whether the session with the given ID may perform an action on a URI
+pub type Authorizer = Box<Fn(ID, Action, &URI) -> bool + Send + Sync>;
+
+/// Statistics about the authorization cache.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuthorizationStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize
+}
+
+impl AuthorizationStats {
+    /// The fraction of authorization checks that were answered from the cache
+    pub fn hit_rate(&self) -> f64 {
+        if self.hits + self.misses == 0 {
+            0.0
+        } else {
+            self.hits as f64 / (self.hits + self.misses) as f64
+        }
+    }
+}
+
+type CacheKey = (ID, Action, String);
+
+/// A least recently used cache of authorization decisions.
+pub struct AuthorizationCache {
+    capacity: usize,
+    // Maps each key to its decision and the tick it was last used at
+    entries: HashMap<CacheKey, (bool, u64)>,
+    // Maps the tick each entry was last used at back to its key, in order of use
+    usage: BTreeMap<u64, CacheKey>,
+    tick: u64,
+    hits: u64,
+    misses: u64
+}
+
+pub struct Authorization {
+    pub authorizer: Option<Authorizer>,
+    pub rules: Vec<AuthorizationRule>,
+    pub cache: AuthorizationCache
+}
+
+impl AuthorizationCache {
+    pub fn new(capacity: usize) -> AuthorizationCache {
+        AuthorizationCache {
+            capacity: capacity,
+            entries: HashMap::new(),
+            usage: BTreeMap::new(),
+            tick: 0,
+            hits: 0,
+            misses: 0
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    pub fn get(&mut self, session: ID, action: Action, uri: &str) -> Option<bool> {
+        let key = (session, action, uri.to_string());
+        let tick = self.next_tick();
+        match self.entries.get_mut(&key) {
+            Some(entry) => {
+                self.usage.remove(&entry.1);
+                entry.1 = tick;
+                self.usage.insert(tick, key);
+                self.hits += 1;
+                Some(entry.0)
+            },
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, session: ID, action: Action, uri: &str, allowed: bool) {
+        if self.capacity == 0 {
+            return;
+        }
+        let key = (session, action, uri.to_string());
+        let tick = self.next_tick();
+        if let Some((_, old_tick)) = self.entries.insert(key.clone(), (allowed, tick)) {
+            self.usage.remove(&old_tick);
+        }
+        self.usage.insert(tick, key);
+        while self.entries.len() > self.capacity {
+            let oldest = match self.usage.keys().next() {
+                Some(oldest) => *oldest,
+                None => break
+            };
+            if let Some(key) = self.usage.remove(&oldest) {
+                self.entries.remove(&key);
+            }
+        }
+    }
+
+    /// Forgets every decision made about the given session
+    pub fn invalidate_session(&mut self, session: ID) {
+        let entries = &mut self.entries;
+        self.usage.retain(|_, key| {
+            if key.0 == session {
+                entries.remove(key);
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.usage.clear();
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.entries.len() > self.capacity {
+            let oldest = match self.usage.keys().next() {
+                Some(oldest) => *oldest,
+                None => break
+            };
+            if let Some(key) = self.usage.remove(&oldest) {
+                self.entries.remove(&key);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> AuthorizationStats {
+        AuthorizationStats {
+            hits: self.hits,
+            misses: self.misses,
+            entries: self.entries.len()
+        }
+    }
+}
+
+impl AuthorizationRule {
+    pub fn matches(&self, authid: Option<&str>, action: Action, uri: &URI) -> bool {
+        uri.uri.starts_with(&self.prefix[..]) &&
+            self.action.map_or(true, |rule_action| rule_action == action) &&
+            self.authid.as_ref().map_or(true, |rule_authid| Some(&rule_authid[..]) == authid)
+    }
+}
+
+impl Authorization {
+    pub fn new() -> Authorization {
+        Authorization {
+            authorizer: None,
+            rules: Vec::new(),
+            cache: AuthorizationCache::new(1024)
+        }
+    }
+
+    /// Checks whether a session may perform an action, consulting the rules and then the cache
+    /// first.  Everything the rules don't decide is allowed if no authorizer has been set.
+    pub fn authorize(&mut self, session: ID, authid: Option<&str>, action: Action, uri: &URI) -> bool {
+        if let Some(rule) = self.rules.iter().find(|rule| rule.matches(authid, action, uri)) {
+            return rule.allow;
+        }
+        if self.authorizer.is_none() {
+            return true;
+        }
+        if let Some(allowed) = self.cache.get(session, action, &uri.uri) {
+            return allowed;
+        }
+        let allowed = match self.authorizer {
+            Some(ref authorizer) => authorizer(session, action, uri),
+            None => true
+        };
+        self.cache.insert(session, action, &uri.uri, allowed);
+        allowed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AuthorizationCache, Authorization, AuthorizationRule, Action};
+    use messages::URI;
+    use serde_json;
+
+    #[test]
+    fn least_recently_used_eviction() {
+        let mut cache = AuthorizationCache::new(2);
+        cache.insert(1, Action::Publish, "ca.test.a", true);
+        cache.insert(1, Action::Publish, "ca.test.b", false);
+        assert_eq!(cache.get(1, Action::Publish, "ca.test.a"), Some(true));
+        cache.insert(1, Action::Publish, "ca.test.c", true);
+        assert_eq!(cache.get(1, Action::Publish, "ca.test.b"), None);
+        assert_eq!(cache.get(1, Action::Publish, "ca.test.a"), Some(true));
+        assert_eq!(cache.get(1, Action::Subscribe, "ca.test.a"), None);
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (2, 2, 2));
+        assert_eq!(stats.hit_rate(), 0.5);
+    }
+
+    #[test]
+    fn session_invalidation() {
+        let mut cache = AuthorizationCache::new(10);
+        cache.insert(1, Action::Call, "ca.test.add", true);
+        cache.insert(2, Action::Call, "ca.test.add", true);
+        cache.invalidate_session(1);
+        assert_eq!(cache.get(1, Action::Call, "ca.test.add"), None);
+        assert_eq!(cache.get(2, Action::Call, "ca.test.add"), Some(true));
+    }
+
+    #[test]
+    fn rules_decide_before_the_authorizer() {
+        let mut authorization = Authorization::new();
+        authorization.authorizer = Some(Box::new(|_, _, uri| !uri.uri.starts_with("ca.test.secret")));
+        authorization.rules = serde_json::from_str(r#"[
+            {"prefix": "ca.test.secret", "authid": "joe", "allow": true},
+            {"prefix": "ca.test", "action": "register", "allow": false}
+        ]"#).unwrap();
+        assert_eq!(authorization.rules[1], AuthorizationRule {prefix: "ca.test".to_string(), action: Some(Action::Register), authid: None, allow: false});
+        assert!(authorization.authorize(1, Some("joe"), Action::Call, &URI::new("ca.test.secret.add")));
+        assert!(!authorization.authorize(2, Some("ann"), Action::Call, &URI::new("ca.test.secret.add")));
+        assert!(!authorization.authorize(2, None, Action::Register, &URI::new("ca.test.add")));
+        assert!(authorization.authorize(2, None, Action::Call, &URI::new("ca.test.add")));
+    }
+}
@@ -0,0 +1,143 @@
+//! Contains the `RouterConfig` struct, which describes realms and the behaviour the router
+//! should have in them from the moment it starts.
+use messages::{URI, Dict, List};
+use serde_json;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use ::{WampResult, Error, ErrorKind};
+
+/// Startup configuration for a router, usually read from a JSON file:
+///
+/// ```text
+/// {
+///     "realms": [{
+///         "name": "realm1",
+///         "seed_events": [{"topic": "ca.test.status", "args": ["ready"]}],
+///         "procedures": [{"uri": "ca.test.echo", "builtin": "echo"}],
+///         "cached_procedures": [{"uri": "ca.test.lookup", "ttl": 5000}],
+///         "event_history": [{"uri": "ca.test.status", "limit": 100, "ttl": 60000}]
+///     }]
+/// }
+/// ```
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct RouterConfig {
+    #[serde(default)]
+    pub realms: Vec<RealmConfig>
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct RealmConfig {
+    pub name: String,
+    /// Events that are retained on their topic and delivered to every new subscriber
+    #[serde(default)]
+    pub seed_events: Vec<SeedEvent>,
+    /// Procedures that the router answers itself
+    #[serde(default)]
+    pub procedures: Vec<BuiltinRegistration>,
+    /// Procedures whose results the router may reuse for identical calls
+    #[serde(default)]
+    pub cached_procedures: Vec<CachedProcedure>,
+    /// Topics whose recent events the router keeps for `wamp.subscription.get_events`
+    #[serde(default)]
+    pub event_history: Vec<EventHistoryTopic>
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct SeedEvent {
+    pub topic: URI,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub args: Option<List>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub kwargs: Option<Dict>
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct BuiltinRegistration {
+    pub uri: URI,
+    pub builtin: BuiltinProcedure
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct CachedProcedure {
+    pub uri: URI,
+    /// How long results are kept, in milliseconds
+    pub ttl: u64
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct EventHistoryTopic {
+    pub uri: URI,
+    /// The most events kept
+    pub limit: usize,
+    /// How long events are kept, in milliseconds.  Without it, events are kept until newer
+    /// ones push them out.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub ttl: Option<u64>
+}
+
+/// The procedures that the router can answer without a callee.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub enum BuiltinProcedure {
+    /// Returns the arguments it was called with
+    #[serde(rename="echo")]
+    Echo,
+    /// Returns the router's current time, in seconds since the Unix epoch
+    #[serde(rename="time")]
+    Time,
+    /// Returns the number of connections, subscriptions and registrations in the realm
+    #[serde(rename="stats")]
+    Stats,
+    /// Sets the log level of one of the crate's subsystems, given their names as the arguments,
+    /// such as `["router", "debug"]`.  See the `logging` module.
+    #[serde(rename="set_log_level")]
+    SetLogLevel
+}
+
+impl RouterConfig {
+    #[inline]
+    pub fn new() -> RouterConfig {
+        RouterConfig {
+            realms: Vec::new()
+        }
+    }
+
+    /// Reads a configuration file in the format above
+    pub fn load<P: AsRef<Path>>(path: P) -> WampResult<RouterConfig> {
+        let mut contents = String::new();
+        let mut file = try!(File::open(path).map_err(|e| Error::new(ErrorKind::IOError(e))));
+        try!(file.read_to_string(&mut contents).map_err(|e| Error::new(ErrorKind::IOError(e))));
+        serde_json::from_str(&contents).map_err(|e| Error::new(ErrorKind::JSONError(e)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RouterConfig, BuiltinProcedure};
+    use messages::{URI, Value};
+    use serde_json;
+
+    #[test]
+    fn parse_config() {
+        let config: RouterConfig = serde_json::from_str(r#"{
+            "realms": [{
+                "name": "realm1",
+                "seed_events": [{"topic": "ca.test.status", "args": ["ready"]}],
+                "procedures": [{"uri": "ca.test.echo", "builtin": "echo"}, {"uri": "ca.test.stats", "builtin": "stats"}],
+                "cached_procedures": [{"uri": "ca.test.lookup", "ttl": 5000}],
+                "event_history": [{"uri": "ca.test.status", "limit": 100}]
+            }, {"name": "realm2"}]
+        }"#).unwrap();
+        assert_eq!(config.realms.len(), 2);
+        let realm = &config.realms[0];
+        assert_eq!(realm.seed_events[0].topic, URI::new("ca.test.status"));
+        assert_eq!(realm.seed_events[0].args, Some(vec![Value::String("ready".to_string())]));
+        assert_eq!(realm.seed_events[0].kwargs, None);
+        assert_eq!(realm.procedures[1].builtin, BuiltinProcedure::Stats);
+        assert_eq!(realm.cached_procedures[0].ttl, 5000);
+        assert_eq!((realm.event_history[0].limit, realm.event_history[0].ttl), (100, None));
+        assert!(config.realms[1].procedures.is_empty());
+        assert!(config.realms[1].cached_procedures.is_empty());
+        assert!(config.realms[1].event_history.is_empty());
+    }
+}
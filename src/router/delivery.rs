@@ -0,0 +1,321 @@
+//! Contains the per-subscriber queues the broker uses to deliver events.
+//!
+//! Rather than writing each event to every subscriber as soon as it is published, the broker
+//! places it in a bounded queue belonging to the subscriber.  Each connection drains its own
+//! queue a few messages at a time from its own timeout, so a subscriber with a large backlog
+//! can't hold up delivery to everyone else.  When a queue is full, the router's
+//! `SlowConsumerPolicy` decides what happens.
+//!
+//! A publication with many subscribers is only serialized once for each serialization in use.
+//! Everything in an EVENT message after the subscription ID is the same for every subscriber
+//! (apart from whether the details name the topic), so `EventEncoder` serializes that part once
+//! and puts each subscriber's subscription ID in front of it.
+use super::{ConnectionHandler, ConnectionInfo};
+use router::messaging::{send_message, send_frame};
+use messages::{Message, EventDetails, URI, to_msgpack, to_cbor};
+use rmp::encode::write_uint;
+use serde_json;
+use serializer::Serialization;
+use ws::{CloseCode, Message as WSMessage};
+use ws::util::Token;
+use std::sync::{Arc, Mutex};
+use ::{WampResult, Error, ErrorKind, ID, List, Dict, MatchingPolicy};
+
+pub const FLUSH_EVENTS: Token = Token(1);
+
+// The number of events a connection writes each time it drains its queue
+const FLUSH_BATCH_SIZE: usize = 64;
+
+/// What the broker does when a subscriber's queue is full.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SlowConsumerPolicy {
+    /// Close the subscriber's connection
+    Disconnect,
+    /// Throw away the oldest queued event to make room for the new one
+    DropOldest,
+    /// Don't deliver the new event to this subscriber
+    Skip
+}
+
+/// Configures the queues the broker delivers events through.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DeliveryPolicy {
+    /// The most events that may be waiting for any one subscriber
+    pub queue_limit: usize,
+    pub slow_consumer: SlowConsumerPolicy,
+    /// Whether events carry the time the router received their publication, so that
+    /// subscribers can measure delivery delay and order events across topics
+    pub timestamps: bool
+}
+
+impl DeliveryPolicy {
+    /// Queues up to 1024 events per subscriber, skips subscribers whose queues are full, and
+    /// doesn't stamp events
+    pub fn new() -> DeliveryPolicy {
+        DeliveryPolicy {
+            queue_limit: 1024,
+            slow_consumer: SlowConsumerPolicy::Skip,
+            timestamps: false
+        }
+    }
+}
+
+/// An event waiting in a subscriber's queue.
+pub enum QueuedEvent {
+    /// An event for a connection with a custom codec, which is encoded when it is written
+    Message(Message),
+    /// An event that has already been serialized
+    Frame(WSMessage)
+}
+
+/// Builds the EVENT messages for one publication, serializing the parts every subscriber shares
+/// at most once for each serialization.
+pub struct EventEncoder<'a> {
+    publication_id: ID,
+    topic: &'a URI,
+    args: &'a Option<List>,
+    kwargs: &'a Option<Dict>,
+    timestamp: Option<u64>,
+    publisher: Option<(ID, Option<String>, Option<String>)>,
+    // Everything after the subscription ID, indexed by whether the details name the topic
+    json_tails: [Option<String>; 2],
+    // The array marker, and everything after the subscription ID
+    msgpack_tails: [Option<(u8, Vec<u8>)>; 2],
+    cbor_tails: [Option<(u8, Vec<u8>)>; 2]
+}
+
+// The start of a serialized EVENT message for subscription 0
+const JSON_EVENT_PREFIX: &'static str = "[36,0,";
+const EVENT_TYPE: u8 = 36;
+// CBOR writes integers from 24 to 255 as a marker byte followed by the integer
+const CBOR_EVENT_TYPE: [u8; 2] = [24, EVENT_TYPE];
+
+impl<'a> EventEncoder<'a> {
+    pub fn new(publication_id: ID, topic: &'a URI, args: &'a Option<List>, kwargs: &'a Option<Dict>) -> EventEncoder<'a> {
+        EventEncoder {
+            publication_id: publication_id,
+            topic: topic,
+            args: args,
+            kwargs: kwargs,
+            timestamp: None,
+            publisher: None,
+            json_tails: [None, None],
+            msgpack_tails: [None, None],
+            cbor_tails: [None, None]
+        }
+    }
+
+    /// Stamps every event with `timestamp`, in milliseconds since the Unix epoch
+    pub fn with_timestamp(mut self, timestamp: u64) -> EventEncoder<'a> {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// Tells every subscriber who published the event
+    pub fn with_publisher(mut self, session_id: ID, authid: Option<String>, authrole: Option<String>) -> EventEncoder<'a> {
+        self.publisher = Some((session_id, authid, authrole));
+        self
+    }
+
+    fn message(&self, subscription_id: ID, named_topic: bool) -> Message {
+        let details = if named_topic {
+            EventDetails::new_with_topic(self.topic.clone())
+        } else {
+            EventDetails::new()
+        };
+        let details = match self.timestamp {
+            Some(timestamp) => details.with_timestamp(timestamp),
+            None => details
+        };
+        let details = match self.publisher {
+            Some((session_id, ref authid, ref authrole)) => details.with_publisher(session_id, authid.clone(), authrole.clone()),
+            None => details
+        };
+        Message::Event(subscription_id, self.publication_id, details, self.args.clone(), self.kwargs.clone())
+    }
+
+    /// Builds the event for a subscriber using the given serialization.  Subscribers that
+    /// matched a pattern are told which topic the event was published to.
+    pub fn encode(&mut self, serialization: Serialization, subscription_id: ID, matching_policy: MatchingPolicy) -> WampResult<WSMessage> {
+        let named_topic = matching_policy != MatchingPolicy::Strict;
+        match serialization {
+            Serialization::Json => self.encode_json(subscription_id, named_topic).map(WSMessage::Text),
+            Serialization::MsgPack => self.encode_msgpack(subscription_id, named_topic).map(WSMessage::Binary),
+            Serialization::Cbor => self.encode_cbor(subscription_id, named_topic).map(WSMessage::Binary)
+        }
+    }
+
+    fn encode_json(&mut self, subscription_id: ID, named_topic: bool) -> WampResult<String> {
+        if self.json_tails[named_topic as usize].is_none() {
+            let whole = try!(serde_json::to_string(&self.message(0, named_topic)).map_err(|e| Error::new(ErrorKind::JSONError(e))));
+            if !whole.starts_with(JSON_EVENT_PREFIX) {
+                return serde_json::to_string(&self.message(subscription_id, named_topic)).map_err(|e| Error::new(ErrorKind::JSONError(e)));
+            }
+            self.json_tails[named_topic as usize] = Some(whole[JSON_EVENT_PREFIX.len()..].to_string());
+        }
+        let tail = self.json_tails[named_topic as usize].as_ref().unwrap();
+        Ok(format!("[{},{},{}", EVENT_TYPE, subscription_id, tail))
+    }
+
+    fn encode_msgpack(&mut self, subscription_id: ID, named_topic: bool) -> WampResult<Vec<u8>> {
+        if self.msgpack_tails[named_topic as usize].is_none() {
+            // A fixed size array marker, the message type and a subscription ID of 0 are one
+            // byte each
+            let whole = try!(to_msgpack(&self.message(0, named_topic)).map_err(|e| Error::new(ErrorKind::MsgPackEncodeError(e))));
+            if whole.len() < 3 || whole[1] != EVENT_TYPE || whole[2] != 0 {
+                return to_msgpack(&self.message(subscription_id, named_topic)).map_err(|e| Error::new(ErrorKind::MsgPackEncodeError(e)));
+            }
+            self.msgpack_tails[named_topic as usize] = Some((whole[0], whole[3..].to_vec()));
+        }
+        let &(marker, ref tail) = self.msgpack_tails[named_topic as usize].as_ref().unwrap();
+        let mut frame = Vec::with_capacity(tail.len() + 11);
+        frame.push(marker);
+        frame.push(EVENT_TYPE);
+        // Writing to a vector can't fail
+        write_uint(&mut frame, subscription_id).unwrap();
+        frame.extend_from_slice(tail);
+        Ok(frame)
+    }
+
+    fn encode_cbor(&mut self, subscription_id: ID, named_topic: bool) -> WampResult<Vec<u8>> {
+        if self.cbor_tails[named_topic as usize].is_none() {
+            // The array marker and a subscription ID of 0 are one byte each, and the message
+            // type two
+            let whole = try!(to_cbor(&self.message(0, named_topic)).map_err(|e| Error::new(ErrorKind::CBORError(e))));
+            if whole.len() < 4 || whole[1..3] != CBOR_EVENT_TYPE || whole[3] != 0 {
+                return to_cbor(&self.message(subscription_id, named_topic)).map_err(|e| Error::new(ErrorKind::CBORError(e)));
+            }
+            self.cbor_tails[named_topic as usize] = Some((whole[0], whole[4..].to_vec()));
+        }
+        let &(marker, ref tail) = self.cbor_tails[named_topic as usize].as_ref().unwrap();
+        let mut frame = Vec::with_capacity(tail.len() + 12);
+        frame.push(marker);
+        frame.extend_from_slice(&CBOR_EVENT_TYPE);
+        write_cbor_uint(&mut frame, subscription_id);
+        frame.extend_from_slice(tail);
+        Ok(frame)
+    }
+}
+
+/// Writes an unsigned integer the way CBOR does, in as few bytes as it fits in
+fn write_cbor_uint(frame: &mut Vec<u8>, value: u64) {
+    let width = if value < 24 {
+        frame.push(value as u8);
+        return;
+    } else if value <= 0xff {
+        frame.push(24);
+        1
+    } else if value <= 0xffff {
+        frame.push(25);
+        2
+    } else if value <= 0xffff_ffff {
+        frame.push(26);
+        4
+    } else {
+        frame.push(27);
+        8
+    };
+    for shift in (0..width).rev() {
+        frame.push((value >> (shift * 8)) as u8);
+    }
+}
+
+/// Adds an event to a subscriber's queue, scheduling the queue to be drained if necessary.
+pub fn queue_event(subscriber: &Arc<Mutex<ConnectionInfo>>, encoder: &mut EventEncoder, subscription_id: ID, matching_policy: MatchingPolicy, policy: &DeliveryPolicy) -> WampResult<()> {
+    let mut info = subscriber.lock().unwrap();
+    if info.events.len() >= policy.queue_limit {
+        info.dropped_events += 1;
+        match policy.slow_consumer {
+            SlowConsumerPolicy::Disconnect => {
+                warn!("[{}] Event queue is full.  Disconnecting", info.tracking_id);
+                info.events.clear();
+                return info.close(CloseCode::Policy).map_err(|e| Error::new(ErrorKind::WSError(e)));
+            },
+            SlowConsumerPolicy::DropOldest => {
+                debug!("[{}] Event queue is full.  Dropping the oldest event", info.tracking_id);
+                info.events.pop_front();
+            },
+            SlowConsumerPolicy::Skip => {
+                debug!("[{}] Event queue is full.  Skipping event", info.tracking_id);
+                return Ok(());
+            }
+        }
+    }
+    let event = match info.serializer.serialization() {
+        Some(serialization) => QueuedEvent::Frame(try!(encoder.encode(serialization, subscription_id, matching_policy))),
+        None => QueuedEvent::Message(encoder.message(subscription_id, matching_policy != MatchingPolicy::Strict))
+    };
+    info.events.push_back(event);
+    if !info.flush_scheduled {
+        try!(info.timeout(0, FLUSH_EVENTS).map_err(|e| Error::new(ErrorKind::WSError(e))));
+        info.flush_scheduled = true;
+    }
+    Ok(())
+}
+
+impl ConnectionHandler {
+    /// Writes the next batch of queued events, and schedules another flush if any remain.
+    pub fn flush_events(&mut self) -> WampResult<()> {
+        let batch: Vec<QueuedEvent> = {
+            let mut info = self.info.lock().unwrap();
+            let count = ::std::cmp::min(info.events.len(), FLUSH_BATCH_SIZE);
+            let batch = info.events.drain(..count).collect();
+            if info.events.is_empty() {
+                info.flush_scheduled = false;
+            } else {
+                try!(info.timeout(0, FLUSH_EVENTS).map_err(|e| Error::new(ErrorKind::WSError(e))));
+            }
+            batch
+        };
+        for event in batch {
+            match event {
+                QueuedEvent::Message(message) => try!(send_message(&self.info, &message)),
+                QueuedEvent::Frame(frame) => try!(send_frame(&self.info, frame))
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::EventEncoder;
+    use messages::{Message, EventDetails, URI, Value, to_msgpack, to_cbor};
+    use serializer::Serialization;
+    use serde_json;
+    use ws::Message as WSMessage;
+    use std::collections::HashMap;
+    use ::MatchingPolicy;
+
+    #[test]
+    fn shared_serialization() {
+        let topic = URI::new("ca.test.topic");
+        let args = Some(vec![Value::String("fan out ✓".to_string()), Value::Bytes(vec![1, 2])]);
+        let mut kwargs = HashMap::new();
+        kwargs.insert("count".to_string(), Value::Integer(3));
+        let kwargs = Some(kwargs);
+        let mut encoder = EventEncoder::new(5, &topic, &args, &kwargs);
+        for &subscription_id in [7, 200, 300, 70000, 1 << 40].iter() {
+            for &policy in [MatchingPolicy::Strict, MatchingPolicy::Prefix].iter() {
+                let details = if policy == MatchingPolicy::Strict {
+                    EventDetails::new()
+                } else {
+                    EventDetails::new_with_topic(topic.clone())
+                };
+                let message = Message::Event(subscription_id, 5, details, args.clone(), kwargs.clone());
+                assert_eq!(encoder.encode(Serialization::Json, subscription_id, policy).unwrap(), WSMessage::Text(serde_json::to_string(&message).unwrap()));
+                assert_eq!(encoder.encode(Serialization::MsgPack, subscription_id, policy).unwrap(), WSMessage::Binary(to_msgpack(&message).unwrap()));
+                assert_eq!(encoder.encode(Serialization::Cbor, subscription_id, policy).unwrap(), WSMessage::Binary(to_cbor(&message).unwrap()));
+            }
+        }
+
+        let mut stamped = EventEncoder::new(5, &topic, &args, &kwargs).with_timestamp(1500000000123);
+        let message = Message::Event(7, 5, EventDetails::new().with_timestamp(1500000000123), args.clone(), kwargs.clone());
+        assert_eq!(stamped.encode(Serialization::Json, 7, MatchingPolicy::Strict).unwrap(), WSMessage::Text(serde_json::to_string(&message).unwrap()));
+        assert_eq!(stamped.encode(Serialization::MsgPack, 7, MatchingPolicy::Strict).unwrap(), WSMessage::Binary(to_msgpack(&message).unwrap()));
+
+        let mut disclosed = EventEncoder::new(5, &topic, &args, &kwargs).with_publisher(42, Some("joe".to_string()), None);
+        let message = Message::Event(7, 5, EventDetails::new().with_publisher(42, Some("joe".to_string()), None), args.clone(), kwargs.clone());
+        assert_eq!(disclosed.encode(Serialization::Json, 7, MatchingPolicy::Strict).unwrap(), WSMessage::Text(serde_json::to_string(&message).unwrap()));
+    }
+}
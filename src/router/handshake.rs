@@ -1,21 +1,32 @@
-use super::{ConnectionHandler, ConnectionState, WAMP_JSON, WAMP_MSGPACK};
+use super::{ConnectionHandler, ConnectionState};
 
 use router::messaging::send_message;
 use ws::{Error as WSError, ErrorKind as WSErrorKind, Result as WSResult, Request, Response, CloseCode};
 
 use messages::{Message, URI, HelloDetails, WelcomeDetails, RouterRoles, ErrorDetails, Reason};
+use logging::TRANSPORT_TARGET;
+use serializer::{self, WAMP_JSON, WAMP_MSGPACK, WAMP_CBOR};
 use ::{WampResult, Error, ErrorKind};
 
 impl ConnectionHandler {
-    pub fn handle_hello(&mut self, realm: URI, _details: HelloDetails) -> WampResult<()> {
-        debug!("Responding to hello message (realm: {:?})", realm);
+    pub fn handle_hello(&mut self, realm: URI, details: HelloDetails) -> WampResult<()> {
+        debug!("[{}] Responding to hello message (realm: {:?})", self.tracking_id, realm);
+        if !self.router.realms.lock().unwrap().contains_key(&realm.uri[..]) {
+            return self.abort(Reason::NoSuchRealm, "the realm doesn't exist");
+        }
+        if self.is_admin_realm(&realm.uri) {
+            return self.challenge_admin(realm, details);
+        }
         let id = {
             let mut info = self.info.lock().unwrap();
             info.state = ConnectionState::Connected;
+            info.authid = details.authid().map(|authid| authid.to_string());
+            info.agent = details.agent().map(|agent| agent.to_string());
             info.id
         };
+        info!("[{}] Session {} joining realm {}", self.tracking_id, id, realm.uri);
 
-        try!(self.set_realm(realm.uri));
+        try!(self.set_realm(realm.uri.to_string()));
         send_message(&self.info, &Message::Welcome(id, WelcomeDetails::new(RouterRoles::new())))
     }
 
@@ -27,55 +38,57 @@ impl ConnectionHandler {
                 Err(Error::new(ErrorKind::InvalidState("Recieved a goodbye message before handshake complete")))
             },
             ConnectionState::Connected => {
-                info!("Recieved goobye message with reason: {:?}", reason);
+                info!("[{}] Recieved goobye message with reason: {:?}", self.tracking_id, reason);
                 self.remove();
                 send_message(&self.info, &Message::Goodbye(ErrorDetails::new(), Reason::GoodbyeAndOut)).ok();
                 let mut info = self.info.lock().unwrap();
                 info.state = ConnectionState::Disconnected;
-                match info.sender.close(CloseCode::Normal) {
+                match info.close(CloseCode::Normal) {
                     Err(e) => Err(Error::new(ErrorKind::WSError(e))),
                     _ => Ok(())
                 }
             },
             ConnectionState::ShuttingDown => {
-                info!("Recieved goobye message in response to our goodbye message with reason: {:?}", reason);
+                info!("[{}] Recieved goobye message in response to our goodbye message with reason: {:?}", self.tracking_id, reason);
                 let mut info = self.info.lock().unwrap();
                 info.state = ConnectionState::Disconnected;
-                match info.sender.close(CloseCode::Normal) {
+                match info.close(CloseCode::Normal) {
                     Err(e) => Err(Error::new(ErrorKind::WSError(e))),
                     _ => Ok(())
                 }
             },
             ConnectionState::Disconnected => {
-                warn!("Recieved goodbye message after closing connection");
+                warn!("[{}] Recieved goodbye message after closing connection", self.tracking_id);
                 Ok(())
             }
         }
     }
 
 
-    fn set_realm(&mut self, realm: String) -> WampResult<()> {
-        debug!("Setting realm to {}", realm);
+    pub fn set_realm(&mut self, realm: String) -> WampResult<()> {
+        debug!("[{}] Setting realm to {}", self.tracking_id, realm);
         let realm = self.router.realms.lock().unwrap()[&realm].clone();
         {
-            realm.lock().unwrap().connections.push(self.info.clone());
+            realm.connections.lock().unwrap().push(self.info.clone());
         }
         self.realm = Some(realm);
         Ok(())
     }
 
     pub fn process_protocol(&mut self, request: &Request, response: &mut Response) -> WSResult<()> {
-        debug!("Checking protocol");
+        debug!(target: TRANSPORT_TARGET, "[{}] Checking protocol", self.tracking_id);
         let protocols = try!(request.protocols());
         for protocol in protocols {
-            if protocol == WAMP_JSON || protocol == WAMP_MSGPACK {
+            let serializer = serializer::for_protocol(protocol, &self.router.codecs.lock().unwrap());
+            if let Some(serializer) = serializer {
                 response.set_protocol(protocol);
                 let mut info = self.info.lock().unwrap();
                 info.protocol = protocol.to_string();
+                info.serializer = serializer;
                 return Ok(())
             }
         }
-        Err(WSError::new(WSErrorKind::Protocol, format!("Neither {} nor {} were selected as Websocket sub-protocols", WAMP_JSON, WAMP_MSGPACK)))
+        Err(WSError::new(WSErrorKind::Protocol, format!("None of {}, {} or {} were selected as Websocket sub-protocols", WAMP_JSON, WAMP_MSGPACK, WAMP_CBOR)))
     }
 
 
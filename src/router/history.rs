@@ -0,0 +1,220 @@
+//! Contains the `EventHistory` struct, which keeps the recent events on the topics a realm was
+//! told to keep history for, and the `wamp.subscription.get_events` meta procedure that returns
+//! them.
+//!
+//! The procedure takes a subscription ID and optionally the most events to return, and returns
+//! a list of the events on topics the subscription matches, oldest first.  Each event is a
+//! dictionary with its `publication` ID, `topic`, `timestamp` in milliseconds since the Unix
+//! epoch, and `args` and `kwargs` if it had them.  The caller must be allowed to subscribe to
+//! the subscription's topic.  Calls for subscriptions whose topics have no history fail with
+//! `wamp.error.history_unavailable`.
+//!
+//! Publications that only some sessions were eligible to receive are never kept.
+use super::{ConnectionHandler, Action};
+use super::config::EventHistoryTopic;
+use matching::matches;
+use messages::{URI, SharedStr, Dict, List, Value, Reason};
+use utils::as_millis;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use ::{MatchingPolicy, ID};
+
+const HISTORY_UNAVAILABLE: &'static str = "wamp.error.history_unavailable";
+
+struct StoredEvent {
+    // Orders events on different topics
+    sequence: u64,
+    recorded: Instant,
+    event: Dict
+}
+
+struct TopicHistory {
+    limit: usize,
+    ttl: Option<Duration>,
+    events: VecDeque<StoredEvent>
+}
+
+impl TopicHistory {
+    fn expire(&mut self, now: Instant) {
+        while self.events.len() > self.limit {
+            self.events.pop_front();
+        }
+        if let Some(ttl) = self.ttl {
+            while self.events.front().map_or(false, |event| now.duration_since(event.recorded) >= ttl) {
+                self.events.pop_front();
+            }
+        }
+    }
+}
+
+/// The recent events of a realm, for each topic that keeps history
+pub struct EventHistory {
+    topics: HashMap<SharedStr, TopicHistory>,
+    sequence: u64
+}
+
+impl EventHistory {
+    pub fn new() -> EventHistory {
+        EventHistory {
+            topics: HashMap::new(),
+            sequence: 0
+        }
+    }
+
+    /// Keeps up to `limit` events on `topic`, each for up to `ttl` if it is given.  A limit of
+    /// zero stops keeping history for the topic and forgets the events already kept.
+    pub fn configure(&mut self, topic: SharedStr, limit: usize, ttl: Option<Duration>) {
+        if limit == 0 {
+            self.topics.remove(&topic);
+            return;
+        }
+        let history = self.topics.entry(topic).or_insert(TopicHistory {
+            limit: limit,
+            ttl: ttl,
+            events: VecDeque::new()
+        });
+        history.limit = limit;
+        history.ttl = ttl;
+        history.expire(Instant::now());
+    }
+
+    /// The topics that keep history, and how much
+    pub fn topics(&self) -> Vec<EventHistoryTopic> {
+        self.topics.iter().map(|(topic, history)| EventHistoryTopic {
+            uri: URI::new(topic),
+            limit: history.limit,
+            ttl: history.ttl.map(as_millis)
+        }).collect()
+    }
+
+    /// Keeps an event published on `topic`, if the topic keeps history
+    pub fn record(&mut self, topic: &URI, publication_id: ID, timestamp: u64, args: &Option<List>, kwargs: &Option<Dict>) {
+        let history = match self.topics.get_mut(&*topic.uri) {
+            Some(history) => history,
+            None => return
+        };
+        let mut event = HashMap::new();
+        event.insert("publication".to_string(), Value::UnsignedInteger(publication_id));
+        event.insert("topic".to_string(), Value::String(topic.uri.to_string()));
+        event.insert("timestamp".to_string(), Value::UnsignedInteger(timestamp));
+        if let Some(ref args) = *args {
+            event.insert("args".to_string(), Value::List(args.clone()));
+        }
+        if let Some(ref kwargs) = *kwargs {
+            event.insert("kwargs".to_string(), Value::Dict(kwargs.clone()));
+        }
+        self.sequence += 1;
+        history.events.push_back(StoredEvent {
+            sequence: self.sequence,
+            recorded: Instant::now(),
+            event: event
+        });
+        history.expire(Instant::now());
+    }
+
+    /// The last `limit` events on the topics that `pattern` matches, oldest first, or `None`
+    /// if none of them keep history
+    pub fn events(&mut self, pattern: &str, policy: MatchingPolicy, limit: Option<usize>) -> Option<List> {
+        let now = Instant::now();
+        let mut found = false;
+        let mut events = Vec::new();
+        for (topic, history) in self.topics.iter_mut() {
+            if matches(pattern, topic, policy) {
+                found = true;
+                history.expire(now);
+                events.extend(history.events.iter());
+            }
+        }
+        if !found {
+            return None;
+        }
+        events.sort_by_key(|event| event.sequence);
+        let skip = events.len().saturating_sub(limit.unwrap_or(events.len()));
+        Some(events.into_iter().skip(skip).map(|event| Value::Dict(event.event.clone())).collect())
+    }
+}
+
+impl ConnectionHandler {
+    /// Produces the result of a call to `wamp.subscription.get_events`
+    pub fn get_events(&self, args: Option<List>) -> Result<(Option<List>, Option<Dict>), Reason> {
+        let (subscription_id, limit) = match args.as_ref().map(|args| &args[..]) {
+            Some(&[ref id]) => (try!(as_id(id)), None),
+            Some(&[ref id, ref limit]) => (try!(as_id(id)), Some(try!(as_id(limit)) as usize)),
+            _ => return Err(Reason::InvalidArgument)
+        };
+        let realm = match self.realm {
+            Some(ref realm) => realm,
+            None => return Err(Reason::NoSuchSubscription)
+        };
+        let (topic, is_prefix) = match realm.subscription_manager.lock().unwrap().subscription_ids_to_uris.get(&subscription_id) {
+            Some(&(ref topic, is_prefix)) => (topic.clone(), is_prefix),
+            None => return Err(Reason::NoSuchSubscription)
+        };
+        if !self.authorize(Action::Subscribe, &URI { uri: topic.clone() }) {
+            return Err(Reason::NotAuthorized);
+        }
+        // Wildcard subscriptions are recorded the same way as exact ones
+        let policy = if is_prefix {
+            MatchingPolicy::Prefix
+        } else if topic.split('.').any(str::is_empty) {
+            MatchingPolicy::Wildcard
+        } else {
+            MatchingPolicy::Strict
+        };
+        let mut manager = realm.subscription_manager.lock().unwrap();
+        match manager.event_history.events(&topic, policy, limit) {
+            Some(events) => Ok((Some(vec![Value::List(events)]), None)),
+            None => Err(Reason::CustomReason(URI::new(HISTORY_UNAVAILABLE)))
+        }
+    }
+}
+
+fn as_id(value: &Value) -> Result<u64, Reason> {
+    match *value {
+        Value::Integer(value) if value >= 0 => Ok(value as u64),
+        Value::UnsignedInteger(value) => Ok(value),
+        _ => Err(Reason::InvalidArgument)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::EventHistory;
+    use messages::{URI, SharedStr, Value};
+    use std::thread;
+    use std::time::Duration;
+    use ::MatchingPolicy;
+
+    fn publications(events: Option<Vec<Value>>) -> Vec<u64> {
+        events.unwrap().into_iter().map(|event| match event {
+            Value::Dict(event) => match event["publication"] {
+                Value::UnsignedInteger(id) => id,
+                _ => panic!("No publication ID")
+            },
+            _ => panic!("Not an event")
+        }).collect()
+    }
+
+    #[test]
+    fn history_is_bounded_per_topic() {
+        let mut history = EventHistory::new();
+        history.configure(SharedStr::from("ca.test.a"), 2, None);
+        history.configure(SharedStr::from("ca.test.b"), 5, Some(Duration::from_millis(50)));
+        let args = Some(vec![Value::Integer(1)]);
+        for id in 1..4 {
+            history.record(&URI::new("ca.test.a"), id, 0, &args, &None);
+        }
+        history.record(&URI::new("ca.test.b"), 4, 0, &None, &None);
+        history.record(&URI::new("ca.test.c"), 5, 0, &args, &None);
+
+        assert_eq!(publications(history.events("ca.test.a", MatchingPolicy::Strict, None)), vec![2, 3]);
+        assert_eq!(publications(history.events("ca.test.a", MatchingPolicy::Strict, Some(1))), vec![3]);
+        assert_eq!(publications(history.events("ca.test", MatchingPolicy::Prefix, None)), vec![2, 3, 4]);
+        assert!(history.events("ca.test.c", MatchingPolicy::Strict, None).is_none());
+
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(publications(history.events("ca..b", MatchingPolicy::Wildcard, None)), Vec::<u64>::new());
+        history.configure(SharedStr::from("ca.test.a"), 0, None);
+        assert!(history.events("ca.test.a", MatchingPolicy::Strict, None).is_none());
+    }
+}
@@ -1,52 +1,42 @@
-use super::{ConnectionHandler, ConnectionInfo, WAMP_JSON, ConnectionState};
-use ws::{Sender, Handler, Message as WSMessage, Error as WSError, ErrorKind as WSErrorKind, Result as WSResult, Request, Response, CloseCode};
+use super::{ConnectionHandler, ConnectionInfo, ConnectionState};
+use router::delivery::FLUSH_EVENTS;
+use ws::util::Token;
+use ws::{Handler, Handshake, Message as WSMessage, Error as WSError, ErrorKind as WSErrorKind, Result as WSResult, Request, Response, CloseCode};
 use std::sync::{Arc, Mutex};
 
 use std::collections::{HashMap};
-use serde_json;
-use serde::{Deserialize, Serialize};
-use rmp_serde::Deserializer as RMPDeserializer;
-use rmp_serde::Serializer;
-use utils::StructMapWriter;
-use std::io::Cursor;
-use messages::{Message, ErrorType, Reason};
+use messages::{Message, ErrorType, ErrorDetails, Reason};
+use codec::Frame;
+use logging::{TRANSPORT_TARGET, PROTOCOL_TARGET};
+use messages::validation::ValidationMode;
+use serializer;
 use ::{ID, WampResult, Error, ErrorKind, Dict, List};
 
 
 pub fn send_message(info: &Arc<Mutex<ConnectionInfo>>, message: &Message) -> WampResult<()> {
-    let info = info.lock().unwrap();
+    let mut info = info.lock().unwrap();
+    info.messages_sent += 1;
 
-    debug!("Sending message {:?} via {}", message, info.protocol);
-    let send_result = if info.protocol == WAMP_JSON {
-        send_message_json(&info.sender, message)
-    } else {
-        send_message_msgpack(&info.sender, message)
+    debug!(target: TRANSPORT_TARGET, "[{}] Sending message {:?} via {}", info.tracking_id, message, info.protocol);
+    let frame = match try!(info.serializer.encode(message)) {
+        Frame::Text(text) => WSMessage::Text(text),
+        Frame::Binary(data) => WSMessage::Binary(data)
     };
-    match send_result {
-        Ok(()) => Ok(()),
-        Err(e) => Err(Error::new(ErrorKind::WSError(e)))
-    }
-}
-
-fn send_message_json(sender: &Sender, message: &Message) -> WSResult<()> {
-    // Send the message
-    sender.send(WSMessage::Text(serde_json::to_string(message).unwrap()))
-
+    info.send(frame).map_err(|e| Error::new(ErrorKind::WSError(e)))
 }
 
-fn send_message_msgpack(sender: &Sender, message: &Message) -> WSResult<()> {
-
-    // Send the message
-    let mut buf: Vec<u8> = Vec::new();
-    message.serialize(&mut Serializer::with(&mut buf, StructMapWriter)).unwrap();
-    sender.send(WSMessage::Binary(buf))
-
+/// Writes a frame that has already been serialized for the connection
+pub fn send_frame(info: &Arc<Mutex<ConnectionInfo>>, frame: WSMessage) -> WampResult<()> {
+    let mut info = info.lock().unwrap();
+    info.messages_sent += 1;
+    trace!(target: TRANSPORT_TARGET, "[{}] Sending a serialized frame via {}", info.tracking_id, info.protocol);
+    info.send(frame).map_err(|e| Error::new(ErrorKind::WSError(e)))
 }
 
 impl ConnectionHandler {
 
     fn handle_message(&mut self, message: Message) -> WampResult<()> {
-        debug!("Recieved message {:?}", message);
+        debug!("[{}] Recieved message {:?}", self.tracking_id, message);
         match message {
             Message::Hello(realm, details) => {
                 self.handle_hello(realm, details)
@@ -63,6 +53,9 @@ impl ConnectionHandler {
             Message::Goodbye(details, reason) => {
                 self.handle_goodbye(details, reason)
             },
+            Message::Authenticate(signature, _extra) => {
+                self.handle_authenticate(signature)
+            },
             Message::Register(request_id, options, procedure) => {
                 self.handle_register(request_id, options, procedure)
             },
@@ -74,6 +67,9 @@ impl ConnectionHandler {
             },
             Message::Yield(invocation_id, options, args, kwargs) => {
                 self.handle_yield(invocation_id, options, args, kwargs)
+            },
+            Message::Cancel(request_id, options) => {
+                self.handle_cancel(request_id, options)
             }
             Message::Error(e_type, request_id, details, reason, args, kwargs) => {
                 self.handle_error(e_type, request_id, details, reason, args, kwargs)
@@ -86,17 +82,20 @@ impl ConnectionHandler {
 
     fn handle_error(&mut self, e_type: ErrorType, request_id: ID, details: Dict, reason: Reason, args: Option<List>, kwargs: Option<Dict>) -> WampResult<()> {
         if e_type == ErrorType::Invocation {
-            debug!("Responding to error message for invocation (id: {})", request_id);
+            debug!("[{}] Responding to error message for invocation (id: {})", self.tracking_id, request_id);
             match self.realm {
                 Some(ref realm) => {
-                    let mut realm = realm.lock().unwrap();
-                    let mut manager = &mut realm.registration_manager;
-                    if let Some((call_id, callee)) = manager.active_calls.remove(&request_id) {
-                        let error_message = Message::Error(ErrorType::Call, call_id, details, reason, args, kwargs);
-                        send_message(&callee, &error_message)
-                    } else {
-                        Err(Error::new(ErrorKind::InvalidState("Recieved an error message for a call that wasn't sent")))
+                    let mut manager = realm.registration_manager.lock().unwrap();
+                    if !try!(self.answers_active_call(&manager, request_id)) {
+                        return Ok(());
+                    }
+                    let call = manager.active_calls.remove(&request_id).unwrap();
+                    if call.cancelled {
+                        debug!("[{}] Dropping the error of cancelled call {}", self.tracking_id, call.request_id);
+                        return Ok(());
                     }
+                    let error_message = Message::Error(ErrorType::Call, call.request_id, details, reason, args, kwargs);
+                    send_message(&call.caller, &error_message)
                 }, None => {
                     Err(Error::new(ErrorKind::InvalidState("Recieved a message while not attached to a realm")))
                 }
@@ -107,25 +106,22 @@ impl ConnectionHandler {
     }
 
     fn parse_message(&self, msg: WSMessage) -> WampResult<Message> {
-        match msg {
-            WSMessage::Text(payload) => {
-                match serde_json::from_str(&payload) {
-                    Ok(message) => Ok(message),
-                    Err(e) => Err(Error::new(ErrorKind::JSONError(e)))
-                }
-            },
-            WSMessage::Binary(payload) => {
-                let mut de = RMPDeserializer::new(Cursor::new(payload));
-                match Deserialize::deserialize(&mut de) {
-                    Ok(message) => {
-                        Ok(message)
-                    },
-                    Err(e) => {
-                        Err(Error::new(ErrorKind::MsgPackError(e)))
-                    }
-                }
+        let frame = match msg {
+            WSMessage::Text(payload) => Frame::Text(payload),
+            WSMessage::Binary(payload) => Frame::Binary(payload)
+        };
+        let serializer = self.info.lock().unwrap().serializer.clone();
+        let mode = *self.router.validation_mode.lock().unwrap();
+        if mode == ValidationMode::Strict {
+            if let Err(violation) = serializer::validate(&*serializer, &frame) {
+                *self.router.protocol_violations.lock().unwrap() += 1;
+                return Err(Error::new(ErrorKind::ProtocolViolation(violation)));
             }
         }
+        serializer.decode(frame).map_err(|e| {
+            *self.router.protocol_violations.lock().unwrap() += 1;
+            e
+        })
     }
 
     fn send_error(&self, err_type: ErrorType, request_id: ID, reason: Reason) -> WSResult<()> {
@@ -144,38 +140,83 @@ impl ConnectionHandler {
             ErrorKind::WSError(e) => Err(e),
             ErrorKind::URLError(_) => {unimplemented!()},
             ErrorKind::UnexpectedMessage(msg) => {
-                error!("Unexpected Message: {}", msg);
+                error!(target: PROTOCOL_TARGET, "[{}] Unexpected Message: {}", self.tracking_id, msg);
                 self.terminate_connection()
             },
             ErrorKind::ThreadError(_) => {unimplemented!()},
             ErrorKind::ConnectionLost => {unimplemented!()},
             ErrorKind::Closing(_) => {unimplemented!{}},
             ErrorKind::JSONError(e) => {
-                error!("Could not parse JSON: {}", e);
+                error!(target: PROTOCOL_TARGET, "[{}] Could not parse JSON: {}", self.tracking_id, e);
                 self.terminate_connection()
             },
             ErrorKind::MsgPackError(e) => {
-                error!("Could not parse MsgPack: {}", e.description());
+                error!(target: PROTOCOL_TARGET, "[{}] Could not parse MsgPack: {}", self.tracking_id, e.description());
+                self.terminate_connection()
+            },
+            ErrorKind::MsgPackEncodeError(e) => {
+                error!(target: PROTOCOL_TARGET, "[{}] Could not write MsgPack: {}", self.tracking_id, e);
+                self.terminate_connection()
+            },
+            ErrorKind::CBORError(e) => {
+                error!(target: PROTOCOL_TARGET, "[{}] Could not parse or write CBOR: {}", self.tracking_id, e);
                 self.terminate_connection()
             },
             ErrorKind::MalformedData => {
                 unimplemented!()
             },
             ErrorKind::InvalidMessageType(msg) => {
-                error!("Router unable to handle message {:?}", msg);
+                error!("[{}] Router unable to handle message {:?}", self.tracking_id, msg);
                 self.terminate_connection()
             },
             ErrorKind::InvalidState(s) => {
-                error!("Invalid State: {}", s);
+                error!("[{}] Invalid State: {}", self.tracking_id, s);
                 self.terminate_connection()
             },
             ErrorKind::Timeout => {
-                error!("Connection timeout");
+                error!("[{}] Connection timeout", self.tracking_id);
                 self.terminate_connection()
 
             }
             ErrorKind::ErrorReason(err_type, id, reason) => {
                 self.send_error(err_type, id, reason)
+            },
+            ErrorKind::IOError(e) => {
+                error!("[{}] IO error: {}", self.tracking_id, e);
+                self.terminate_connection()
+            },
+            ErrorKind::InvalidInterface(s) => {
+                error!("[{}] Invalid interface: {}", self.tracking_id, s);
+                self.terminate_connection()
+            },
+            ErrorKind::CodecError(s) => {
+                error!(target: PROTOCOL_TARGET, "[{}] Could not decode message: {}", self.tracking_id, s);
+                self.terminate_connection()
+            },
+            ErrorKind::NotAllowed(uri) => {
+                error!("[{}] Not allowed: {}", self.tracking_id, uri);
+                self.terminate_connection()
+            },
+            ErrorKind::RateLimited(uri) => {
+                error!("[{}] Rate limited: {}", self.tracking_id, uri);
+                self.terminate_connection()
+            },
+            ErrorKind::CallFailed(e) => {
+                error!("[{}] Call failed: {}", self.tracking_id, e);
+                self.terminate_connection()
+            },
+            ErrorKind::ConversionError(e) => {
+                error!("[{}] {}", self.tracking_id, e);
+                self.terminate_connection()
+            },
+            ErrorKind::InvalidRealm(s) | ErrorKind::TransportError(s) => {
+                error!("[{}] {}", self.tracking_id, s);
+                self.terminate_connection()
+            },
+            ErrorKind::ProtocolViolation(violation) => {
+                error!(target: PROTOCOL_TARGET, "[{}] Protocol violation: {}", self.tracking_id, violation);
+                send_message(&self.info, &Message::Abort(ErrorDetails::new_with_message(&violation.to_string()), Reason::ProtocolViolation)).ok();
+                self.terminate_connection()
             }
         }
     }
@@ -185,21 +226,29 @@ impl ConnectionHandler {
 impl Handler for ConnectionHandler {
 
     fn on_request(&mut self, request: &Request) -> WSResult<Response> {
-        info!("New request");
+        info!(target: TRANSPORT_TARGET, "[{}] New request", self.tracking_id);
         let mut response = match Response::from_request(request) {
             Ok(response) => response,
             Err(e) => {
-                error!("Could not create response: {}", e);
+                error!(target: TRANSPORT_TARGET, "[{}] Could not create response: {}", self.tracking_id, e);
                 return Err(e);
             }
         };
         try!(self.process_protocol(request, &mut response));
-        debug!("Sending response");
+        debug!(target: TRANSPORT_TARGET, "[{}] Sending response", self.tracking_id);
         Ok(response)
    }
 
+    fn on_open(&mut self, handshake: Handshake) -> WSResult<()> {
+        let peer = try!(handshake.remote_addr());
+        info!(target: TRANSPORT_TARGET, "[{}] Connection opened from {}", self.tracking_id, peer.as_ref().map(|peer| peer.as_str()).unwrap_or("an unknown address"));
+        self.info.lock().unwrap().peer = peer;
+        Ok(())
+    }
+
     fn on_message(&mut self, msg: WSMessage) -> WSResult<()> {
-        debug!("Receveied message: {:?}", msg);
+        debug!(target: TRANSPORT_TARGET, "[{}] Receveied message: {:?}", self.tracking_id, msg);
+        self.info.lock().unwrap().messages_received += 1;
         let message = match self.parse_message(msg) {
             Err(e) => return self.on_message_error(e),
             Ok(m) => m
@@ -210,10 +259,19 @@ impl Handler for ConnectionHandler {
         }
     }
 
+    fn on_timeout(&mut self, token: Token) -> WSResult<()> {
+        if token == FLUSH_EVENTS {
+            if let Err(e) = self.flush_events() {
+                return self.on_message_error(e);
+            }
+        }
+        Ok(())
+    }
+
     fn on_close(&mut self, _code: CloseCode, _reason: &str) {
         let state = self.info.lock().unwrap().state.clone();
         if state != ConnectionState::Disconnected {
-            trace!("Client disconnected.  Closing connection");
+            trace!(target: TRANSPORT_TARGET, "[{}] Client disconnected.  Closing connection", self.tracking_id);
             self.terminate_connection().ok();
         }
     }
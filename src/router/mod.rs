@@ -1,39 +1,137 @@
+mod admin;
+mod authorization;
+mod config;
+mod delivery;
 mod handshake;
+mod history;
 mod messaging;
+mod persistence;
 mod pubsub;
 mod rpc;
+mod sessions;
+mod workers;
 
 
-use ws::{listen as ws_listen, Sender, Result as WSResult };
+use ws::{listen as ws_listen, Sender, Result as WSResult, Message as WSMessage, CloseCode};
+use ws::util::Token;
 use std::sync::{Arc, Mutex};
-use std::collections::{HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::marker::Sync;
-use rand::{thread_rng};
+use rand::{thread_rng, Rng};
 use rand::distributions::{Range, IndependentSample};
 use router::pubsub::SubscriptionPatternNode;
 use router::rpc::RegistrationPatternNode;
-use super::ID;
+use router::rpc::cache::ResultCache;
+use super::{ID, WampResult};
+use utils::as_millis;
+use codec::Codec;
+use serializer::{Serializer, JsonSerializer};
+use messages::validation::ValidationMode;
 use std::thread::{self, JoinHandle};
 use std::time::Duration;
 use router::messaging::send_message;
-use messages::{ErrorDetails, Reason, Message};
+use messages::{ErrorDetails, Reason, Message, URI, SharedStr, Dict, List, Value, InvocationDetails};
+pub use router::config::{RouterConfig, RealmConfig, SeedEvent, BuiltinRegistration, BuiltinProcedure, CachedProcedure, EventHistoryTopic};
+pub use router::authorization::{Action, Authorizer, AuthorizationStats, AuthorizationRule};
+pub use router::admin::ADMIN_ROLE;
+use router::admin::AdminRealm;
+use router::authorization::Authorization;
+pub use router::delivery::{DeliveryPolicy, SlowConsumerPolicy};
+use router::delivery::QueuedEvent;
+use router::history::EventHistory;
+pub use router::sessions::SessionSummary;
+pub use router::persistence::{StateStore, FileStore, PersistedState, PersistedRealm, STATE_VERSION};
+use router::persistence::RetentionLog;
+use router::workers::WorkerPool;
+use store::Store;
 
 
 struct SubscriptionManager {
     subscriptions : SubscriptionPatternNode<Arc<Mutex<ConnectionInfo>>>,
-    subscription_ids_to_uris: HashMap<u64, (String, bool)>
+    subscription_ids_to_uris: HashMap<u64, (SharedStr, bool)>,
+    // Keyed by (subscription id, connection id)
+    shard_groups: HashMap<(ID, ID), String>,
+    // Keyed by topic
+    retained_events: HashMap<SharedStr, (Option<List>, Option<Dict>)>,
+    retention_log: Option<RetentionLog>,
+    event_history: EventHistory
+}
+
+impl SubscriptionManager {
+    /// Keeps an event for the sessions that subscribe to its topic later, replacing the one
+    /// kept before it
+    fn retain(&mut self, topic: URI, args: Option<List>, kwargs: Option<Dict>) {
+        if let Some(ref mut log) = self.retention_log {
+            let event = SeedEvent {
+                topic: topic.clone(),
+                args: args.clone(),
+                kwargs: kwargs.clone()
+            };
+            if let Err(e) = log.retain(&event) {
+                warn!("Could not write the event retained on {} to the retention store: {}", topic.uri, e);
+            }
+        }
+        self.retained_events.insert(topic.uri, (args, kwargs));
+    }
+}
+
+/// A call that has been passed on to a callee, which hasn't answered yet.
+struct ActiveCall {
+    // The caller's request id
+    request_id: ID,
+    registration_id: ID,
+    caller: Arc<Mutex<ConnectionInfo>>,
+    callee: Arc<Mutex<ConnectionInfo>>,
+    // Calls whose result may be cached record the procedure, the cache key of their arguments
+    // and how long to keep the result
+    cache_entry: Option<(SharedStr, String, Duration)>,
+    // Set once the caller has been told the call was cancelled, so that the callee's answer is
+    // dropped
+    cancelled: bool,
+    // Whether the caller accepts progressive results
+    receive_progress: bool,
+    // While the caller is still sending the chunks of a progressive call, the details passed on
+    // with each chunk
+    chunk_details: Option<InvocationDetails>
 }
 
 struct RegistrationManager {
     registrations : RegistrationPatternNode<Arc<Mutex<ConnectionInfo>>>,
-    registration_ids_to_uris: HashMap<u64, (String, bool)>,
-    active_calls: HashMap<ID, (ID, Arc<Mutex<ConnectionInfo>>)>
+    registration_ids_to_uris: HashMap<u64, (SharedStr, bool)>,
+    // Keyed by invocation id
+    active_calls: HashMap<ID, ActiveCall>,
+    builtin_procedures: HashMap<SharedStr, BuiltinProcedure>,
+    // Keyed by procedure URI, from the router's configuration
+    cached_procedures: HashMap<SharedStr, Duration>,
+    // Keyed by registration id, from the options the callee registered with
+    registration_cache_ttls: HashMap<ID, Duration>,
+    // The registrations whose callees asked to be told who is calling
+    disclosing_registrations: HashSet<ID>,
+    result_cache: ResultCache,
+    // The procedures that were registered when the router's state was last saved
+    recorded_registrations: Vec<URI>
 }
 
+/// The broker's state, the dealer's state and the list of connections are locked separately, so
+/// that sessions handled on different workers only wait for each other when they use the same
+/// part of the realm.  Code that needs more than one of the locks takes them in the order they
+/// are declared.
 struct Realm {
-    subscription_manager: SubscriptionManager,
-    registration_manager: RegistrationManager,
-    connections: Vec<Arc<Mutex<ConnectionInfo>>>
+    subscription_manager: Mutex<SubscriptionManager>,
+    registration_manager: Mutex<RegistrationManager>,
+    connections: Mutex<Vec<Arc<Mutex<ConnectionInfo>>>>
+}
+
+impl Realm {
+    /// Counts the realm's connections, subscriptions and registrations
+    fn stats(&self) -> Dict {
+        let mut stats = HashMap::new();
+        stats.insert("connections".to_string(), Value::Integer(self.connections.lock().unwrap().len() as i64));
+        stats.insert("subscriptions".to_string(), Value::Integer(self.subscription_manager.lock().unwrap().subscription_ids_to_uris.len() as i64));
+        stats.insert("registrations".to_string(), Value::Integer(self.registration_manager.lock().unwrap().registration_ids_to_uris.len() as i64));
+        stats
+    }
 }
 
 pub struct Router {
@@ -41,22 +139,50 @@ pub struct Router {
 }
 
 struct RouterInfo {
-    realms: Mutex<HashMap<String, Arc<Mutex<Realm>>>>,
+    realms: Mutex<HashMap<String, Arc<Realm>>>,
+    authorization: Mutex<Authorization>,
+    delivery: Mutex<DeliveryPolicy>,
+    store: Mutex<Option<Box<StateStore>>>,
+    codecs: Mutex<Vec<Arc<Codec>>>,
+    validation_mode: Mutex<ValidationMode>,
+    protocol_violations: Mutex<u64>,
+    admin: Mutex<Option<AdminRealm>>,
+    // The number of worker threads each listener starts
+    workers: Mutex<usize>
 }
 
 struct ConnectionHandler {
     info: Arc<Mutex<ConnectionInfo>>,
     router: Arc<RouterInfo>,
-    realm: Option<Arc<Mutex<Realm>>>,
+    realm: Option<Arc<Realm>>,
     subscribed_topics: Vec<ID>,
     registered_procedures: Vec<ID>,
+    // A copy of the connection's tracking ID, for logging
+    tracking_id: String,
+    // The realm the session asked to join, while its authentication challenge is unanswered
+    challenged_realm: Option<URI>
 }
 
 pub struct ConnectionInfo {
     state: ConnectionState,
     sender: Sender,
     protocol: String,
-    id: u64
+    serializer: Arc<Serializer>,
+    id: u64,
+    tracking_id: String,
+    peer: Option<String>,
+    authid: Option<String>,
+    // Only set for sessions that authenticated
+    authrole: Option<String>,
+    agent: Option<String>,
+    messages_received: u64,
+    messages_sent: u64,
+    // Events waiting to be written to this connection
+    events: VecDeque<QueuedEvent>,
+    flush_scheduled: bool,
+    dropped_events: u64,
+    // Set by the event loop once the connection has closed, see `ConnectionInfo::send()`
+    closed: Arc<AtomicBool>
 }
 
 #[derive(Clone, PartialEq)]
@@ -67,8 +193,39 @@ enum ConnectionState {
     Disconnected
 }
 
-static WAMP_JSON:&'static str = "wamp.2.json";
-static WAMP_MSGPACK:&'static str = "wamp.2.msgpack";
+impl ConnectionInfo {
+    /// Writes a frame to the connection, unless it has already closed.  A worker can get to a
+    /// connection after the event loop has let it go, and the event loop reuses the token of a
+    /// closed connection for the next one, so its sender would write to that connection instead.
+    fn send(&self, frame: WSMessage) -> WSResult<()> {
+        if self.is_closed() {
+            return Ok(());
+        }
+        self.sender.send(frame)
+    }
+
+    /// Closes the connection, unless it has already closed
+    fn close(&self, code: CloseCode) -> WSResult<()> {
+        if self.is_closed() {
+            return Ok(());
+        }
+        self.sender.close(code)
+    }
+
+    /// Schedules a timeout for the connection, unless it has already closed
+    fn timeout(&self, ms: u64, token: Token) -> WSResult<()> {
+        if self.is_closed() {
+            return Ok(());
+        }
+        self.sender.timeout(ms, token)
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+}
+
+static SET_LOG_LEVEL_PROCEDURE:&'static str = "wamp.debug.set_level";
 
 fn random_id() -> u64 {
     let mut rng = thread_rng();
@@ -77,6 +234,11 @@ fn random_id() -> u64 {
     between.ind_sample(&mut rng)
 }
 
+/// Makes the short ID that identifies a connection in the router's logs
+fn tracking_id() -> String {
+    format!("{:08x}", thread_rng().gen::<u32>())
+}
+
 
 unsafe impl Sync for Router {}
 
@@ -86,6 +248,14 @@ impl Router {
         Router{
             info: Arc::new(RouterInfo {
                 realms: Mutex::new(HashMap::new()),
+                authorization: Mutex::new(Authorization::new()),
+                delivery: Mutex::new(DeliveryPolicy::new()),
+                store: Mutex::new(None),
+                codecs: Mutex::new(Vec::new()),
+                validation_mode: Mutex::new(ValidationMode::Lenient),
+                protocol_violations: Mutex::new(0),
+                admin: Mutex::new(None),
+                workers: Mutex::new(0)
             })
         }
     }
@@ -93,48 +263,312 @@ impl Router {
     pub fn listen(&self, url: &str) -> JoinHandle<()> {
         let router_info = self.info.clone();
         let url = url.to_string();
+        let mut pool = WorkerPool::new(*self.info.workers.lock().unwrap());
         thread::spawn(move ||{
             ws_listen(&url[..], |sender| {
-                ConnectionHandler {
+                let tracking_id = tracking_id();
+                pool.dispatch(ConnectionHandler {
                     info: Arc::new(Mutex::new(ConnectionInfo{
                         state: ConnectionState::Initializing,
                         sender: sender,
                         protocol: String::new(),
-                        id: random_id()
+                        serializer: Arc::new(JsonSerializer),
+                        id: random_id(),
+                        tracking_id: tracking_id.clone(),
+                        peer: None,
+                        authid: None,
+                        authrole: None,
+                        agent: None,
+                        messages_received: 0,
+                        messages_sent: 0,
+                        events: VecDeque::new(),
+                        flush_scheduled: false,
+                        closed: Arc::new(AtomicBool::new(false)),
+                        dropped_events: 0
                     })),
                     subscribed_topics: Vec::new(),
                     registered_procedures: Vec::new(),
                     realm: None,
-                    router: router_info.clone()
-                }
+                    router: router_info.clone(),
+                    tracking_id: tracking_id,
+                    challenged_realm: None
+                })
             }).unwrap();
         })
 
     }
 
+    /// Sets how many worker threads the listeners started from now on handle messages on.  With
+    /// the default of none, each listener handles every message on its event loop, one at a
+    /// time.  With workers, each connection is handled by one of them, so that connections are
+    /// handled in parallel while each connection's messages are still handled in order.
+    pub fn set_workers(&self, workers: usize) {
+        *self.info.workers.lock().unwrap() = workers;
+    }
+
     pub fn add_realm(&mut self, realm: &str) {
         let mut realms = self.info.realms.lock().unwrap();
         if realms.contains_key(realm) {
             return
         }
-        realms.insert(realm.to_string(), Arc::new(Mutex::new(Realm {
-            connections: Vec::new(),
-            subscription_manager: SubscriptionManager {
+        realms.insert(realm.to_string(), Arc::new(Realm {
+            connections: Mutex::new(Vec::new()),
+            subscription_manager: Mutex::new(SubscriptionManager {
                 subscriptions: SubscriptionPatternNode::new(),
-                subscription_ids_to_uris: HashMap::new()
-            },
-            registration_manager: RegistrationManager {
+                subscription_ids_to_uris: HashMap::new(),
+                shard_groups: HashMap::new(),
+                retained_events: HashMap::new(),
+                retention_log: None,
+                event_history: EventHistory::new()
+            }),
+            registration_manager: Mutex::new(RegistrationManager {
                 registrations: RegistrationPatternNode::new(),
                 registration_ids_to_uris: HashMap::new(),
-                active_calls: HashMap::new()
-            }
-        })));
+                active_calls: HashMap::new(),
+                builtin_procedures: HashMap::new(),
+                cached_procedures: HashMap::new(),
+                registration_cache_ttls: HashMap::new(),
+                disclosing_registrations: HashSet::new(),
+                result_cache: ResultCache::new(),
+                recorded_registrations: Vec::new()
+            })
+        }));
         debug!("Added realm {}", realm);
     }
 
+    /// Adds the realms described in the given configuration, along with their seed events and
+    /// built in procedures.
+    pub fn apply_config(&mut self, config: RouterConfig) {
+        for realm in config.realms {
+            self.add_realm(&realm.name);
+            for event in realm.seed_events {
+                self.add_seed_event(&realm.name, event.topic, event.args, event.kwargs);
+            }
+            for registration in realm.procedures {
+                self.add_builtin_procedure(&realm.name, registration.uri, registration.builtin);
+            }
+            for procedure in realm.cached_procedures {
+                self.set_procedure_cache_ttl(&realm.name, procedure.uri, Duration::from_millis(procedure.ttl));
+            }
+            for topic in realm.event_history {
+                self.set_event_history(&realm.name, topic.uri, topic.limit, topic.ttl.map(Duration::from_millis));
+            }
+        }
+    }
+
+    /// Retains an event on the given topic, which is delivered to every client that subscribes
+    /// to a matching topic.  The realm is added if it doesn't already exist.
+    pub fn add_seed_event(&mut self, realm: &str, topic: URI, args: Option<List>, kwargs: Option<Dict>) {
+        self.add_realm(realm);
+        let realms = self.info.realms.lock().unwrap();
+        realms[realm].subscription_manager.lock().unwrap().retain(topic, args, kwargs);
+    }
+
+    /// Keeps the realm's retained events in `store` from now on, writing each one as soon as it
+    /// is retained.  Events already in the store are retained again, replacing any retained
+    /// on the same topic, and events already retained are written to the store.  The realm is
+    /// added if it doesn't already exist.
+    pub fn set_retention_store(&mut self, realm: &str, store: Box<Store>) -> WampResult<()> {
+        self.add_realm(realm);
+        let (mut log, events) = try!(RetentionLog::open(store));
+        let realms = self.info.realms.lock().unwrap();
+        let mut manager = realms[realm].subscription_manager.lock().unwrap();
+        for (topic, &(ref args, ref kwargs)) in manager.retained_events.iter() {
+            if !log.contains(topic) {
+                try!(log.retain(&SeedEvent {
+                    topic: URI::new(topic),
+                    args: args.clone(),
+                    kwargs: kwargs.clone()
+                }));
+            }
+        }
+        info!("Restoring {} retained events from the retention store", events.len());
+        for event in events {
+            manager.retained_events.insert(event.topic.uri, (event.args, event.kwargs));
+        }
+        manager.retention_log = Some(log);
+        Ok(())
+    }
+
+    /// Makes the router answer calls to `procedure` itself.  The realm is added if it doesn't
+    /// already exist.
+    pub fn add_builtin_procedure(&mut self, realm: &str, procedure: URI, builtin: BuiltinProcedure) {
+        self.add_realm(realm);
+        let realms = self.info.realms.lock().unwrap();
+        debug!("Adding built in procedure {:?} at {}", builtin, procedure.uri);
+        realms[realm].registration_manager.lock().unwrap().builtin_procedures.insert(procedure.uri, builtin);
+    }
+
+    /// Lets clients of the realm change the crate's log levels by calling `wamp.debug.set_level`
+    /// with a subsystem and a level, such as `["router", "debug"]`.  Since any client that can
+    /// call the procedure can make the router log every message, the realm should usually have
+    /// an authorizer that limits who may call it.
+    pub fn add_log_level_procedure(&mut self, realm: &str) {
+        self.add_builtin_procedure(realm, URI::new(SET_LOG_LEVEL_PROCEDURE), BuiltinProcedure::SetLogLevel);
+    }
+
+    /// Lets the router answer calls to `procedure` with the result of an earlier call with the
+    /// same arguments, for up to `ttl` after that result was produced.  This takes precedence
+    /// over any TTL the callee asked for when registering.  The realm is added if it doesn't
+    /// already exist.
+    pub fn set_procedure_cache_ttl(&mut self, realm: &str, procedure: URI, ttl: Duration) {
+        self.add_realm(realm);
+        let realms = self.info.realms.lock().unwrap();
+        realms[realm].registration_manager.lock().unwrap().cached_procedures.insert(procedure.uri, ttl);
+    }
+
+    /// Keeps the last `limit` events published on `topic`, each for up to `ttl` if it is given,
+    /// for clients to fetch with `wamp.subscription.get_events`.  See the `history` module.  A
+    /// limit of zero stops keeping them.  The realm is added if it doesn't already exist.
+    pub fn set_event_history(&mut self, realm: &str, topic: URI, limit: usize, ttl: Option<Duration>) {
+        self.add_realm(realm);
+        let realms = self.info.realms.lock().unwrap();
+        realms[realm].subscription_manager.lock().unwrap().event_history.configure(topic.uri, limit, ttl);
+    }
+
+    /// Lets clients connect using a custom codec, as long as they ask for its subprotocol.
+    pub fn add_codec(&self, codec: Arc<Codec>) {
+        debug!("Adding codec for {}", codec.protocol());
+        self.info.codecs.lock().unwrap().push(codec);
+    }
+
+    /// Sets how strictly incoming messages are checked.  The default is
+    /// `ValidationMode::Lenient`.  In strict mode, connections that send a malformed message are
+    /// aborted with `Reason::ProtocolViolation` and a description of the problem.
+    pub fn set_validation_mode(&self, mode: ValidationMode) {
+        *self.info.validation_mode.lock().unwrap() = mode;
+    }
+
+    /// The number of malformed messages the router has recieved
+    pub fn protocol_violations(&self) -> u64 {
+        *self.info.protocol_violations.lock().unwrap()
+    }
+
+    /// Sets the function that decides which sessions may publish, subscribe, call and register
+    /// on which URIs.  Requests that it rejects fail with `Reason::NotAuthorized`.
+    ///
+    /// Decisions are cached per session, action and URI, so the authorizer should give the same
+    /// answer each time unless the cache is invalidated.
+    pub fn set_authorizer(&self, authorizer: Authorizer) {
+        let mut authorization = self.info.authorization.lock().unwrap();
+        authorization.authorizer = Some(authorizer);
+        authorization.cache.clear();
+    }
+
+    /// Sets how many authorization decisions are cached.  The default is 1024, and 0 disables
+    /// the cache.
+    pub fn set_authorization_cache_size(&self, capacity: usize) {
+        self.info.authorization.lock().unwrap().cache.set_capacity(capacity);
+    }
+
+    /// Forgets the cached authorization decisions for a session, which should be done whenever
+    /// the session's permissions change.
+    pub fn invalidate_authorization(&self, session_id: ID) {
+        self.info.authorization.lock().unwrap().cache.invalidate_session(session_id);
+    }
+
+    /// Forgets every cached authorization decision
+    pub fn clear_authorization_cache(&self) {
+        self.info.authorization.lock().unwrap().cache.clear();
+    }
+
+    pub fn authorization_stats(&self) -> AuthorizationStats {
+        self.info.authorization.lock().unwrap().cache.stats()
+    }
+
+    /// Sets how many events may be queued for each subscriber, and what happens to subscribers
+    /// that fall behind.
+    pub fn set_delivery_policy(&self, policy: DeliveryPolicy) {
+        *self.info.delivery.lock().unwrap() = policy;
+    }
+
+    /// Restores the realms saved in `store`, and saves the router's state there from now on.
+    /// The state is saved when the router shuts down, or whenever `save_state()` is called.
+    pub fn set_state_store(&mut self, store: Box<StateStore>) -> WampResult<()> {
+        if let Some(state) = try!(store.load()) {
+            info!("Restoring {} realms from saved state", state.realms.len());
+            for saved in state.realms {
+                self.add_realm(&saved.name);
+                for event in saved.retained_events {
+                    self.add_seed_event(&saved.name, event.topic, event.args, event.kwargs);
+                }
+                for registration in saved.procedures {
+                    self.add_builtin_procedure(&saved.name, registration.uri, registration.builtin);
+                }
+                for procedure in saved.cached_procedures {
+                    self.set_procedure_cache_ttl(&saved.name, procedure.uri, Duration::from_millis(procedure.ttl));
+                }
+                for topic in saved.event_history {
+                    self.set_event_history(&saved.name, topic.uri, topic.limit, topic.ttl.map(Duration::from_millis));
+                }
+                let realms = self.info.realms.lock().unwrap();
+                realms[&saved.name].registration_manager.lock().unwrap().recorded_registrations = saved.registrations;
+            }
+        }
+        *self.info.store.lock().unwrap() = Some(store);
+        Ok(())
+    }
+
+    /// Saves the state of every realm to the router's state store, if it has one.
+    pub fn save_state(&self) -> WampResult<()> {
+        match *self.info.store.lock().unwrap() {
+            Some(ref store) => store.save(&self.snapshot()),
+            None => Ok(())
+        }
+    }
+
+    fn snapshot(&self) -> PersistedState {
+        let realms = self.info.realms.lock().unwrap();
+        let mut saved_realms = Vec::new();
+        for (name, realm) in realms.iter() {
+            let subscription_manager = realm.subscription_manager.lock().unwrap();
+            let manager = realm.registration_manager.lock().unwrap();
+            let registrations = manager.registration_ids_to_uris.iter().filter(|&(registration_id, &(ref uri, _))| {
+                match manager.registrations.get_registrant_for(URI::new(uri)) {
+                    Ok((_, id, _)) => id == *registration_id,
+                    Err(_) => false
+                }
+            }).map(|(_, &(ref uri, _))| URI::new(uri)).collect();
+            saved_realms.push(PersistedRealm {
+                name: name.clone(),
+                retained_events: subscription_manager.retained_events.iter().map(|(topic, &(ref args, ref kwargs))| SeedEvent {
+                    topic: URI::new(topic),
+                    args: args.clone(),
+                    kwargs: kwargs.clone()
+                }).collect(),
+                procedures: manager.builtin_procedures.iter().map(|(uri, builtin)| BuiltinRegistration {
+                    uri: URI::new(uri),
+                    builtin: *builtin
+                }).collect(),
+                cached_procedures: manager.cached_procedures.iter().map(|(uri, ttl)| CachedProcedure {
+                    uri: URI::new(uri),
+                    ttl: as_millis(*ttl)
+                }).collect(),
+                event_history: subscription_manager.event_history.topics(),
+                registrations: registrations
+            });
+        }
+        PersistedState {
+            version: STATE_VERSION,
+            realms: saved_realms
+        }
+    }
+
+    /// The procedures that were registered in the realm when the router's state was last saved,
+    /// which callees are expected to register again.
+    pub fn recorded_registrations(&self, realm: &str) -> Vec<URI> {
+        match self.info.realms.lock().unwrap().get(realm) {
+            Some(realm) => realm.registration_manager.lock().unwrap().recorded_registrations.clone(),
+            None => Vec::new()
+        }
+    }
+
     pub fn shutdown(&self) {
+        if let Err(e) = self.save_state() {
+            error!("Could not save router state: {}", e);
+        }
         for realm in self.info.realms.lock().unwrap().values() {
-            for connection in realm.lock().unwrap().connections.iter() {
+            for connection in realm.connections.lock().unwrap().iter() {
                 send_message(connection, &Message::Goodbye(ErrorDetails::new(), Reason::SystemShutdown)).ok();
                 let mut connection = connection.lock().unwrap();
                 connection.state = ConnectionState::ShuttingDown;
@@ -143,7 +577,7 @@ impl Router {
         info!("Goodbye messages sent.  Waiting 5 seconds for response");
         thread::sleep(Duration::from_secs(5));
         for realm in self.info.realms.lock().unwrap().values() {
-            for connection in realm.lock().unwrap().connections.iter() {
+            for connection in realm.connections.lock().unwrap().iter() {
                 let connection = connection.lock().unwrap();
                 connection.sender.shutdown().ok();
             }
@@ -155,16 +589,29 @@ impl Router {
 
 impl ConnectionHandler{
 
+    fn authorize(&self, action: Action, uri: &URI) -> bool {
+        let (session_id, authid) = {
+            let info = self.info.lock().unwrap();
+            (info.id, info.authid.clone())
+        };
+        let allowed = self.router.authorization.lock().unwrap().authorize(session_id, authid.as_ref().map(|authid| &authid[..]), action, uri);
+        if !allowed {
+            info!("[{}] Session {} is not authorized to {:?} {}", self.tracking_id, session_id, action, uri.uri);
+        }
+        allowed
+    }
+
     fn remove(&mut self) {
         match self.realm {
             Some(ref realm) => {
-
-                let mut realm = realm.lock().unwrap();
                 {
-                    trace!("Removing subscriptions for client {}", self.info.lock().unwrap().id);
-                    let mut manager = &mut realm.subscription_manager;
+                    let my_id = self.info.lock().unwrap().id;
+                    trace!("[{}] Removing subscriptions for client {}", self.tracking_id, my_id);
+                    let mut manager = realm.subscription_manager.lock().unwrap();
+                    let manager = &mut *manager;
                     for subscription_id in self.subscribed_topics.iter() {
                         trace!("Looking for subscription {}", subscription_id);
+                        manager.shard_groups.remove(&(*subscription_id, my_id));
                         match manager.subscription_ids_to_uris.get(&subscription_id) {
                             Some(&(ref topic_uri, is_prefix)) => {
                                 trace!("Removing subscription to {:?}", topic_uri);
@@ -176,7 +623,8 @@ impl ConnectionHandler{
                     }
                 }
                 {
-                    let mut manager = &mut realm.registration_manager;
+                    let mut manager = realm.registration_manager.lock().unwrap();
+                    let manager = &mut *manager;
                     for registration_id in self.registered_procedures.iter() {
                         match manager.registration_ids_to_uris.get(&registration_id) {
                             Some(&(ref topic_uri, is_prefix)) => {
@@ -187,7 +635,8 @@ impl ConnectionHandler{
                     }
                 }
                 let my_id = self.info.lock().unwrap().id.clone();
-                realm.connections.retain(|connection| {
+                self.router.authorization.lock().unwrap().cache.invalidate_session(my_id);
+                realm.connections.lock().unwrap().retain(|connection| {
                     connection.lock().unwrap().id != my_id
                 });
             },
@@ -0,0 +1,230 @@
+//! Contains the `StateStore` trait, which lets a router keep the state of its realms across
+//! restarts, along with `FileStore`, which keeps it in a JSON file.
+//!
+//! The state is saved as a versioned document.  When the format changes, `STATE_VERSION` is
+//! increased and `migrate()` learns how to bring documents written by older versions up to date,
+//! so a router can always read what an earlier release wrote.
+//!
+//! A realm's retained events can also be kept in a `Store`, which is written to whenever an
+//! event is retained rather than only when the state is saved.
+use super::config::{SeedEvent, BuiltinRegistration, CachedProcedure, EventHistoryTopic};
+use messages::URI;
+use store::Store;
+use serde_json::{self, Value as JSONValue};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Write, ErrorKind as IOErrorKind};
+use std::path::PathBuf;
+use ::{WampResult, Error, ErrorKind};
+
+/// The version of the state format written by this release
+pub const STATE_VERSION: u64 = 1;
+
+/// The state of every realm, as it is saved to a `StateStore`.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct PersistedState {
+    pub version: u64,
+    #[serde(default)]
+    pub realms: Vec<PersistedRealm>
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct PersistedRealm {
+    pub name: String,
+    #[serde(default)]
+    pub retained_events: Vec<SeedEvent>,
+    #[serde(default)]
+    pub procedures: Vec<BuiltinRegistration>,
+    #[serde(default)]
+    pub cached_procedures: Vec<CachedProcedure>,
+    /// The topics that keep event history.  The events themselves aren't saved.
+    #[serde(default)]
+    pub event_history: Vec<EventHistoryTopic>,
+    /// The procedures that callees had registered when the state was saved.  Registrations
+    /// belong to connections, so they can't be restored, but they are kept as a record of which
+    /// callees are expected to come back.
+    #[serde(default)]
+    pub registrations: Vec<URI>
+}
+
+/// Somewhere a router's state can be saved to and loaded from.
+pub trait StateStore: Send {
+    /// Loads the most recently saved state, or `None` if nothing has been saved yet.
+    fn load(&self) -> WampResult<Option<PersistedState>>;
+
+    fn save(&self, state: &PersistedState) -> WampResult<()>;
+}
+
+/// Keeps the router's state in a JSON file.
+pub struct FileStore {
+    path: PathBuf
+}
+
+impl FileStore {
+    pub fn new<P: Into<PathBuf>>(path: P) -> FileStore {
+        FileStore {
+            path: path.into()
+        }
+    }
+}
+
+impl StateStore for FileStore {
+    fn load(&self) -> WampResult<Option<PersistedState>> {
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == IOErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Error::new(ErrorKind::IOError(e)))
+        };
+        let mut contents = String::new();
+        try!(file.read_to_string(&mut contents).map_err(|e| Error::new(ErrorKind::IOError(e))));
+        let document = try!(serde_json::from_str(&contents).map_err(|e| Error::new(ErrorKind::JSONError(e))));
+        migrate(document).map(Some)
+    }
+
+    fn save(&self, state: &PersistedState) -> WampResult<()> {
+        // Write to a temporary file first, so a crash while saving can't leave a truncated file
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(".tmp");
+        let contents = try!(serde_json::to_string_pretty(state).map_err(|e| Error::new(ErrorKind::JSONError(e))));
+        {
+            let mut file = try!(File::create(&temp_path).map_err(|e| Error::new(ErrorKind::IOError(e))));
+            try!(file.write_all(contents.as_bytes()).map_err(|e| Error::new(ErrorKind::IOError(e))));
+            try!(file.sync_all().map_err(|e| Error::new(ErrorKind::IOError(e))));
+        }
+        fs::rename(&temp_path, &self.path).map_err(|e| Error::new(ErrorKind::IOError(e)))
+    }
+}
+
+/// How many retained events are replaced between compactions of a retention store
+const COMPACT_INTERVAL: usize = 64;
+
+/// Keeps a realm's retained events in a `Store`, with one record for each topic.
+pub struct RetentionLog {
+    store: Box<Store>,
+    // The sequence number of each topic's record, keyed by topic
+    sequences: HashMap<String, u64>,
+    replaced_since_compact: usize
+}
+
+impl RetentionLog {
+    /// Reads the events retained in `store`.  If a topic somehow has more than one record, the
+    /// latest one wins and the others are removed.
+    pub fn open(mut store: Box<Store>) -> WampResult<(RetentionLog, Vec<SeedEvent>)> {
+        let mut events: HashMap<String, (u64, SeedEvent)> = HashMap::new();
+        let mut superseded = Vec::new();
+        for (sequence, record) in store.iter() {
+            let event: SeedEvent = try!(serde_json::from_slice(&record).map_err(|e| Error::new(ErrorKind::JSONError(e))));
+            if let Some((old_sequence, _)) = events.insert(event.topic.uri.to_string(), (sequence, event)) {
+                superseded.push(old_sequence);
+            }
+        }
+        for sequence in superseded {
+            try!(store.ack(sequence));
+        }
+        let log = RetentionLog {
+            store: store,
+            sequences: events.iter().map(|(topic, &(sequence, _))| (topic.clone(), sequence)).collect(),
+            replaced_since_compact: 0
+        };
+        Ok((log, events.into_iter().map(|(_, (_, event))| event).collect()))
+    }
+
+    pub fn contains(&self, topic: &str) -> bool {
+        self.sequences.contains_key(topic)
+    }
+
+    /// Writes the event retained on a topic, replacing the one retained before it
+    pub fn retain(&mut self, event: &SeedEvent) -> WampResult<()> {
+        let record = try!(serde_json::to_vec(event).map_err(|e| Error::new(ErrorKind::JSONError(e))));
+        let sequence = try!(self.store.append(&record));
+        if let Some(old_sequence) = self.sequences.insert(event.topic.uri.to_string(), sequence) {
+            try!(self.store.ack(old_sequence));
+            self.replaced_since_compact += 1;
+            if self.replaced_since_compact >= COMPACT_INTERVAL {
+                self.replaced_since_compact = 0;
+                try!(self.store.compact());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Brings a saved document up to the current version of the format.
+pub fn migrate(document: JSONValue) -> WampResult<PersistedState> {
+    let version = match document.get("version").and_then(|version| version.as_u64()) {
+        Some(version) => version,
+        None => return Err(Error::new(ErrorKind::InvalidState("Persisted router state has no version")))
+    };
+    if version > STATE_VERSION {
+        return Err(Error::new(ErrorKind::InvalidState("Persisted router state was written by a newer version of the router")));
+    }
+    // Older versions are upgraded here, one version at a time, before the document is parsed
+    serde_json::from_value(document).map_err(|e| Error::new(ErrorKind::JSONError(e)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FileStore, StateStore, PersistedState, PersistedRealm, RetentionLog, STATE_VERSION, migrate};
+    use router::config::SeedEvent;
+    use messages::{URI, Value};
+    use store::{Store, MemoryStore};
+    use serde_json;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn save_and_load() {
+        let path = env::temp_dir().join(format!("wamp-router-state-{}.json", ::std::process::id()));
+        let store = FileStore::new(path.clone());
+        assert_eq!(store.load().unwrap(), None);
+
+        let state = PersistedState {
+            version: STATE_VERSION,
+            realms: vec![PersistedRealm {
+                name: "realm1".to_string(),
+                retained_events: vec![SeedEvent {
+                    topic: URI::new("ca.test.status"),
+                    args: Some(vec![Value::String("ready".to_string())]),
+                    kwargs: None
+                }],
+                procedures: Vec::new(),
+                cached_procedures: Vec::new(),
+                event_history: Vec::new(),
+                registrations: vec![URI::new("ca.test.add")]
+            }]
+        };
+        store.save(&state).unwrap();
+        assert_eq!(store.load().unwrap(), Some(state));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reject_newer_versions() {
+        let document = serde_json::from_str(&format!("{{\"version\": {}}}", STATE_VERSION + 1)).unwrap();
+        assert!(migrate(document).is_err());
+        let document = serde_json::from_str("{\"realms\": []}").unwrap();
+        assert!(migrate(document).is_err());
+        let document = serde_json::from_str("{\"version\": 1, \"realms\": [{\"name\": \"realm1\"}]}").unwrap();
+        assert_eq!(migrate(document).unwrap().realms[0].name, "realm1");
+    }
+
+    #[test]
+    fn retention_keeps_one_event_per_topic() {
+        let event = |status: &str| SeedEvent {
+            topic: URI::new("ca.test.status"),
+            args: Some(vec![Value::String(status.to_string())]),
+            kwargs: None
+        };
+        let mut store = MemoryStore::new();
+        store.append(&serde_json::to_vec(&event("starting")).unwrap()).unwrap();
+        store.append(&serde_json::to_vec(&event("ready")).unwrap()).unwrap();
+        let (mut log, events) = RetentionLog::open(Box::new(store)).unwrap();
+        assert_eq!(events, vec![event("ready")]);
+        assert!(log.contains("ca.test.status"));
+
+        log.retain(&event("stopping")).unwrap();
+        let records: Vec<_> = log.store.iter().collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!(serde_json::from_slice::<SeedEvent>(&records[0].1).unwrap(), event("stopping"));
+    }
+}
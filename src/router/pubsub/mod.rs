@@ -1,18 +1,48 @@
 mod patterns;
-use super::{ConnectionHandler, random_id};
+use super::{ConnectionHandler, Action, random_id};
 
 use router::messaging::send_message;
+use router::delivery::{queue_event, EventEncoder};
+use matching::matches;
 use messages::{Message, URI, SubscribeOptions, PublishOptions, EventDetails, ErrorType, Reason};
 use ::{List, Dict,  MatchingPolicy, WampResult, Error, ErrorKind};
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use rand::{thread_rng, Rng};
 pub use router::pubsub::patterns::SubscriptionPatternNode;
 
+/// Picks which member of a shard group should receive a publication.
+///
+/// Publications carrying a shard key always go to the same member (as long as the group
+/// doesn't change), while those without one are spread randomly across the group.  The
+/// members must be given in the same order each time, so they are sorted first.
+fn select_shard(shard_key: &Option<String>, group_size: usize) -> usize {
+    match *shard_key {
+        Some(ref key) => {
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            (hasher.finish() % group_size as u64) as usize
+        },
+        None => thread_rng().gen_range(0, group_size)
+    }
+}
+
+fn unix_millis() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    now.as_secs() * 1000 + now.subsec_nanos() as u64 / 1_000_000
+}
+
 impl ConnectionHandler{
     pub fn handle_subscribe(&mut self, request_id: u64, options: SubscribeOptions, topic: URI) -> WampResult<()> {
-        debug!("Responding to subscribe message (id: {}, topic: {})", request_id, topic.uri);
+        debug!("[{}] Responding to subscribe message (id: {}, topic: {})", self.tracking_id, request_id, topic.uri);
+        if !self.authorize(Action::Subscribe, &topic) {
+            return Err(Error::new(ErrorKind::ErrorReason(ErrorType::Subscribe, request_id, Reason::NotAuthorized)));
+        }
         match self.realm {
             Some(ref realm) => {
-                let mut realm = realm.lock().unwrap();
-                let mut manager = &mut realm.subscription_manager;
+                let mut manager = realm.subscription_manager.lock().unwrap();
                 let topic_id = {
                     let topic_id = match manager.subscriptions.subscribe_with(&topic, self.info.clone(), options.pattern_match.clone()) {
                         Ok(topic_id) => topic_id,
@@ -21,8 +51,22 @@ impl ConnectionHandler{
                     self.subscribed_topics.push(topic_id);
                     topic_id
                 };
-                manager.subscription_ids_to_uris.insert(topic_id, (topic.uri, options.pattern_match == MatchingPolicy::Prefix));
-                send_message(&self.info, &Message::Subscribed(request_id, topic_id))
+                manager.subscription_ids_to_uris.insert(topic_id, (topic.uri.clone(), options.pattern_match == MatchingPolicy::Prefix));
+                if let Some(group) = options.shard_group {
+                    let my_id = self.info.lock().unwrap().id;
+                    manager.shard_groups.insert((topic_id, my_id), group);
+                }
+                try!(send_message(&self.info, &Message::Subscribed(request_id, topic_id)));
+                for (retained_topic, &(ref args, ref kwargs)) in manager.retained_events.iter() {
+                    if matches(&topic.uri, retained_topic, options.pattern_match) {
+                        let mut details = EventDetails::new();
+                        if options.pattern_match != MatchingPolicy::Strict {
+                            details.topic = Some(URI::new(retained_topic));
+                        }
+                        try!(send_message(&self.info, &Message::Event(topic_id, random_id(), details, args.clone(), kwargs.clone())));
+                    }
+                }
+                Ok(())
             },
              None => {
                 Err(Error::new(ErrorKind::InvalidState("Recieved a message while not attached to a realm")))
@@ -33,8 +77,7 @@ impl ConnectionHandler{
     pub fn handle_unsubscribe(&mut self, request_id: u64, topic_id: u64) -> WampResult<()> {
         match self.realm {
             Some(ref realm) => {
-                let mut realm = realm.lock().unwrap();
-                let mut manager = &mut realm.subscription_manager;
+                let mut manager = realm.subscription_manager.lock().unwrap();
                 let (topic_uri, is_prefix) =  match manager.subscription_ids_to_uris.get(&topic_id) {
                     Some(&(ref uri, ref is_prefix)) => (uri.clone(), is_prefix.clone()),
                     None => return Err(Error::new(ErrorKind::ErrorReason(ErrorType::Unsubscribe, request_id, Reason::NoSuchSubscription)))
@@ -48,6 +91,8 @@ impl ConnectionHandler{
                 self.subscribed_topics.retain(|id| {
                     *id != topic_id
                 });
+                let my_id = self.info.lock().unwrap().id;
+                manager.shard_groups.remove(&(topic_id, my_id));
                 send_message(&self.info, &Message::Unsubscribed(request_id))
             },
             None => {
@@ -57,30 +102,64 @@ impl ConnectionHandler{
     }
 
     pub fn handle_publish(&mut self, request_id: u64, options: PublishOptions, topic: URI, args: Option<List>, kwargs: Option<Dict>) -> WampResult<()> {
-        debug!("Responding to publish message (id: {}, topic: {})", request_id, topic.uri);
+        debug!("[{}] Responding to publish message (id: {}, topic: {})", self.tracking_id, request_id, topic.uri);
+        if !self.authorize(Action::Publish, &topic) {
+            if options.should_acknowledge() {
+                return Err(Error::new(ErrorKind::ErrorReason(ErrorType::Publish, request_id, Reason::NotAuthorized)));
+            } else {
+                return Ok(());
+            }
+        }
         match self.realm {
             Some(ref realm) => {
-                let realm = realm.lock().unwrap();
-                let manager = &realm.subscription_manager;
+                let mut manager = realm.subscription_manager.lock().unwrap();
                 let publication_id = random_id();
-                let mut event_message = Message::Event(1, publication_id, EventDetails::new(), args.clone(), kwargs.clone());
+                let policy = self.router.delivery.lock().unwrap().clone();
                 let my_id = {
                     self.info.lock().unwrap().id.clone()
                 };
                 info!("Current topic tree: {:?}", manager.subscriptions);
+                let mut recipients = Vec::new();
+                let mut shard_groups: HashMap<&str, Vec<_>> = HashMap::new();
                 for (subscriber, topic_id, policy) in manager.subscriptions.filter(topic.clone()) {
-                    if subscriber.lock().unwrap().id != my_id {
-                        if let Message::Event(ref mut old_topic, ref _publish_id, ref mut details, ref _args, ref _kwargs) = event_message {
-                            *old_topic = topic_id;
-                            details.topic = if policy == MatchingPolicy::Strict {
-                                None
-                            } else {
-                                Some(topic.clone())
-                            };
+                    let (subscriber_id, admitted) = {
+                        let subscriber = subscriber.lock().unwrap();
+                        let authid = subscriber.authid.as_ref().map(|authid| authid.as_str());
+                        let authrole = subscriber.authrole.as_ref().map(|authrole| authrole.as_str());
+                        (subscriber.id, options.admits(subscriber.id, authid, authrole))
+                    };
+                    if (subscriber_id != my_id || !options.excludes_publisher()) && admitted {
+                        match manager.shard_groups.get(&(topic_id, subscriber_id)) {
+                            Some(group) => shard_groups.entry(group).or_insert(Vec::new()).push((subscriber_id, (subscriber, topic_id, policy))),
+                            None => recipients.push((subscriber, topic_id, policy))
                         }
-                        try!(send_message(subscriber, &event_message));
                     }
                 }
+                for (_, mut members) in shard_groups {
+                    // The topic tree doesn't keep subscribers in any particular order
+                    members.sort_by_key(|&(subscriber_id, (_, topic_id, _))| (subscriber_id, topic_id));
+                    let index = select_shard(&options.shard_key, members.len());
+                    recipients.push(members.swap_remove(index).1);
+                }
+                let mut encoder = EventEncoder::new(publication_id, &topic, &args, &kwargs);
+                if policy.timestamps {
+                    encoder = encoder.with_timestamp(unix_millis());
+                }
+                if options.discloses_publisher() {
+                    let info = self.info.lock().unwrap();
+                    encoder = encoder.with_publisher(my_id, info.authid.clone(), info.authrole.clone());
+                }
+                for (subscriber, topic_id, matching_policy) in recipients {
+                    if let Err(e) = queue_event(subscriber, &mut encoder, topic_id, matching_policy, &policy) {
+                        warn!("[{}] Could not deliver event from publication {}: {}", self.tracking_id, publication_id, e);
+                    }
+                }
+                if !options.is_restricted() {
+                    manager.event_history.record(&topic, publication_id, unix_millis(), &args, &kwargs);
+                }
+                if options.should_retain() {
+                    manager.retain(topic, args, kwargs);
+                }
                 if options.should_acknowledge() {
                     try!(send_message(&self.info, &Message::Published(request_id, publication_id)));
                 }
@@ -93,3 +172,107 @@ impl ConnectionHandler{
     }
 
 }
+
+#[cfg(all(test, feature = "publisher", feature = "subscriber"))]
+mod test {
+    use client::{Client, Subscription};
+    use messages::{URI, PublishOptions};
+    use testing::{start_router, join};
+    use std::sync::mpsc::{channel, Sender, Receiver};
+    use std::time::Duration;
+    use ::Value;
+
+    // Subscribes as a member of `group`, tagging the events it receives with `member`
+    fn subscribe_member(client: &mut Client, topic: &str, group: &str, member: usize, events: &Sender<(usize, Vec<Value>)>) -> Subscription {
+        let events = events.clone();
+        client.subscribe_group(URI::new(topic), group, Box::new(move |args, _| {
+            events.send((member, args)).unwrap();
+        })).unwrap().wait().unwrap()
+    }
+
+    fn publish(publisher: &mut Client, topic: &str, n: i64, shard_key: Option<&str>) {
+        let options = match shard_key {
+            Some(key) => PublishOptions::new_with_shard_key(false, key),
+            None => PublishOptions::new(false)
+        };
+        publisher.publish_with_options(URI::new(topic), Some(vec![Value::Integer(n)]), None, options).unwrap();
+    }
+
+    // The next `count` events and the members that received them, checking that no more
+    // arrive
+    fn received_by(received: &Receiver<(usize, Vec<Value>)>, count: usize) -> Vec<(usize, Vec<Value>)> {
+        let members = (0..count).map(|_| received.recv_timeout(Duration::from_secs(5)).unwrap()).collect();
+        assert!(received.recv_timeout(Duration::from_millis(200)).is_err());
+        members
+    }
+
+    #[test]
+    fn shard_keys_pick_the_same_member() {
+        let (_router, url) = start_router();
+        let (events, received) = channel();
+        let mut members: Vec<_> = (0..3).map(|member| {
+            let mut client = join(&url);
+            let subscription = subscribe_member(&mut client, "ca.test.orders", "workers", member, &events);
+            (client, Some(subscription))
+        }).collect();
+        let mut publisher = join(&url);
+        // Resubscribing moves a member to the end of the topic's subscribers, without changing
+        // who is in the group
+        let mut receivers = Vec::new();
+        for n in 0..9 {
+            publish(&mut publisher, "ca.test.orders", n as i64, Some("customer.1"));
+            receivers.push(received_by(&received, 1)[0].0);
+            let (ref mut client, ref mut subscription) = members[n % 3];
+            client.unsubscribe(subscription.take().unwrap()).unwrap().wait().unwrap();
+            *subscription = Some(subscribe_member(client, "ca.test.orders", "workers", n % 3, &events));
+        }
+        assert!(receivers.iter().all(|&member| member == receivers[0]), "Events were spread over the group: {:?}", receivers);
+    }
+
+    #[test]
+    fn each_group_receives_each_event_once() {
+        let (_router, url) = start_router();
+        let (worker_events, workers) = channel();
+        let (auditor_events, auditors) = channel();
+        let (plain_events, plain) = channel();
+        let mut _members = Vec::new();
+        for &(group, count, ref events) in &[("workers", 3, &worker_events), ("auditors", 2, &auditor_events)] {
+            for member in 0..count {
+                let mut client = join(&url);
+                subscribe_member(&mut client, "ca.test.jobs", group, member, events);
+                _members.push(client);
+            }
+        }
+        let mut subscriber = join(&url);
+        subscriber.subscribe(URI::new("ca.test.jobs"), Box::new(move |args, _| {
+            plain_events.send((0, args)).unwrap();
+        })).unwrap().wait().unwrap();
+
+        let mut publisher = join(&url);
+        for n in 0..6 {
+            publish(&mut publisher, "ca.test.jobs", n, None);
+        }
+        // Subscribers outside any group still receive everything
+        received_by(&workers, 6);
+        received_by(&auditors, 6);
+        received_by(&plain, 6);
+
+        // Each key sticks to one member of each group, but different keys may go to different
+        // members
+        let keys = ["a", "b", "c"];
+        for _ in 0..6 {
+            for (n, key) in keys.iter().enumerate() {
+                publish(&mut publisher, "ca.test.jobs", n as i64, Some(key));
+            }
+        }
+        for received in &[workers, auditors] {
+            let events = received_by(received, 18);
+            for n in 0..keys.len() {
+                let chosen: Vec<_> = events.iter().filter(|&&(_, ref args)| args[0] == Value::Integer(n as i64)).map(|&(member, _)| member).collect();
+                assert_eq!(chosen.len(), 6);
+                assert!(chosen.iter().all(|&member| member == chosen[0]), "Key {} was spread over the group: {:?}", keys[n], chosen);
+            }
+        }
+        received_by(&plain, 18);
+    }
+}
@@ -0,0 +1,74 @@
+//! Contains the `ResultCache` struct, which lets the dealer answer repeated calls to idempotent
+//! procedures without invoking the callee each time.
+use messages::{Dict, List};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+struct CachedResult {
+    expires: Instant,
+    args: Option<List>,
+    kwargs: Option<Dict>
+}
+
+pub struct ResultCache {
+    // Keyed by procedure and the canonical form of the call's arguments
+    entries: HashMap<(String, String), CachedResult>,
+    pub hits: u64,
+    pub misses: u64
+}
+
+impl ResultCache {
+    pub fn new() -> ResultCache {
+        ResultCache {
+            entries: HashMap::new(),
+            hits: 0,
+            misses: 0
+        }
+    }
+
+    /// Gets the cached result of calling `procedure` with the arguments `key` was made from, if
+    /// it hasn't expired.
+    pub fn get(&mut self, procedure: &str, key: &str) -> Option<(Option<List>, Option<Dict>)> {
+        let now = Instant::now();
+        let result = match self.entries.get(&(procedure.to_string(), key.to_string())) {
+            Some(cached) if cached.expires > now => Some((cached.args.clone(), cached.kwargs.clone())),
+            _ => None
+        };
+        if result.is_some() {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+        }
+        result
+    }
+
+    pub fn insert(&mut self, procedure: String, key: String, ttl: Duration, args: Option<List>, kwargs: Option<Dict>) {
+        let now = Instant::now();
+        self.entries.retain(|_, cached| cached.expires > now);
+        self.entries.insert((procedure, key), CachedResult {
+            expires: now + ttl,
+            args: args,
+            kwargs: kwargs
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ResultCache;
+    use messages::Value;
+    use utils::canonical_key;
+    use std::time::Duration;
+
+    #[test]
+    fn cached_results_expire() {
+        let mut cache = ResultCache::new();
+        let key = canonical_key(&Some(vec![Value::Integer(1)]), &None);
+        cache.insert("ca.test.lookup".to_string(), key.clone(), Duration::from_secs(60), Some(vec![Value::Integer(2)]), None);
+        cache.insert("ca.test.stale".to_string(), key.clone(), Duration::from_secs(0), Some(vec![Value::Integer(2)]), None);
+        assert_eq!(cache.get("ca.test.lookup", &key), Some((Some(vec![Value::Integer(2)]), None)));
+        assert_eq!(cache.get("ca.test.stale", &key), None);
+        assert_eq!(cache.get("ca.test.lookup", "[2]{}"), None);
+        assert_eq!((cache.hits, cache.misses), (1, 2));
+    }
+}
@@ -1,19 +1,51 @@
 mod patterns;
+pub mod cache;
 pub use router::rpc::patterns::RegistrationPatternNode;
 
-use super::{ConnectionHandler, random_id};
+use super::{ConnectionHandler, Realm, ActiveCall, RegistrationManager, BuiltinProcedure, Action, random_id};
 
 use router::messaging::send_message;
-use messages::{Message, URI, RegisterOptions, CallOptions, InvocationDetails, YieldOptions, ResultDetails, ErrorType, Reason};
-use ::{List, Dict,  MatchingPolicy, WampResult, Error, ErrorKind, ID};
+use utils::canonical_key;
+use logging;
+use messages::{GET_EVENTS_PROCEDURE, Message, URI, RegisterOptions, CallOptions, CancelOptions, CancelMode, InterruptOptions, InvocationDetails, YieldOptions, ResultDetails, ErrorType, Reason};
+use ::{List, Dict, Value, MatchingPolicy, WampResult, Error, ErrorKind, ID};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Produces the result of a call to one of the router's built in procedures
+fn call_builtin(builtin: BuiltinProcedure, realm: &Realm, args: Option<List>, kwargs: Option<Dict>) -> Result<(Option<List>, Option<Dict>), Reason> {
+    Ok(match builtin {
+        BuiltinProcedure::Echo => (args, kwargs),
+        BuiltinProcedure::Time => {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+            (Some(vec![Value::Integer(now as i64)]), None)
+        },
+        BuiltinProcedure::SetLogLevel => {
+            let (subsystem, level) = match args.as_ref().map(|args| &args[..]) {
+                Some(&[Value::String(ref subsystem), Value::String(ref level)]) => (subsystem, level),
+                _ => return Err(Reason::InvalidArgument)
+            };
+            let level = try!(logging::set_level_by_name(subsystem, level).map_err(|e| {
+                info!("Could not set log level: {}", e);
+                Reason::InvalidArgument
+            }));
+            info!("Set the {} log level to {}", subsystem, level);
+            (Some(vec![Value::String(level.to_string().to_lowercase())]), None)
+        },
+        BuiltinProcedure::Stats => (None, Some(realm.stats()))
+    })
+}
 
 impl ConnectionHandler{
     pub fn handle_register(&mut self, request_id: ID, options: RegisterOptions, procedure: URI) -> WampResult<()> {
-        debug!("Responding to register message (id: {}, procedure: {})", request_id, procedure.uri);
+        debug!("[{}] Responding to register message (id: {}, procedure: {})", self.tracking_id, request_id, procedure.uri);
+        if !self.authorize(Action::Register, &procedure) {
+            return Err(Error::new(ErrorKind::ErrorReason(ErrorType::Register, request_id, Reason::NotAuthorized)));
+        }
         match self.realm {
             Some(ref realm) => {
-                let mut realm = realm.lock().unwrap();
-                let mut manager = &mut realm.registration_manager;
+                let mut manager = realm.registration_manager.lock().unwrap();
                 let procedure_id = {
                     let procedure_id = match manager.registrations.register_with(&procedure, self.info.clone(), options.pattern_match.clone(), options.invocation_policy.clone()) {
                         Ok(procedure_id) => procedure_id,
@@ -22,6 +54,12 @@ impl ConnectionHandler{
                     self.registered_procedures.push(procedure_id);
                     procedure_id
                 };
+                if let Some(ttl) = options.cache_ttl {
+                    manager.registration_cache_ttls.insert(procedure_id, Duration::from_millis(ttl));
+                }
+                if options.discloses_caller() {
+                    manager.disclosing_registrations.insert(procedure_id);
+                }
                 manager.registration_ids_to_uris.insert(procedure_id, (procedure.uri, options.pattern_match == MatchingPolicy::Prefix));
                 send_message(&self.info, &Message::Registered(request_id, procedure_id))
             },
@@ -34,8 +72,7 @@ impl ConnectionHandler{
     pub fn handle_unregister(&mut self, request_id: ID, procedure_id: ID) -> WampResult<()> {
         match self.realm {
             Some(ref realm) => {
-                let mut realm = realm.lock().unwrap();
-                let mut manager = &mut realm.registration_manager;
+                let mut manager = realm.registration_manager.lock().unwrap();
                 let (procedure_uri, is_prefix) =  match manager.registration_ids_to_uris.get(&procedure_id) {
                     Some(&(ref uri, ref is_prefix)) => (uri.clone(), is_prefix.clone()),
                     None => return Err(Error::new(ErrorKind::ErrorReason(ErrorType::Unregister, request_id, Reason::NoSuchProcedure)))
@@ -49,6 +86,8 @@ impl ConnectionHandler{
                 self.registered_procedures.retain(|id| {
                     *id != procedure_id
                 });
+                manager.registration_cache_ttls.remove(&procedure_id);
+                manager.disclosing_registrations.remove(&procedure_id);
                 send_message(&self.info, &Message::Unregistered(request_id))
             },
             None => {
@@ -57,25 +96,86 @@ impl ConnectionHandler{
         }
     }
 
-    pub fn handle_call(&mut self, request_id: ID, _options: CallOptions, procedure: URI, args: Option<List>, kwargs: Option<Dict>) -> WampResult<()> {
-         debug!("Responding to call message (id: {}, procedure: {})", request_id, procedure.uri);
+    pub fn handle_call(&mut self, request_id: ID, options: CallOptions, procedure: URI, args: Option<List>, kwargs: Option<Dict>) -> WampResult<()> {
+         debug!("[{}] Responding to call message (id: {}, procedure: {})", self.tracking_id, request_id, procedure.uri);
+         if !self.authorize(Action::Call, &procedure) {
+             return Err(Error::new(ErrorKind::ErrorReason(ErrorType::Call, request_id, Reason::NotAuthorized)));
+         }
+         if self.is_admin_call(&procedure) {
+             let (args, kwargs) = try!(self.call_admin(&procedure, args).map_err(|reason| Error::new(ErrorKind::ErrorReason(ErrorType::Call, request_id, reason))));
+             return send_message(&self.info, &Message::Result(request_id, ResultDetails::new(), args, kwargs));
+         }
+         if procedure.uri == GET_EVENTS_PROCEDURE {
+             let (args, kwargs) = try!(self.get_events(args).map_err(|reason| Error::new(ErrorKind::ErrorReason(ErrorType::Call, request_id, reason))));
+             return send_message(&self.info, &Message::Result(request_id, ResultDetails::new(), args, kwargs));
+         }
          match self.realm {
              Some(ref realm) => {
-                 let mut realm = realm.lock().unwrap();
-                 let mut manager = &mut realm.registration_manager;
+                 let mut manager = realm.registration_manager.lock().unwrap();
+                 if let Some(result) = self.continue_progressive_call(&mut manager, request_id, &options, &args, &kwargs) {
+                     return result;
+                 }
+                 if let Some(&builtin) = manager.builtin_procedures.get(&procedure.uri) {
+                     // Built in procedures may take the realm's other locks
+                     drop(manager);
+                     let (args, kwargs) = try!(call_builtin(builtin, realm, args, kwargs).map_err(|reason| Error::new(ErrorKind::ErrorReason(ErrorType::Call, request_id, reason))));
+                     return send_message(&self.info, &Message::Result(request_id, ResultDetails::new(), args, kwargs));
+                 }
+                 let manager = &mut *manager;
                  let invocation_id = random_id();
                  info!("Current procedure tree: {:?}", manager.registrations);
                  let  (registrant, procedure_id, policy) = match manager.registrations.get_registrant_for(procedure.clone()) {
                      Ok(registrant) => registrant,
                      Err(e) => return Err(Error::new(ErrorKind::ErrorReason(ErrorType::Call, request_id, e.reason())))
                  };
-                 manager.active_calls.insert(invocation_id, (request_id, self.info.clone()));
+                 // The first chunk of a progressive call says little about its result
+                 let cache_ttl = if options.is_progressive() {
+                     None
+                 } else {
+                     manager.cached_procedures.get(&procedure.uri).or_else(|| manager.registration_cache_ttls.get(&procedure_id)).cloned()
+                 };
+                 let cache_entry = match cache_ttl {
+                     Some(ttl) => {
+                         let key = canonical_key(&args, &kwargs);
+                         if let Some((args, kwargs)) = manager.result_cache.get(&procedure.uri, &key) {
+                             debug!("[{}] Answering call to {} from the result cache", self.tracking_id, procedure.uri);
+                             return send_message(&self.info, &Message::Result(request_id, ResultDetails::new(), args, kwargs));
+                         }
+                         Some((procedure.uri.clone(), key, ttl))
+                     },
+                     None => None
+                 };
                  let mut details = InvocationDetails::new();
                  details.procedure = if policy == MatchingPolicy::Strict {
                      None
                  } else {
                      Some(procedure)
                  };
+                 if options.discloses_caller() || manager.disclosing_registrations.contains(&procedure_id) {
+                     let info = self.info.lock().unwrap();
+                     details.caller = Some(info.id);
+                     details.caller_authid = info.authid.clone();
+                     details.caller_authrole = info.authrole.clone();
+                 }
+                 if options.receives_progress() {
+                     details.receive_progress = Some(true);
+                 }
+                 let chunk_details = if options.is_progressive() {
+                     details.progress = Some(true);
+                     Some(details.clone())
+                 } else {
+                     None
+                 };
+                 manager.active_calls.insert(invocation_id, ActiveCall {
+                     request_id: request_id,
+                     registration_id: procedure_id,
+                     caller: self.info.clone(),
+                     callee: registrant.clone(),
+                     cache_entry: cache_entry,
+                     cancelled: false,
+                     receive_progress: options.receives_progress(),
+                     chunk_details: chunk_details
+                 });
                  let invocation_message = Message::Invocation(invocation_id, procedure_id, details, args, kwargs);
                  try!(send_message(registrant, &invocation_message));
 
@@ -88,24 +188,202 @@ impl ConnectionHandler{
          }
     }
 
-    pub fn handle_yield(&mut self, invocation_id: ID, _options: YieldOptions, args: Option<List>, kwargs: Option<Dict>) -> WampResult<()> {
-        debug!("Responding to yield message (id: {})", invocation_id);
+    /// Passes a chunk of a progressive call on to the callee handling the call, if the caller
+    /// has a call open with the request ID.  Every chunk is passed on with the details of the
+    /// first.
+    fn continue_progressive_call(&self, manager: &mut RegistrationManager, request_id: ID, options: &CallOptions, args: &Option<List>, kwargs: &Option<Dict>) -> Option<WampResult<()>> {
+        let info = &self.info;
+        let (invocation_id, call) = match manager.active_calls.iter_mut().find(|&(_, ref call)| call.chunk_details.is_some() && call.request_id == request_id && Arc::ptr_eq(&call.caller, info)) {
+            Some((invocation_id, call)) => (*invocation_id, call),
+            None => return None
+        };
+        debug!("[{}] Passing on a chunk of call {} (more follow: {})", self.tracking_id, request_id, options.is_progressive());
+        let details = if options.is_progressive() {
+            call.chunk_details.clone().unwrap()
+        } else {
+            let mut details = call.chunk_details.take().unwrap();
+            details.progress = None;
+            details
+        };
+        if call.cancelled {
+            return Some(Ok(()));
+        }
+        let invocation_message = Message::Invocation(invocation_id, call.registration_id, details, args.clone(), kwargs.clone());
+        Some(send_message(&call.callee, &invocation_message))
+    }
+
+    /// Whether this session's answer to the invocation `invocation_id` should be passed on.
+    /// Calls killed without waiting for the callee are forgotten straight away, so answers to
+    /// invocations that aren't active are dropped, but only the callee may answer a call.
+    pub fn answers_active_call(&self, manager: &RegistrationManager, invocation_id: ID) -> WampResult<bool> {
+        match manager.active_calls.get(&invocation_id) {
+            Some(call) if Arc::ptr_eq(&call.callee, &self.info) => Ok(true),
+            Some(_) => Err(Error::new(ErrorKind::InvalidState("Recieved an answer to an invocation sent to another session"))),
+            None => {
+                debug!("[{}] Dropping an answer to invocation {}, which is no longer active", self.tracking_id, invocation_id);
+                Ok(false)
+            }
+        }
+    }
+
+    pub fn handle_yield(&mut self, invocation_id: ID, options: YieldOptions, args: Option<List>, kwargs: Option<Dict>) -> WampResult<()> {
+        debug!("[{}] Responding to yield message (id: {})", self.tracking_id, invocation_id);
         match self.realm {
             Some(ref realm) => {
-                let mut realm = realm.lock().unwrap();
-                let mut manager = &mut realm.registration_manager;
-                if let Some((call_id, callee)) = manager.active_calls.remove(&invocation_id) {
-                    let result_message = Message::Result(call_id, ResultDetails::new(), args, kwargs);
-                    send_message(&callee, &result_message)
-                } else {
-                    Err(Error::new(ErrorKind::InvalidState("Recieved a yield message for a call that wasn't sent")))
+                let mut manager = realm.registration_manager.lock().unwrap();
+                if !try!(self.answers_active_call(&manager, invocation_id)) {
+                    return Ok(());
                 }
+                if options.is_progressive() {
+                    // Progressive results leave the call active, and are neither cached nor
+                    // passed on to callers that didn't ask for them
+                    let call = &manager.active_calls[&invocation_id];
+                    if call.cancelled || !call.receive_progress {
+                        debug!("[{}] Dropping a progressive result of call {}", self.tracking_id, call.request_id);
+                        return Ok(());
+                    }
+                    return send_message(&call.caller, &Message::Result(call.request_id, ResultDetails::progressive(), args, kwargs));
+                }
+                let call = manager.active_calls.remove(&invocation_id).unwrap();
+                if let Some((procedure, key, ttl)) = call.cache_entry {
+                    manager.result_cache.insert(procedure.to_string(), key, ttl, args.clone(), kwargs.clone());
+                }
+                if call.cancelled {
+                    debug!("[{}] Dropping the result of cancelled call {}", self.tracking_id, call.request_id);
+                    return Ok(());
+                }
+                let result_message = Message::Result(call.request_id, ResultDetails::new(), args, kwargs);
+                send_message(&call.caller, &result_message)
+            }, None => {
+                Err(Error::new(ErrorKind::InvalidState("Recieved a message while not attached to a realm")))
+            }
+        }
+    }
+
+    pub fn handle_cancel(&mut self, request_id: ID, options: CancelOptions) -> WampResult<()> {
+        let mode = options.mode();
+        debug!("[{}] Responding to cancel message (id: {}, mode: {:?})", self.tracking_id, request_id, mode);
+        match self.realm {
+            Some(ref realm) => {
+                let mut manager = realm.registration_manager.lock().unwrap();
+                let info = &self.info;
+                let invocation_id = manager.active_calls.iter()
+                    .find(|&(_, call)| call.request_id == request_id && !call.cancelled && Arc::ptr_eq(&call.caller, info))
+                    .map(|(invocation_id, _)| *invocation_id);
+                let invocation_id = match invocation_id {
+                    Some(invocation_id) => invocation_id,
+                    None => {
+                        // The call may have been answered while the cancel was on its way
+                        debug!("[{}] No call {} to cancel", self.tracking_id, request_id);
+                        return Ok(());
+                    }
+                };
+                let call = manager.active_calls.get_mut(&invocation_id).unwrap();
+                if mode != CancelMode::Skip {
+                    let interrupt_message = Message::Interrupt(invocation_id, InterruptOptions::new(mode));
+                    if let Err(e) = send_message(&call.callee, &interrupt_message) {
+                        warn!("[{}] Could not interrupt invocation {}: {}", self.tracking_id, invocation_id, e);
+                    }
+                }
+                let caller = call.caller.clone();
+                match mode {
+                    // The caller hears about the cancellation once the callee answers
+                    CancelMode::Kill => return Ok(()),
+                    // The callee still answers, which ends the call
+                    CancelMode::Skip => call.cancelled = true,
+                    // The callee needn't answer, so the call ends now
+                    CancelMode::KillNoWait => {
+                        manager.active_calls.remove(&invocation_id);
+                    }
+                }
+                send_message(&caller, &Message::Error(ErrorType::Call, request_id, HashMap::new(), Reason::Cancelled, None, None))
             }, None => {
                 Err(Error::new(ErrorKind::InvalidState("Recieved a message while not attached to a realm")))
             }
         }
     }
+}
+
+#[cfg(all(test, feature = "caller", feature = "callee"))]
+mod test {
+    use client::Reply;
+    use messages::{URI, CallOptions, CancelMode, Reason};
+    use testing::{start_router, join};
+    use std::collections::HashMap;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::Duration;
+    use ::Value;
+
+    #[test]
+    fn killed_calls_interrupt_the_callee() {
+        let (_router, url) = start_router();
+        let mut callee = join(&url);
+        let mut caller = join(&url);
+        let (interrupted, interrupts) = channel();
+        let (responded, responses) = channel();
+        callee.register_deferred(URI::new("ca.test.slow"), Box::new(move |_, _, responder| {
+            let interrupted = interrupted.clone();
+            let responded = responded.clone();
+            thread::spawn(move || {
+                interrupted.send(responder.cancellation_token().wait_timeout(Duration::from_secs(5))).unwrap();
+                // The caller has been told the call was cancelled, so this is dropped
+                responder.respond(Some(vec![Value::Integer(1)]), None).unwrap();
+                responded.send(()).unwrap();
+            });
+            Reply::Deferred
+        })).unwrap().wait().unwrap();
 
+        let call = caller.call(URI::new("ca.test.slow"), None, None).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        call.cancel(CancelMode::Kill).unwrap();
+        // The router only fails a killed call once the callee answers the interrupt
+        let error = call.wait_timeout(Duration::from_secs(5)).unwrap_err();
+        assert_eq!(*error.get_reason(), Reason::Cancelled);
+        assert!(interrupts.recv_timeout(Duration::from_secs(5)).unwrap());
 
+        let answered = caller.call(URI::new("ca.test.slow"), None, None).unwrap();
+        answered.cancel(CancelMode::KillNoWait).unwrap();
+        assert_eq!(*answered.wait_timeout(Duration::from_secs(5)).unwrap_err().get_reason(), Reason::Cancelled);
+        assert!(interrupts.recv_timeout(Duration::from_secs(5)).unwrap());
 
+        // The router has already forgotten the call, and drops the callee's late answer without
+        // disconnecting it
+        responses.recv_timeout(Duration::from_secs(5)).unwrap();
+        responses.recv_timeout(Duration::from_secs(5)).unwrap();
+        let killed = caller.call(URI::new("ca.test.slow"), None, None).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        killed.cancel(CancelMode::Kill).unwrap();
+        assert_eq!(*killed.wait_timeout(Duration::from_secs(5)).unwrap_err().get_reason(), Reason::Cancelled);
+        assert!(interrupts.recv_timeout(Duration::from_secs(5)).unwrap());
+    }
+
+    #[test]
+    fn progressive_calls_reach_the_callee_in_chunks() {
+        let (_router, url) = start_router();
+        let mut callee = join(&url);
+        let mut caller = join(&url);
+        let (chunks, received) = channel();
+        let mut sums = HashMap::new();
+        callee.register_progressive(URI::new("ca.test.sum"), Box::new(move |args, _, context| {
+            chunks.send(context.progress).unwrap();
+            let sum = sums.entry(context.request_id).or_insert(0);
+            *sum += args.iter().map(|arg| match *arg {
+                Value::Integer(value) => value,
+                _ => 0
+            }).sum::<i64>();
+            if context.progress {
+                Reply::Deferred
+            } else {
+                Reply::Done(Ok((Some(vec![Value::Integer(*sum)]), None)))
+            }
+        })).unwrap().wait().unwrap();
+
+        // Options that already ask for progress mustn't keep the last chunk open
+        let mut call = caller.call_progressive(URI::new("ca.test.sum"), Some(vec![Value::Integer(1)]), None, CallOptions::new().with_progress(true)).unwrap();
+        call.send(Some(vec![Value::Integer(2)]), None).unwrap();
+        let (args, _) = call.finish(Some(vec![Value::Integer(3)]), None).unwrap().wait_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(args, vec![Value::Integer(6)]);
+        assert_eq!(received.try_iter().collect::<Vec<_>>(), vec![true, true, false]);
+    }
 }
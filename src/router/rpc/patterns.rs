@@ -336,4 +336,22 @@ impl<P:PatternData> RegistrationPatternNode<P> {
         assert_eq!(root.get_registrant_for(URI::new("com.example.test.specific.topic")).unwrap().1, ids[1]);
 
      }
+
+     #[test]
+     fn sharing_registrations() {
+        let mut root = RegistrationPatternNode::new();
+        let id = root.register_with(&URI::new("com.example.work"), MockData::new(1), MatchingPolicy::Strict, InvocationPolicy::RoundRobin).unwrap();
+        assert_eq!(root.register_with(&URI::new("com.example.work"), MockData::new(2), MatchingPolicy::Strict, InvocationPolicy::RoundRobin).unwrap(), id);
+        assert!(root.register_with(&URI::new("com.example.work"), MockData::new(3), MatchingPolicy::Strict, InvocationPolicy::Last).is_err());
+
+        let picked: Vec<ID> = (0..4).map(|_| root.get_registrant_for(URI::new("com.example.work")).unwrap().0.get_id()).collect();
+        assert_eq!(picked, vec![1, 2, 1, 2]);
+
+        root.unregister_with("com.example.work", &MockData::new(1), false).unwrap();
+        assert_eq!(root.get_registrant_for(URI::new("com.example.work")).unwrap().0.get_id(), 2);
+
+        let id = root.register_with(&URI::new("com.example.single"), MockData::new(1), MatchingPolicy::Strict, InvocationPolicy::Single).unwrap();
+        assert!(root.register_with(&URI::new("com.example.single"), MockData::new(2), MatchingPolicy::Strict, InvocationPolicy::Single).is_err());
+        assert_eq!(root.get_registrant_for(URI::new("com.example.single")).unwrap().1, id);
+     }
  }
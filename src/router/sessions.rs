@@ -0,0 +1,55 @@
+//! Contains `SessionSummary`, which describes a live session for debugging.
+use super::{Router, ConnectionState};
+use ::ID;
+
+/// A snapshot of one of the router's sessions.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionSummary {
+    pub session_id: ID,
+    /// A short ID that appears in every log line the router writes about the session
+    pub tracking_id: String,
+    pub realm: String,
+    /// The authentication ID the client announced, if any
+    pub authid: Option<String>,
+    /// The role the session was given when it authenticated, if it did
+    pub authrole: Option<String>,
+    /// The client's description of itself, if it gave one
+    pub agent: Option<String>,
+    /// The websocket subprotocol the session uses, e.g. `wamp.2.json`
+    pub transport: String,
+    /// The address the client connected from, if it is known
+    pub peer: Option<String>,
+    pub messages_received: u64,
+    pub messages_sent: u64,
+    /// Events that were not delivered because the session's queue was full
+    pub dropped_events: u64
+}
+
+impl Router {
+    /// Lists every session that has joined a realm and not yet left it.
+    pub fn sessions(&self) -> Vec<SessionSummary> {
+        let mut sessions = Vec::new();
+        for (name, realm) in self.info.realms.lock().unwrap().iter() {
+            for connection in realm.connections.lock().unwrap().iter() {
+                let info = connection.lock().unwrap();
+                if info.state != ConnectionState::Connected {
+                    continue;
+                }
+                sessions.push(SessionSummary {
+                    session_id: info.id,
+                    tracking_id: info.tracking_id.clone(),
+                    realm: name.clone(),
+                    authid: info.authid.clone(),
+                    authrole: info.authrole.clone(),
+                    agent: info.agent.clone(),
+                    transport: info.protocol.clone(),
+                    peer: info.peer.clone(),
+                    messages_received: info.messages_received,
+                    messages_sent: info.messages_sent,
+                    dropped_events: info.dropped_events
+                });
+            }
+        }
+        sessions
+    }
+}
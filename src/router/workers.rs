@@ -0,0 +1,130 @@
+//! Contains the pool of worker threads that can handle the router's messages.
+//!
+//! By default, a listener handles every message on the thread running its event loop, one at a
+//! time.  With `Router::set_workers()`, each new connection is given to one of a pool of worker
+//! threads instead, in turn.  A connection's messages, timeouts and closing are always handled by
+//! the same worker, in the order the event loop saw them, while connections on different workers
+//! are handled in parallel.  The event loop itself only reads, writes and hands frames over.
+use super::ConnectionHandler;
+use ws::{Handler, Handshake, Message as WSMessage, Error as WSError, ErrorKind as WSErrorKind, Result as WSResult, Request, Response, CloseCode};
+use ws::util::Token;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::thread;
+
+/// Something the event loop saw happen to a connection.
+enum Job {
+    Message(WSMessage),
+    Timeout(Token),
+    Close(CloseCode, String)
+}
+
+type Task = (Arc<Mutex<ConnectionHandler>>, Job);
+
+/// The worker threads of one listener.
+pub struct WorkerPool {
+    workers: Vec<Sender<Task>>,
+    next: usize
+}
+
+impl WorkerPool {
+    /// Starts `workers` threads.  With none, connections are handled on the event loop.
+    pub fn new(workers: usize) -> WorkerPool {
+        let workers = (0..workers).map(|index| {
+            let (sender, receiver) = channel();
+            thread::Builder::new().name(format!("wamp-router-worker-{}", index)).spawn(move || run(receiver)).unwrap();
+            sender
+        }).collect();
+        WorkerPool {
+            workers: workers,
+            next: 0
+        }
+    }
+
+    /// Wraps the handler of a new connection, giving it to the next worker
+    pub fn dispatch(&mut self, handler: ConnectionHandler) -> Dispatcher {
+        let worker = if self.workers.is_empty() {
+            None
+        } else {
+            let worker = self.workers[self.next].clone();
+            self.next = (self.next + 1) % self.workers.len();
+            Some(worker)
+        };
+        let closed = handler.info.lock().unwrap().closed.clone();
+        Dispatcher {
+            handler: Arc::new(Mutex::new(handler)),
+            worker: worker,
+            closed: closed
+        }
+    }
+}
+
+fn handle(handler: &mut ConnectionHandler, job: Job) -> WSResult<()> {
+    match job {
+        Job::Message(message) => handler.on_message(message),
+        Job::Timeout(token) => handler.on_timeout(token),
+        Job::Close(code, reason) => {
+            handler.on_close(code, &reason);
+            Ok(())
+        }
+    }
+}
+
+fn run(tasks: Receiver<Task>) {
+    for (handler, job) in tasks {
+        let mut handler = handler.lock().unwrap();
+        let result = handle(&mut handler, job);
+        // The event loop would close the connection if the handler failed there
+        if let Err(e) = result {
+            error!("[{}] Closing the connection after an error: {}", handler.tracking_id, e);
+            handler.info.lock().unwrap().close(CloseCode::Error).ok();
+        }
+    }
+}
+
+/// The handler the event loop runs for a connection, which passes what happens to the connection
+/// on to its worker.  The WebSocket handshake is always handled on the event loop, since its
+/// response can't wait.
+pub struct Dispatcher {
+    handler: Arc<Mutex<ConnectionHandler>>,
+    // None when the listener has no workers
+    worker: Option<Sender<Task>>,
+    // The connection's closed flag, which can be set without waiting for its worker
+    closed: Arc<AtomicBool>
+}
+
+impl Dispatcher {
+    fn send(&mut self, job: Job) -> WSResult<()> {
+        match self.worker {
+            Some(ref worker) => worker.send((self.handler.clone(), job)).map_err(|_| WSError::new(WSErrorKind::Internal, "The router's worker has stopped")),
+            None => handle(&mut self.handler.lock().unwrap(), job)
+        }
+    }
+}
+
+impl Handler for Dispatcher {
+    fn on_request(&mut self, request: &Request) -> WSResult<Response> {
+        self.handler.lock().unwrap().on_request(request)
+    }
+
+    fn on_open(&mut self, handshake: Handshake) -> WSResult<()> {
+        self.handler.lock().unwrap().on_open(handshake)
+    }
+
+    fn on_message(&mut self, message: WSMessage) -> WSResult<()> {
+        self.send(Job::Message(message))
+    }
+
+    fn on_timeout(&mut self, token: Token) -> WSResult<()> {
+        self.send(Job::Timeout(token))
+    }
+
+    fn on_close(&mut self, code: CloseCode, reason: &str) {
+        // The token is free for the next connection from now on, so nothing may use the sender
+        self.closed.store(true, Ordering::SeqCst);
+        if let Err(e) = self.send(Job::Close(code, reason.to_string())) {
+            warn!("Could not pass on the closing of a connection: {}", e);
+        }
+    }
+}
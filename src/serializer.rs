@@ -0,0 +1,237 @@
+//! Contains the `Serializer` trait, which turns messages into websocket frames and back, and its
+//! implementations for the serializations WAMP defines, JSON, MsgPack and CBOR.
+//!
+//! A connection picks its serializer once, from the subprotocol chosen in the websocket
+//! handshake, and writes and reads every message through it.  Custom codecs are used through the
+//! same trait, so nothing else needs to know which serialization a connection speaks.
+//!
+//! JSON is written in text frames and MsgPack and CBOR in binary frames, as the specification
+//! requires, and a frame of the wrong kind is a protocol violation.  Binary values are base64
+//! strings in JSON, as WAMP defines, and binary values in MsgPack and CBOR.
+use codec::{Codec, Frame};
+use messages::{Message, to_msgpack, to_cbor};
+use messages::validation::{ProtocolViolation, validate_json, validate_msgpack, validate_cbor};
+use rmp_serde::Deserializer as RMPDeserializer;
+use serde::Deserialize;
+use serde_cbor;
+use serde_json;
+use std::io::Cursor;
+use std::sync::Arc;
+use ::{WampResult, Error, ErrorKind};
+
+pub const WAMP_JSON: &'static str = "wamp.2.json";
+pub const WAMP_MSGPACK: &'static str = "wamp.2.msgpack";
+pub const WAMP_CBOR: &'static str = "wamp.2.cbor";
+
+/// The serializations WAMP defines.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Serialization {
+    Json,
+    MsgPack,
+    Cbor
+}
+
+/// Converts WAMP messages to and from websocket frames for one subprotocol.
+pub trait Serializer: Send + Sync {
+    /// The websocket subprotocol the serializer implements
+    fn protocol(&self) -> &str;
+
+    /// Which of WAMP's serializations the serializer writes, or `None` for custom codecs
+    fn serialization(&self) -> Option<Serialization> {
+        None
+    }
+
+    fn encode(&self, message: &Message) -> WampResult<Frame>;
+
+    fn decode(&self, frame: Frame) -> WampResult<Message>;
+}
+
+pub struct JsonSerializer;
+
+pub struct MsgPackSerializer;
+
+pub struct CborSerializer;
+
+/// Uses a custom codec as a serializer.
+pub struct CodecSerializer {
+    codec: Arc<Codec>
+}
+
+fn wrong_frame(expected: &str, got: &str) -> Error {
+    Error::new(ErrorKind::ProtocolViolation(ProtocolViolation {
+        message: None,
+        field: None,
+        expected: expected.to_string(),
+        got: got.to_string()
+    }))
+}
+
+impl Serializer for JsonSerializer {
+    fn protocol(&self) -> &str {
+        WAMP_JSON
+    }
+
+    fn serialization(&self) -> Option<Serialization> {
+        Some(Serialization::Json)
+    }
+
+    fn encode(&self, message: &Message) -> WampResult<Frame> {
+        serde_json::to_string(message).map(Frame::Text).map_err(|e| Error::new(ErrorKind::JSONError(e)))
+    }
+
+    /// Decodes a text frame.  A message that doesn't parse is explained as a protocol violation
+    /// if it can be.
+    fn decode(&self, frame: Frame) -> WampResult<Message> {
+        let payload = match frame {
+            Frame::Text(payload) => payload,
+            Frame::Binary(_) => return Err(wrong_frame("a text frame", "a binary frame"))
+        };
+        serde_json::from_str(&payload).map_err(|e| match validate_json(&payload) {
+            Err(violation) => Error::new(ErrorKind::ProtocolViolation(violation)),
+            Ok(()) => Error::new(ErrorKind::JSONError(e))
+        })
+    }
+}
+
+impl Serializer for MsgPackSerializer {
+    fn protocol(&self) -> &str {
+        WAMP_MSGPACK
+    }
+
+    fn serialization(&self) -> Option<Serialization> {
+        Some(Serialization::MsgPack)
+    }
+
+    fn encode(&self, message: &Message) -> WampResult<Frame> {
+        to_msgpack(message).map(Frame::Binary).map_err(|e| Error::new(ErrorKind::MsgPackEncodeError(e)))
+    }
+
+    /// Decodes a binary frame, explaining messages that don't parse like `JsonSerializer`
+    fn decode(&self, frame: Frame) -> WampResult<Message> {
+        let payload = match frame {
+            Frame::Binary(payload) => payload,
+            Frame::Text(_) => return Err(wrong_frame("a binary frame", "a text frame"))
+        };
+        let mut de = RMPDeserializer::new(Cursor::new(&payload[..]));
+        Deserialize::deserialize(&mut de).map_err(|e| match validate_msgpack(&payload) {
+            Err(violation) => Error::new(ErrorKind::ProtocolViolation(violation)),
+            Ok(()) => Error::new(ErrorKind::MsgPackError(e))
+        })
+    }
+}
+
+impl Serializer for CborSerializer {
+    fn protocol(&self) -> &str {
+        WAMP_CBOR
+    }
+
+    fn serialization(&self) -> Option<Serialization> {
+        Some(Serialization::Cbor)
+    }
+
+    fn encode(&self, message: &Message) -> WampResult<Frame> {
+        to_cbor(message).map(Frame::Binary).map_err(|e| Error::new(ErrorKind::CBORError(e)))
+    }
+
+    /// Decodes a binary frame, explaining messages that don't parse like `JsonSerializer`
+    fn decode(&self, frame: Frame) -> WampResult<Message> {
+        let payload = match frame {
+            Frame::Binary(payload) => payload,
+            Frame::Text(_) => return Err(wrong_frame("a binary frame", "a text frame"))
+        };
+        serde_cbor::from_slice(&payload).map_err(|e| match validate_cbor(&payload) {
+            Err(violation) => Error::new(ErrorKind::ProtocolViolation(violation)),
+            Ok(()) => Error::new(ErrorKind::CBORError(e))
+        })
+    }
+}
+
+impl CodecSerializer {
+    pub fn new(codec: Arc<Codec>) -> CodecSerializer {
+        CodecSerializer {
+            codec: codec
+        }
+    }
+}
+
+impl Serializer for CodecSerializer {
+    fn protocol(&self) -> &str {
+        self.codec.protocol()
+    }
+
+    fn encode(&self, message: &Message) -> WampResult<Frame> {
+        self.codec.encode(message).map_err(|e| Error::new(ErrorKind::CodecError(e)))
+    }
+
+    fn decode(&self, frame: Frame) -> WampResult<Message> {
+        self.codec.decode(frame).map_err(|e| Error::new(ErrorKind::CodecError(e)))
+    }
+}
+
+/// The serializer for `protocol`, which is either one of WAMP's serializations or implemented by
+/// one of `codecs`
+pub fn for_protocol(protocol: &str, codecs: &[Arc<Codec>]) -> Option<Arc<Serializer>> {
+    if protocol == WAMP_JSON {
+        return Some(Arc::new(JsonSerializer));
+    }
+    if protocol == WAMP_MSGPACK {
+        return Some(Arc::new(MsgPackSerializer));
+    }
+    if protocol == WAMP_CBOR {
+        return Some(Arc::new(CborSerializer));
+    }
+    codecs.iter().find(|codec| codec.protocol() == protocol).map(|codec| Arc::new(CodecSerializer::new(codec.clone())) as Arc<Serializer>)
+}
+
+/// Checks a frame in strict validation mode.  Frames for custom codecs can't be checked.
+pub fn validate(serializer: &Serializer, frame: &Frame) -> Result<(), ProtocolViolation> {
+    match (serializer.serialization(), frame) {
+        (Some(Serialization::Json), &Frame::Text(ref payload)) => validate_json(payload),
+        (Some(Serialization::MsgPack), &Frame::Binary(ref payload)) => validate_msgpack(payload),
+        (Some(Serialization::Cbor), &Frame::Binary(ref payload)) => validate_cbor(payload),
+        _ => Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Serializer, JsonSerializer, MsgPackSerializer, CborSerializer};
+    use codec::Frame;
+    use messages::{Message, ErrorDetails, Reason, Value};
+    use ::ErrorKind;
+
+    #[test]
+    fn serializers_round_trip_and_check_frames() {
+        let message = Message::Publish(7, ::PublishOptions::new(false), ::URI::new("ca.test"), Some(vec![Value::Bytes(vec![1, 2, 3]), Value::Integer(-2)]), None);
+        for serializer in [&JsonSerializer as &Serializer, &MsgPackSerializer, &CborSerializer].iter() {
+            let frame = serializer.encode(&message).unwrap();
+            assert_eq!(serializer.decode(frame).unwrap(), message);
+        }
+        match JsonSerializer.encode(&message).unwrap() {
+            Frame::Text(text) => assert!(text.contains("\"\\u0000AQID\"")),
+            frame => panic!("JSON was written as {:?}", frame)
+        }
+
+        // Each serialization has its own kind of frame
+        let goodbye = Message::Goodbye(ErrorDetails::new(), Reason::GoodbyeAndOut);
+        let binary = MsgPackSerializer.encode(&goodbye).unwrap();
+        match JsonSerializer.decode(binary).unwrap_err().get_kind() {
+            ErrorKind::ProtocolViolation(violation) => assert_eq!(violation.got, "a binary frame"),
+            kind => panic!("Unexpected error {:?}", kind)
+        }
+        match MsgPackSerializer.decode(Frame::Binary(vec![0xc1])).unwrap_err().get_kind() {
+            ErrorKind::ProtocolViolation(_) | ErrorKind::MsgPackError(_) => {},
+            kind => panic!("Unexpected error {:?}", kind)
+        }
+        match CborSerializer.decode(Frame::Binary(vec![0x82, 0x06])).unwrap_err().get_kind() {
+            ErrorKind::ProtocolViolation(_) | ErrorKind::CBORError(_) => {},
+            kind => panic!("Unexpected error {:?}", kind)
+        }
+
+        // CBOR writes binary values as byte strings
+        match CborSerializer.encode(&message).unwrap() {
+            Frame::Binary(data) => assert!(data.windows(4).any(|bytes| bytes == [0x43, 1, 2, 3])),
+            frame => panic!("CBOR was written as {:?}", frame)
+        }
+    }
+}
@@ -0,0 +1,253 @@
+//! Contains the `Store` trait, which durable publish queues and router retention keep their
+//! records in, along with an in-memory store and a file-backed one.
+//!
+//! A store is an append-only log of opaque records.  Each record is given a sequence number when
+//! it is appended, and stays in the store until it is acknowledged.  Acknowledged records may
+//! still take up space until the store is compacted, so that a backend can make acknowledging
+//! cheap.  Other backends, such as sled or SQLite, can be used by implementing the trait.
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use utils::{base64_encode, base64_decode};
+use ::{WampResult, Error, ErrorKind};
+
+pub trait Store: Send {
+    /// Stores a record, and returns its sequence number.  Sequence numbers increase with every
+    /// record appended to the store.
+    fn append(&mut self, record: &[u8]) -> WampResult<u64>;
+
+    /// The records that haven't been acknowledged, with their sequence numbers, oldest first
+    fn iter<'a>(&'a self) -> Box<Iterator<Item = (u64, Vec<u8>)> + 'a>;
+
+    /// Removes a record from the store.  Acknowledging a record that isn't in the store does
+    /// nothing.
+    fn ack(&mut self, sequence: u64) -> WampResult<()>;
+
+    /// Reclaims the space taken up by acknowledged records
+    fn compact(&mut self) -> WampResult<()>;
+}
+
+/// Keeps records in memory, so they last only as long as the process.  This is the default
+/// store.
+pub struct MemoryStore {
+    records: BTreeMap<u64, Vec<u8>>,
+    next_sequence: u64
+}
+
+/// Keeps records in an append-only file, which is rewritten without the acknowledged records
+/// when the store is compacted.
+///
+/// Every line of the file either adds a record, as `+<sequence> <base64 data>`, or acknowledges
+/// one, as `-<sequence>`.  A compacted file starts with `=<sequence>`, the sequence number of the
+/// next record, so that numbers aren't reused.  Each line is synced to disk before `append` or
+/// `ack` returns.
+pub struct FileLogStore {
+    path: PathBuf,
+    file: File,
+    records: MemoryStore
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore {
+            records: BTreeMap::new(),
+            next_sequence: 1
+        }
+    }
+
+    fn insert(&mut self, sequence: u64, record: Vec<u8>) {
+        self.records.insert(sequence, record);
+        if sequence >= self.next_sequence {
+            self.next_sequence = sequence + 1;
+        }
+    }
+}
+
+impl Store for MemoryStore {
+    fn append(&mut self, record: &[u8]) -> WampResult<u64> {
+        let sequence = self.next_sequence;
+        self.insert(sequence, record.to_vec());
+        Ok(sequence)
+    }
+
+    fn iter<'a>(&'a self) -> Box<Iterator<Item = (u64, Vec<u8>)> + 'a> {
+        Box::new(self.records.iter().map(|(sequence, record)| (*sequence, record.clone())))
+    }
+
+    fn ack(&mut self, sequence: u64) -> WampResult<()> {
+        self.records.remove(&sequence);
+        Ok(())
+    }
+
+    fn compact(&mut self) -> WampResult<()> {
+        Ok(())
+    }
+}
+
+impl FileLogStore {
+    /// Opens the store kept in the file at `path`, creating the file if it doesn't exist.
+    ///
+    /// A final line that was only partly written, because the process stopped while writing it,
+    /// is ignored.  Any other line that can't be read is an error.
+    pub fn open<P: Into<PathBuf>>(path: P) -> WampResult<FileLogStore> {
+        let path = path.into();
+        let mut records = MemoryStore::new();
+        let mut torn = false;
+        if path.exists() {
+            let mut contents = String::new();
+            let mut file = try!(File::open(&path).map_err(|e| Error::new(ErrorKind::IOError(e))));
+            try!(file.read_to_string(&mut contents).map_err(|e| Error::new(ErrorKind::IOError(e))));
+            let mut lines: Vec<&str> = contents.split('\n').collect();
+            // Every complete line ends with a newline, so anything after the last one is torn
+            torn = !lines.pop().unwrap_or("").is_empty();
+            for line in lines {
+                if !apply_line(&mut records, line) {
+                    return Err(Error::new(ErrorKind::InvalidState("Store file contains a line that isn't a record")));
+                }
+            }
+        }
+        let file = try!(open_for_append(&path));
+        let mut store = FileLogStore {
+            path: path,
+            file: file,
+            records: records
+        };
+        if torn {
+            warn!("Ignoring a partly written record at the end of {}", store.path.display());
+            try!(store.compact());
+        }
+        Ok(store)
+    }
+
+    fn write_line(&mut self, line: &str) -> WampResult<()> {
+        try!(self.file.write_all(line.as_bytes()).map_err(|e| Error::new(ErrorKind::IOError(e))));
+        self.file.sync_data().map_err(|e| Error::new(ErrorKind::IOError(e)))
+    }
+}
+
+impl Store for FileLogStore {
+    fn append(&mut self, record: &[u8]) -> WampResult<u64> {
+        let sequence = self.records.next_sequence;
+        try!(self.write_line(&format!("+{} {}\n", sequence, base64_encode(record))));
+        self.records.insert(sequence, record.to_vec());
+        Ok(sequence)
+    }
+
+    fn iter<'a>(&'a self) -> Box<Iterator<Item = (u64, Vec<u8>)> + 'a> {
+        self.records.iter()
+    }
+
+    fn ack(&mut self, sequence: u64) -> WampResult<()> {
+        if self.records.records.contains_key(&sequence) {
+            try!(self.write_line(&format!("-{}\n", sequence)));
+            self.records.records.remove(&sequence);
+        }
+        Ok(())
+    }
+
+    fn compact(&mut self) -> WampResult<()> {
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(".tmp");
+        {
+            let mut file = try!(File::create(&temp_path).map_err(|e| Error::new(ErrorKind::IOError(e))));
+            try!(file.write_all(format!("={}\n", self.records.next_sequence).as_bytes()).map_err(|e| Error::new(ErrorKind::IOError(e))));
+            for (sequence, record) in self.records.records.iter() {
+                try!(file.write_all(format!("+{} {}\n", sequence, base64_encode(record)).as_bytes()).map_err(|e| Error::new(ErrorKind::IOError(e))));
+            }
+            try!(file.sync_all().map_err(|e| Error::new(ErrorKind::IOError(e))));
+        }
+        try!(fs::rename(&temp_path, &self.path).map_err(|e| Error::new(ErrorKind::IOError(e))));
+        self.file = try!(open_for_append(&self.path));
+        Ok(())
+    }
+}
+
+fn open_for_append(path: &PathBuf) -> WampResult<File> {
+    OpenOptions::new().create(true).append(true).open(path).map_err(|e| Error::new(ErrorKind::IOError(e)))
+}
+
+/// Applies one line of a store file, returning false if it isn't a valid line
+fn apply_line(records: &mut MemoryStore, line: &str) -> bool {
+    if line.starts_with('+') {
+        let mut parts = line[1..].splitn(2, ' ');
+        let sequence = parts.next().and_then(|sequence| sequence.parse::<u64>().ok());
+        let record = parts.next().and_then(base64_decode);
+        match (sequence, record) {
+            (Some(sequence), Some(record)) => {
+                records.insert(sequence, record);
+                true
+            },
+            _ => false
+        }
+    } else if line.starts_with('=') {
+        match line[1..].parse::<u64>() {
+            Ok(sequence) => {
+                records.next_sequence = sequence;
+                true
+            },
+            Err(_) => false
+        }
+    } else if line.starts_with('-') {
+        match line[1..].parse::<u64>() {
+            Ok(sequence) => {
+                records.records.remove(&sequence);
+                true
+            },
+            Err(_) => false
+        }
+    } else {
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Store, MemoryStore, FileLogStore};
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+
+    fn contents(store: &Store) -> Vec<(u64, Vec<u8>)> {
+        store.iter().collect()
+    }
+
+    #[test]
+    fn memory_store() {
+        let mut store = MemoryStore::new();
+        assert_eq!(store.append(b"one").unwrap(), 1);
+        assert_eq!(store.append(b"two").unwrap(), 2);
+        store.ack(1).unwrap();
+        store.ack(7).unwrap();
+        assert_eq!(contents(&store), vec![(2, b"two".to_vec())]);
+    }
+
+    #[test]
+    fn file_store_survives_reopening() {
+        let path = env::temp_dir().join(format!("wamp-store-{}.log", ::std::process::id()));
+        fs::remove_file(&path).ok();
+        {
+            let mut store = FileLogStore::open(path.clone()).unwrap();
+            store.append(b"one").unwrap();
+            store.append(b"two").unwrap();
+            store.append(&[0, 255, 10]).unwrap();
+            store.ack(2).unwrap();
+        }
+        // A line cut short by a crash is ignored
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(b"+4 AAE").unwrap();
+        let mut store = FileLogStore::open(path.clone()).unwrap();
+        assert_eq!(contents(&store), vec![(1, b"one".to_vec()), (3, vec![0, 255, 10])]);
+
+        store.ack(1).unwrap();
+        store.ack(3).unwrap();
+        store.compact().unwrap();
+        drop(store);
+        // Sequence numbers of compacted records aren't reused
+        let mut store = FileLogStore::open(path.clone()).unwrap();
+        assert_eq!(store.append(b"four").unwrap(), 4);
+        drop(store);
+        let store = FileLogStore::open(path.clone()).unwrap();
+        assert_eq!(contents(&store), vec![(4, b"four".to_vec())]);
+        fs::remove_file(path).unwrap();
+    }
+}
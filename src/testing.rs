@@ -0,0 +1,139 @@
+//! Fixtures shared by the tests that talk to a router, real or scripted
+// Which fixtures are used depends on the features being tested
+#![allow(dead_code)]
+
+use client::{Client, Connection};
+use messages::{Message, WelcomeDetails, RouterRoles};
+#[cfg(feature = "router")]
+use router::Router;
+use serde_json;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::thread;
+use std::time::Duration;
+use ws;
+
+/// The realm every test router serves
+pub const REALM: &'static str = "ca.test";
+
+/// Starts a router for `ca.test` on a free loopback port, and returns it with its URL once it
+/// is listening.  The router keeps listening until the tests end.
+#[cfg(feature = "router")]
+pub fn start_router() -> (Router, String) {
+    let mut router = Router::new();
+    router.add_realm(REALM);
+    router.set_workers(2);
+    let address = free_address();
+    router.listen(&address);
+    wait_for_listener(&address);
+    (router, format!("ws://{}/ws", address))
+}
+
+/// Joins `ca.test` on the router at `url`
+pub fn join(url: &str) -> Client {
+    Connection::new(url, REALM).connect().unwrap()
+}
+
+/// A free loopback address to listen on.  Servers don't say which port they listen on, so one
+/// is found first.
+pub fn free_address() -> String {
+    let port = TcpListener::bind("127.0.0.1:0").and_then(|listener| listener.local_addr()).unwrap().port();
+    format!("127.0.0.1:{}", port)
+}
+
+/// Waits until something accepts connections on `address`.  The probe isn't a websocket
+/// handshake, so the server drops it rather than keeping it around as a connection.
+pub fn wait_for_listener(address: &str) {
+    for _ in 0..500 {
+        if let Ok(mut probe) = TcpStream::connect(address) {
+            probe.set_read_timeout(Some(Duration::from_secs(1))).ok();
+            probe.write_all(b"GET / HTTP/1.1\r\n\r\n").ok();
+            probe.read_to_end(&mut Vec::new()).ok();
+            return
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("Nothing started listening on {}", address);
+}
+
+/// A router that welcomes the client and accepts its registrations, giving each the ID of its
+/// REGISTER request, and leaves the rest of its side of the conversation to the test
+pub struct ScriptedRouter {
+    pub url: String,
+    out: ws::Sender,
+    received: Receiver<String>
+}
+
+struct ScriptedConnection {
+    out: ws::Sender,
+    received: Sender<String>
+}
+
+impl ws::Handler for ScriptedConnection {
+    fn on_request(&mut self, request: &ws::Request) -> ws::Result<ws::Response> {
+        let mut response = try!(ws::Response::from_request(request));
+        response.set_protocol("wamp.2.json");
+        Ok(response)
+    }
+
+    fn on_message(&mut self, message: ws::Message) -> ws::Result<()> {
+        let text = try!(message.into_text());
+        match serde_json::from_str(&text) {
+            Ok(Message::Hello(..)) => {
+                self.out.send(serde_json::to_string(&Message::Welcome(1, WelcomeDetails::new(RouterRoles::new()))).unwrap())
+            },
+            Ok(Message::Register(request_id, ..)) => {
+                self.out.send(serde_json::to_string(&Message::Registered(request_id, request_id)).unwrap())
+            },
+            _ => {
+                self.received.send(text).ok();
+                Ok(())
+            }
+        }
+    }
+}
+
+impl ScriptedRouter {
+    pub fn start() -> ScriptedRouter {
+        let (received, messages) = channel();
+        let socket = ws::WebSocket::new(move |out| ScriptedConnection {
+            out: out,
+            received: received.clone()
+        }).unwrap();
+        let out = socket.broadcaster();
+        let address = free_address();
+        let listen_address = address.clone();
+        thread::spawn(move || {
+            socket.listen(&listen_address[..]).unwrap();
+        });
+        wait_for_listener(&address);
+        ScriptedRouter {
+            url: format!("ws://{}/ws", address),
+            out: out,
+            received: messages
+        }
+    }
+
+    /// Sends `message` to every client connected to the router
+    pub fn send(&self, message: &Message) {
+        self.out.send(serde_json::to_string(message).unwrap()).unwrap();
+    }
+
+    /// Closes the connection to every client
+    pub fn close(&self) {
+        self.out.close(ws::CloseCode::Normal).unwrap();
+    }
+
+    /// The next message a client sent that the router didn't answer itself
+    pub fn next(&self) -> String {
+        self.received.recv_timeout(Duration::from_secs(5)).unwrap()
+    }
+
+    /// Checks that the clients have nothing more to send
+    pub fn assert_quiet(&self) {
+        if let Ok(message) = self.received.recv_timeout(Duration::from_millis(200)) {
+            panic!("Unexpected message {}", message);
+        }
+    }
+}
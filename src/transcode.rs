@@ -0,0 +1,341 @@
+//! Converts payloads between JSON and MsgPack without building an intermediate `Value` tree.
+//!
+//! `Transcoder` wraps a deserializer so that it can be handed to any serializer, which then
+//! receives each value as the deserializer reads it.  This works for every serializer that
+//! accepts sequences and maps of unknown length, such as JSON's.  MsgPack writes the length of
+//! each sequence and map before its contents, which JSON doesn't give, so `json_to_msgpack` uses
+//! its own writer that buffers each container's contents until their length is known.
+//!
+//! MsgPack has binary values but JSON doesn't, so WAMP sends binary values over JSON as strings
+//! holding a NUL character followed by the base64 encoded bytes.  The transcoder can convert
+//! between the two, as set by `BinaryConversion`.
+use serde::de::{self, Deserializer, DeserializeSeed, Visitor, SeqVisitor, MapVisitor};
+use serde::ser::{self, Serialize, Serializer, SerializeSeq, SerializeMap};
+use serde_json;
+use rmp::encode;
+use rmp_serde::Deserializer as RMPDeserializer;
+use std::cell::RefCell;
+use std::fmt;
+use std::io::Cursor;
+use utils::{base64_encode, base64_decode};
+
+/// How binary values are treated while transcoding.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryConversion {
+    /// Binary values and strings are passed through as they are
+    Unchanged,
+    /// Strings holding base64 encoded bytes, as WAMP encodes binary values in JSON, become
+    /// binary values
+    FromBase64,
+    /// Binary values become strings holding base64 encoded bytes
+    ToBase64
+}
+
+/// Serializes whatever its deserializer reads.  A transcoder can only be serialized once.
+pub struct Transcoder<D> {
+    deserializer: RefCell<Option<D>>,
+    binary: BinaryConversion
+}
+
+impl<D> Transcoder<D> {
+    pub fn new(deserializer: D) -> Transcoder<D> {
+        Transcoder {
+            deserializer: RefCell::new(Some(deserializer)),
+            binary: BinaryConversion::Unchanged
+        }
+    }
+
+    pub fn with_binary(mut self, binary: BinaryConversion) -> Transcoder<D> {
+        self.binary = binary;
+        self
+    }
+}
+
+impl<D> Serialize for Transcoder<D> where D: Deserializer {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        match self.deserializer.borrow_mut().take() {
+            Some(deserializer) => deserializer.deserialize(TranscodingVisitor {
+                serializer: serializer,
+                binary: self.binary
+            }).map_err(|e| ser::Error::custom(e.to_string())),
+            None => Err(ser::Error::custom("A transcoder can only be serialized once"))
+        }
+    }
+}
+
+/// Reads everything `deserializer` produces and writes it to `serializer`
+pub fn transcode<D, S>(deserializer: D, serializer: S, binary: BinaryConversion) -> Result<S::Ok, S::Error> where D: Deserializer, S: Serializer {
+    Transcoder::new(deserializer).with_binary(binary).serialize(serializer)
+}
+
+/// Converts a MsgPack payload to JSON, encoding binary values as WAMP does
+pub fn msgpack_to_json(data: &[u8]) -> Result<String, String> {
+    let mut de = RMPDeserializer::new(Cursor::new(data));
+    let mut json = Vec::with_capacity(data.len() * 2);
+    try!(transcode(&mut de, &mut serde_json::Serializer::new(&mut json), BinaryConversion::ToBase64).map_err(|e| e.to_string()));
+    String::from_utf8(json).map_err(|e| e.to_string())
+}
+
+/// Converts a JSON payload to MsgPack, decoding binary values from the strings WAMP encodes them
+/// as
+pub fn json_to_msgpack(json: &str) -> Result<Vec<u8>, String> {
+    let mut de = serde_json::Deserializer::from_str(json);
+    let mut data = Vec::with_capacity(json.len());
+    try!(de::Deserializer::deserialize(&mut de, MsgPackWriter { buf: &mut data }).map_err(|e| e.to_string()));
+    try!(de.end().map_err(|e| e.to_string()));
+    Ok(data)
+}
+
+/// Decodes a string holding base64 encoded bytes, as WAMP encodes binary values in JSON
+fn binary_from_str(value: &str) -> Option<Vec<u8>> {
+    if value.starts_with('\0') {
+        base64_decode(&value[1..])
+    } else {
+        None
+    }
+}
+
+fn binary_to_string(value: &[u8]) -> String {
+    format!("\0{}", base64_encode(value))
+}
+
+/// The number of elements a sequence or map will have, if it is known
+fn exact_len(size_hint: (usize, Option<usize>)) -> Option<usize> {
+    match size_hint {
+        (lower, Some(upper)) if lower == upper => Some(lower),
+        _ => None
+    }
+}
+
+struct TranscodingVisitor<S> {
+    serializer: S,
+    binary: BinaryConversion
+}
+
+impl<S> TranscodingVisitor<S> where S: Serializer {
+    fn done<E>(result: Result<S::Ok, S::Error>) -> Result<S::Ok, E> where E: de::Error {
+        result.map_err(|e| E::custom(e.to_string()))
+    }
+}
+
+impl<S> Visitor for TranscodingVisitor<S> where S: Serializer {
+    type Value = S::Ok;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("any value")
+    }
+
+    fn visit_bool<E>(self, value: bool) -> Result<S::Ok, E> where E: de::Error {
+        Self::done(self.serializer.serialize_bool(value))
+    }
+
+    fn visit_i64<E>(self, value: i64) -> Result<S::Ok, E> where E: de::Error {
+        Self::done(self.serializer.serialize_i64(value))
+    }
+
+    fn visit_u64<E>(self, value: u64) -> Result<S::Ok, E> where E: de::Error {
+        Self::done(self.serializer.serialize_u64(value))
+    }
+
+    fn visit_f64<E>(self, value: f64) -> Result<S::Ok, E> where E: de::Error {
+        Self::done(self.serializer.serialize_f64(value))
+    }
+
+    fn visit_str<E>(self, value: &str) -> Result<S::Ok, E> where E: de::Error {
+        if self.binary == BinaryConversion::FromBase64 {
+            if let Some(bytes) = binary_from_str(value) {
+                return Self::done(self.serializer.serialize_bytes(&bytes));
+            }
+        }
+        Self::done(self.serializer.serialize_str(value))
+    }
+
+    fn visit_bytes<E>(self, value: &[u8]) -> Result<S::Ok, E> where E: de::Error {
+        if self.binary == BinaryConversion::ToBase64 {
+            Self::done(self.serializer.serialize_str(&binary_to_string(value)))
+        } else {
+            Self::done(self.serializer.serialize_bytes(value))
+        }
+    }
+
+    fn visit_unit<E>(self) -> Result<S::Ok, E> where E: de::Error {
+        Self::done(self.serializer.serialize_unit())
+    }
+
+    fn visit_none<E>(self) -> Result<S::Ok, E> where E: de::Error {
+        Self::done(self.serializer.serialize_none())
+    }
+
+    fn visit_some<D>(self, deserializer: D) -> Result<S::Ok, D::Error> where D: Deserializer {
+        Self::done(self.serializer.serialize_some(&Transcoder::new(deserializer).with_binary(self.binary)))
+    }
+
+    fn visit_seq<V>(self, mut visitor: V) -> Result<S::Ok, V::Error> where V: SeqVisitor {
+        let mut seq = try!(self.serializer.serialize_seq(exact_len(visitor.size_hint())).map_err(|e| de::Error::custom(e.to_string())));
+        while let Some(()) = try!(visitor.visit_seed(ElementSeed { seq: &mut seq, binary: self.binary })) {}
+        seq.end().map_err(|e| de::Error::custom(e.to_string()))
+    }
+
+    fn visit_map<V>(self, mut visitor: V) -> Result<S::Ok, V::Error> where V: MapVisitor {
+        let mut map = try!(self.serializer.serialize_map(exact_len(visitor.size_hint())).map_err(|e| de::Error::custom(e.to_string())));
+        while let Some(()) = try!(visitor.visit_key_seed(KeySeed { map: &mut map, binary: self.binary })) {
+            try!(visitor.visit_value_seed(ValueSeed { map: &mut map, binary: self.binary }));
+        }
+        map.end().map_err(|e| de::Error::custom(e.to_string()))
+    }
+}
+
+struct ElementSeed<'a, S: 'a> {
+    seq: &'a mut S,
+    binary: BinaryConversion
+}
+
+impl<'a, S> DeserializeSeed for ElementSeed<'a, S> where S: SerializeSeq {
+    type Value = ();
+
+    fn deserialize<D>(self, deserializer: D) -> Result<(), D::Error> where D: Deserializer {
+        self.seq.serialize_element(&Transcoder::new(deserializer).with_binary(self.binary)).map_err(|e| de::Error::custom(e.to_string()))
+    }
+}
+
+struct KeySeed<'a, S: 'a> {
+    map: &'a mut S,
+    binary: BinaryConversion
+}
+
+impl<'a, S> DeserializeSeed for KeySeed<'a, S> where S: SerializeMap {
+    type Value = ();
+
+    fn deserialize<D>(self, deserializer: D) -> Result<(), D::Error> where D: Deserializer {
+        self.map.serialize_key(&Transcoder::new(deserializer).with_binary(self.binary)).map_err(|e| de::Error::custom(e.to_string()))
+    }
+}
+
+struct ValueSeed<'a, S: 'a> {
+    map: &'a mut S,
+    binary: BinaryConversion
+}
+
+impl<'a, S> DeserializeSeed for ValueSeed<'a, S> where S: SerializeMap {
+    type Value = ();
+
+    fn deserialize<D>(self, deserializer: D) -> Result<(), D::Error> where D: Deserializer {
+        self.map.serialize_value(&Transcoder::new(deserializer).with_binary(self.binary)).map_err(|e| de::Error::custom(e.to_string()))
+    }
+}
+
+/// Writes whatever a deserializer reads as MsgPack, decoding binary values from base64 strings
+struct MsgPackWriter<'a> {
+    buf: &'a mut Vec<u8>
+}
+
+impl<'a> MsgPackWriter<'a> {
+    fn done<T, R, E>(result: Result<T, R>) -> Result<(), E> where R: fmt::Display, E: de::Error {
+        result.map(|_| ()).map_err(|e| E::custom(e.to_string()))
+    }
+}
+
+impl<'a> DeserializeSeed for MsgPackWriter<'a> {
+    type Value = ();
+
+    fn deserialize<D>(self, deserializer: D) -> Result<(), D::Error> where D: Deserializer {
+        deserializer.deserialize(self)
+    }
+}
+
+impl<'a> Visitor for MsgPackWriter<'a> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("any value")
+    }
+
+    fn visit_bool<E>(self, value: bool) -> Result<(), E> where E: de::Error {
+        Self::done(encode::write_bool(self.buf, value))
+    }
+
+    fn visit_i64<E>(self, value: i64) -> Result<(), E> where E: de::Error {
+        Self::done(encode::write_sint(self.buf, value))
+    }
+
+    fn visit_u64<E>(self, value: u64) -> Result<(), E> where E: de::Error {
+        Self::done(encode::write_uint(self.buf, value))
+    }
+
+    fn visit_f64<E>(self, value: f64) -> Result<(), E> where E: de::Error {
+        Self::done(encode::write_f64(self.buf, value))
+    }
+
+    fn visit_str<E>(self, value: &str) -> Result<(), E> where E: de::Error {
+        match binary_from_str(value) {
+            Some(bytes) => Self::done(encode::write_bin(self.buf, &bytes)),
+            None => Self::done(encode::write_str(self.buf, value))
+        }
+    }
+
+    fn visit_unit<E>(self) -> Result<(), E> where E: de::Error {
+        Self::done(encode::write_nil(self.buf))
+    }
+
+    fn visit_seq<V>(self, mut visitor: V) -> Result<(), V::Error> where V: SeqVisitor {
+        let mut elements = Vec::new();
+        let mut len = 0;
+        while let Some(()) = try!(visitor.visit_seed(MsgPackWriter { buf: &mut elements })) {
+            len += 1;
+        }
+        try!(Self::done(encode::write_array_len(self.buf, len)));
+        self.buf.extend_from_slice(&elements);
+        Ok(())
+    }
+
+    fn visit_map<V>(self, mut visitor: V) -> Result<(), V::Error> where V: MapVisitor {
+        let mut entries = Vec::new();
+        let mut len = 0;
+        while let Some(key) = try!(visitor.visit_key::<String>()) {
+            try!(Self::done(encode::write_str(&mut entries, &key)));
+            try!(visitor.visit_value_seed(MsgPackWriter { buf: &mut entries }));
+            len += 1;
+        }
+        try!(Self::done(encode::write_map_len(self.buf, len)));
+        self.buf.extend_from_slice(&entries);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{json_to_msgpack, msgpack_to_json};
+    use rmp_serde::Serializer;
+    use serde::Serialize;
+    use messages::Value;
+
+    #[test]
+    fn round_trip() {
+        let json = r#"[1,-2,18446744073709551615,1.5,"héllo ✓",null,{"nested":[true,false]}]"#;
+        let data = json_to_msgpack(json).unwrap();
+        assert_eq!(msgpack_to_json(&data).unwrap(), json);
+    }
+
+    #[test]
+    fn matches_the_msgpack_serializer() {
+        let value = vec![Value::Integer(300), Value::String("été".to_string()), Value::List(vec![Value::Boolean(true)])];
+        let mut expected = Vec::new();
+        value.serialize(&mut Serializer::new(&mut expected)).unwrap();
+        assert_eq!(json_to_msgpack(r#"[300,"été",[true]]"#).unwrap(), expected);
+    }
+
+    #[test]
+    fn binary_values() {
+        let json = "[\"\\u0000AAEC\",\"plain\"]";
+        let data = json_to_msgpack(json).unwrap();
+        assert_eq!(data, vec![0x92, 0xc4, 0x03, 0x00, 0x01, 0x02, 0xa5, b'p', b'l', b'a', b'i', b'n']);
+        assert_eq!(msgpack_to_json(&data).unwrap(), json);
+    }
+
+    #[test]
+    fn invalid_input() {
+        assert!(json_to_msgpack("[1, 2").is_err());
+        assert!(json_to_msgpack("[1] trailing").is_err());
+        assert!(msgpack_to_json(&[0x92, 0x01]).is_err());
+    }
+}
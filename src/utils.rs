@@ -1,7 +1,10 @@
 use rmp::Marker;
 use rmp::encode::{ValueWriteError, write_map_len, write_str};
 use rmp_serde::encode::VariantWriter;
+use messages::{Dict, List, Value};
+use serde_json;
 use std::io::Write;
+use std::time::Duration;
 
 
 pub struct StructMapWriter;
@@ -19,3 +22,124 @@ impl VariantWriter for StructMapWriter {
         write_str(wr, _key)
     }
 }
+
+static BASE64_ALPHABET: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Encodes binary data with the standard base64 alphabet, so that it can be carried in a string `Value`
+pub fn base64_encode(data: &[u8]) -> String {
+    let mut result = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = ((b[0] as u32) << 16) | ((b[1] as u32) << 8) | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                result.push(BASE64_ALPHABET[((n >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                result.push('=');
+            }
+        }
+    }
+    result
+}
+
+/// Decodes a string produced by `base64_encode()`, returning `None` if it isn't valid base64
+pub fn base64_decode(data: &str) -> Option<Vec<u8>> {
+    let data = data.trim_end_matches('=').as_bytes();
+    let mut result = Vec::with_capacity(data.len() * 3 / 4);
+    for chunk in data.chunks(4) {
+        if chunk.len() == 1 {
+            return None;
+        }
+        let mut n = 0u32;
+        for (i, c) in chunk.iter().enumerate() {
+            let value = match BASE64_ALPHABET.iter().position(|a| a == c) {
+                Some(value) => value as u32,
+                None => return None
+            };
+            n |= value << (18 - 6 * i);
+        }
+        for i in 0..chunk.len() - 1 {
+            result.push((n >> (16 - 8 * i)) as u8);
+        }
+    }
+    Some(result)
+}
+
+pub fn as_millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + (duration.subsec_nanos() / 1000000) as u64
+}
+
+/// Checks whether `uri` is `prefix` itself or a URI below it, so `com.myapp.status` is under
+/// `com.myapp` but `com.myapplication` isn't.
+pub fn under_prefix(uri: &str, prefix: &str) -> bool {
+    uri == prefix || (uri.starts_with(prefix) && uri[prefix.len()..].starts_with('.'))
+}
+
+/// Writes the value in a form that doesn't depend on the order of dictionary keys, so that calls
+/// with the same arguments always produce the same cache key.
+fn write_canonical(value: &Value, out: &mut String) {
+    match *value {
+        Value::Dict(ref dict) => write_canonical_dict(dict, out),
+        Value::List(ref list) => {
+            out.push('[');
+            for (i, item) in list.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical(item, out);
+            }
+            out.push(']');
+        },
+        ref scalar => out.push_str(&serde_json::to_string(scalar).unwrap())
+    }
+}
+
+fn write_canonical_dict(dict: &Dict, out: &mut String) {
+    let mut keys: Vec<&String> = dict.keys().collect();
+    keys.sort();
+    out.push('{');
+    for (i, key) in keys.into_iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        out.push_str(&serde_json::to_string(key).unwrap());
+        out.push(':');
+        write_canonical(&dict[key], out);
+    }
+    out.push('}');
+}
+
+/// Builds a string that identifies a call's arguments, regardless of the order of their keys
+pub fn canonical_key(args: &Option<List>, kwargs: &Option<Dict>) -> String {
+    let mut key = String::new();
+    match *args {
+        Some(ref args) => write_canonical(&Value::List(args.clone()), &mut key),
+        None => key.push_str("[]")
+    }
+    match *kwargs {
+        Some(ref kwargs) => write_canonical_dict(kwargs, &mut key),
+        None => key.push_str("{}")
+    }
+    key
+}
+
+#[cfg(test)]
+mod test {
+    use super::canonical_key;
+    use messages::Value;
+    use std::collections::HashMap;
+
+    #[test]
+    fn keys_ignore_dict_order() {
+        let mut first = HashMap::new();
+        let mut second = HashMap::new();
+        for i in 0..20 {
+            first.insert(format!("key{}", i), Value::Integer(i));
+        }
+        for i in (0..20).rev() {
+            second.insert(format!("key{}", i), Value::Integer(i));
+        }
+        assert_eq!(canonical_key(&None, &Some(first)), canonical_key(&None, &Some(second)));
+        assert!(canonical_key(&Some(vec![Value::Integer(1)]), &None) != canonical_key(&Some(vec![Value::String("1".to_string())]), &None));
+    }
+}